@@ -1,11 +1,246 @@
+pub mod server;
+
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use webrtc::{
     ice_transport::ice_candidate::RTCIceCandidate,
     peer_connection::sdp::session_description::RTCSessionDescription,
 };
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct BroadcastCandidateArgs {
     pub candidates: Vec<RTCIceCandidate>,
     pub session_description: Option<RTCSessionDescription>,
+    /// The candidates and session description encrypted by the client, for rooms with a secret.
+    /// Stored and handed out as is, since the server can't read it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sealed: Option<Vec<u8>>,
+    /// The settings the room is created with, if it doesn't exist yet. The password has to
+    /// match the room's for the announcement to be accepted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub room_settings: Option<RoomSettings>,
+    /// Free-form details about the peer, like its display name, region or version, handed out
+    /// along with its announcement so others can show who is in a room before connecting
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub metadata: BTreeMap<String, String>,
+    /// The client's signature over everything it announced, so peers can tell if the server or
+    /// anyone else replaced it. Stored and handed out as is
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<AnnouncementSignature>,
+}
+
+/// An Ed25519 signature over everything a peer announced in a room, or left for another peer
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AnnouncementSignature {
+    /// The public key the announcement was signed with
+    pub public_key: Vec<u8>,
+    pub signature: Vec<u8>,
+}
+
+/// The settings of a room, which the first peer announcing itself in it picks
+#[derive(Clone, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct RoomSettings {
+    /// The password every peer has to announce itself with
+    pub password: Option<String>,
+    /// The most peers the room may hold, if it is to hold fewer than the server allows
+    pub max_peers: Option<usize>,
+    /// Free-form details about the room, listed along with it
+    pub metadata: BTreeMap<String, String>,
+}
+
+/// Leaves out the password, so logging a room doesn't give it away
+impl std::fmt::Debug for RoomSettings {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RoomSettings")
+            .field("password", &self.password.as_ref().map(|_| "<redacted>"))
+            .field("max_peers", &self.max_peers)
+            .field("metadata", &self.metadata)
+            .finish()
+    }
+}
+
+/// Everything a peer has announced in a room
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PeerSignal {
+    pub candidates: Vec<RTCIceCandidate>,
+    pub session_description: Option<RTCSessionDescription>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sealed: Option<Vec<u8>>,
+    /// The details the peer announced about itself
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub metadata: BTreeMap<String, String>,
+    /// The peer's signature over everything it announced, only handed out along with all of it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<AnnouncementSignature>,
+    /// The revision of the peer's latest change. Passed as `since` to `/candidate`, only what
+    /// changed after it is handed out
+    #[serde(default)]
+    pub cursor: u64,
+    /// Whether the client checked the signature against the key it trusts for the peer. Set by
+    /// the client, never sent
+    #[serde(skip)]
+    pub verified: bool,
+}
+
+/// A room of a channel, as listed by the server
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RoomInfo {
+    pub name: String,
+    /// How many peers have announced themselves in the room
+    pub peer_count: usize,
+    /// Whether peers need a password to join the room
+    #[serde(default)]
+    pub password_protected: bool,
+    /// Free-form details about the room, such as the game mode of a match
+    #[serde(default)]
+    pub metadata: BTreeMap<String, String>,
+}
+
+/// What a peer leaves for another on `/offer` and `/answer`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DirectedArgs {
+    /// The session description, unless it is sealed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub session_description: Option<RTCSessionDescription>,
+    /// The session description encrypted under the room's secret, opaque to the server
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sealed: Option<Vec<u8>>,
+    /// The sender's signature over the session description, stored and handed out as is
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<AnnouncementSignature>,
+}
+
+/// A session description one peer left for another in the server's mailboxes, such as an offer
+/// meant for it alone
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DirectedSignal {
+    pub from: String,
+    /// The session description, which the client always sets once it has opened a sealed one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub session_description: Option<RTCSessionDescription>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sealed: Option<Vec<u8>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<AnnouncementSignature>,
+    /// Whether the client checked the signature against the key it trusts for the sender. Set
+    /// by the client, never sent
+    #[serde(skip)]
+    pub verified: bool,
+}
+
+/// Pushed by the server over the WebSocket of a peer in a room, as what it would otherwise
+/// poll for happens. Sent as JSON text messages
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum RoomNotice {
+    /// A peer announced itself in the room, or was already there when the socket opened
+    PeerAnnounced { peer_id: String },
+    /// A peer already in the room announced itself again, such as with more candidates
+    PeerUpdated { peer_id: String },
+    /// A peer withdrew its announcement from the room, or it went stale
+    PeerWithdrawn { peer_id: String },
+    /// A peer left an offer for this peer
+    Offer(DirectedSignal),
+    /// A peer left an answer for this peer
+    Answer(DirectedSignal),
+}
+
+/// Sent by the server as the Server-Sent Events of a room, as peers come and go
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum RoomEvent {
+    /// A peer announced itself in the room, or was already there when the events were
+    /// subscribed to
+    PeerJoined { peer_id: String },
+    /// A peer already in the room announced itself again, such as with more candidates
+    PeerUpdated { peer_id: String },
+    /// A peer withdrew its announcement from the room, or it went stale
+    PeerLeft { peer_id: String },
+}
+
+/// The version of the signaling protocol spoken by this server. Clients send theirs in the
+/// `PROTOCOL_VERSION_HEADER` header
+pub const PROTOCOL_VERSION: u32 = 1;
+pub const PROTOCOL_VERSION_HEADER: &str = "X-Signal-Protocol-Version";
+
+/// What the server answers on `/version`, so clients only use the features it supports
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ServerInfo {
+    /// The version of the signaling protocol the server speaks, see `PROTOCOL_VERSION`
+    pub protocol_version: u32,
+    /// The version of the `signal_server` crate the server runs
+    pub server_version: String,
+    pub capabilities: Vec<Capability>,
+}
+
+/// A feature added to the signaling server after its first release, which older servers lack
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Capability {
+    /// Pushes room notices over the WebSocket on `/ws`
+    WebSocket,
+    /// Holds offers and answers left for a single peer on `/offer` and `/answer`
+    DirectedMailbox,
+    /// Streams room events as Server-Sent Events on `/events`
+    EventStream,
+    /// Holds `/wait_for_peer` until there is a peer in the room
+    LongPoll,
+    /// Takes deflated announcements, and deflates the announcements it hands out
+    Compression,
+    /// Hands out only what a peer announced after `since` on `/candidate`
+    IncrementalFetch,
+    /// Hands out a session token for each announced peer, see `SESSION_TOKEN_HEADER`
+    SessionToken,
+    /// A capability of a newer server which this version doesn't know
+    #[serde(other)]
+    Unknown,
+}
+
+/// The header a client may carry the server's auth token in, instead of an
+/// `Authorization: Bearer <token>` header
+pub const AUTH_TOKEN_HEADER: &str = "X-Signal-Token";
+
+/// The header a client presents the password of a room in, when it reads from the room rather
+/// than announcing itself to it
+pub const ROOM_PASSWORD_HEADER: &str = "X-Room-Password";
+
+/// The header a client presents the session token of a peer in, when it updates or withdraws
+/// the peer's announcement. Once a peer is announced, only requests presenting its token may
+/// change it, so no one else can take over its peer id. A peer the server doesn't know, such as
+/// after the server restarted, may be announced with the token handed out for it before, so the
+/// client resumes its session, but a token the server can't verify is refused
+pub const SESSION_TOKEN_HEADER: &str = "X-Signal-Session";
+
+/// What the server answers an announcement with
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AnnounceResponse {
+    /// The token to present in the `SESSION_TOKEN_HEADER` on every later update of the peer
+    pub session_token: String,
+    /// Whether the server already had the peer's announcement, and so kept the candidates it
+    /// announced before. Servers from before it never say so
+    #[serde(default)]
+    pub known: bool,
+}
+
+/// Why the server refused a request. Sent as the JSON body of the error response
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "reason", rename_all = "snake_case")]
+pub enum SignalRejection {
+    /// The room already holds the maximum number of peers
+    RoomFull,
+    /// The peer is not allowed to use this server
+    Banned,
+    /// The room is password protected, and the given password was wrong
+    WrongPassword,
+    /// The channel already holds the maximum number of rooms
+    QuotaExceeded,
+    /// The client speaks a different version of the signaling protocol
+    VersionMismatch { server_version: u32 },
+    /// The server requires an auth token, and the request carried none or a wrong one
+    Unauthorized,
+    /// The peer is already announced, and the request didn't present its session token
+    SessionMismatch,
+    /// The recipient, or the room, already has the maximum number of offers and answers waiting
+    /// to be fetched
+    MailboxFull,
 }
@@ -0,0 +1,1464 @@
+use crate::{
+    AnnounceResponse, AnnouncementSignature, BroadcastCandidateArgs, Capability, DirectedArgs,
+    DirectedSignal, PeerSignal, RoomEvent, RoomInfo, RoomNotice, RoomSettings, ServerInfo,
+    SignalRejection, AUTH_TOKEN_HEADER, PROTOCOL_VERSION, PROTOCOL_VERSION_HEADER,
+    ROOM_PASSWORD_HEADER, SESSION_TOKEN_HEADER,
+};
+use hmac::{Hmac, Mac};
+use rocket::{
+    catch, catchers,
+    data::{self, Data, FromData, Limits},
+    delete,
+    fairing::AdHoc,
+    futures::{SinkExt, StreamExt},
+    get,
+    http::{ContentType, Status},
+    post,
+    request::{FromRequest, Outcome},
+    response::{
+        self,
+        status::{BadRequest, Custom, NotFound},
+        stream::{Event, EventStream},
+        Response,
+    },
+    routes,
+    serde::{json::Json, Serialize},
+    tokio::sync::{broadcast, RwLock},
+    Build, Request, Responder, Rocket, Shutdown, State,
+};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    sync::Arc,
+};
+use subtle::ConstantTimeEq;
+use uuid::Uuid;
+use webrtc::{
+    ice_transport::ice_candidate::RTCIceCandidate,
+    peer_connection::sdp::session_description::RTCSessionDescription,
+};
+
+fn get_now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+#[derive(Debug)]
+struct IceCandidateWithInitTime {
+    /// The announced candidates, with the revision each was announced in
+    candidate: Vec<(RTCIceCandidate, u64)>,
+    session_description: Option<RTCSessionDescription>,
+    sealed: Option<Vec<u8>>,
+    metadata: BTreeMap<String, String>,
+    signature: Option<AnnouncementSignature>,
+    /// The revision the session description, the sealed announcement or the metadata last
+    /// changed in
+    description_revision: u64,
+    init_time: u64,
+}
+
+impl Default for IceCandidateWithInitTime {
+    fn default() -> Self {
+        Self {
+            session_description: None,
+            candidate: Vec::new(),
+            sealed: None,
+            metadata: BTreeMap::new(),
+            signature: None,
+            description_revision: 0,
+            init_time: get_now(),
+        }
+    }
+}
+
+impl IceCandidateWithInitTime {
+    /// The revision of the latest change to the announcement
+    fn revision(&self) -> u64 {
+        self.candidate
+            .iter()
+            .map(|(_, revision)| *revision)
+            .fold(self.description_revision, u64::max)
+    }
+}
+
+/// Whether two session descriptions are the same, since re-announcements resend it unchanged
+fn same_description(a: &Option<RTCSessionDescription>, b: &Option<RTCSessionDescription>) -> bool {
+    match (a, b) {
+        (Some(a), Some(b)) => a.sdp_type == b.sdp_type && a.sdp == b.sdp,
+        (a, b) => a.is_none() && b.is_none(),
+    }
+}
+
+/// The settings a room was created with, keeping only a salted hash of its password
+#[derive(Clone, Default)]
+struct StoredSettings {
+    password: Option<PasswordDigest>,
+    max_peers: Option<usize>,
+    metadata: BTreeMap<String, String>,
+}
+
+impl From<&RoomSettings> for StoredSettings {
+    fn from(settings: &RoomSettings) -> Self {
+        Self {
+            password: settings.password.as_deref().map(PasswordDigest::new),
+            max_peers: settings.max_peers,
+            metadata: settings.metadata.clone(),
+        }
+    }
+}
+
+impl StoredSettings {
+    /// Whether `password` opens the room, which any does if the room has none
+    fn admits(&self, password: Option<&str>) -> bool {
+        self.password
+            .as_ref()
+            .is_none_or(|digest| password.is_some_and(|password| digest.matches(password)))
+    }
+}
+
+/// A salted SHA-256 hash of a room password, so the server doesn't hold on to the password
+#[derive(Clone)]
+struct PasswordDigest {
+    salt: [u8; 16],
+    hash: [u8; 32],
+}
+
+impl PasswordDigest {
+    fn new(password: &str) -> Self {
+        let salt = Uuid::new_v4().into_bytes();
+        Self {
+            salt,
+            hash: Self::hash(&salt, password),
+        }
+    }
+
+    fn hash(salt: &[u8], password: &str) -> [u8; 32] {
+        Sha256::new()
+            .chain_update(salt)
+            .chain_update(password.as_bytes())
+            .finalize()
+            .into()
+    }
+
+    /// Compared in constant time, so the password can't be guessed from how long it takes
+    fn matches(&self, password: &str) -> bool {
+        Self::hash(&self.salt, password).ct_eq(&self.hash).into()
+    }
+}
+
+/// The peers in each room of a channel, and the settings each room was created with
+struct SocketRooms(
+    HashMap<String, HashMap<Uuid, IceCandidateWithInitTime>>,
+    HashMap<String, StoredSettings>,
+);
+
+/// The rooms of each channel, and the revision of the latest announcement in any of them. Every
+/// announcement gets a new revision, so clients can fetch only what changed since one
+struct SocketChannels(HashMap<String, SocketRooms>, u64);
+
+type RoomMap = Arc<RwLock<SocketChannels>>;
+
+/// The session descriptions peers left for each other, until they are fetched
+#[derive(Default)]
+struct Mailbox {
+    /// The offers for each peer, with who sent them
+    offers: HashMap<Uuid, Vec<(Uuid, DirectedArgs, u64)>>,
+    /// The answer for each peer from each peer it sent an offer to
+    answers: HashMap<(Uuid, Uuid), (DirectedArgs, u64)>,
+}
+
+impl Mailbox {
+    /// Refuses new mail for `to` once it, or the whole room, has as much waiting as `config`
+    /// allows. Mail replacing some which wasn't fetched yet is always taken
+    fn admits(&self, to: Uuid, config: &ServerConfig) -> Result<(), SignalRejection> {
+        let offers_for = self.offers.get(&to).map_or(0, Vec::len);
+        let answers_for = self.answers.keys().filter(|(peer, _)| *peer == to).count();
+        let held = self.offers.values().map(Vec::len).sum::<usize>() + self.answers.len();
+        if offers_for + answers_for >= config.max_mail_per_peer || held >= config.max_mail_per_room
+        {
+            return Err(SignalRejection::MailboxFull);
+        }
+        Ok(())
+    }
+}
+
+/// The mailboxes of every room, keyed by channel and room
+type MailboxMap = Arc<RwLock<HashMap<(String, String), Mailbox>>>;
+
+/// A notice for the sockets open in a room, or only the one of `to`
+#[derive(Clone)]
+struct Notice {
+    channel: String,
+    room: String,
+    to: Option<Uuid>,
+    notice: RoomNotice,
+}
+
+/// Hands every notice to the tasks serving the open sockets and event streams
+#[derive(Clone)]
+struct Notices(broadcast::Sender<Notice>);
+
+impl Notices {
+    fn send(&self, channel: &str, room: &str, to: Option<Uuid>, notice: RoomNotice) {
+        let _ = self.0.send(Notice {
+            channel: channel.to_owned(),
+            room: room.to_owned(),
+            to,
+            notice,
+        });
+    }
+}
+
+/// Limits enforced by the signaling server
+#[derive(Clone)]
+pub struct ServerConfig {
+    /// The maximum number of peers which may announce themselves in a single room
+    pub max_peers_per_room: usize,
+    /// The maximum number of rooms a single channel may hold
+    pub max_rooms_per_channel: usize,
+    /// Peers which are refused by the server
+    pub banned_peers: HashSet<Uuid>,
+    /// If set, every request has to carry this token, either as an `Authorization: Bearer`
+    /// header or in the `AUTH_TOKEN_HEADER` header
+    pub auth_token: Option<String>,
+    /// The maximum number of offers and answers waiting for a single peer to fetch them
+    pub max_mail_per_peer: usize,
+    /// The maximum number of offers and answers waiting in a single room to be fetched
+    pub max_mail_per_room: usize,
+    /// The key session tokens are derived from, see `ServerConfig::session_token`. Random by
+    /// default, so tokens handed out before a restart only verify afterwards if the same secret
+    /// is given to the restarted server
+    pub session_secret: Vec<u8>,
+}
+
+/// Leaves out the session secret, which would let anyone reading it act as any peer
+impl std::fmt::Debug for ServerConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ServerConfig")
+            .field("max_peers_per_room", &self.max_peers_per_room)
+            .field("max_rooms_per_channel", &self.max_rooms_per_channel)
+            .field("banned_peers", &self.banned_peers)
+            .field(
+                "auth_token",
+                &self.auth_token.as_ref().map(|_| "<redacted>"),
+            )
+            .field("max_mail_per_peer", &self.max_mail_per_peer)
+            .field("max_mail_per_room", &self.max_mail_per_room)
+            .finish_non_exhaustive()
+    }
+}
+
+impl ServerConfig {
+    /// The token a client has to present to act as `peer_id` in the room, such as to update or
+    /// withdraw its announcement or to take its mail. It is an HMAC-SHA256 of the channel, room
+    /// and peer id under the session secret, so the server can check a token without having
+    /// handed it out itself, such as after restarting
+    pub fn session_token(&self, channel: &str, room: &str, peer_id: &Uuid) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.session_secret)
+            .expect("HMAC takes keys of any length");
+        // Each part is prefixed by its length, so no two rooms run together into the same input
+        for part in [channel.as_bytes(), room.as_bytes(), peer_id.as_bytes()] {
+            mac.update(&(part.len() as u64).to_be_bytes());
+            mac.update(part);
+        }
+        mac.finalize()
+            .into_bytes()
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect()
+    }
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            max_peers_per_room: 64,
+            max_rooms_per_channel: 1024,
+            banned_peers: HashSet::new(),
+            auth_token: None,
+            max_mail_per_peer: 64,
+            max_mail_per_room: 1024,
+            session_secret: [Uuid::new_v4(), Uuid::new_v4()]
+                .iter()
+                .flat_map(|uuid| uuid.into_bytes())
+                .collect(),
+        }
+    }
+}
+
+#[derive(Responder)]
+enum AnnounceError {
+    BadRequest(BadRequest<()>),
+    NotFound(NotFound<()>),
+    Rejected(Custom<Json<SignalRejection>>),
+}
+
+impl From<BadRequest<()>> for AnnounceError {
+    fn from(bad_request: BadRequest<()>) -> Self {
+        Self::BadRequest(bad_request)
+    }
+}
+
+impl From<NotFound<()>> for AnnounceError {
+    fn from(not_found: NotFound<()>) -> Self {
+        Self::NotFound(not_found)
+    }
+}
+
+impl From<SignalRejection> for AnnounceError {
+    fn from(rejection: SignalRejection) -> Self {
+        let status = match rejection {
+            SignalRejection::RoomFull => Status::Conflict,
+            SignalRejection::Banned => Status::Forbidden,
+            SignalRejection::WrongPassword => Status::Unauthorized,
+            SignalRejection::QuotaExceeded => Status::TooManyRequests,
+            SignalRejection::VersionMismatch { .. } => Status::UpgradeRequired,
+            SignalRejection::Unauthorized => Status::Unauthorized,
+            SignalRejection::SessionMismatch => Status::Forbidden,
+            SignalRejection::MailboxFull => Status::TooManyRequests,
+        };
+        Self::Rejected(Custom(status, Json(rejection)))
+    }
+}
+
+/// The protocol version sent by the client, if it sent one
+struct ClientProtocolVersion(Option<u32>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ClientProtocolVersion {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        Outcome::Success(Self(
+            request
+                .headers()
+                .get_one(PROTOCOL_VERSION_HEADER)
+                .and_then(|version| version.parse().ok()),
+        ))
+    }
+}
+
+/// The session token sent by the client, if it sent one
+struct ClientSessionToken(Option<String>);
+
+impl ClientSessionToken {
+    /// Whether the request presents `expected`, the token of the peer it acts as.
+    /// Compared in constant time, so the token can't be guessed from how long it takes
+    fn matches(&self, expected: &str) -> bool {
+        self.0
+            .as_ref()
+            .is_some_and(|token| token.as_bytes().ct_eq(expected.as_bytes()).into())
+    }
+}
+
+/// The room password sent by the client, if it sent one
+struct ClientRoomPassword(Option<String>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ClientRoomPassword {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        Outcome::Success(Self(
+            request
+                .headers()
+                .get_one(ROOM_PASSWORD_HEADER)
+                .map(str::to_owned),
+        ))
+    }
+}
+
+/// Lets a request read from the room only if the room has no password, or the request presents
+/// it. Rooms which don't exist have nothing to read
+async fn may_read(
+    room_map_state: &RoomMap,
+    channel: &str,
+    room: &str,
+    password: &ClientRoomPassword,
+) -> Result<(), SignalRejection> {
+    let room_map = room_map_state.read().await;
+    let admitted = room_map
+        .0
+        .get(channel)
+        .and_then(|rooms| rooms.1.get(room))
+        .is_none_or(|settings| settings.admits(password.0.as_deref()));
+    if admitted {
+        Ok(())
+    } else {
+        Err(SignalRejection::WrongPassword)
+    }
+}
+
+/// Lets a request act as `peer_id` in the room, such as to leave or take its mail, only if the
+/// peer is announced in it and the request presents its session token
+async fn holds_session(
+    room_map_state: &RoomMap,
+    channel: &str,
+    room: &str,
+    peer_id: Uuid,
+    session_token: &ClientSessionToken,
+    config: &ServerConfig,
+) -> Result<(), SignalRejection> {
+    let room_map = room_map_state.read().await;
+    let announced = room_map
+        .0
+        .get(channel)
+        .and_then(|rooms| rooms.0.get(room))
+        .is_some_and(|peers| peers.contains_key(&peer_id));
+    if announced && session_token.matches(&config.session_token(channel, room, &peer_id)) {
+        Ok(())
+    } else {
+        Err(SignalRejection::SessionMismatch)
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ClientSessionToken {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        Outcome::Success(Self(
+            request
+                .headers()
+                .get_one(SESSION_TOKEN_HEADER)
+                .map(str::to_owned),
+        ))
+    }
+}
+
+/// Responses smaller than this are sent as they are, even to clients which accept deflate
+const DEFLATE_THRESHOLD: usize = 512;
+
+/// The announcement of a peer, which the client may have deflated, sending it with a
+/// `Content-Encoding: deflate` header
+struct Announcement(BroadcastCandidateArgs);
+
+#[rocket::async_trait]
+impl<'r> FromData<'r> for Announcement {
+    type Error = ();
+
+    async fn from_data(request: &'r Request<'_>, data: Data<'r>) -> data::Outcome<'r, Self> {
+        let limit = request.limits().get("json").unwrap_or(Limits::JSON);
+        let body = match data.open(limit).into_bytes().await {
+            Ok(body) if body.is_complete() => body.into_inner(),
+            Ok(_) => return data::Outcome::Error((Status::PayloadTooLarge, ())),
+            Err(_) => return data::Outcome::Error((Status::BadRequest, ())),
+        };
+
+        let deflated = request
+            .headers()
+            .get_one("Content-Encoding")
+            .is_some_and(|encoding| encoding.eq_ignore_ascii_case("deflate"));
+        let body = if deflated {
+            // Inflating is held to the same limit, so a small body can't expand without bound
+            let max_size = limit.as_u64() as usize;
+            match miniz_oxide::inflate::decompress_to_vec_zlib_with_limit(&body, max_size) {
+                Ok(body) => body,
+                Err(_) => return data::Outcome::Error((Status::BadRequest, ())),
+            }
+        } else {
+            body
+        };
+
+        match rocket::serde::json::from_slice(&body) {
+            Ok(args) => data::Outcome::Success(Self(args)),
+            Err(_) => data::Outcome::Error((Status::UnprocessableEntity, ())),
+        }
+    }
+}
+
+/// Responds with `T` as JSON, deflated if the client accepts it and the body is large enough
+/// for it to pay off
+struct MaybeDeflated<T>(T);
+
+impl<'r, T: Serialize> response::Responder<'r, 'static> for MaybeDeflated<T> {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        let json =
+            rocket::serde::json::to_string(&self.0).map_err(|_| Status::InternalServerError)?;
+        let accepts_deflate = request
+            .headers()
+            .get("Accept-Encoding")
+            .flat_map(|encodings| encodings.split(','))
+            .any(|encoding| encoding.trim().starts_with("deflate"));
+
+        let mut response = Response::build();
+        response.header(ContentType::JSON);
+        if accepts_deflate && json.len() >= DEFLATE_THRESHOLD {
+            let deflated = miniz_oxide::deflate::compress_to_vec_zlib(json.as_bytes(), 6);
+            response
+                .raw_header("Content-Encoding", "deflate")
+                .sized_body(deflated.len(), std::io::Cursor::new(deflated));
+        } else {
+            response.sized_body(json.len(), std::io::Cursor::new(json));
+        }
+        response.ok()
+    }
+}
+
+/// Lets a request through if the server requires no auth token, or the request carries it
+struct Authorized;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Authorized {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let expected = request
+            .rocket()
+            .state::<ServerConfig>()
+            .and_then(|config| config.auth_token.as_deref());
+        let Some(expected) = expected else {
+            return Outcome::Success(Self);
+        };
+
+        let headers = request.headers();
+        let given = headers
+            .get_one("Authorization")
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .or_else(|| headers.get_one(AUTH_TOKEN_HEADER));
+        // Compared in constant time, so the token can't be guessed from how long it takes
+        let matches = given.is_some_and(|given| given.as_bytes().ct_eq(expected.as_bytes()).into());
+        if matches {
+            Outcome::Success(Self)
+        } else {
+            Outcome::Error((Status::Unauthorized, ()))
+        }
+    }
+}
+
+/// Answers requests refused by `Authorized` with a typed rejection
+#[catch(401)]
+fn unauthorized() -> Json<SignalRejection> {
+    Json(SignalRejection::Unauthorized)
+}
+
+/// Tells clients what this server supports. Needs no auth token, so clients can check before
+/// they are configured with one
+#[get("/version")]
+fn version() -> Json<ServerInfo> {
+    Json(ServerInfo {
+        protocol_version: PROTOCOL_VERSION,
+        server_version: env!("CARGO_PKG_VERSION").to_owned(),
+        capabilities: vec![
+            Capability::WebSocket,
+            Capability::DirectedMailbox,
+            Capability::EventStream,
+            Capability::LongPoll,
+            Capability::Compression,
+            Capability::IncrementalFetch,
+            Capability::SessionToken,
+        ],
+    })
+}
+
+/// Hands out what the peer announced, or with `since` only what changed after that revision
+#[get("/candidate?<channel>&<room>&<candidate_id>&<since>")]
+async fn get_room_candidate(
+    _auth: Authorized,
+    room_map_state: &State<RoomMap>,
+    channel: String,
+    room: String,
+    candidate_id: String,
+    since: Option<u64>,
+    password: ClientRoomPassword,
+) -> Result<MaybeDeflated<PeerSignal>, AnnounceError> {
+    let candidate_uuid = Uuid::parse_str(candidate_id.as_str()).map_err(|_| NotFound(()))?;
+    may_read(room_map_state, &channel, &room, &password).await?;
+
+    let room_map = room_map_state.read().await;
+    let rooms = room_map.0.get(channel.as_str()).ok_or(NotFound(()))?;
+    let room = rooms.0.get(room.as_str()).ok_or(NotFound(()))?;
+    let candidate = room.get(&candidate_uuid).ok_or(NotFound(()))?;
+
+    let since = since.unwrap_or(0);
+    let description_changed = candidate.description_revision > since;
+    Ok(MaybeDeflated(PeerSignal {
+        candidates: candidate
+            .candidate
+            .iter()
+            .filter(|(_, revision)| *revision > since)
+            .map(|(candidate, _)| candidate.clone())
+            .collect(),
+        session_description: candidate
+            .session_description
+            .clone()
+            .filter(|_| description_changed),
+        sealed: candidate.sealed.clone().filter(|_| description_changed),
+        metadata: if description_changed {
+            candidate.metadata.clone()
+        } else {
+            BTreeMap::new()
+        },
+        // The signature covers the whole announcement, so it's useless along with only a part
+        signature: candidate.signature.clone().filter(|_| since == 0),
+        cursor: candidate.revision(),
+        verified: false,
+    }))
+}
+
+#[get("/all_candidates?<channel>&<room>")]
+async fn get_candidates_in_room(
+    _auth: Authorized,
+    room_map_state: &State<RoomMap>,
+    channel: String,
+    room: String,
+    password: ClientRoomPassword,
+) -> Result<Json<Vec<String>>, AnnounceError> {
+    may_read(room_map_state, &channel, &room, &password).await?;
+    let room_map = room_map_state.read().await;
+    let rooms = room_map.0.get(channel.as_str()).ok_or(NotFound(()))?;
+    let room = rooms.0.get(room.as_str()).ok_or(NotFound(()))?;
+
+    Ok(Json(room.keys().map(|v| v.to_string()).collect()))
+}
+
+#[get("/rooms?<channel>")]
+async fn get_rooms(
+    _auth: Authorized,
+    room_map_state: &State<RoomMap>,
+    channel: String,
+) -> Result<Json<Vec<RoomInfo>>, NotFound<()>> {
+    let room_map = room_map_state.read().await;
+    let rooms = room_map.0.get(channel.as_str()).ok_or(NotFound(()))?;
+
+    Ok(Json(
+        rooms
+            .0
+            .iter()
+            .map(|(name, peers)| {
+                let settings = rooms.1.get(name);
+                RoomInfo {
+                    name: name.clone(),
+                    peer_count: peers.len(),
+                    password_protected: settings
+                        .is_some_and(|settings| settings.password.is_some()),
+                    metadata: settings
+                        .map(|settings| settings.metadata.clone())
+                        .unwrap_or_default(),
+                }
+            })
+            .collect(),
+    ))
+}
+
+#[post(
+    "/announce?<channel>&<room>&<peer_id>",
+    format = "json",
+    data = "<candidate_args>"
+)]
+// Every argument is a request guard or parameter picked by the route
+#[allow(clippy::too_many_arguments)]
+async fn broadcast_candidate(
+    _auth: Authorized,
+    channel: String,
+    room: String,
+    peer_id: String,
+    candidate_args: Announcement,
+    protocol_version: ClientProtocolVersion,
+    session_token: ClientSessionToken,
+    room_map_state: &State<RoomMap>,
+    config: &State<ServerConfig>,
+    notices: &State<Notices>,
+) -> Result<Json<AnnounceResponse>, AnnounceError> {
+    if protocol_version
+        .0
+        .is_some_and(|version| version != PROTOCOL_VERSION)
+    {
+        return Err(SignalRejection::VersionMismatch {
+            server_version: PROTOCOL_VERSION,
+        }
+        .into());
+    }
+
+    let uuid =
+        Uuid::parse_str(peer_id.as_str()).map_err(|_| AnnounceError::BadRequest(BadRequest(())))?;
+    if config.banned_peers.contains(&uuid) {
+        return Err(SignalRejection::Banned.into());
+    }
+
+    let mut room_map = room_map_state.write().await;
+    let SocketChannels(channels, latest_revision) = &mut *room_map;
+
+    let channel_entry = channels
+        .entry(channel.clone())
+        .or_insert_with(|| SocketRooms(HashMap::new(), HashMap::new()));
+
+    if !channel_entry.0.contains_key(room.as_str())
+        && channel_entry.0.len() >= config.max_rooms_per_channel
+    {
+        return Err(SignalRejection::QuotaExceeded.into());
+    }
+
+    let mut args = candidate_args.0;
+    let announced_settings = args.room_settings.take().unwrap_or_default();
+    let is_new_room = channel_entry
+        .0
+        .get(room.as_str())
+        .is_none_or(HashMap::is_empty);
+    if is_new_room {
+        channel_entry
+            .1
+            .insert(room.clone(), StoredSettings::from(&announced_settings));
+    }
+    let settings = channel_entry
+        .1
+        .get(room.as_str())
+        .cloned()
+        .unwrap_or_default();
+
+    if !settings.admits(announced_settings.password.as_deref()) {
+        return Err(SignalRejection::WrongPassword.into());
+    }
+
+    let room_entry = channel_entry
+        .0
+        .entry(room.clone())
+        .or_insert_with(HashMap::new);
+
+    let max_peers = settings
+        .max_peers
+        .map_or(config.max_peers_per_room, |max_peers| {
+            max_peers.min(config.max_peers_per_room)
+        });
+    if !room_entry.contains_key(&uuid) && room_entry.len() >= max_peers {
+        return Err(SignalRejection::RoomFull.into());
+    }
+    // Peers which aren't announced yet are taken up without a token, or with the one handed out
+    // before, such as before the server restarted. Tokens which don't verify are refused either
+    // way, rather than trusted
+    let known = room_entry.contains_key(&uuid);
+    let token = config.session_token(&channel, &room, &uuid);
+    if (known || session_token.0.is_some()) && !session_token.matches(&token) {
+        return Err(SignalRejection::SessionMismatch.into());
+    }
+
+    *latest_revision += 1;
+    let revision = *latest_revision;
+
+    let entry = room_entry.entry(uuid).or_default();
+    // Re-announcements, like heartbeats, resend the candidates which are already known
+    for new_candidate in args.candidates {
+        if !entry
+            .candidate
+            .iter()
+            .any(|(existing, _)| *existing == new_candidate)
+        {
+            entry.candidate.push((new_candidate, revision));
+        }
+    }
+    if !same_description(&entry.session_description, &args.session_description)
+        || entry.sealed != args.sealed
+        || entry.metadata != args.metadata
+        || entry.signature != args.signature
+    {
+        entry.description_revision = revision;
+    }
+    entry.session_description = args.session_description;
+    entry.sealed = args.sealed;
+    entry.metadata = args.metadata;
+    entry.signature = args.signature;
+    // Every announcement keeps the peer in the room for another 60 seconds
+    entry.init_time = get_now();
+
+    let notice = if known {
+        RoomNotice::PeerUpdated { peer_id }
+    } else {
+        RoomNotice::PeerAnnounced { peer_id }
+    };
+    let response = AnnounceResponse {
+        session_token: token,
+        known,
+    };
+    notices.send(&channel, &room, None, notice);
+
+    Ok(Json(response))
+}
+
+#[delete("/announce?<channel>&<room>&<peer_id>")]
+// Every argument is a request guard or parameter picked by the route
+#[allow(clippy::too_many_arguments)]
+async fn withdraw_candidate(
+    _auth: Authorized,
+    channel: String,
+    room: String,
+    peer_id: String,
+    session_token: ClientSessionToken,
+    room_map_state: &State<RoomMap>,
+    notices: &State<Notices>,
+    config: &State<ServerConfig>,
+) -> Result<(), AnnounceError> {
+    let uuid =
+        Uuid::parse_str(peer_id.as_str()).map_err(|_| AnnounceError::BadRequest(BadRequest(())))?;
+
+    let mut room_map = room_map_state.write().await;
+    if let Some(rooms) = room_map.0.get_mut(channel.as_str()) {
+        if let Some(peers) = rooms.0.get_mut(room.as_str()) {
+            if peers.contains_key(&uuid)
+                && !session_token.matches(&config.session_token(&channel, &room, &uuid))
+            {
+                return Err(SignalRejection::SessionMismatch.into());
+            }
+            if peers.remove(&uuid).is_some() {
+                notices.send(&channel, &room, None, RoomNotice::PeerWithdrawn { peer_id });
+            }
+            if peers.is_empty() {
+                rooms.0.remove(room.as_str());
+                rooms.1.remove(room.as_str());
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// What `from` left for a peer, as handed out to it
+fn directed_signal(from: Uuid, args: DirectedArgs) -> DirectedSignal {
+    DirectedSignal {
+        from: from.to_string(),
+        session_description: args.session_description,
+        sealed: args.sealed,
+        signature: args.signature,
+        verified: false,
+    }
+}
+
+fn parse_peers(from: &str, to: &str) -> Result<(Uuid, Uuid), BadRequest<()>> {
+    let from = Uuid::parse_str(from).map_err(|_| BadRequest(()))?;
+    let to = Uuid::parse_str(to).map_err(|_| BadRequest(()))?;
+    Ok((from, to))
+}
+
+#[post(
+    "/offer?<channel>&<room>&<from>&<to>",
+    format = "json",
+    data = "<offer>"
+)]
+// Every argument is a request guard or parameter picked by the route
+#[allow(clippy::too_many_arguments)]
+async fn send_offer(
+    _auth: Authorized,
+    channel: String,
+    room: String,
+    from: String,
+    to: String,
+    offer: Json<DirectedArgs>,
+    session_token: ClientSessionToken,
+    password: ClientRoomPassword,
+    room_map_state: &State<RoomMap>,
+    mailbox_state: &State<MailboxMap>,
+    notices: &State<Notices>,
+    config: &State<ServerConfig>,
+) -> Result<(), AnnounceError> {
+    let (from, to) = parse_peers(&from, &to)?;
+    may_read(room_map_state, &channel, &room, &password).await?;
+    holds_session(
+        room_map_state,
+        &channel,
+        &room,
+        from,
+        &session_token,
+        config,
+    )
+    .await?;
+    let offer = offer.into_inner();
+
+    let mut mailboxes = mailbox_state.write().await;
+    let mailbox = mailboxes
+        .entry((channel.clone(), room.clone()))
+        .or_default();
+    // A newer offer from the same peer replaces the one which wasn't fetched yet
+    let replaces = mailbox
+        .offers
+        .get(&to)
+        .is_some_and(|offers| offers.iter().any(|(sender, _, _)| *sender == from));
+    if !replaces {
+        mailbox.admits(to, config)?;
+    }
+    let offers = mailbox.offers.entry(to).or_default();
+    offers.retain(|(sender, _, _)| *sender != from);
+    offers.push((from, offer.clone(), get_now()));
+    drop(mailboxes);
+
+    let signal = directed_signal(from, offer);
+    notices.send(&channel, &room, Some(to), RoomNotice::Offer(signal));
+
+    Ok(())
+}
+
+/// Hands out the offers left for `peer_id`, which are removed from its mailbox. Only the client
+/// holding the peer's session can take them, so no one else can drain its mailbox
+#[get("/offers?<channel>&<room>&<peer_id>")]
+// Every argument is a request guard or parameter picked by the route
+#[allow(clippy::too_many_arguments)]
+async fn fetch_offers(
+    _auth: Authorized,
+    channel: String,
+    room: String,
+    peer_id: String,
+    session_token: ClientSessionToken,
+    password: ClientRoomPassword,
+    room_map_state: &State<RoomMap>,
+    mailbox_state: &State<MailboxMap>,
+    config: &State<ServerConfig>,
+) -> Result<Json<Vec<DirectedSignal>>, AnnounceError> {
+    let uuid = Uuid::parse_str(peer_id.as_str()).map_err(|_| BadRequest(()))?;
+    may_read(room_map_state, &channel, &room, &password).await?;
+    holds_session(
+        room_map_state,
+        &channel,
+        &room,
+        uuid,
+        &session_token,
+        config,
+    )
+    .await?;
+
+    let mut mailboxes = mailbox_state.write().await;
+    let offers = mailboxes
+        .get_mut(&(channel, room))
+        .and_then(|mailbox| mailbox.offers.remove(&uuid))
+        .unwrap_or_default();
+
+    Ok(Json(
+        offers
+            .into_iter()
+            .map(|(from, offer, _)| directed_signal(from, offer))
+            .collect(),
+    ))
+}
+
+#[post(
+    "/answer?<channel>&<room>&<from>&<to>",
+    format = "json",
+    data = "<answer>"
+)]
+// Every argument is a request guard or parameter picked by the route
+#[allow(clippy::too_many_arguments)]
+async fn send_answer(
+    _auth: Authorized,
+    channel: String,
+    room: String,
+    from: String,
+    to: String,
+    answer: Json<DirectedArgs>,
+    session_token: ClientSessionToken,
+    password: ClientRoomPassword,
+    room_map_state: &State<RoomMap>,
+    mailbox_state: &State<MailboxMap>,
+    notices: &State<Notices>,
+    config: &State<ServerConfig>,
+) -> Result<(), AnnounceError> {
+    let (from, to) = parse_peers(&from, &to)?;
+    may_read(room_map_state, &channel, &room, &password).await?;
+    holds_session(
+        room_map_state,
+        &channel,
+        &room,
+        from,
+        &session_token,
+        config,
+    )
+    .await?;
+    let answer = answer.into_inner();
+
+    let mut mailboxes = mailbox_state.write().await;
+    let mailbox = mailboxes
+        .entry((channel.clone(), room.clone()))
+        .or_default();
+    if !mailbox.answers.contains_key(&(to, from)) {
+        mailbox.admits(to, config)?;
+    }
+    mailbox
+        .answers
+        .insert((to, from), (answer.clone(), get_now()));
+    drop(mailboxes);
+
+    let signal = directed_signal(from, answer);
+    notices.send(&channel, &room, Some(to), RoomNotice::Answer(signal));
+
+    Ok(())
+}
+
+/// Hands out the answer `from` left for `to`, which is removed from its mailbox
+#[get("/answer?<channel>&<room>&<from>&<to>")]
+// Every argument is a request guard or parameter picked by the route
+#[allow(clippy::too_many_arguments)]
+async fn fetch_answer(
+    _auth: Authorized,
+    channel: String,
+    room: String,
+    from: String,
+    to: String,
+    session_token: ClientSessionToken,
+    password: ClientRoomPassword,
+    room_map_state: &State<RoomMap>,
+    mailbox_state: &State<MailboxMap>,
+    config: &State<ServerConfig>,
+) -> Result<Json<DirectedSignal>, AnnounceError> {
+    let (from, to) = parse_peers(&from, &to).map_err(|_| NotFound(()))?;
+    may_read(room_map_state, &channel, &room, &password).await?;
+    holds_session(room_map_state, &channel, &room, to, &session_token, config).await?;
+
+    let mut mailboxes = mailbox_state.write().await;
+    let (answer, _) = mailboxes
+        .get_mut(&(channel, room))
+        .and_then(|mailbox| mailbox.answers.remove(&(to, from)))
+        .ok_or(AnnounceError::NotFound(NotFound(())))?;
+
+    Ok(Json(directed_signal(from, answer)))
+}
+
+/// Keeps a WebSocket open for `peer_id`, pushing the notices of the room to it instead of it
+/// polling for them. The peers already in the room are pushed first
+#[get("/ws?<channel>&<room>&<peer_id>")]
+// Every argument is a request guard or parameter picked by the route
+#[allow(clippy::too_many_arguments)]
+async fn room_socket(
+    _auth: Authorized,
+    ws: rocket_ws::WebSocket,
+    channel: String,
+    room: String,
+    peer_id: String,
+    session_token: ClientSessionToken,
+    password: ClientRoomPassword,
+    room_map_state: &State<RoomMap>,
+    notices: &State<Notices>,
+    config: &State<ServerConfig>,
+) -> Result<rocket_ws::Channel<'static>, AnnounceError> {
+    let uuid = Uuid::parse_str(peer_id.as_str()).map_err(|_| BadRequest(()))?;
+    may_read(room_map_state, &channel, &room, &password).await?;
+    holds_session(
+        room_map_state,
+        &channel,
+        &room,
+        uuid,
+        &session_token,
+        config,
+    )
+    .await?;
+
+    // Subscribed before the snapshot is taken, so no peer falls in between
+    let mut receiver = notices.0.subscribe();
+    let present = present_peers(room_map_state, &channel, &room).await;
+
+    Ok(ws.channel(move |mut stream| {
+        Box::pin(async move {
+            for peer_id in present {
+                let notice = RoomNotice::PeerAnnounced { peer_id };
+                stream.send(to_message(&notice)).await?;
+            }
+
+            loop {
+                rocket::tokio::select! {
+                    notice = receiver.recv() => match notice {
+                        Ok(notice) => {
+                            let for_us = notice.channel == channel
+                                && notice.room == room
+                                && notice.to.is_none_or(|to| to == uuid);
+                            if for_us {
+                                stream.send(to_message(&notice.notice)).await?;
+                            }
+                        }
+                        Err(broadcast::error::RecvError::Lagged(_)) => {}
+                        Err(broadcast::error::RecvError::Closed) => break,
+                    },
+                    message = stream.next() => match message {
+                        Some(Ok(rocket_ws::Message::Close(_))) | Some(Err(_)) | None => break,
+                        Some(Ok(_)) => {}
+                    },
+                }
+            }
+
+            Ok(())
+        })
+    }))
+}
+
+/// Streams the peers coming and going in the room as Server-Sent Events, starting with the
+/// peers already in it
+#[get("/events?<channel>&<room>")]
+async fn room_events(
+    _auth: Authorized,
+    channel: String,
+    room: String,
+    password: ClientRoomPassword,
+    room_map_state: &State<RoomMap>,
+    notices: &State<Notices>,
+    mut shutdown: Shutdown,
+) -> Result<EventStream![], AnnounceError> {
+    may_read(room_map_state, &channel, &room, &password).await?;
+    // Subscribed before the snapshot is taken, so no peer falls in between
+    let mut receiver = notices.0.subscribe();
+    let present = present_peers(room_map_state, &channel, &room).await;
+
+    Ok(EventStream! {
+        for peer_id in present {
+            yield Event::json(&RoomEvent::PeerJoined { peer_id });
+        }
+
+        loop {
+            let notice = rocket::tokio::select! {
+                notice = receiver.recv() => match notice {
+                    Ok(notice) => notice,
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => break,
+                },
+                _ = &mut shutdown => break,
+            };
+            if notice.channel != channel || notice.room != room {
+                continue;
+            }
+
+            let event = match notice.notice {
+                RoomNotice::PeerAnnounced { peer_id } => RoomEvent::PeerJoined { peer_id },
+                RoomNotice::PeerUpdated { peer_id } => RoomEvent::PeerUpdated { peer_id },
+                RoomNotice::PeerWithdrawn { peer_id } => RoomEvent::PeerLeft { peer_id },
+                RoomNotice::Offer(_) | RoomNotice::Answer(_) => continue,
+            };
+            yield Event::json(&event);
+        }
+    })
+}
+
+/// The longest a request to `/wait_for_peer` is held before it is answered without any peers
+const LONG_POLL_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+
+/// Answers with the peers in the room other than `peer_id`, holding the request until there is
+/// at least one, for up to 30 seconds, after which it is answered with none
+#[get("/wait_for_peer?<channel>&<room>&<peer_id>")]
+async fn wait_for_peer(
+    _auth: Authorized,
+    channel: String,
+    room: String,
+    peer_id: String,
+    password: ClientRoomPassword,
+    room_map_state: &State<RoomMap>,
+    notices: &State<Notices>,
+) -> Result<Json<Vec<String>>, AnnounceError> {
+    may_read(room_map_state, &channel, &room, &password).await?;
+    let mut receiver = notices.0.subscribe();
+    let others = |peers: Vec<String>| {
+        peers
+            .into_iter()
+            .filter(|other| *other != peer_id)
+            .collect::<Vec<_>>()
+    };
+
+    let wait = async {
+        loop {
+            let peers = others(present_peers(room_map_state, &channel, &room).await);
+            if !peers.is_empty() {
+                return peers;
+            }
+            // Any change may be the peer, which the next check finds
+            if let Err(broadcast::error::RecvError::Closed) = receiver.recv().await {
+                return peers;
+            }
+        }
+    };
+
+    Ok(Json(
+        rocket::tokio::time::timeout(LONG_POLL_TIMEOUT, wait)
+            .await
+            .unwrap_or_default(),
+    ))
+}
+
+/// The ids of the peers announced in the room
+async fn present_peers(room_map_state: &RoomMap, channel: &str, room: &str) -> Vec<String> {
+    let room_map = room_map_state.read().await;
+    room_map
+        .0
+        .get(channel)
+        .and_then(|rooms| rooms.0.get(room))
+        .map(|peers| peers.keys().map(Uuid::to_string).collect())
+        .unwrap_or_default()
+}
+
+fn to_message(notice: &RoomNotice) -> rocket_ws::Message {
+    rocket_ws::Message::Text(
+        rocket::serde::json::to_string(notice).expect("Unable to serialize a room notice"),
+    )
+}
+
+/// Builds the signaling server with the default `ServerConfig`, ready to be launched.
+/// Announced candidates are purged from their rooms 60 seconds after they were last announced
+pub fn build() -> Rocket<Build> {
+    build_with(ServerConfig::default())
+}
+
+/// Builds the signaling server enforcing the limits of `config`, ready to be launched
+pub fn build_with(config: ServerConfig) -> Rocket<Build> {
+    let room_map_state: RoomMap = Arc::new(RwLock::new(SocketChannels(HashMap::new(), 0)));
+    let mailbox_state: MailboxMap = Arc::new(RwLock::new(HashMap::new()));
+
+    let notices = Notices(broadcast::channel(256).0);
+
+    let cloned_room_state = room_map_state.clone();
+    let cloned_mailbox_state = mailbox_state.clone();
+    let cloned_notices = notices.clone();
+    rocket::build()
+        .manage(room_map_state)
+        .manage(mailbox_state)
+        .manage(notices)
+        .manage(config)
+        .attach(AdHoc::on_liftoff("Purge stale candidates", |_| {
+            Box::pin(async move {
+                rocket::tokio::spawn(purge_stale_candidates(cloned_room_state, cloned_notices));
+                rocket::tokio::spawn(purge_stale_mail(cloned_mailbox_state));
+            })
+        }))
+        .mount(
+            "/",
+            routes![
+                version,
+                get_candidates_in_room,
+                get_room_candidate,
+                get_rooms,
+                broadcast_candidate,
+                withdraw_candidate,
+                send_offer,
+                fetch_offers,
+                send_answer,
+                fetch_answer,
+                room_socket,
+                room_events,
+                wait_for_peer
+            ],
+        )
+        .register("/", catchers![unauthorized])
+}
+
+async fn purge_stale_candidates(room_state: RoomMap, notices: Notices) {
+    loop {
+        rocket::tokio::time::sleep(rocket::tokio::time::Duration::from_secs(10)).await;
+        let mut room_map = room_state.write().await;
+
+        for (channel, rooms) in room_map.0.iter_mut() {
+            for (room_name, room) in rooms.0.iter_mut() {
+                let now = std::time::SystemTime::now()
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .unwrap()
+                    .as_secs();
+
+                room.retain(|uuid, v| {
+                    let fresh = now - v.init_time < 60;
+                    if !fresh {
+                        let peer_id = uuid.to_string();
+                        let notice = RoomNotice::PeerWithdrawn { peer_id };
+                        notices.send(channel, room_name, None, notice);
+                    }
+                    fresh
+                });
+            }
+
+            let SocketRooms(peers, settings) = rooms;
+            peers.retain(|_, room| !room.is_empty());
+            settings.retain(|room_name, _| peers.contains_key(room_name));
+        }
+
+        // Filter the rooms that have no candidates
+        room_map.0.retain(|_, v| !v.0.is_empty());
+    }
+}
+
+/// Drops the session descriptions which weren't fetched within 60 seconds, like announcements
+async fn purge_stale_mail(mailbox_state: MailboxMap) {
+    loop {
+        rocket::tokio::time::sleep(rocket::tokio::time::Duration::from_secs(10)).await;
+        let mut mailboxes = mailbox_state.write().await;
+        let now = get_now();
+
+        for mailbox in mailboxes.values_mut() {
+            for offers in mailbox.offers.values_mut() {
+                offers.retain(|(_, _, sent_at)| now - sent_at < 60);
+            }
+            mailbox.offers.retain(|_, offers| !offers.is_empty());
+            mailbox
+                .answers
+                .retain(|_, (_, sent_at)| now - *sent_at < 60);
+        }
+
+        mailboxes.retain(|_, mailbox| !mailbox.offers.is_empty() || !mailbox.answers.is_empty());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rocket::http::Header;
+    use rocket::local::asynchronous::{Client, LocalResponse};
+
+    const CHANNEL: &str = "test";
+    const ROOM: &str = "room";
+
+    async fn client(config: ServerConfig) -> Client {
+        Client::tracked(build_with(config))
+            .await
+            .expect("The server builds")
+    }
+
+    fn announcement(room_settings: Option<RoomSettings>) -> BroadcastCandidateArgs {
+        BroadcastCandidateArgs {
+            candidates: Vec::new(),
+            session_description: None,
+            sealed: None,
+            room_settings,
+            metadata: BTreeMap::new(),
+            signature: None,
+        }
+    }
+
+    async fn announce<'c>(
+        client: &'c Client,
+        peer_id: Uuid,
+        session_token: Option<&str>,
+        room_settings: Option<RoomSettings>,
+    ) -> LocalResponse<'c> {
+        let mut request = client
+            .post(format!(
+                "/announce?channel={CHANNEL}&room={ROOM}&peer_id={peer_id}"
+            ))
+            .json(&announcement(room_settings));
+        if let Some(token) = session_token {
+            request = request.header(Header::new(SESSION_TOKEN_HEADER, token.to_owned()));
+        }
+        request.dispatch().await
+    }
+
+    /// Announces `peer_id` to the room, returning the session token handed out for it
+    async fn join(client: &Client, peer_id: Uuid) -> String {
+        let response = announce(client, peer_id, None, None).await;
+        assert_eq!(response.status(), Status::Ok);
+        response
+            .into_json::<AnnounceResponse>()
+            .await
+            .expect("The response carries a token")
+            .session_token
+    }
+
+    async fn rejection(response: LocalResponse<'_>) -> Option<SignalRejection> {
+        response.into_json().await
+    }
+
+    #[rocket::async_test]
+    async fn test_session_token_is_required_to_update_and_withdraw() {
+        let client = client(ServerConfig::default()).await;
+        let peer_id = Uuid::new_v4();
+        let token = join(&client, peer_id).await;
+
+        for presented in [None, Some("wrong")] {
+            let response = announce(&client, peer_id, presented, None).await;
+            assert_eq!(response.status(), Status::Forbidden);
+            assert_eq!(
+                rejection(response).await,
+                Some(SignalRejection::SessionMismatch)
+            );
+
+            let mut request = client.delete(format!(
+                "/announce?channel={CHANNEL}&room={ROOM}&peer_id={peer_id}"
+            ));
+            if let Some(token) = presented {
+                request = request.header(Header::new(SESSION_TOKEN_HEADER, token));
+            }
+            let response = request.dispatch().await;
+            assert_eq!(response.status(), Status::Forbidden);
+            assert_eq!(
+                rejection(response).await,
+                Some(SignalRejection::SessionMismatch)
+            );
+        }
+
+        // The holder of the token still updates and withdraws the peer
+        let response = announce(&client, peer_id, Some(&token), None).await;
+        assert_eq!(response.status(), Status::Ok);
+        let response = client
+            .delete(format!(
+                "/announce?channel={CHANNEL}&room={ROOM}&peer_id={peer_id}"
+            ))
+            .header(Header::new(SESSION_TOKEN_HEADER, token))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+    }
+
+    #[rocket::async_test]
+    async fn test_room_password_is_required_to_read() {
+        let client = client(ServerConfig::default()).await;
+        let settings = RoomSettings {
+            password: Some("hunter2".to_owned()),
+            ..Default::default()
+        };
+        let response = announce(&client, Uuid::new_v4(), None, Some(settings)).await;
+        assert_eq!(response.status(), Status::Ok);
+
+        let read = |password: Option<&'static str>| {
+            let mut request = client.get(format!("/all_candidates?channel={CHANNEL}&room={ROOM}"));
+            if let Some(password) = password {
+                request = request.header(Header::new(ROOM_PASSWORD_HEADER, password));
+            }
+            request.dispatch()
+        };
+        for password in [None, Some("wrong")] {
+            let response = read(password).await;
+            assert_eq!(response.status(), Status::Unauthorized);
+            assert_eq!(
+                rejection(response).await,
+                Some(SignalRejection::WrongPassword)
+            );
+        }
+        assert_eq!(read(Some("hunter2")).await.status(), Status::Ok);
+    }
+
+    #[rocket::async_test]
+    async fn test_mailboxes_are_capped() {
+        let client = client(ServerConfig {
+            max_mail_per_peer: 1,
+            max_mail_per_room: 2,
+            ..Default::default()
+        })
+        .await;
+        let peers = [Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4()];
+        let mut tokens = Vec::new();
+        for peer_id in peers {
+            tokens.push(join(&client, peer_id).await);
+        }
+
+        let offer = |from: usize, to: usize| {
+            client
+                .post(format!(
+                    "/offer?channel={CHANNEL}&room={ROOM}&from={}&to={}",
+                    peers[from], peers[to]
+                ))
+                .header(Header::new(SESSION_TOKEN_HEADER, tokens[from].clone()))
+                .json(&DirectedArgs {
+                    session_description: None,
+                    sealed: None,
+                    signature: None,
+                })
+                .dispatch()
+        };
+
+        assert_eq!(offer(0, 1).await.status(), Status::Ok);
+        // The second peer already has as much mail waiting as it may
+        let response = offer(2, 1).await;
+        assert_eq!(response.status(), Status::TooManyRequests);
+        assert_eq!(
+            rejection(response).await,
+            Some(SignalRejection::MailboxFull)
+        );
+        // Replacing mail which wasn't fetched yet is always taken
+        assert_eq!(offer(0, 1).await.status(), Status::Ok);
+
+        assert_eq!(offer(0, 2).await.status(), Status::Ok);
+        // The room already has as much mail waiting as it may
+        let response = offer(1, 0).await;
+        assert_eq!(response.status(), Status::TooManyRequests);
+        assert_eq!(
+            rejection(response).await,
+            Some(SignalRejection::MailboxFull)
+        );
+    }
+}
@@ -0,0 +1,334 @@
+//! A harness which runs many in-process peers over a virtual network, connected through a local
+//! `signal_server`, so multi-peer scenarios can be scripted and their mesh state asserted on
+
+pub mod scenarios;
+
+use anyhow::{anyhow, Result as AResult};
+use futures::stream::{self, StreamExt};
+use rust_p2p::lobby::Lobby;
+use rust_p2p::p2p_client::P2PClient;
+use rust_p2p::signaling::{RoomConfig, SignalServer};
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, Ipv4Addr, TcpListener};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::sync::Mutex;
+use webrtc::ice::mdns::MulticastDnsMode;
+use webrtc::util::vnet::net::{Net, NetConfig};
+use webrtc::util::vnet::router::{Router, RouterConfig};
+
+/// How the virtual network between the peers behaves
+#[derive(Debug, Clone, Default)]
+pub struct NetworkConditions {
+    /// The minimum delay of every packet
+    pub latency: Duration,
+    /// The maximum random delay added on top of `latency`
+    pub jitter: Duration,
+    /// The probability of any packet being dropped, between 0 and 1
+    pub loss: f64,
+}
+
+/// Faults injected into the virtual network by the running scenario
+#[derive(Default)]
+struct Faults {
+    loss: f64,
+    crashed: HashSet<IpAddr>,
+    partition: Vec<HashSet<IpAddr>>,
+}
+
+impl Faults {
+    fn allows(&self, source: IpAddr, destination: IpAddr) -> bool {
+        if self.crashed.contains(&source) || self.crashed.contains(&destination) {
+            return false;
+        }
+
+        let same_side = self.partition.is_empty()
+            || self
+                .partition
+                .iter()
+                .any(|group| group.contains(&source) && group.contains(&destination));
+
+        same_side && (self.loss <= 0.0 || rand::random::<f64>() >= self.loss)
+    }
+}
+
+/// A peer of the simulation, with its own address on the virtual network
+pub struct SimPeer {
+    pub client: P2PClient,
+    pub ip: IpAddr,
+}
+
+/// Which lobby members every live peer is connected to
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MeshState {
+    pub links: HashMap<String, HashSet<String>>,
+}
+
+impl MeshState {
+    /// Whether both peers see a connection to each other
+    pub fn is_connected(&self, a: &str, b: &str) -> bool {
+        let links_to = |from: &str, to: &str| self.links.get(from).is_some_and(|l| l.contains(to));
+        links_to(a, b) && links_to(b, a)
+    }
+
+    /// Whether every one of `peers` is connected to every other one
+    pub fn is_full_mesh(&self, peers: &[String]) -> bool {
+        peers.iter().all(|a| {
+            peers
+                .iter()
+                .filter(|b| *b != a)
+                .all(|b| self.is_connected(a, b))
+        })
+    }
+
+    /// Whether none of `peers` is connected to any of `others`, in either direction
+    pub fn is_disjoint(&self, peers: &[String], others: &[String]) -> bool {
+        let links_to = |from: &str, to: &str| self.links.get(from).is_some_and(|l| l.contains(to));
+        peers
+            .iter()
+            .all(|a| others.iter().all(|b| !links_to(a, b) && !links_to(b, a)))
+    }
+}
+
+/// A set of peers on a virtual network, sharing a lobby on a local signal server
+pub struct Simulation {
+    signal_server: SignalServer,
+    room: RoomConfig,
+    // Kept alive for as long as the peers use the virtual network
+    _router: Arc<Mutex<Router>>,
+    faults: Arc<RwLock<Faults>>,
+    peers: Vec<SimPeer>,
+}
+
+impl Simulation {
+    /// Starts a local signal server and a virtual network of `peer_count` peers
+    pub async fn new(peer_count: usize, conditions: NetworkConditions) -> AResult<Self> {
+        Self::with_clients(peer_count, conditions, |client| client).await
+    }
+
+    /// Like `Simulation::new`, but passes the client of every peer through `configure` first
+    pub async fn with_clients(
+        peer_count: usize,
+        conditions: NetworkConditions,
+        configure: impl Fn(P2PClient) -> P2PClient,
+    ) -> AResult<Self> {
+        let signal_server = SignalServer::new(spawn_signal_server().await?);
+        let faults = Arc::new(RwLock::new(Faults {
+            loss: conditions.loss,
+            ..Default::default()
+        }));
+
+        let router = Arc::new(Mutex::new(Router::new(RouterConfig {
+            cidr: "10.0.0.0/24".to_owned(),
+            min_delay: conditions.latency,
+            max_jitter: conditions.jitter,
+            ..Default::default()
+        })?));
+
+        let filter_faults = faults.clone();
+        router
+            .lock()
+            .await
+            .add_chunk_filter(Box::new(move |chunk| {
+                filter_faults
+                    .read()
+                    .expect("Unable to aquire faults lock")
+                    .allows(chunk.get_source_ip(), chunk.get_destination_ip())
+            }))
+            .await;
+
+        let mut peers = Vec::with_capacity(peer_count);
+        for index in 0..peer_count {
+            let ip = IpAddr::V4(Ipv4Addr::new(10, 0, 0, index as u8 + 1));
+            let net = Arc::new(Net::new(Some(NetConfig {
+                static_ips: vec![ip.to_string()],
+                ..Default::default()
+            })));
+
+            let nic = net.get_nic()?;
+            router.lock().await.add_net(nic.clone()).await?;
+            nic.lock().await.set_router(router.clone()).await?;
+
+            let client = configure(P2PClient::new(Vec::<String>::new()))
+                .with_connect_timeout(Duration::from_secs(20))
+                .with_setting_engine(|setting_engine| {
+                    setting_engine.set_vnet(Some(net));
+                    setting_engine.set_ice_multicast_dns_mode(MulticastDnsMode::Disabled);
+                    setting_engine.set_ice_timeouts(
+                        Some(Duration::from_secs(2)),
+                        Some(Duration::from_secs(4)),
+                        Some(Duration::from_millis(500)),
+                    );
+                });
+
+            peers.push(SimPeer { client, ip });
+        }
+
+        router.lock().await.start().await?;
+
+        Ok(Self {
+            signal_server,
+            room: RoomConfig::new("sim", uuid::Uuid::new_v4().to_string()),
+            _router: router,
+            faults,
+            peers,
+        })
+    }
+
+    pub fn peers(&self) -> &[SimPeer] {
+        &self.peers
+    }
+
+    /// The ids of the peers at `indices`
+    pub fn peer_ids(&self, indices: impl IntoIterator<Item = usize>) -> Vec<String> {
+        indices
+            .into_iter()
+            .map(|index| self.peers[index].client.peer_id())
+            .collect()
+    }
+
+    /// The indices of the peers which have not crashed
+    pub fn live_peers(&self) -> Vec<usize> {
+        let faults = self.faults.read().expect("Unable to aquire faults lock");
+        (0..self.peers.len())
+            .filter(|index| !faults.crashed.contains(&self.peers[*index].ip))
+            .collect()
+    }
+
+    /// Creates the lobby of every peer, in the same order as the peers
+    pub fn lobbies(&self) -> Vec<Lobby<'_>> {
+        self.peers
+            .iter()
+            .map(|peer| {
+                peer.client
+                    .join_lobby(&self.signal_server, self.room.clone(), true)
+            })
+            .collect()
+    }
+
+    /// Gets which members every live peer's lobby has a connection to
+    pub async fn mesh_state(&self, lobbies: &[Lobby<'_>]) -> MeshState {
+        let mut links = HashMap::new();
+        for index in self.live_peers() {
+            let client = &self.peers[index].client;
+            let connected = client.connected_peers().await;
+            links.insert(
+                client.peer_id(),
+                lobbies[index]
+                    .members()
+                    .into_iter()
+                    .filter(|member| connected.contains(member))
+                    .collect(),
+            );
+        }
+        MeshState { links }
+    }
+
+    /// Drives the lobbies of the live peers until `predicate` holds for the mesh state, failing
+    /// if it doesn't within `timeout`
+    pub async fn drive_until(
+        &self,
+        lobbies: &[Lobby<'_>],
+        timeout: Duration,
+        predicate: impl Fn(&MeshState) -> bool,
+    ) -> AResult<MeshState> {
+        let live = self.live_peers();
+        let runs = stream::select_all(
+            lobbies
+                .iter()
+                .enumerate()
+                .filter(|(index, _)| live.contains(index))
+                .map(|(_, lobby)| lobby.run().boxed()),
+        );
+        futures::pin_mut!(runs);
+
+        let mut check = tokio::time::interval(Duration::from_millis(50));
+        let deadline = tokio::time::sleep(timeout);
+        futures::pin_mut!(deadline);
+
+        loop {
+            tokio::select! {
+                _ = runs.next() => {}
+                _ = check.tick() => {
+                    let state = self.mesh_state(lobbies).await;
+                    if predicate(&state) {
+                        return Ok(state);
+                    }
+                }
+                _ = &mut deadline => {
+                    return Err(anyhow!(
+                        "Mesh never reached the expected state: {:?}",
+                        self.mesh_state(lobbies).await
+                    ));
+                }
+            }
+        }
+    }
+
+    /// Drives the lobbies of the live peers for `duration`, whatever becomes of the mesh
+    pub async fn drive_for(&self, lobbies: &[Lobby<'_>], duration: Duration) {
+        let _ = self.drive_until(lobbies, duration, |_| false).await;
+    }
+
+    /// Crashes the peers at `indices`. Their packets are dropped from then on, and their lobbies
+    /// are no longer driven
+    pub fn crash(&self, indices: impl IntoIterator<Item = usize>) {
+        let mut faults = self.faults.write().expect("Unable to aquire faults lock");
+        faults
+            .crashed
+            .extend(indices.into_iter().map(|index| self.peers[index].ip));
+    }
+
+    /// Splits the network so that packets only flow between peers in the same group
+    pub fn partition(&self, groups: &[&[usize]]) {
+        let mut faults = self.faults.write().expect("Unable to aquire faults lock");
+        faults.partition = groups
+            .iter()
+            .map(|group| group.iter().map(|index| self.peers[*index].ip).collect())
+            .collect();
+    }
+
+    /// Drops every packet with the probability `loss` from then on, between 0 and 1
+    pub fn set_loss(&self, loss: f64) {
+        self.faults
+            .write()
+            .expect("Unable to aquire faults lock")
+            .loss = loss;
+    }
+
+    /// Removes any partition of the network
+    pub fn heal(&self) {
+        self.faults
+            .write()
+            .expect("Unable to aquire faults lock")
+            .partition
+            .clear();
+    }
+}
+
+async fn spawn_signal_server() -> AResult<String> {
+    let port = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?
+        .local_addr()?
+        .port();
+
+    let mut config = rocket::Config::debug_default();
+    config.address = Ipv4Addr::LOCALHOST.into();
+    config.port = port;
+    config.log_level = rocket::config::LogLevel::Off;
+    config.shutdown.ctrlc = false;
+
+    tokio::spawn(signal_server::server::build().configure(config).launch());
+
+    let url = format!("http://127.0.0.1:{port}");
+    for _ in 0..100 {
+        if tokio::net::TcpStream::connect((Ipv4Addr::LOCALHOST, port))
+            .await
+            .is_ok()
+        {
+            return Ok(url);
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+
+    Err(anyhow!("Signal server never became reachable"))
+}
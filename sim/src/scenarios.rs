@@ -0,0 +1,238 @@
+//! Scripted multi-peer scenarios, each asserting the invariants the mesh has to hold afterwards
+
+use crate::{MeshState, Simulation};
+use anyhow::{anyhow, Result as AResult};
+use rust_p2p::p2p_connection::Message;
+use std::sync::Arc;
+use std::time::Duration;
+
+const SETTLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Every peer joins the lobby at once, and has to end up connected to every other peer
+pub async fn join_storm(sim: &Simulation) -> AResult<MeshState> {
+    let lobbies = sim.lobbies();
+    let peers = sim.peer_ids(sim.live_peers());
+
+    sim.drive_until(&lobbies, SETTLE_TIMEOUT, |mesh| mesh.is_full_mesh(&peers))
+        .await
+}
+
+/// After the mesh is formed, the peer at `host` crashes. The survivors have to notice, while
+/// staying connected to each other
+pub async fn host_crash(sim: &Simulation, host: usize) -> AResult<MeshState> {
+    crash_peers(sim, &[host]).await
+}
+
+/// After the mesh is formed, every peer in `crashed` disconnects at once. The survivors have to
+/// notice, while staying connected to each other
+pub async fn mass_disconnect(sim: &Simulation, crashed: &[usize]) -> AResult<MeshState> {
+    crash_peers(sim, crashed).await
+}
+
+/// After the mesh is formed, the network is split in two. Each side has to stay fully connected,
+/// with no connection left across the partition
+pub async fn network_partition(
+    sim: &Simulation,
+    side_a: &[usize],
+    side_b: &[usize],
+) -> AResult<MeshState> {
+    let lobbies = sim.lobbies();
+    let all = sim.peer_ids(sim.live_peers());
+    sim.drive_until(&lobbies, SETTLE_TIMEOUT, |mesh| mesh.is_full_mesh(&all))
+        .await?;
+
+    sim.partition(&[side_a, side_b]);
+
+    let (a, b) = (
+        sim.peer_ids(side_a.iter().copied()),
+        sim.peer_ids(side_b.iter().copied()),
+    );
+    sim.drive_until(&lobbies, SETTLE_TIMEOUT, |mesh| {
+        mesh.is_full_mesh(&a) && mesh.is_full_mesh(&b) && mesh.is_disjoint(&a, &b)
+    })
+    .await
+}
+
+/// After the mesh is formed, the network is split in two, and healed `outage` after the
+/// connections across it have dropped. With clients created with `P2PClient::with_reconnect`,
+/// every peer has to end up connected to every other one again over the same connections, with
+/// a message sent across the partition while it was down delivered
+pub async fn partition_heal(
+    sim: &Simulation,
+    side_a: &[usize],
+    side_b: &[usize],
+    outage: Duration,
+) -> AResult<MeshState> {
+    let lobbies = sim.lobbies();
+    let all = sim.peer_ids(sim.live_peers());
+    sim.drive_until(&lobbies, SETTLE_TIMEOUT, |mesh| mesh.is_full_mesh(&all))
+        .await?;
+
+    let mut across = Vec::new();
+    for a in side_a {
+        for b in side_b {
+            let (client_a, client_b) = (&sim.peers()[*a].client, &sim.peers()[*b].client);
+            let (Some(from), Some(to)) = (
+                client_a.get_connection(&client_b.peer_id()).await,
+                client_b.get_connection(&client_a.peer_id()).await,
+            ) else {
+                return Err(anyhow!("Missing connection between peers {a} and {b}"));
+            };
+            across.push((*a, *b, from, to));
+        }
+    }
+
+    sim.partition(&[side_a, side_b]);
+
+    let (a, b) = (
+        sim.peer_ids(side_a.iter().copied()),
+        sim.peer_ids(side_b.iter().copied()),
+    );
+    sim.drive_until(&lobbies, SETTLE_TIMEOUT, |mesh| mesh.is_disjoint(&a, &b))
+        .await?;
+    for (_, _, from, _) in &across {
+        from.send(b"held").await?;
+    }
+
+    sim.drive_for(&lobbies, outage).await;
+    sim.heal();
+
+    let mesh = sim
+        .drive_until(&lobbies, SETTLE_TIMEOUT, |mesh| mesh.is_full_mesh(&all))
+        .await?;
+    for (a, b, from, to) in across {
+        let (client_a, client_b) = (&sim.peers()[a].client, &sim.peers()[b].client);
+        let same = |connection: Option<Arc<_>>, original| {
+            connection.is_some_and(|connection| Arc::ptr_eq(&connection, original))
+        };
+        if !same(client_a.get_connection(&client_b.peer_id()).await, &from)
+            || !same(client_b.get_connection(&client_a.peer_id()).await, &to)
+        {
+            return Err(anyhow!(
+                "Peers {a} and {b} reconnected over a new connection"
+            ));
+        }
+
+        let held = tokio::time::timeout(SETTLE_TIMEOUT, to.recv()).await?;
+        if held != Some(Message::Binary(b"held"[..].into())) {
+            return Err(anyhow!(
+                "Peer {b} received {held:?} instead of the held message"
+            ));
+        }
+    }
+    Ok(mesh)
+}
+
+/// After the mesh is formed, the network starts dropping packets with the probability `loss`.
+/// Every peer has to stay connected to every other one, with a message sent to each of them
+/// delivered despite the loss
+pub async fn lossy_links(sim: &Simulation, loss: f64) -> AResult<MeshState> {
+    let lobbies = sim.lobbies();
+    let all = sim.peer_ids(sim.live_peers());
+    sim.drive_until(&lobbies, SETTLE_TIMEOUT, |mesh| mesh.is_full_mesh(&all))
+        .await?;
+
+    sim.set_loss(loss);
+
+    for from in sim.peers() {
+        for to in sim.peers() {
+            if from.client.peer_id() == to.client.peer_id() {
+                continue;
+            }
+            let (Some(sender), Some(receiver)) = (
+                from.client.get_connection(&to.client.peer_id()).await,
+                to.client.get_connection(&from.client.peer_id()).await,
+            ) else {
+                return Err(anyhow!("Missing connection between peers"));
+            };
+
+            sender.send(b"lossy").await?;
+            let received = tokio::time::timeout(SETTLE_TIMEOUT, receiver.recv()).await?;
+            if received != Some(Message::Binary(b"lossy"[..].into())) {
+                return Err(anyhow!("Received {received:?} instead of the sent message"));
+            }
+        }
+    }
+
+    sim.drive_until(&lobbies, SETTLE_TIMEOUT, |mesh| mesh.is_full_mesh(&all))
+        .await
+}
+
+async fn crash_peers(sim: &Simulation, crashed: &[usize]) -> AResult<MeshState> {
+    let lobbies = sim.lobbies();
+    let all = sim.peer_ids(sim.live_peers());
+    sim.drive_until(&lobbies, SETTLE_TIMEOUT, |mesh| mesh.is_full_mesh(&all))
+        .await?;
+
+    sim.crash(crashed.iter().copied());
+
+    let crashed = sim.peer_ids(crashed.iter().copied());
+    let survivors = sim.peer_ids(sim.live_peers());
+    sim.drive_until(&lobbies, SETTLE_TIMEOUT, |mesh| {
+        mesh.is_full_mesh(&survivors) && mesh.is_disjoint(&survivors, &crashed)
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::NetworkConditions;
+
+    // Random loss is left out while the mesh forms: webrtc-rs doesn't always recover from a
+    // lost final DTLS flight, which leaves one side of a pair unconnected. `lossy_links` turns
+    // it on once the mesh is up
+    fn laggy_network() -> NetworkConditions {
+        NetworkConditions {
+            latency: Duration::from_millis(20),
+            jitter: Duration::from_millis(10),
+            loss: 0.0,
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_join_storm() -> AResult<()> {
+        let sim = Simulation::new(5, laggy_network()).await?;
+        join_storm(&sim).await?;
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_host_crash() -> AResult<()> {
+        let sim = Simulation::new(4, NetworkConditions::default()).await?;
+        host_crash(&sim, 0).await?;
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_mass_disconnect() -> AResult<()> {
+        let sim = Simulation::new(5, laggy_network()).await?;
+        mass_disconnect(&sim, &[0, 2, 4]).await?;
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_lossy_links() -> AResult<()> {
+        let sim = Simulation::new(4, laggy_network()).await?;
+        lossy_links(&sim, 0.05).await?;
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_network_partition() -> AResult<()> {
+        let sim = Simulation::new(4, NetworkConditions::default()).await?;
+        network_partition(&sim, &[0, 1], &[2, 3]).await?;
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_partition_heal() -> AResult<()> {
+        let sim = Simulation::with_clients(4, NetworkConditions::default(), |client| {
+            client.with_reconnect(64 * 1024)
+        })
+        .await?;
+        // Long enough for ICE to fail, so the connections only come back through a restart
+        partition_heal(&sim, &[0, 1], &[2, 3], Duration::from_secs(6)).await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,70 @@
+use anyhow::{anyhow, Result as AResult};
+use rcgen::{KeyPair, PKCS_ECDSA_P256_SHA256};
+use webrtc::peer_connection::certificate::RTCCertificate;
+
+/// The certificate and key pair a connection presents in DTLS. Every connection presents a new
+/// one unless the client is given one with `P2PClient::with_certificate`, so saving one with
+/// `to_pem` and restoring it with `from_pem` keeps the fingerprint the peer sees stable across
+/// runs. The peer can then trust it on first use, and pin it with
+/// `P2PConnection::expect_remote_fingerprint` from then on
+#[derive(Clone)]
+pub struct Certificate(RTCCertificate);
+
+impl Certificate {
+    /// Generates a new ECDSA P-256 certificate
+    pub fn generate() -> AResult<Self> {
+        let key_pair = KeyPair::generate_for(&PKCS_ECDSA_P256_SHA256)?;
+        Ok(Self(RTCCertificate::from_key_pair(key_pair)?))
+    }
+
+    /// Restores a certificate saved with `to_pem`
+    pub fn from_pem(pem: &str) -> AResult<Self> {
+        Ok(Self(RTCCertificate::from_pem(pem)?))
+    }
+
+    /// The certificate along with its private key, to be stored somewhere safe
+    pub fn to_pem(&self) -> String {
+        self.0.serialize_pem()
+    }
+
+    /// The SHA-256 fingerprint the peer sees through `P2PConnection::remote_fingerprint`
+    pub fn fingerprint(&self) -> AResult<String> {
+        self.0
+            .get_fingerprints()
+            .into_iter()
+            .next()
+            .map(|fingerprint| fingerprint.value.to_uppercase())
+            .ok_or(anyhow!("The certificate is empty"))
+    }
+
+    pub(crate) fn rtc_certificate(&self) -> RTCCertificate {
+        self.0.clone()
+    }
+}
+
+impl std::fmt::Debug for Certificate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&format!(
+            "Certificate: {}",
+            self.fingerprint().unwrap_or_default()
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pem_round_trip() -> AResult<()> {
+        let certificate = Certificate::generate()?;
+        let restored = Certificate::from_pem(&certificate.to_pem())?;
+        assert_eq!(restored.fingerprint()?, certificate.fingerprint()?);
+        assert_ne!(
+            Certificate::generate()?.fingerprint()?,
+            certificate.fingerprint()?
+        );
+        assert!(Certificate::from_pem("not a certificate").is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,1201 @@
+use crate::codec::{Bincode, Codec};
+use crate::compression;
+use crate::encryption::{self, Encryption, KeyExchange, SessionCipher};
+use crate::error::{ConnectionError, RpcError, SendError};
+use crate::fault::FaultInjector;
+use crate::framing::{self, FrameKind, Reassembler};
+use crate::mux::{Mux, MuxStream};
+use crate::rate_limit::RateLimiter;
+use crate::receipt::{self, Receipts};
+use crate::receive_buffer::{Pushed, ReceiveBuffer};
+use crate::rpc::{self, Rpc};
+use crate::topic::{TopicOrder, TopicSequences};
+use anyhow::Result as AResult;
+use bytes::Bytes;
+use futures::Stream;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::sync::{broadcast, oneshot, Notify};
+use webrtc::data_channel::data_channel_init::RTCDataChannelInit;
+use webrtc::data_channel::data_channel_state::RTCDataChannelState;
+use webrtc::data_channel::RTCDataChannel;
+
+pub use crate::receipt::Delivery;
+pub use crate::receive_buffer::OverflowPolicy;
+pub use tokio_util::sync::CancellationToken;
+
+/// A message received from the peer over a data channel
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    Binary(Bytes),
+    Text(String),
+}
+
+impl Message {
+    /// The raw contents of the message
+    pub fn as_bytes(&self) -> &[u8] {
+        match self {
+            Self::Binary(data) => data,
+            Self::Text(text) => text.as_bytes(),
+        }
+    }
+
+    /// The text of a message sent with `send_text`, `None` for a binary message
+    pub fn as_text(&self) -> Option<&str> {
+        match self {
+            Self::Text(text) => Some(text),
+            Self::Binary(_) => None,
+        }
+    }
+
+    fn from_frame(kind: FrameKind, data: Bytes) -> Self {
+        match kind {
+            // Decompressed and decrypted messages own their buffer, which becomes the string as is
+            FrameKind::Text => Self::Text(
+                String::from_utf8(Vec::from(data))
+                    .unwrap_or_else(|err| String::from_utf8_lossy(err.as_bytes()).into_owned()),
+            ),
+            _ => Self::Binary(data),
+        }
+    }
+}
+
+/// Changes to the state of a channel, from `Channel::events`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChannelEvent {
+    /// Messages can be sent over the channel from now on
+    Opened,
+    /// The channel was closed by either end, and can't be sent over anymore
+    Closed,
+    /// More than the client's send high-water mark is queued on the channel
+    HighWaterMark,
+    /// The queue drained down to the low-water mark, half the high-water mark, after having
+    /// gone over the high-water mark
+    LowWaterMark,
+}
+
+/// How hard a channel tries to deliver each message. Anything but `Reliable` suits real-time
+/// state, where a late message is worth less than a missing one
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ChannelReliability {
+    /// Every message is retransmitted until it arrives
+    #[default]
+    Reliable,
+    /// A message is given up on after this many retransmissions
+    MaxRetransmits(u16),
+    /// A message is given up on once it has been in flight this long. Lifetimes are carried in
+    /// whole milliseconds, up to `u16::MAX`
+    MaxLifetime(Duration),
+}
+
+/// How a channel opened with `P2PConnection::open_channel` delivers its messages
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChannelOptions {
+    /// If `true`, messages arrive in the order they were sent
+    pub ordered: bool,
+    pub reliability: ChannelReliability,
+    /// The sub-protocol spoken over the channel, such as a version of the application's wire
+    /// protocol, which a peer can refuse with `P2PClient::with_channel_protocols`. Empty for none
+    pub protocol: String,
+}
+
+impl ChannelOptions {
+    /// Unordered but reliable delivery, for messages sent with `Channel::send_in_topic`. The
+    /// messages of each topic arrive in the order they were sent, while a message held up in
+    /// one topic doesn't hold up the others
+    pub fn per_topic() -> Self {
+        Self {
+            ordered: false,
+            reliability: ChannelReliability::Reliable,
+            protocol: String::new(),
+        }
+    }
+
+    /// Unordered delivery which gives up on a message after `max_retransmits` retransmissions
+    pub fn unreliable(max_retransmits: u16) -> Self {
+        Self {
+            ordered: false,
+            reliability: ChannelReliability::MaxRetransmits(max_retransmits),
+            protocol: String::new(),
+        }
+    }
+
+    /// Announces the channel as speaking `protocol`
+    pub fn with_protocol(mut self, protocol: impl Into<String>) -> Self {
+        self.protocol = protocol.into();
+        self
+    }
+}
+
+impl Default for ChannelOptions {
+    fn default() -> Self {
+        Self {
+            ordered: true,
+            reliability: ChannelReliability::Reliable,
+            protocol: String::new(),
+        }
+    }
+}
+
+impl From<ChannelOptions> for RTCDataChannelInit {
+    fn from(options: ChannelOptions) -> Self {
+        let (max_retransmits, max_packet_life_time) = match options.reliability {
+            ChannelReliability::Reliable => (None, None),
+            ChannelReliability::MaxRetransmits(retransmits) => (Some(retransmits), None),
+            ChannelReliability::MaxLifetime(lifetime) => (
+                None,
+                Some(lifetime.as_millis().min(u16::MAX as u128) as u16),
+            ),
+        };
+
+        Self {
+            ordered: Some(options.ordered),
+            max_retransmits,
+            max_packet_life_time,
+            protocol: (!options.protocol.is_empty()).then_some(options.protocol),
+            ..Default::default()
+        }
+    }
+}
+
+/// A data channel to a peer. Every `P2PConnection` has a default one, and more can be opened
+/// with `P2PConnection::open_channel`
+pub struct Channel {
+    outbound: Arc<Outbound>,
+    received: Arc<ReceiveBuffer>,
+    rpc: Arc<Rpc>,
+    mux: Arc<Mux>,
+    receipts: Arc<Receipts>,
+    topic_sequences: TopicSequences,
+    last_received: Arc<std::sync::Mutex<Instant>>,
+    pending_pings: PendingPings,
+    next_ping_id: AtomicU32,
+    latency: std::sync::Mutex<Option<Duration>>,
+}
+
+/// Everything needed to send messages over a channel, shared with the tasks answering the
+/// peer's calls and the channel's streams
+pub(crate) struct Outbound {
+    data_channel: Arc<RTCDataChannel>,
+    next_message_id: AtomicU32,
+    high_water_mark: usize,
+    buffered_amount_low: Arc<Notify>,
+    rate_limiter: Arc<RateLimiter>,
+    handshake: Handshake,
+    events: broadcast::Sender<ChannelEvent>,
+    /// Whether the queue went over the high-water mark, and hasn't drained since
+    above_high_water_mark: Arc<AtomicBool>,
+    max_message_size: Arc<AtomicUsize>,
+    faults: Arc<FaultInjector>,
+    ended: CancellationToken,
+}
+
+/// How every channel of a connection is set up, from the settings of its client
+#[derive(Clone)]
+pub(crate) struct ChannelSettings {
+    pub(crate) high_water_mark: usize,
+    /// Shared by every channel of the connection
+    pub(crate) rate_limiter: Arc<RateLimiter>,
+    /// Messages of at least this many bytes are compressed, once the peer has said it opted in
+    /// to compression as well
+    pub(crate) compression_threshold: Option<usize>,
+    pub(crate) encryption: Option<Encryption>,
+    /// How many messages from the peer are held until they are received
+    pub(crate) receive_buffer: usize,
+    pub(crate) overflow_policy: OverflowPolicy,
+    /// The largest message the peer takes, shared by every channel of the connection and
+    /// updated once the peer's session description arrives
+    pub(crate) max_message_size: Arc<AtomicUsize>,
+    /// Shared by every channel of the connection
+    pub(crate) faults: Arc<FaultInjector>,
+    /// Cancelled once the connection has failed or closed for good, which ends every pending
+    /// send and receive on its channels
+    pub(crate) ended: CancellationToken,
+}
+
+/// What the two ends of a channel told each other with their capabilities, which go out along
+/// with the first message either side sends, as the peer may not be listening on the channel
+/// before then
+struct Handshake {
+    compression_threshold: Option<usize>,
+    encryption: Option<Encryption>,
+    /// Our end of the channel's key exchange, while encrypting
+    key_exchange: Option<std::sync::Mutex<KeyExchange>>,
+    sent: AtomicBool,
+    peer: std::sync::Mutex<PeerCapabilities>,
+    peer_changed: Notify,
+}
+
+#[derive(Default)]
+struct PeerCapabilities {
+    compresses: bool,
+    /// Seals and opens messages, once both ends have agreed on a key
+    cipher: Option<SessionCipher>,
+    identity: Option<[u8; 32]>,
+    /// The peer said it doesn't encrypt its messages, while we do
+    unencrypted: bool,
+}
+
+impl Handshake {
+    fn new(compression_threshold: Option<usize>, encryption: Option<Encryption>) -> Self {
+        // Even with a pre-shared key, nothing is sealed before the key exchange is done
+        let key_exchange = encryption
+            .as_ref()
+            .map(|_| std::sync::Mutex::new(KeyExchange::new()));
+
+        Self {
+            compression_threshold,
+            encryption,
+            key_exchange,
+            sent: AtomicBool::new(false),
+            peer: std::sync::Mutex::new(PeerCapabilities::default()),
+            peer_changed: Notify::new(),
+        }
+    }
+
+    /// Sends our capabilities to the peer, once. Without anything to advertise they only go out
+    /// as a `reply`, so a peer which encrypts learns that we don't
+    async fn send(&self, data_channel: &RTCDataChannel, reply: bool) -> AResult<()> {
+        let advertises = self.compression_threshold.is_some() || self.encryption.is_some();
+        if !(advertises || reply) || self.sent.swap(true, Ordering::Relaxed) {
+            return Ok(());
+        }
+
+        let mut flags = 0;
+        if self.compression_threshold.is_some() {
+            flags |= compression::LZ4;
+        }
+        if self.encryption.is_some() {
+            flags |= encryption::ENCRYPTED;
+        }
+        let mut capabilities = vec![flags];
+        if let Some(key_exchange) = self.key_exchange() {
+            capabilities.extend_from_slice(&key_exchange.public_key());
+        }
+        if let Some(public_key) = self.encryption.as_ref().and_then(Encryption::public_key) {
+            capabilities.extend_from_slice(&public_key);
+        }
+
+        let frame = framing::control(FrameKind::Capabilities, &capabilities);
+        data_channel.send(&frame).await?;
+        Ok(())
+    }
+
+    /// Takes in the capabilities the peer sent: its flags, then its end of the key exchange and
+    /// its identity if it has them
+    fn receive(&self, capabilities: &[u8]) {
+        let flags = capabilities.first().copied().unwrap_or_default();
+        let key = |at: usize| {
+            capabilities
+                .get(at..at + 32)
+                .and_then(|key| <[u8; 32]>::try_from(key).ok())
+        };
+        let (exchanged_key, public_key) = (key(1), key(33));
+
+        {
+            let mut peer = self.lock();
+            peer.compresses = flags & compression::LZ4 != 0;
+            if let (Some(encryption), Some(mut key_exchange)) =
+                (&self.encryption, self.key_exchange())
+            {
+                if flags & encryption::ENCRYPTED == 0 {
+                    peer.unencrypted = true;
+                } else if let Some(cipher) = exchanged_key.and_then(|exchanged_key| {
+                    key_exchange.finish(encryption, &exchanged_key, public_key.as_ref())
+                }) {
+                    peer.cipher = Some(cipher);
+                    peer.identity = public_key;
+                }
+            }
+        }
+        self.peer_changed.notify_waiters();
+    }
+
+    fn is_compressing(&self) -> bool {
+        self.compression_threshold.is_some() && self.lock().compresses
+    }
+
+    /// The cipher to seal messages with. `Ok(None)` while the peer's key hasn't arrived yet
+    fn cipher(&self) -> Result<Option<SessionCipher>, ConnectionError> {
+        let peer = self.lock();
+        if peer.unencrypted {
+            return Err(ConnectionError::PeerUnencrypted);
+        }
+        Ok(peer.cipher.clone())
+    }
+
+    /// Turns a complete frame from the peer into the kind of message it carries and its
+    /// contents. While encrypting, messages which aren't encrypted, or don't decrypt, are dropped
+    fn open(&self, kind: FrameKind, data: Bytes) -> Option<(FrameKind, Bytes)> {
+        let (kind, data) = match (kind, &self.encryption) {
+            (FrameKind::Encrypted, Some(_)) => self.lock().cipher.as_ref()?.open(&data).ok()?,
+            (FrameKind::Encrypted, None) | (_, Some(_)) => return None,
+            (kind, None) => (kind, data),
+        };
+
+        match kind {
+            // Compressed messages are always understood, even without having opted in to
+            // sending them
+            FrameKind::CompressedBinary | FrameKind::CompressedText => {
+                let data = compression::decompress(&data).ok()?;
+                Some((kind.decompressed(), data))
+            }
+            FrameKind::Binary
+            | FrameKind::Text
+            | FrameKind::Request
+            | FrameKind::Response
+            | FrameKind::Mux
+            | FrameKind::Tracked
+            | FrameKind::Ack
+            | FrameKind::Topic => Some((kind, data)),
+            _ => None,
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, PeerCapabilities> {
+        self.peer
+            .lock()
+            .expect("Unable to aquire peer capabilities lock")
+    }
+
+    fn key_exchange(&self) -> Option<std::sync::MutexGuard<'_, KeyExchange>> {
+        self.key_exchange.as_ref().map(|key_exchange| {
+            key_exchange
+                .lock()
+                .expect("Unable to aquire key exchange lock")
+        })
+    }
+}
+
+/// Pings sent over a channel which are waiting for their echo
+#[derive(Clone, Default)]
+struct PendingPings(Arc<std::sync::Mutex<HashMap<u32, oneshot::Sender<()>>>>);
+
+impl PendingPings {
+    fn insert(&self, id: u32) -> oneshot::Receiver<()> {
+        let (sx, rx) = oneshot::channel();
+        self.lock().insert(id, sx);
+        rx
+    }
+
+    fn complete(&self, id: u32) {
+        if let Some(sx) = self.lock().remove(&id) {
+            let _ = sx.send(());
+        }
+    }
+
+    fn remove(&self, id: u32) {
+        self.lock().remove(&id);
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<u32, oneshot::Sender<()>>> {
+        self.0.lock().expect("Unable to aquire pending pings lock")
+    }
+}
+
+impl std::fmt::Debug for Channel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&format!("Channel: {}", self.label()))
+    }
+}
+
+impl Channel {
+    /// Wraps `data_channel`, which must not have delivered any message yet
+    pub(crate) async fn new(data_channel: Arc<RTCDataChannel>, settings: ChannelSettings) -> Self {
+        let ChannelSettings {
+            high_water_mark,
+            rate_limiter,
+            compression_threshold,
+            encryption,
+            receive_buffer,
+            overflow_policy,
+            max_message_size,
+            faults,
+            ended,
+        } = settings;
+        let received = Arc::new(ReceiveBuffer::new(receive_buffer, overflow_policy));
+        {
+            // The data channel may never report closing once the connection has failed
+            let (received, ended) = (Arc::downgrade(&received), ended.clone());
+            tokio::spawn(async move {
+                ended.cancelled().await;
+                if let Some(received) = received.upgrade() {
+                    received.close();
+                }
+            });
+        }
+        let (events, _) = broadcast::channel(8);
+        {
+            let events = events.clone();
+            data_channel.on_open(Box::new(move || {
+                let _ = events.send(ChannelEvent::Opened);
+                Box::pin(async {})
+            }));
+        }
+        {
+            let (received, events) = (received.clone(), events.clone());
+            data_channel.on_close(Box::new(move || {
+                received.close();
+                let _ = events.send(ChannelEvent::Closed);
+                Box::pin(async {})
+            }));
+        }
+
+        let buffered_amount_low = Arc::new(Notify::new());
+        let above_high_water_mark = Arc::new(AtomicBool::new(false));
+        {
+            let drained = buffered_amount_low.clone();
+            let (events, above) = (events.clone(), above_high_water_mark.clone());
+            data_channel
+                .set_buffered_amount_low_threshold(high_water_mark / 2)
+                .await;
+            data_channel
+                .on_buffered_amount_low(Box::new(move || {
+                    drained.notify_waiters();
+                    if above.swap(false, Ordering::Relaxed) {
+                        let _ = events.send(ChannelEvent::LowWaterMark);
+                    }
+                    Box::pin(async {})
+                }))
+                .await;
+        }
+
+        let outbound = Arc::new(Outbound {
+            data_channel: data_channel.clone(),
+            next_message_id: AtomicU32::new(0),
+            high_water_mark,
+            buffered_amount_low,
+            rate_limiter,
+            handshake: Handshake::new(compression_threshold, encryption),
+            events,
+            above_high_water_mark,
+            max_message_size,
+            faults: faults.clone(),
+            ended,
+        });
+
+        // Large messages arrive in several frames, which are put back together before being handed
+        // to the receiver
+        let reassembler = Arc::new(std::sync::Mutex::new(Reassembler::default()));
+        let pending_pings = PendingPings::default();
+        let rpc = Arc::new(Rpc::default());
+        let mux = Arc::new(Mux::new());
+        let receipts = Arc::new(Receipts::default());
+        // Only channels without a retransmit or lifetime limit deliver every message
+        let reliable =
+            data_channel.max_retransmits() == 0 && data_channel.max_packet_lifetime() == 0;
+        let topics = Arc::new(std::sync::Mutex::new(TopicOrder::new(reliable)));
+        let last_received = Arc::new(std::sync::Mutex::new(Instant::now()));
+        {
+            let (pending_pings, rpc, mux) = (pending_pings.clone(), rpc.clone(), mux.clone());
+            let (received, receipts, topics) = (received.clone(), receipts.clone(), topics.clone());
+            let last_received = last_received.clone();
+            // Held weakly, as the channel owns this handler
+            let weak_outbound = Arc::downgrade(&outbound);
+            data_channel.on_message(Box::new(move |msg| {
+                // Nothing gets through while cut off by `P2PConnection::force_disconnect`
+                if faults.is_disconnected() {
+                    return Box::pin(async {});
+                }
+                let faults = faults.clone();
+                let (received, pending_pings) = (received.clone(), pending_pings.clone());
+                let (rpc, mux, receipts) = (rpc.clone(), mux.clone(), receipts.clone());
+                let topics = topics.clone();
+                let weak_outbound = weak_outbound.clone();
+                *last_received
+                    .lock()
+                    .expect("Unable to aquire last received lock") = Instant::now();
+                let message = reassembler
+                    .lock()
+                    .expect("Unable to aquire reassembler lock")
+                    .push(msg.data);
+                Box::pin(async move {
+                    let Some(outbound) = weak_outbound.upgrade() else {
+                        return;
+                    };
+                    match message {
+                        Ok(Some((FrameKind::Ping, data))) => {
+                            let pong = framing::control(FrameKind::Pong, &data);
+                            let _ = outbound.data_channel.send(&pong).await;
+                        }
+                        Ok(Some((FrameKind::Pong, data))) => {
+                            if let Ok(id) = <[u8; 4]>::try_from(data.as_ref()) {
+                                pending_pings.complete(u32::from_be_bytes(id));
+                            }
+                        }
+                        Ok(Some((FrameKind::Capabilities, data))) => {
+                            outbound.handshake.receive(&data);
+                            // Answered with our own, unless they were already sent
+                            let _ = outbound.handshake.send(&outbound.data_channel, true).await;
+                        }
+                        // Lost to the loss rate injected with `P2PConnection::inject_faults`
+                        Ok(Some(_)) if faults.lose() => {}
+                        Ok(Some((kind, data))) => match outbound.handshake.open(kind, data) {
+                            Some((FrameKind::Request, request)) => {
+                                // Handlers may take a while, so they don't hold up the messages
+                                // behind them
+                                tokio::spawn(async move {
+                                    if let Some(response) = rpc.answer(request).await {
+                                        let _ = outbound
+                                            .send_frames(FrameKind::Response, &response, false)
+                                            .await;
+                                    }
+                                });
+                            }
+                            Some((FrameKind::Response, response)) => rpc.complete(response),
+                            Some((FrameKind::Mux, frame)) => mux.receive(frame, &outbound),
+                            Some((FrameKind::Ack, ack)) => receipts.acknowledge(&ack),
+                            Some((FrameKind::Tracked, tracked)) => {
+                                let Ok((ack, kind, data)) = receipt::open(tracked) else {
+                                    return;
+                                };
+                                match received.push(Message::from_frame(kind, data)).await {
+                                    Pushed::Queued => {
+                                        let _ =
+                                            outbound.send_frames(FrameKind::Ack, &ack, false).await;
+                                    }
+                                    Pushed::Overflowed => {
+                                        let _ = outbound.data_channel.close().await;
+                                    }
+                                    Pushed::Closed => {}
+                                }
+                            }
+                            Some((FrameKind::Topic, message)) => {
+                                let ready = topics
+                                    .lock()
+                                    .expect("Unable to aquire topics lock")
+                                    .receive(message);
+                                let Some(ready) = ready else {
+                                    let _ = outbound.data_channel.close().await;
+                                    return;
+                                };
+                                for (kind, data) in ready {
+                                    let message = Message::from_frame(kind, data);
+                                    if received.push(message).await == Pushed::Overflowed {
+                                        let _ = outbound.data_channel.close().await;
+                                    }
+                                }
+                            }
+                            Some((kind, data)) => {
+                                let message = Message::from_frame(kind, data);
+                                if received.push(message).await == Pushed::Overflowed {
+                                    let _ = outbound.data_channel.close().await;
+                                }
+                            }
+                            None => {}
+                        },
+                        _ => {}
+                    }
+                })
+            }));
+        }
+
+        Self {
+            outbound,
+            received,
+            rpc,
+            mux,
+            receipts,
+            topic_sequences: TopicSequences::default(),
+            last_received,
+            pending_pings,
+            next_ping_id: AtomicU32::new(0),
+            latency: std::sync::Mutex::new(None),
+        }
+    }
+
+    pub fn label(&self) -> &str {
+        self.outbound.data_channel.label()
+    }
+
+    /// The sub-protocol the channel was opened with, empty for none. See `ChannelOptions::protocol`
+    pub fn protocol(&self) -> &str {
+        self.outbound.data_channel.protocol()
+    }
+
+    /// Whether messages can currently be sent over the channel
+    pub fn is_open(&self) -> bool {
+        self.ensure_open().is_ok()
+    }
+
+    /// Subscribes to the channel opening and closing. Events from before subscribing aren't
+    /// repeated, so check `is_open` as well, or use `wait_open`
+    pub fn events(&self) -> broadcast::Receiver<ChannelEvent> {
+        self.outbound.events.subscribe()
+    }
+
+    /// Waits for the channel to open, after which `send` succeeds until it closes. Fails with
+    /// `ConnectionError::ChannelNotOpen` if the channel closes first
+    pub async fn wait_open(&self) -> AResult<()> {
+        let mut events = self.events();
+        loop {
+            match self.outbound.data_channel.ready_state() {
+                RTCDataChannelState::Open => return Ok(()),
+                RTCDataChannelState::Connecting => {}
+                state => return Err(ConnectionError::ChannelNotOpen(state).into()),
+            }
+
+            // The state is checked again after every event, and after a lag
+            let _ = events.recv().await;
+        }
+    }
+
+    /// Whether messages at or above the client's compression threshold are sent compressed,
+    /// which takes both peers opting in with `P2PClient::with_compression`
+    pub fn is_compressing(&self) -> bool {
+        self.outbound.handshake.is_compressing()
+    }
+
+    /// Whether messages are encrypted with `P2PClient::with_encryption`, which with exchanged
+    /// keys takes the peer's key to have arrived
+    pub fn is_encrypted(&self) -> bool {
+        self.outbound.handshake.lock().cipher.is_some()
+    }
+
+    /// The public key of the peer's `Identity`, once it has arrived
+    pub fn peer_identity(&self) -> Option<[u8; 32]> {
+        self.outbound.handshake.lock().identity
+    }
+
+    /// The number of bytes queued on the channel which haven't been sent yet
+    pub async fn buffered_amount(&self) -> usize {
+        self.outbound.data_channel.buffered_amount().await
+    }
+
+    /// How many queued bytes `send_with_backpressure` waits at and `ChannelEvent::HighWaterMark`
+    /// is emitted above, from `P2PClient::with_send_high_water_mark`
+    pub fn high_water_mark(&self) -> usize {
+        self.outbound.high_water_mark
+    }
+
+    /// How many queued bytes the queue has to drain down to for waiting sends to resume, and
+    /// for `ChannelEvent::LowWaterMark` to be emitted
+    pub fn low_water_mark(&self) -> usize {
+        self.outbound.high_water_mark / 2
+    }
+
+    /// Sends `data` to the peer over the channel.
+    /// Fails with `ConnectionError::ChannelNotOpen` if the channel hasn't opened yet, or has
+    /// been closed
+    pub async fn send(&self, data: &[u8]) -> AResult<()> {
+        self.send_frames(FrameKind::Binary, data, false).await
+    }
+
+    /// Sends `data` to the peer like `send`, but first waits for the channel's queue to drain
+    /// whenever more than the client's send high-water mark is waiting to go out. Use this when
+    /// sending faster than the link can carry, so the queue doesn't grow without bound
+    pub async fn send_with_backpressure(&self, data: &[u8]) -> AResult<()> {
+        self.send_frames(FrameKind::Binary, data, true).await
+    }
+
+    /// Sends `data` to the peer like `send_with_backpressure`, but gives up if the channel
+    /// hasn't taken it within `timeout`, such as when it is congested or the rate limit holds
+    /// it back, failing with `SendError::Timeout` which hands `data` back
+    pub async fn send_with_timeout(&self, data: &[u8], timeout: Duration) -> AResult<()> {
+        let timed_out = async {
+            tokio::time::sleep(timeout).await;
+            SendError::Timeout(Bytes::copy_from_slice(data)).into()
+        };
+        self.outbound
+            .send_frames_unless(FrameKind::Binary, data, true, timed_out)
+            .await
+    }
+
+    /// Sends `data` to the peer like `send_with_backpressure`, but gives up once `cancel` is
+    /// cancelled if the channel hasn't taken it yet, failing with `SendError::Cancelled` which
+    /// hands `data` back
+    pub async fn send_cancellable(&self, data: &[u8], cancel: &CancellationToken) -> AResult<()> {
+        let cancelled = async {
+            cancel.cancelled().await;
+            SendError::Cancelled(Bytes::copy_from_slice(data)).into()
+        };
+        self.outbound
+            .send_frames_unless(FrameKind::Binary, data, true, cancelled)
+            .await
+    }
+
+    /// Sends `data` to the peer like `send_with_backpressure`, but drops it if it goes stale
+    /// within `ttl` before the channel takes it, such as a position update queued behind a
+    /// congested channel, failing with `SendError::Expired` which hands `data` back
+    pub async fn send_with_ttl(&self, data: &[u8], ttl: Duration) -> AResult<()> {
+        let expired = async {
+            tokio::time::sleep(ttl).await;
+            SendError::Expired(Bytes::copy_from_slice(data)).into()
+        };
+        self.outbound
+            .send_frames_unless(FrameKind::Binary, data, true, expired)
+            .await
+    }
+
+    /// Sends `text` to the peer over the channel as a text message.
+    /// Fails with `ConnectionError::ChannelNotOpen` if the channel hasn't opened yet, or has
+    /// been closed
+    pub async fn send_text(&self, text: &str) -> AResult<()> {
+        self.send_frames(FrameKind::Text, text.as_bytes(), false)
+            .await
+    }
+
+    /// Sends `data` to the peer like `send`, returning a `Delivery` which resolves once the
+    /// peer has received it, such as for a "delivered" indicator on a chat message
+    pub async fn send_tracked(&self, data: &[u8]) -> AResult<Delivery> {
+        self.ensure_open()?;
+        let (id, tracked, ack) = self.receipts.start(FrameKind::Binary, data);
+        let delivery = Delivery::new(id, ack, self.receipts.clone(), self.outbound.clone());
+        self.send_frames(FrameKind::Tracked, &tracked, false)
+            .await?;
+        Ok(delivery)
+    }
+
+    /// Sends `data` to the peer as part of `topic`. The peer receives the messages of a topic
+    /// in the order they were sent even over an unordered channel, such as one opened with
+    /// `ChannelOptions::per_topic`, so only messages of the same topic wait on each other. Over
+    /// a channel which may drop messages, a topic stops waiting for a lost message once 64
+    /// later ones have arrived
+    pub async fn send_in_topic(&self, topic: u16, data: &[u8]) -> AResult<()> {
+        self.ensure_open()?;
+        let message = self.topic_sequences.next(topic, FrameKind::Binary, data);
+        self.send_frames(FrameKind::Topic, &message, false).await
+    }
+
+    /// Encodes `message` with the default `Bincode` codec and sends it to the peer
+    pub async fn send_msg<T: Serialize>(&self, message: &T) -> AResult<()> {
+        self.send_msg_with(&Bincode, message).await
+    }
+
+    /// Encodes `message` with `codec` and sends it to the peer
+    pub async fn send_msg_with<C: Codec, T: Serialize>(
+        &self,
+        codec: &C,
+        message: &T,
+    ) -> AResult<()> {
+        self.send(&codec.encode(message)?).await
+    }
+
+    /// Waits for the next message from the peer. Returns `None` once the channel is gone, or
+    /// the connection has failed or closed and the messages which arrived before have been
+    /// received
+    pub async fn recv(&self) -> Option<Message> {
+        self.received.recv().await
+    }
+
+    /// Waits for the next message from the peer like `recv`, then moves up to `limit` of the
+    /// messages which have arrived into `buffer` at once, returning how many. Returns 0 once
+    /// the channel is gone, or if `limit` is 0
+    pub async fn recv_many(&self, buffer: &mut Vec<Message>, limit: usize) -> usize {
+        self.received.recv_many(buffer, limit).await
+    }
+
+    /// Gets the next message from the peer, if one has already arrived
+    pub fn try_recv(&self) -> Option<Message> {
+        self.received.try_recv()
+    }
+
+    /// Stops taking in messages from the peer until `resume`, such as during a loading screen.
+    /// Messages which already arrived can still be received, but no more are buffered: the
+    /// channel stops reading, so the transport's flow control pushes back on the peer once its
+    /// buffers fill up. Pings aren't answered while paused either, so keep pauses shorter than
+    /// the peer's keepalive timeout
+    pub fn pause(&self) {
+        self.received.pause();
+    }
+
+    /// Takes in messages from the peer again after `pause`
+    pub fn resume(&self) {
+        self.received.resume();
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.received.is_paused()
+    }
+
+    /// Whether the channel was closed because messages from the peer arrived faster than they
+    /// were received, under `OverflowPolicy::Close`
+    pub fn overflowed(&self) -> bool {
+        self.received.overflowed()
+    }
+
+    /// Waits for the next message from the peer and decodes it with the default `Bincode` codec.
+    /// Returns `None` once the channel is gone
+    pub async fn recv_msg<T: DeserializeOwned>(&self) -> AResult<Option<T>> {
+        self.recv_msg_with(&Bincode).await
+    }
+
+    /// Waits for the next message from the peer and decodes it with `codec`.
+    /// Returns `None` once the channel is gone
+    pub async fn recv_msg_with<C: Codec, T: DeserializeOwned>(
+        &self,
+        codec: &C,
+    ) -> AResult<Option<T>> {
+        match self.recv().await {
+            Some(message) => Ok(Some(codec.decode(message.as_bytes())?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Sends a small numbered frame which the peer echoes straight back, resolving to the round
+    /// trip time once the echo arrives. On a channel which may drop messages, wrap it in a
+    /// timeout
+    pub async fn ping(&self) -> AResult<Duration> {
+        self.ensure_open()?;
+        let id = self.next_ping_id.fetch_add(1, Ordering::Relaxed);
+        let mut echo = self.pending_pings.insert(id);
+
+        let sent_at = Instant::now();
+        let ping = framing::control(FrameKind::Ping, &id.to_be_bytes());
+        if let Err(err) = self.outbound.data_channel.send(&ping).await {
+            self.pending_pings.remove(id);
+            return Err(err.into());
+        }
+
+        // The echo never arrives if the channel closes in the meantime
+        while tokio::time::timeout(Duration::from_millis(100), &mut echo)
+            .await
+            .is_err()
+        {
+            if let Err(err) = self.ensure_open() {
+                self.pending_pings.remove(id);
+                return Err(err.into());
+            }
+        }
+
+        let round_trip = sent_at.elapsed();
+        let mut latency = self.latency.lock().expect("Unable to aquire latency lock");
+        *latency = Some(match *latency {
+            Some(average) => (average * 7 + round_trip) / 8,
+            None => round_trip,
+        });
+        Ok(round_trip)
+    }
+
+    /// The rolling average of the round trip times measured by `ping`, weighted towards the
+    /// latest ones. `None` until the first ping has been answered
+    pub fn latency(&self) -> Option<Duration> {
+        *self.latency.lock().expect("Unable to aquire latency lock")
+    }
+
+    /// Calls `method` on the peer with `payload`, resolving to what the handler the peer
+    /// registered for it with `register_handler` responds with. Fails with `RpcError::Timeout`
+    /// if no response arrives within ten seconds
+    pub async fn call(&self, method: &str, payload: &[u8]) -> AResult<Bytes> {
+        self.call_with_timeout(method, payload, rpc::DEFAULT_CALL_TIMEOUT)
+            .await
+    }
+
+    /// Calls `method` on the peer like `call`, failing with `RpcError::Timeout` if no response
+    /// arrives within `timeout`
+    pub async fn call_with_timeout(
+        &self,
+        method: &str,
+        payload: &[u8],
+        timeout: Duration,
+    ) -> AResult<Bytes> {
+        self.ensure_open()?;
+        let (id, request, mut response) = self.rpc.start(method, payload)?;
+        if let Err(err) = self.send_frames(FrameKind::Request, &request, false).await {
+            self.rpc.cancel(id);
+            return Err(err);
+        }
+
+        // The response never arrives if the channel closes in the meantime
+        let deadline = Instant::now() + timeout;
+        loop {
+            let wait = deadline
+                .saturating_duration_since(Instant::now())
+                .min(Duration::from_millis(100));
+            if let Ok(response) = tokio::time::timeout(wait, &mut response).await {
+                return Ok(response.map_err(|_| RpcError::Timeout)??);
+            }
+            if let Err(err) = self.ensure_open() {
+                self.rpc.cancel(id);
+                return Err(err.into());
+            }
+            if Instant::now() >= deadline {
+                self.rpc.cancel(id);
+                return Err(RpcError::Timeout.into());
+            }
+        }
+    }
+
+    /// Answers the peer's calls to `method` on this channel with `handler`, which is handed
+    /// the payload of each call. An error returned by the handler reaches the caller as
+    /// `RpcError::Failed`. Replaces any handler `method` already had
+    pub fn register_handler<F, Fut>(&self, method: &str, handler: F)
+    where
+        F: Fn(Bytes) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = AResult<Bytes>> + Send + 'static,
+    {
+        self.rpc
+            .register(method, Arc::new(move |payload| Box::pin(handler(payload))));
+    }
+
+    /// Opens a new stream over the channel, which the peer picks up with `accept_stream`
+    pub async fn open_stream(&self) -> AResult<MuxStream> {
+        self.mux.open(self.outbound.clone()).await
+    }
+
+    /// Waits for the next stream the peer opens over the channel. Returns `None` once the
+    /// channel is gone. Streams the peer opens while it already has 256 open are closed instead
+    pub async fn accept_stream(&self) -> Option<MuxStream> {
+        self.mux.accept().await
+    }
+
+    /// Sends a ping without waiting for its echo, which still counts as hearing from the peer
+    pub(crate) async fn send_heartbeat(&self) -> AResult<()> {
+        self.ensure_open()?;
+        let id = self.next_ping_id.fetch_add(1, Ordering::Relaxed);
+        let ping = framing::control(FrameKind::Ping, &id.to_be_bytes());
+        self.outbound.data_channel.send(&ping).await?;
+        Ok(())
+    }
+
+    /// When anything last arrived from the peer, or when the channel was created if nothing has
+    pub(crate) fn last_received(&self) -> Instant {
+        *self
+            .last_received
+            .lock()
+            .expect("Unable to aquire last received lock")
+    }
+
+    pub(crate) fn data_channel(&self) -> &Arc<RTCDataChannel> {
+        &self.outbound.data_channel
+    }
+
+    async fn send_frames(&self, kind: FrameKind, data: &[u8], backpressure: bool) -> AResult<()> {
+        self.outbound.send_frames(kind, data, backpressure).await
+    }
+
+    fn ensure_open(&self) -> Result<(), ConnectionError> {
+        self.outbound.ensure_open()
+    }
+}
+
+impl Outbound {
+    /// Writes `data` to the channel, compressed if it is worth it and encrypted if the client
+    /// encrypts, split into as many frames as it needs, once the connection's rate limit allows it
+    pub(crate) async fn send_frames(
+        &self,
+        kind: FrameKind,
+        data: &[u8],
+        backpressure: bool,
+    ) -> AResult<()> {
+        self.send_frames_unless(kind, data, backpressure, std::future::pending())
+            .await
+    }
+
+    /// Writes `data` to the channel like `send_frames`, but fails with the error `give_up`
+    /// resolves to if it resolves before the first frame has been written, or with
+    /// `ConnectionError::ConnectionClosed` if the connection ends first. Once it has, the rest
+    /// of the frames go out regardless, so the peer never gets part of a message
+    pub(crate) async fn send_frames_unless(
+        &self,
+        kind: FrameKind,
+        data: &[u8],
+        backpressure: bool,
+        give_up: impl Future<Output = anyhow::Error>,
+    ) -> AResult<()> {
+        self.ensure_open()?;
+        let give_up = async {
+            tokio::select! {
+                err = give_up => err,
+                _ = self.ended.cancelled() => ConnectionError::ConnectionClosed.into(),
+            }
+        };
+        tokio::pin!(give_up);
+
+        let (kind, data) = tokio::select! {
+            biased;
+            encoded = self.encode(kind, data) => encoded?,
+            err = &mut give_up => return Err(err),
+        };
+        if data.len() > framing::MAX_REASSEMBLED_SIZE {
+            return Err(ConnectionError::MessageTooLarge(data.len()).into());
+        }
+
+        // The rate limit is paid up front, so a send given up on pays it back
+        tokio::select! {
+            biased;
+            _ = self.rate_limiter.acquire(data.len()) => {}
+            err = &mut give_up => {
+                self.rate_limiter.refund(data.len());
+                return Err(err);
+            }
+        }
+        if backpressure {
+            tokio::select! {
+                biased;
+                drained = self.wait_for_drain() => drained?,
+                err = &mut give_up => {
+                    self.rate_limiter.refund(data.len());
+                    return Err(err);
+                }
+            }
+        }
+        // The channel may have closed while the send waited for its turn
+        if let Err(err) = self.ensure_open() {
+            self.rate_limiter.refund(data.len());
+            return Err(err.into());
+        }
+
+        let latency = self.faults.latency();
+        if !latency.is_zero() {
+            tokio::time::sleep(latency).await;
+        }
+        if self.faults.lose() {
+            return Ok(());
+        }
+
+        let message_id = self.next_message_id.fetch_add(1, Ordering::Relaxed);
+        let chunk_size = framing::chunk_size(self.max_message_size.load(Ordering::Relaxed));
+        for (i, frame) in framing::split(kind, message_id, &data, chunk_size)
+            .into_iter()
+            .enumerate()
+        {
+            if backpressure && i > 0 {
+                self.wait_for_drain().await?;
+            }
+            self.data_channel.send(&frame).await?;
+        }
+
+        if self.data_channel.buffered_amount().await > self.high_water_mark
+            && !self.above_high_water_mark.swap(true, Ordering::Relaxed)
+        {
+            let _ = self.events.send(ChannelEvent::HighWaterMark);
+        }
+        Ok(())
+    }
+
+    /// Makes sure the peer has the handshake, then compresses and seals `data` the way it is
+    /// written to the channel
+    async fn encode<'a>(
+        &self,
+        kind: FrameKind,
+        data: &'a [u8],
+    ) -> AResult<(FrameKind, Cow<'a, [u8]>)> {
+        self.handshake.send(&self.data_channel, false).await?;
+        let cipher = self.wait_for_cipher().await?;
+
+        let (kind, data) = match self.compress(kind, data) {
+            Some(compressed) => (kind.compressed(), Cow::Owned(compressed)),
+            None => (kind, Cow::Borrowed(data)),
+        };
+        Ok(match cipher {
+            Some(cipher) => (FrameKind::Encrypted, Cow::Owned(cipher.seal(kind, &data)?)),
+            None => (kind, data),
+        })
+    }
+
+    /// Compresses `data` if compression is on, `data` is large enough for it to pay off and
+    /// there is a compressed form of `kind`
+    fn compress(&self, kind: FrameKind, data: &[u8]) -> Option<Vec<u8>> {
+        let threshold = self.handshake.compression_threshold?;
+        if data.len() < threshold || kind.compressed() == kind || !self.handshake.is_compressing() {
+            return None;
+        }
+        compression::compress(data)
+    }
+
+    /// The cipher to seal messages with, waiting for the peer's key if it hasn't arrived yet.
+    /// `None` if the client doesn't encrypt
+    async fn wait_for_cipher(&self) -> AResult<Option<SessionCipher>> {
+        if self.handshake.encryption.is_none() {
+            return Ok(None);
+        }
+
+        loop {
+            let changed = self.handshake.peer_changed.notified();
+            if let Some(cipher) = self.handshake.cipher()? {
+                return Ok(Some(cipher));
+            }
+
+            // The peer's key never arrives if the channel closes in the meantime
+            let _ = tokio::time::timeout(Duration::from_millis(100), changed).await;
+            self.ensure_open()?;
+        }
+    }
+
+    /// Once more than the high-water mark is queued on the channel, waits until the queue has
+    /// drained down to the low-water threshold
+    async fn wait_for_drain(&self) -> AResult<()> {
+        if self.data_channel.buffered_amount().await <= self.high_water_mark {
+            return Ok(());
+        }
+
+        loop {
+            let drained = self.buffered_amount_low.notified();
+            if self.data_channel.buffered_amount().await <= self.high_water_mark / 2 {
+                return Ok(());
+            }
+
+            // The low-water callback never fires if the channel closes in the meantime
+            let _ = tokio::time::timeout(Duration::from_millis(100), drained).await;
+            self.ensure_open()?;
+        }
+    }
+
+    pub(crate) fn ensure_open(&self) -> Result<(), ConnectionError> {
+        if self.ended.is_cancelled() {
+            return Err(ConnectionError::ConnectionClosed);
+        }
+        match self.data_channel.ready_state() {
+            RTCDataChannelState::Open => Ok(()),
+            state => Err(ConnectionError::ChannelNotOpen(state)),
+        }
+    }
+}
+
+impl Stream for Channel {
+    type Item = Message;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.received.poll_recv(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reliability_maps_to_channel_init() {
+        let init = RTCDataChannelInit::from(ChannelOptions::default());
+        assert_eq!(init.ordered, Some(true));
+        assert_eq!(
+            (init.max_retransmits, init.max_packet_life_time),
+            (None, None)
+        );
+
+        let init = RTCDataChannelInit::from(ChannelOptions::unreliable(0));
+        assert_eq!(init.ordered, Some(false));
+        assert_eq!(
+            (init.max_retransmits, init.max_packet_life_time),
+            (Some(0), None)
+        );
+
+        let init = RTCDataChannelInit::from(ChannelOptions {
+            ordered: false,
+            reliability: ChannelReliability::MaxLifetime(Duration::from_millis(150)),
+            protocol: "game/1".to_owned(),
+        });
+        assert_eq!(
+            (init.max_retransmits, init.max_packet_life_time),
+            (None, Some(150))
+        );
+        assert_eq!(init.protocol.as_deref(), Some("game/1"));
+
+        let init = RTCDataChannelInit::from(ChannelOptions {
+            ordered: true,
+            reliability: ChannelReliability::MaxLifetime(Duration::from_secs(600)),
+            protocol: String::new(),
+        });
+        assert_eq!(init.max_packet_life_time, Some(u16::MAX));
+        assert_eq!(init.protocol, None);
+    }
+}
@@ -0,0 +1,51 @@
+use anyhow::Result as AResult;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+/// Turns typed messages into the bytes sent over a data channel, and back
+pub trait Codec {
+    fn encode<T: Serialize>(&self, message: &T) -> AResult<Vec<u8>>;
+    fn decode<T: DeserializeOwned>(&self, data: &[u8]) -> AResult<T>;
+}
+
+/// The default `Codec`, a compact binary encoding using `bincode`
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Bincode;
+
+impl Codec for Bincode {
+    fn encode<T: Serialize>(&self, message: &T) -> AResult<Vec<u8>> {
+        Ok(bincode::serialize(message)?)
+    }
+
+    fn decode<T: DeserializeOwned>(&self, data: &[u8]) -> AResult<T> {
+        Ok(bincode::deserialize(data)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Position {
+        x: f32,
+        y: f32,
+        label: String,
+    }
+
+    #[test]
+    fn test_bincode_round_trip() -> AResult<()> {
+        let position = Position {
+            x: 1.5,
+            y: -2.0,
+            label: "spawn".to_owned(),
+        };
+
+        let data = Bincode.encode(&position)?;
+        assert_eq!(Bincode.decode::<Position>(&data)?, position);
+        assert!(Bincode.decode::<Position>(&data[..4]).is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,57 @@
+use anyhow::{anyhow, Result as AResult};
+use bytes::Bytes;
+
+/// The capability flag a channel advertises to the peer when it sends LZ4 compressed messages
+pub(crate) const LZ4: u8 = 1;
+
+/// The largest size a compressed message may claim to expand to, so a peer can't make us
+/// allocate without bound
+const MAX_DECOMPRESSED_SIZE: usize = 64 * 1024 * 1024;
+
+/// Compresses `data` with LZ4, prefixed with its uncompressed size. `None` if compressing it
+/// doesn't make it any smaller
+pub(crate) fn compress(data: &[u8]) -> Option<Vec<u8>> {
+    let compressed = lz4_flex::compress_prepend_size(data);
+    (compressed.len() < data.len()).then_some(compressed)
+}
+
+/// Reverses `compress`
+pub(crate) fn decompress(data: &[u8]) -> AResult<Bytes> {
+    let size = data
+        .get(..4)
+        .and_then(|size| <[u8; 4]>::try_from(size).ok())
+        .map(u32::from_le_bytes)
+        .ok_or_else(|| anyhow!("Compressed message is missing its size"))?;
+    if size as usize > MAX_DECOMPRESSED_SIZE {
+        return Err(anyhow!("Compressed message expands to {size} bytes"));
+    }
+
+    Ok(lz4_flex::decompress_size_prepended(data)?.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() -> AResult<()> {
+        let data = r#"{"position":[1.0,2.0,3.0],"velocity":[0.0,0.0,0.0]}"#.repeat(64);
+
+        let compressed = compress(data.as_bytes()).expect("Repetitive data should compress");
+        assert!(compressed.len() < data.len());
+        assert_eq!(decompress(&compressed)?, Bytes::from(data));
+        Ok(())
+    }
+
+    #[test]
+    fn test_skips_incompressible_data() {
+        assert_eq!(compress(&[]), None);
+        assert_eq!(compress(b"abc"), None);
+    }
+
+    #[test]
+    fn test_rejects_oversized_messages() {
+        assert!(decompress(&[1, 2]).is_err());
+        assert!(decompress(&u32::MAX.to_le_bytes()).is_err());
+    }
+}
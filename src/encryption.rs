@@ -0,0 +1,479 @@
+use crate::framing::FrameKind;
+use anyhow::{anyhow, Result as AResult};
+use bytes::{Buf, Bytes};
+use chacha20poly1305::aead::{Aead, AeadCore, AeadInPlace, KeyInit, OsRng};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use ed25519_dalek::SigningKey;
+use hkdf::Hkdf;
+use sha2::Sha256;
+use std::collections::BTreeSet;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use x25519_dalek::{EphemeralSecret, PublicKey, StaticSecret};
+
+/// The capability flag a channel advertises to the peer when it encrypts its messages
+pub(crate) const ENCRYPTED: u8 = 2;
+
+const NONCE_SIZE: usize = 24;
+
+const TAG_SIZE: usize = 16;
+
+const COUNTER_SIZE: usize = 8;
+
+/// How far behind the newest message from the peer a message may arrive and still be opened.
+/// Unordered channels deliver messages out of order, but one further behind can't be told apart
+/// from a replay anymore
+const REPLAY_WINDOW: u64 = 1024;
+
+/// How messages are encrypted on top of DTLS, so they stay unreadable to anything relaying the
+/// connection, such as a compromised TURN server. Both peers have to use the same kind of
+/// encryption. Every channel also agrees on keys of its own with a fresh key exchange, so the
+/// messages of a session stay unreadable even if the key or identity leaks later
+#[derive(Clone)]
+pub enum Encryption {
+    /// Both peers were handed the same key out of band
+    PreSharedKey([u8; 32]),
+    /// Each peer has its own identity, and the peers send each other their public keys along
+    /// with the first message on each channel. Anyone can present a public key, so check
+    /// `P2PConnection::peer_identity` against the identity you expect the peer to have
+    Identity(Identity),
+}
+
+impl Encryption {
+    /// The public key sent to the peer, when the key is exchanged rather than pre-shared
+    pub(crate) fn public_key(&self) -> Option<[u8; 32]> {
+        match self {
+            Self::PreSharedKey(_) => None,
+            Self::Identity(identity) => Some(identity.public_key()),
+        }
+    }
+
+    /// The long-lived key shared with a peer which presented `peer_key`, if it presented one
+    fn shared_key(&self, peer_key: Option<&[u8; 32]>) -> Option<[u8; 32]> {
+        match (self, peer_key) {
+            (Self::PreSharedKey(key), _) => Some(*key),
+            (Self::Identity(identity), Some(peer_key)) => Some(identity.agree(peer_key)),
+            (Self::Identity(_), None) => None,
+        }
+    }
+}
+
+/// A long-lived X25519 key pair which identifies a peer to the application
+#[derive(Clone)]
+pub struct Identity(StaticSecret);
+
+impl Identity {
+    /// Generates a new random identity
+    pub fn generate() -> Self {
+        Self(StaticSecret::random_from_rng(OsRng))
+    }
+
+    /// Restores an identity saved with `to_bytes`
+    pub fn from_bytes(secret: [u8; 32]) -> Self {
+        Self(StaticSecret::from(secret))
+    }
+
+    /// The secret half of the identity, to be stored somewhere safe
+    pub fn to_bytes(&self) -> [u8; 32] {
+        self.0.to_bytes()
+    }
+
+    /// The public half of the identity, which the peer sees through
+    /// `P2PConnection::peer_identity`
+    pub fn public_key(&self) -> [u8; 32] {
+        PublicKey::from(&self.0).to_bytes()
+    }
+
+    /// The public half of the Ed25519 key the identity signs announcements with, see
+    /// `RoomConfig::with_signing_identity`. Hand it to the peers which should trust them
+    pub fn signing_public_key(&self) -> [u8; 32] {
+        self.signing_key().verifying_key().to_bytes()
+    }
+
+    /// The Ed25519 key for signing announcements, derived from the identity so there is only
+    /// one secret to keep
+    pub(crate) fn signing_key(&self) -> SigningKey {
+        let mut key = [0u8; 32];
+        Hkdf::<Sha256>::new(None, self.0.as_bytes())
+            .expand(b"rust_p2p signing key", &mut key)
+            .expect("32 bytes is a valid HKDF output length");
+        SigningKey::from_bytes(&key)
+    }
+
+    /// Both peers arrive at the same key, bound to both of their identities
+    fn agree(&self, peer_key: &[u8; 32]) -> [u8; 32] {
+        let shared = self.0.diffie_hellman(&PublicKey::from(*peer_key));
+
+        let own_key = self.public_key();
+        let (first, second) = if own_key < *peer_key {
+            (own_key, *peer_key)
+        } else {
+            (*peer_key, own_key)
+        };
+        let info = [b"rust_p2p message key".as_slice(), &first, &second].concat();
+
+        let mut key = [0u8; 32];
+        Hkdf::<Sha256>::new(None, shared.as_bytes())
+            .expand(&info, &mut key)
+            .expect("32 bytes is a valid HKDF output length");
+        key
+    }
+}
+
+impl std::fmt::Debug for Identity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&format!("Identity: {:?}", self.public_key()))
+    }
+}
+
+/// One end of the key exchange on a channel. Its secret is fresh for every channel and gone
+/// once the exchange is done, which is what keeps earlier sessions unreadable
+pub(crate) struct KeyExchange {
+    secret: Option<EphemeralSecret>,
+    public_key: [u8; 32],
+}
+
+impl KeyExchange {
+    pub(crate) fn new() -> Self {
+        let secret = EphemeralSecret::random_from_rng(OsRng);
+        Self {
+            public_key: PublicKey::from(&secret).to_bytes(),
+            secret: Some(secret),
+        }
+    }
+
+    /// The key sent to the peer along with the channel's capabilities
+    pub(crate) fn public_key(&self) -> [u8; 32] {
+        self.public_key
+    }
+
+    /// Agrees on the keys of the session with a peer which sent `peer_key`, and `peer_identity`
+    /// if it has one. `None` if `encryption` needs the peer's identity and it didn't send one,
+    /// or if the exchange is already done
+    pub(crate) fn finish(
+        &mut self,
+        encryption: &Encryption,
+        peer_key: &[u8; 32],
+        peer_identity: Option<&[u8; 32]>,
+    ) -> Option<SessionCipher> {
+        let shared_key = encryption.shared_key(peer_identity)?;
+        let exchanged = self
+            .secret
+            .take()?
+            .diffie_hellman(&PublicKey::from(*peer_key));
+
+        // Each direction has its own key, so both ends can count their nonces from 0
+        let sends_first = self.public_key < *peer_key;
+        let (first, second) = if sends_first {
+            (self.public_key, *peer_key)
+        } else {
+            (*peer_key, self.public_key)
+        };
+        let hkdf = Hkdf::<Sha256>::new(None, &[shared_key, exchanged.to_bytes()].concat());
+        let mut keys = [[0u8; 32]; 2];
+        for (key, direction) in keys.iter_mut().zip([b"first".as_slice(), b"second"]) {
+            let info = [
+                b"rust_p2p session key".as_slice(),
+                direction,
+                &first,
+                &second,
+            ]
+            .concat();
+            hkdf.expand(&info, key)
+                .expect("32 bytes is a valid HKDF output length");
+        }
+
+        let [from_first, from_second] = keys;
+        Some(if sends_first {
+            SessionCipher::new(&from_first, &from_second)
+        } else {
+            SessionCipher::new(&from_second, &from_first)
+        })
+    }
+}
+
+/// Seals messages for the peer and opens the ones it sent over a channel, under the keys its
+/// key exchange agreed on. Every message carries a counter as its nonce, so a message which was
+/// already opened, or is too far behind the newest one, is rejected as a replay
+#[derive(Clone)]
+pub(crate) struct SessionCipher(Arc<Session>);
+
+struct Session {
+    sealing: XChaCha20Poly1305,
+    opening: XChaCha20Poly1305,
+    next_counter: AtomicU64,
+    opened: Mutex<ReplayWindow>,
+}
+
+impl SessionCipher {
+    fn new(sealing_key: &[u8; 32], opening_key: &[u8; 32]) -> Self {
+        Self(Arc::new(Session {
+            sealing: XChaCha20Poly1305::new(sealing_key.into()),
+            opening: XChaCha20Poly1305::new(opening_key.into()),
+            next_counter: AtomicU64::new(0),
+            opened: Mutex::new(ReplayWindow::default()),
+        }))
+    }
+
+    /// Encrypts `data` along with the kind of message it is, under the next counter, which
+    /// prefixes the result
+    pub(crate) fn seal(&self, kind: FrameKind, data: &[u8]) -> AResult<Vec<u8>> {
+        let counter = self.0.next_counter.fetch_add(1, Ordering::Relaxed);
+        let mut sealed = Vec::with_capacity(COUNTER_SIZE + 1 + data.len() + TAG_SIZE);
+        sealed.extend_from_slice(&counter.to_be_bytes());
+        sealed.push(kind as u8);
+        sealed.extend_from_slice(data);
+
+        let tag = self
+            .0
+            .sealing
+            .encrypt_in_place_detached(&counter_nonce(counter), &[], &mut sealed[COUNTER_SIZE..])
+            .map_err(|_| anyhow!("Unable to encrypt message"))?;
+        sealed.extend_from_slice(&tag);
+        Ok(sealed)
+    }
+
+    /// Reverses `seal`, failing if the message was tampered with, sealed under another key, or
+    /// replayed
+    pub(crate) fn open(&self, sealed: &[u8]) -> AResult<(FrameKind, Bytes)> {
+        let Some((counter, ciphertext)) = sealed.split_first_chunk::<COUNTER_SIZE>() else {
+            return Err(anyhow!("Encrypted message is shorter than its counter"));
+        };
+        let counter = u64::from_be_bytes(*counter);
+        if !self.lock().admits(counter) {
+            return Err(anyhow!("Encrypted message was replayed"));
+        }
+
+        let plaintext = self
+            .0
+            .opening
+            .decrypt(&counter_nonce(counter), ciphertext)
+            .map_err(|_| anyhow!("Unable to decrypt message"))?;
+        // Only a message which decrypted counts, so forged ones can't fill the window
+        if !self.lock().insert(counter) {
+            return Err(anyhow!("Encrypted message was replayed"));
+        }
+        split_kind(plaintext)
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, ReplayWindow> {
+        self.0
+            .opened
+            .lock()
+            .expect("Unable to aquire replay window lock")
+    }
+}
+
+fn counter_nonce(counter: u64) -> XNonce {
+    let mut nonce = XNonce::default();
+    nonce[NONCE_SIZE - COUNTER_SIZE..].copy_from_slice(&counter.to_be_bytes());
+    nonce
+}
+
+/// The counters of the messages opened lately
+#[derive(Default)]
+struct ReplayWindow {
+    newest: u64,
+    opened: BTreeSet<u64>,
+}
+
+impl ReplayWindow {
+    /// Whether the message with `counter` wasn't opened yet, and isn't too far behind the newest
+    fn admits(&self, counter: u64) -> bool {
+        counter.saturating_add(REPLAY_WINDOW) > self.newest && !self.opened.contains(&counter)
+    }
+
+    /// Records the message with `counter` as opened, unless it isn't admitted
+    fn insert(&mut self, counter: u64) -> bool {
+        if !self.admits(counter) {
+            return false;
+        }
+        self.opened.insert(counter);
+        self.newest = self.newest.max(counter);
+        // The ones this far behind are rejected by `admits` anyway
+        while self
+            .opened
+            .first()
+            .is_some_and(|oldest| oldest + REPLAY_WINDOW <= self.newest)
+        {
+            self.opened.pop_first();
+        }
+        true
+    }
+}
+
+/// Seals data under a long-lived key, such as the announcements of a room with a secret, each
+/// under a random nonce
+#[derive(Clone)]
+pub(crate) struct Cipher(XChaCha20Poly1305);
+
+impl Cipher {
+    pub(crate) fn new(key: &[u8; 32]) -> Self {
+        Self(XChaCha20Poly1305::new(key.into()))
+    }
+
+    /// Encrypts `data` along with the kind of message it is, under a random nonce which
+    /// prefixes the result. `data` is copied once, straight into the buffer it is encrypted in
+    pub(crate) fn seal(&self, kind: FrameKind, data: &[u8]) -> AResult<Vec<u8>> {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let mut sealed = Vec::with_capacity(NONCE_SIZE + 1 + data.len() + TAG_SIZE);
+        sealed.extend_from_slice(&nonce);
+        sealed.push(kind as u8);
+        sealed.extend_from_slice(data);
+
+        let tag = self
+            .0
+            .encrypt_in_place_detached(&nonce, &[], &mut sealed[NONCE_SIZE..])
+            .map_err(|_| anyhow!("Unable to encrypt message"))?;
+        sealed.extend_from_slice(&tag);
+        Ok(sealed)
+    }
+
+    /// Reverses `seal`, failing if the message was tampered with or sealed under another key
+    pub(crate) fn open(&self, sealed: &[u8]) -> AResult<(FrameKind, Bytes)> {
+        if sealed.len() < NONCE_SIZE {
+            return Err(anyhow!("Encrypted message is shorter than its nonce"));
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_SIZE);
+        let plaintext = self
+            .0
+            .decrypt(XNonce::from_slice(nonce), ciphertext)
+            .map_err(|_| anyhow!("Unable to decrypt message"))?;
+        split_kind(plaintext)
+    }
+}
+
+/// Splits decrypted data into the kind of message it is and its contents
+fn split_kind(plaintext: Vec<u8>) -> AResult<(FrameKind, Bytes)> {
+    let mut plaintext = Bytes::from(plaintext);
+    if plaintext.is_empty() {
+        return Err(anyhow!("Encrypted message is missing its kind"));
+    }
+    let kind = FrameKind::try_from(plaintext.get_u8())?;
+    Ok((kind, plaintext))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Runs the key exchange of a channel between two ends
+    fn exchange(
+        first: &Encryption,
+        second: &Encryption,
+    ) -> (Option<SessionCipher>, Option<SessionCipher>) {
+        let (mut first_exchange, mut second_exchange) = (KeyExchange::new(), KeyExchange::new());
+        let (first_key, second_key) = (first_exchange.public_key(), second_exchange.public_key());
+        (
+            first_exchange.finish(first, &second_key, second.public_key().as_ref()),
+            second_exchange.finish(second, &first_key, first.public_key().as_ref()),
+        )
+    }
+
+    #[test]
+    fn test_pre_shared_key_round_trip() -> AResult<()> {
+        let key = Encryption::PreSharedKey([7; 32]);
+        let (Some(sender), Some(receiver)) = exchange(&key, &key) else {
+            panic!("A pre-shared key needs no identity");
+        };
+
+        let sealed = sender.seal(FrameKind::Text, b"hello")?;
+        assert!(!sealed.windows(5).any(|window| window == b"hello"));
+        assert_eq!(
+            receiver.open(&sealed)?,
+            (FrameKind::Text, Bytes::from_static(b"hello"))
+        );
+        // The other direction has a key of its own
+        assert!(sender.open(&sealed).is_err());
+        assert!(receiver.open(&sealed[..COUNTER_SIZE]).is_err());
+
+        let (Some(stranger), _) = exchange(&Encryption::PreSharedKey([8; 32]), &key) else {
+            panic!("A pre-shared key needs no identity");
+        };
+        assert!(stranger
+            .open(&sender.seal(FrameKind::Text, b"hello")?)
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_identities_agree_on_a_key() -> AResult<()> {
+        let (alice, bob, eve) = (
+            Identity::generate(),
+            Identity::generate(),
+            Identity::generate(),
+        );
+        let (mut exchange_without_identity, peer) = (KeyExchange::new(), KeyExchange::new());
+        assert!(exchange_without_identity
+            .finish(
+                &Encryption::Identity(alice.clone()),
+                &peer.public_key(),
+                None
+            )
+            .is_none());
+
+        let (Some(alice_cipher), Some(bob_cipher)) = exchange(
+            &Encryption::Identity(alice.clone()),
+            &Encryption::Identity(Identity::from_bytes(bob.to_bytes())),
+        ) else {
+            panic!("Both identities were presented");
+        };
+        let (_, Some(eve_cipher)) = exchange(
+            &Encryption::Identity(bob.clone()),
+            &Encryption::Identity(eve),
+        ) else {
+            panic!("Both identities were presented");
+        };
+
+        let sealed = alice_cipher.seal(FrameKind::Binary, &[1, 2, 3])?;
+        assert_eq!(
+            bob_cipher.open(&sealed)?,
+            (FrameKind::Binary, Bytes::from_static(&[1, 2, 3]))
+        );
+        assert!(eve_cipher.open(&sealed).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_sessions_have_their_own_keys() -> AResult<()> {
+        let (alice, bob) = (
+            Encryption::Identity(Identity::generate()),
+            Encryption::Identity(Identity::generate()),
+        );
+        let (Some(earlier), _) = exchange(&alice, &bob) else {
+            panic!("Both identities were presented");
+        };
+        let (_, Some(later)) = exchange(&alice, &bob) else {
+            panic!("Both identities were presented");
+        };
+
+        // Knowing both identities isn't enough to open what another session sealed
+        assert!(later
+            .open(&earlier.seal(FrameKind::Text, b"hello")?)
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_replays_are_rejected() -> AResult<()> {
+        let key = Encryption::PreSharedKey([7; 32]);
+        let (Some(sender), Some(receiver)) = exchange(&key, &key) else {
+            panic!("A pre-shared key needs no identity");
+        };
+
+        let sealed = (0..REPLAY_WINDOW + 3)
+            .map(|i| sender.seal(FrameKind::Binary, &i.to_be_bytes()))
+            .collect::<AResult<Vec<_>>>()?;
+        receiver.open(&sealed[1])?;
+        assert!(receiver.open(&sealed[1]).is_err());
+        // Messages may arrive out of order, as long as they are within the window
+        receiver.open(&sealed[0])?;
+        assert!(receiver.open(&sealed[0]).is_err());
+
+        // One which never arrived is given up on once it falls out of the window
+        receiver.open(&sealed[REPLAY_WINDOW as usize + 2])?;
+        assert!(receiver.open(&sealed[2]).is_err());
+        receiver.open(&sealed[3])?;
+        Ok(())
+    }
+}
@@ -0,0 +1,142 @@
+use bytes::Bytes;
+use signal_server::{Capability, SignalRejection};
+use thiserror::Error;
+use webrtc::data_channel::data_channel_state::RTCDataChannelState;
+
+/// Errors produced by the `P2PClient` while managing its connections
+#[derive(Debug, Error)]
+pub enum ClientError {
+    /// The connection to the peer was not established within the configured connect timeout
+    #[error("Timed out connecting to peer {0}")]
+    ConnectTimeout(String),
+    /// The client has no connection to the peer
+    #[error("No connection to peer {0}")]
+    UnknownPeer(String),
+    /// The incoming connection from the peer was rejected by the `on_incoming` handler
+    #[error("Incoming connection from peer {0} was rejected")]
+    Rejected(String),
+}
+
+/// Errors produced by a `P2PConnection`
+#[derive(Debug, Error)]
+pub enum ConnectionError {
+    /// The data channel can't be written to in its current state
+    #[error("The data channel is not open, it is {0}")]
+    ChannelNotOpen(RTCDataChannelState),
+    /// The connection was not established within the client's connect timeout
+    #[error("Timed out waiting for the connection to be established")]
+    Timeout,
+    /// The client encrypts its messages, but the peer doesn't
+    #[error("The peer doesn't encrypt its messages")]
+    PeerUnencrypted,
+    /// The peer's session description carries another DTLS fingerprint than the one set with
+    /// `P2PConnection::expect_remote_fingerprint`
+    #[error("The peer's DTLS fingerprint {actual:?} doesn't match the expected {expected}")]
+    FingerprintMismatch {
+        expected: String,
+        actual: Option<String>,
+    },
+    /// The connection is reconnecting, and the messages held until it is back already fill the
+    /// client's reconnect buffer of this many bytes
+    #[error("The reconnect buffer of {0} bytes is full")]
+    ReconnectBufferFull(usize),
+    /// The connection is reconnecting, and the send can't be held until it is back, such as
+    /// unreliable and tracked sends
+    #[error("The connection is reconnecting")]
+    Reconnecting,
+    /// The message is larger than the most a peer reassembles, even once compressed
+    #[error("The message of {0} bytes is too large to send")]
+    MessageTooLarge(usize),
+    /// The connection failed or was closed, so nothing more goes out over it
+    #[error("The connection to the peer failed or was closed")]
+    ConnectionClosed,
+}
+
+/// A send made with `Channel::send_with_timeout`, `Channel::send_cancellable` or
+/// `Channel::send_with_ttl` which was given up on before any of it went out, handing back the
+/// payload so it can be retried or dropped
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum SendError {
+    /// The channel didn't take the message within the send's timeout
+    #[error("Timed out waiting for the channel to take the message")]
+    Timeout(Bytes),
+    /// The send's cancellation token fired first
+    #[error("The send was cancelled")]
+    Cancelled(Bytes),
+    /// The message went stale before the channel took it, so it was dropped
+    #[error("The message expired before it could be sent")]
+    Expired(Bytes),
+}
+
+impl SendError {
+    /// The payload which wasn't sent
+    pub fn into_payload(self) -> Bytes {
+        match self {
+            Self::Timeout(payload) | Self::Cancelled(payload) | Self::Expired(payload) => payload,
+        }
+    }
+}
+
+/// Errors produced by a call made with `Channel::call`
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum RpcError {
+    /// The peer didn't respond within the call's timeout
+    #[error("Timed out waiting for the peer to respond")]
+    Timeout,
+    /// The peer has no handler registered for the method
+    #[error("The peer has no handler for {0}")]
+    UnknownMethod(String),
+    /// The peer's handler returned an error, carried here as its message
+    #[error("The peer's handler failed: {0}")]
+    Failed(String),
+}
+
+/// Errors produced when the signaling server refuses a request
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum SignalError {
+    #[error("The room is full")]
+    RoomFull,
+    #[error("This peer is banned from the signaling server")]
+    Banned,
+    #[error("Wrong room password")]
+    WrongPassword,
+    #[error("The signaling server quota has been exceeded")]
+    QuotaExceeded,
+    #[error("Signaling protocol version mismatch, the server speaks version {server_version}")]
+    VersionMismatch { server_version: u32 },
+    #[error("The signaling server requires a valid auth token")]
+    Unauthorized,
+    #[error("The signaling server doesn't support {0:?}")]
+    Unsupported(Capability),
+    /// Someone else announced the peer id, so this client holds no session token for it, or
+    /// another one, see `SignalServer::resume_session`
+    #[error("The peer is announced under another session")]
+    SessionMismatch,
+    /// The recipient of an offer or answer, or its room, has too many waiting to be fetched
+    #[error("The mailbox of the peer or room is full")]
+    MailboxFull,
+    /// The peer's announcement carries a signature which doesn't match it, so it was changed
+    /// after it was signed
+    #[error("The signature doesn't match the peer's announcement")]
+    InvalidSignature,
+    /// The peer's announcement isn't signed with any key the room trusts
+    #[error("The peer's announcement isn't signed by a trusted key")]
+    UntrustedSigner,
+}
+
+impl From<SignalRejection> for SignalError {
+    fn from(rejection: SignalRejection) -> Self {
+        match rejection {
+            SignalRejection::RoomFull => Self::RoomFull,
+            SignalRejection::Banned => Self::Banned,
+            SignalRejection::WrongPassword => Self::WrongPassword,
+            SignalRejection::QuotaExceeded => Self::QuotaExceeded,
+            SignalRejection::VersionMismatch { server_version } => {
+                Self::VersionMismatch { server_version }
+            }
+            SignalRejection::Unauthorized => Self::Unauthorized,
+            SignalRejection::SessionMismatch => Self::SessionMismatch,
+            SignalRejection::MailboxFull => Self::MailboxFull,
+        }
+    }
+}
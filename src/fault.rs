@@ -0,0 +1,128 @@
+use std::time::Duration;
+
+/// Faults injected into the messages of a `P2PConnection` with `P2PConnection::inject_faults`,
+/// so the retry and interpolation logic of an application can be tested without a flaky
+/// network. Only available with the `fault-injection` feature
+#[cfg(any(test, feature = "fault-injection"))]
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Faults {
+    /// How much longer every send takes before its message goes out
+    pub latency: Duration,
+    /// The share of messages, from 0 to 1, which are lost on their way out or in
+    pub loss: f64,
+    /// Picks which messages are lost, so a test loses the same ones every run
+    pub seed: u64,
+}
+
+/// The faults injected into every channel of a connection, and whether it is cut off from
+/// the peer by `P2PConnection::force_disconnect`
+#[cfg(any(test, feature = "fault-injection"))]
+#[derive(Default)]
+pub(crate) struct FaultInjector(std::sync::Mutex<State>);
+
+#[cfg(any(test, feature = "fault-injection"))]
+#[derive(Default)]
+struct State {
+    faults: Faults,
+    /// The state of the generator picking lost messages
+    rng: u64,
+    disconnected: bool,
+}
+
+#[cfg(any(test, feature = "fault-injection"))]
+impl FaultInjector {
+    /// Replaces the injected faults, starting over from their seed
+    pub(crate) fn set(&self, faults: Faults) {
+        let mut state = self.lock();
+        state.faults = faults;
+        state.rng = faults.seed;
+    }
+
+    pub(crate) fn set_disconnected(&self, disconnected: bool) {
+        self.lock().disconnected = disconnected;
+    }
+
+    pub(crate) fn is_disconnected(&self) -> bool {
+        self.lock().disconnected
+    }
+
+    /// Whether the next message is lost, to the loss rate or a forced disconnect
+    pub(crate) fn lose(&self) -> bool {
+        let mut state = self.lock();
+        if state.disconnected {
+            return true;
+        }
+        if state.faults.loss <= 0.0 {
+            return false;
+        }
+
+        // SplitMix64, which is plenty for picking messages and needs no dependency
+        state.rng = state.rng.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state.rng;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^= z >> 31;
+        ((z >> 11) as f64 / (1u64 << 53) as f64) < state.faults.loss
+    }
+
+    pub(crate) fn latency(&self) -> Duration {
+        self.lock().faults.latency
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, State> {
+        self.0.lock().expect("Unable to aquire fault injector lock")
+    }
+}
+
+/// Stands in for the fault injector when the `fault-injection` feature is off, never injecting
+/// anything
+#[cfg(not(any(test, feature = "fault-injection")))]
+#[derive(Default)]
+pub(crate) struct FaultInjector(());
+
+#[cfg(not(any(test, feature = "fault-injection")))]
+impl FaultInjector {
+    pub(crate) fn is_disconnected(&self) -> bool {
+        false
+    }
+
+    pub(crate) fn lose(&self) -> bool {
+        false
+    }
+
+    pub(crate) fn latency(&self) -> Duration {
+        Duration::ZERO
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_loss_follows_seed() {
+        let injector = FaultInjector::default();
+        assert!(!injector.lose());
+
+        injector.set(Faults {
+            loss: 0.25,
+            seed: 7,
+            ..Default::default()
+        });
+        let lost = (0..1000).map(|_| injector.lose()).collect::<Vec<_>>();
+        let count = lost.iter().filter(|lost| **lost).count();
+        assert!((200..300).contains(&count));
+
+        // The same seed loses the same messages
+        injector.set(Faults {
+            loss: 0.25,
+            seed: 7,
+            ..Default::default()
+        });
+        assert!(lost.iter().all(|lost| injector.lose() == *lost));
+
+        injector.set(Faults::default());
+        injector.set_disconnected(true);
+        assert!(injector.lose());
+    }
+}
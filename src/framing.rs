@@ -0,0 +1,411 @@
+use anyhow::{anyhow, Result as AResult};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+
+/// The largest payload carried by a single frame. SCTP in webrtc-rs refuses messages above
+/// ~64 KiB, and some browsers are stricter still
+pub(crate) const MAX_CHUNK_SIZE: usize = 16 * 1024;
+
+/// The largest message SCTP in webrtc-rs takes, which is also what a peer that doesn't say
+/// otherwise is assumed to take
+pub(crate) const DEFAULT_MAX_MESSAGE_SIZE: usize = 64 * 1024;
+
+/// How many partially received messages are kept before the oldest one is dropped, which bounds
+/// memory when chunks are lost on unreliable channels
+const MAX_PARTIAL_MESSAGES: usize = 64;
+
+/// The largest message reassembled from frames. Messages the peer claims are larger are
+/// refused rather than buffered
+pub(crate) const MAX_REASSEMBLED_SIZE: usize = 16 * 1024 * 1024;
+
+/// How many bytes the partially received messages may hold together before the oldest ones are
+/// dropped
+const MAX_BUFFERED_SIZE: usize = 2 * MAX_REASSEMBLED_SIZE;
+
+/// kind (1) + message id (4) + chunk index (4) + chunk count (4)
+const HEADER_SIZE: usize = 13;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum FrameKind {
+    Binary = 0,
+    Text = 1,
+    /// Asks the peer to echo the payload back as a `Pong`
+    Ping = 2,
+    Pong = 3,
+    /// Advertises the capability flags of the sender, such as `compression::LZ4`
+    Capabilities = 4,
+    CompressedBinary = 5,
+    CompressedText = 6,
+    /// Carries another kind of message, encrypted
+    Encrypted = 7,
+    /// Calls a handler the peer registered, see `rpc::Rpc`
+    Request = 8,
+    Response = 9,
+    /// Belongs to one of the streams multiplexed over the channel, see `mux::Mux`
+    Mux = 10,
+    /// A message the peer acknowledges once it has been delivered, see `receipt::Receipts`
+    Tracked = 11,
+    Ack = 12,
+    /// A message which is put in order with the others of its topic, see `topic::TopicOrder`
+    Topic = 13,
+}
+
+impl FrameKind {
+    /// The kind a message of this kind is sent as once it has been compressed
+    pub(crate) fn compressed(self) -> Self {
+        match self {
+            Self::Binary => Self::CompressedBinary,
+            Self::Text => Self::CompressedText,
+            kind => kind,
+        }
+    }
+
+    /// Reverses `compressed`
+    pub(crate) fn decompressed(self) -> Self {
+        match self {
+            Self::CompressedBinary => Self::Binary,
+            Self::CompressedText => Self::Text,
+            kind => kind,
+        }
+    }
+}
+
+impl TryFrom<u8> for FrameKind {
+    type Error = anyhow::Error;
+
+    fn try_from(value: u8) -> AResult<Self> {
+        match value {
+            0 => Ok(Self::Binary),
+            1 => Ok(Self::Text),
+            2 => Ok(Self::Ping),
+            3 => Ok(Self::Pong),
+            4 => Ok(Self::Capabilities),
+            5 => Ok(Self::CompressedBinary),
+            6 => Ok(Self::CompressedText),
+            7 => Ok(Self::Encrypted),
+            8 => Ok(Self::Request),
+            9 => Ok(Self::Response),
+            10 => Ok(Self::Mux),
+            11 => Ok(Self::Tracked),
+            12 => Ok(Self::Ack),
+            13 => Ok(Self::Topic),
+            _ => Err(anyhow!("Unknown frame kind {value}")),
+        }
+    }
+}
+
+/// The payload each frame carries, so whole frames stay within the largest message the peer
+/// takes
+pub(crate) fn chunk_size(max_message_size: usize) -> usize {
+    max_message_size
+        .saturating_sub(HEADER_SIZE)
+        .clamp(1, MAX_CHUNK_SIZE)
+}
+
+/// Splits `data` into frames of at most `chunk_size` bytes of payload, each prefixed with the
+/// header needed to reassemble them. The frames share a single allocation
+pub(crate) fn split(
+    kind: FrameKind,
+    message_id: u32,
+    data: &[u8],
+    chunk_size: usize,
+) -> Vec<Bytes> {
+    let count = data.len().div_ceil(chunk_size).max(1);
+    let mut frames = BytesMut::with_capacity(count * HEADER_SIZE + data.len());
+
+    if data.is_empty() {
+        put_frame(&mut frames, kind, message_id, 0, 1, &[]);
+        return vec![frames.freeze()];
+    }
+
+    data.chunks(chunk_size)
+        .enumerate()
+        .map(|(index, chunk)| {
+            put_frame(
+                &mut frames,
+                kind,
+                message_id,
+                index as u32,
+                count as u32,
+                chunk,
+            );
+            frames.split().freeze()
+        })
+        .collect()
+}
+
+/// A frame for a control message, such as a `Ping`, which always fits in a single frame
+pub(crate) fn control(kind: FrameKind, payload: &[u8]) -> Bytes {
+    let mut frame = BytesMut::with_capacity(HEADER_SIZE + payload.len());
+    put_frame(&mut frame, kind, 0, 0, 1, payload);
+    frame.freeze()
+}
+
+fn put_frame(
+    frames: &mut BytesMut,
+    kind: FrameKind,
+    message_id: u32,
+    index: u32,
+    count: u32,
+    payload: &[u8],
+) {
+    frames.put_u8(kind as u8);
+    frames.put_u32(message_id);
+    frames.put_u32(index);
+    frames.put_u32(count);
+    frames.put_slice(payload);
+}
+
+struct PartialMessage {
+    kind: FrameKind,
+    count: usize,
+    /// The chunks received so far by their index, which are only allocated as they arrive since
+    /// the count comes from the peer
+    chunks: BTreeMap<usize, Bytes>,
+    /// The bytes of the chunks received so far
+    size: usize,
+}
+
+/// Collects frames, in any order, until every chunk of a message has arrived
+#[derive(Default)]
+pub(crate) struct Reassembler {
+    partial: HashMap<u32, PartialMessage>,
+    arrival_order: VecDeque<u32>,
+    /// The `size` of every partial message together
+    buffered: usize,
+}
+
+impl Reassembler {
+    /// Adds a frame, returning the full message once its last missing chunk arrives
+    pub(crate) fn push(&mut self, mut frame: Bytes) -> AResult<Option<(FrameKind, Bytes)>> {
+        if frame.len() < HEADER_SIZE {
+            return Err(anyhow!("Frame is shorter than its header"));
+        }
+
+        let kind = FrameKind::try_from(frame.get_u8())?;
+        let message_id = frame.get_u32();
+        let index = frame.get_u32() as usize;
+        let count = frame.get_u32() as usize;
+
+        if index >= count {
+            return Err(anyhow!("Chunk {index} is out of range for {count} chunks"));
+        }
+        if count == 1 {
+            return Ok(Some((kind, frame)));
+        }
+        // Only a message of a single chunk is ever empty, so a message can't have more chunks
+        // than bytes
+        if count > MAX_REASSEMBLED_SIZE || frame.is_empty() {
+            return Err(anyhow!(
+                "Message {message_id} of {count} chunks is larger than {MAX_REASSEMBLED_SIZE} bytes"
+            ));
+        }
+
+        if !self.partial.contains_key(&message_id) {
+            if self.partial.len() >= MAX_PARTIAL_MESSAGES {
+                self.drop_oldest();
+            }
+            self.arrival_order.push_back(message_id);
+        }
+
+        let message = self
+            .partial
+            .entry(message_id)
+            .or_insert_with(|| PartialMessage {
+                kind,
+                count,
+                chunks: BTreeMap::new(),
+                size: 0,
+            });
+
+        if message.count != count {
+            return Err(anyhow!("Chunk count changed within message {message_id}"));
+        }
+        if !message.chunks.contains_key(&index) {
+            let size = frame.len();
+            if message.size + size > MAX_REASSEMBLED_SIZE {
+                self.remove(message_id);
+                return Err(anyhow!(
+                    "Message {message_id} is larger than {MAX_REASSEMBLED_SIZE} bytes"
+                ));
+            }
+            message.chunks.insert(index, frame);
+            message.size += size;
+            self.buffered += size;
+        }
+        if message.chunks.len() < count {
+            while self.buffered > MAX_BUFFERED_SIZE && self.arrival_order.len() > 1 {
+                self.drop_oldest();
+            }
+            return Ok(None);
+        }
+
+        let message = self.remove(message_id).expect("Message was just inserted");
+        let len = message.chunks.values().map(Bytes::len).sum();
+        let mut data = BytesMut::with_capacity(len);
+        for chunk in message.chunks.into_values() {
+            data.extend_from_slice(&chunk);
+        }
+        Ok(Some((message.kind, data.freeze())))
+    }
+
+    /// Drops the partial message which started arriving first
+    fn drop_oldest(&mut self) {
+        if let Some(&oldest) = self.arrival_order.front() {
+            self.remove(oldest);
+        }
+    }
+
+    fn remove(&mut self, message_id: u32) -> Option<PartialMessage> {
+        let message = self.partial.remove(&message_id)?;
+        self.arrival_order.retain(|id| *id != message_id);
+        self.buffered -= message.size;
+        Some(message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_small_message_is_one_frame() -> AResult<()> {
+        let frames = split(FrameKind::Text, 7, b"hello", MAX_CHUNK_SIZE);
+        assert_eq!(frames.len(), 1);
+
+        let mut reassembler = Reassembler::default();
+        assert_eq!(
+            reassembler.push(frames[0].clone())?,
+            Some((FrameKind::Text, Bytes::from_static(b"hello")))
+        );
+
+        let empty = split(FrameKind::Binary, 8, &[], MAX_CHUNK_SIZE);
+        assert_eq!(
+            reassembler.push(empty[0].clone())?,
+            Some((FrameKind::Binary, Bytes::new()))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_control_frames() -> AResult<()> {
+        let mut reassembler = Reassembler::default();
+        assert_eq!(
+            reassembler.push(control(FrameKind::Ping, &7u32.to_be_bytes()))?,
+            Some((FrameKind::Ping, Bytes::copy_from_slice(&7u32.to_be_bytes())))
+        );
+        assert_eq!(
+            reassembler.push(control(FrameKind::Pong, &[]))?,
+            Some((FrameKind::Pong, Bytes::new()))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_reassembles_out_of_order_and_interleaved() -> AResult<()> {
+        let first = (0..MAX_CHUNK_SIZE * 3 + 5)
+            .map(|i| i as u8)
+            .collect::<Vec<_>>();
+        let second = "é".repeat(MAX_CHUNK_SIZE);
+
+        let first_frames = split(FrameKind::Binary, 1, &first, MAX_CHUNK_SIZE);
+        let second_frames = split(FrameKind::Text, 2, second.as_bytes(), MAX_CHUNK_SIZE);
+        assert_eq!(first_frames.len(), 4);
+        assert_eq!(second_frames.len(), 2);
+
+        let frames = [
+            &first_frames[3],
+            &second_frames[1],
+            &first_frames[1],
+            &second_frames[0],
+            &first_frames[0],
+            &first_frames[2],
+        ];
+
+        let mut reassembler = Reassembler::default();
+        let mut completed = Vec::new();
+        for frame in frames {
+            completed.extend(reassembler.push(frame.clone())?);
+        }
+
+        assert_eq!(
+            completed,
+            vec![
+                (FrameKind::Text, Bytes::from(second)),
+                (FrameKind::Binary, Bytes::from(first)),
+            ]
+        );
+        assert!(reassembler.partial.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_frames_fit_the_max_message_size() -> AResult<()> {
+        assert_eq!(chunk_size(DEFAULT_MAX_MESSAGE_SIZE), MAX_CHUNK_SIZE);
+        assert_eq!(chunk_size(0), 1);
+
+        let data = vec![3u8; 1000];
+        let frames = split(FrameKind::Binary, 1, &data, chunk_size(256));
+        assert_eq!(frames.len(), 5);
+        assert!(frames.iter().all(|frame| frame.len() <= 256));
+        // Every frame is cut from the same buffer
+        assert!(frames
+            .windows(2)
+            .all(|pair| pair[0].as_ptr_range().end == pair[1].as_ptr()));
+
+        let mut reassembler = Reassembler::default();
+        let mut completed = None;
+        for frame in frames {
+            completed = reassembler.push(frame)?;
+        }
+        assert_eq!(completed, Some((FrameKind::Binary, Bytes::from(data))));
+        Ok(())
+    }
+
+    #[test]
+    fn test_drops_oldest_partial_message() -> AResult<()> {
+        let data = vec![1u8; MAX_CHUNK_SIZE + 1];
+        let mut reassembler = Reassembler::default();
+
+        for message_id in 0..=MAX_PARTIAL_MESSAGES as u32 {
+            let frames = split(FrameKind::Binary, message_id, &data, MAX_CHUNK_SIZE);
+            assert_eq!(reassembler.push(frames[0].clone())?, None);
+        }
+        assert_eq!(reassembler.partial.len(), MAX_PARTIAL_MESSAGES);
+        assert!(!reassembler.partial.contains_key(&0));
+
+        assert!(reassembler.push(Bytes::from_static(b"short")).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_refuses_oversized_messages() -> AResult<()> {
+        let mut reassembler = Reassembler::default();
+
+        // A peer claiming billions of chunks doesn't get them allocated up front
+        let mut frame = BytesMut::new();
+        put_frame(&mut frame, FrameKind::Binary, 1, 0, u32::MAX, b"chunk");
+        assert!(reassembler.push(frame.freeze()).is_err());
+        assert!(reassembler.partial.is_empty());
+
+        // Nor can it keep sending chunks of a message past the largest one reassembled
+        let count = (MAX_REASSEMBLED_SIZE / MAX_CHUNK_SIZE + 2) as u32;
+        let chunk = vec![0u8; MAX_CHUNK_SIZE];
+        let mut refused = false;
+        for index in 0..count {
+            let mut frame = BytesMut::new();
+            put_frame(&mut frame, FrameKind::Binary, 2, index, count, &chunk);
+            match reassembler.push(frame.freeze()) {
+                Ok(completed) => assert_eq!(completed, None),
+                Err(_) => {
+                    refused = true;
+                    break;
+                }
+            }
+        }
+        assert!(refused);
+        assert!(reassembler.partial.is_empty());
+        assert_eq!(reassembler.buffered, 0);
+        Ok(())
+    }
+}
@@ -0,0 +1,407 @@
+use crate::signaling::{
+    resumed_room_event_stream, BroadcastCandidateArgs, PeerSignal, RoomConfig, RoomEvent,
+    RoomSettings, Signaling, TlsConfig, DEFAULT_POLL_INTERVAL,
+};
+use anyhow::Result as AResult;
+use futures::stream::BoxStream;
+use futures::{Stream, StreamExt};
+use std::collections::HashSet;
+use tonic::transport::Channel;
+
+mod proto {
+    // The variants are named after the messages in `proto/signaling.proto`
+    #![allow(clippy::enum_variant_names)]
+    tonic::include_proto!("rust_p2p.signaling.v1");
+}
+
+use proto::signaling_service_client::SignalingServiceClient;
+
+/// Signals through a gRPC signaling server speaking `proto/signaling.proto`, for deployments
+/// which standardize on gRPC. Announcements and signals travel as the same JSON the
+/// `signal_server` takes and hands out, and rooms with a secret are encrypted the same way.
+/// Room events are streamed by the server, or polled for every second if it doesn't stream them
+#[derive(Clone)]
+pub struct GrpcSignaling {
+    client: SignalingServiceClient<Channel>,
+}
+
+impl GrpcSignaling {
+    /// Connects to the gRPC signaling server at `url`, e.g. `http://localhost:50051`
+    pub async fn connect(url: impl Into<String>) -> AResult<Self> {
+        Ok(Self {
+            client: SignalingServiceClient::connect(url.into()).await?,
+        })
+    }
+
+    /// Connects to the gRPC signaling server at `url` over TLS, e.g. `https://localhost:50051`,
+    /// verifying its certificate according to `tls` the same way a `SignalServer` does
+    pub async fn connect_with_tls(url: impl Into<String>, tls: &TlsConfig) -> AResult<Self> {
+        let channel = Channel::from_shared(url.into())?
+            .tls_config(tls.client_tls_config())?
+            .connect()
+            .await?;
+
+        Ok(Self::with_channel(channel))
+    }
+
+    /// Signals over an already configured `channel`, such as one with TLS or timeouts
+    pub fn with_channel(channel: Channel) -> Self {
+        Self {
+            client: SignalingServiceClient::new(channel),
+        }
+    }
+
+    /// Polls `room` every second, for a subscriber which already knows `known` to be in it
+    fn poll<'a>(&'a self, room: &'a RoomConfig, known: HashSet<String>) -> EventSource<'a> {
+        EventSource::Polling(
+            resumed_room_event_stream(
+                known,
+                || async { Ok(None) },
+                move || async move { self.peers(room).await.ok() },
+                || DEFAULT_POLL_INTERVAL,
+                "",
+            )
+            .boxed(),
+        )
+    }
+}
+
+fn proto_room(room: &RoomConfig) -> proto::Room {
+    proto::Room {
+        channel: room.channel.clone(),
+        room: room.room.clone(),
+    }
+}
+
+fn peer_request(room: &RoomConfig, peer_id: &str) -> proto::PeerRequest {
+    proto::PeerRequest {
+        room: Some(proto_room(room)),
+        peer_id: peer_id.to_owned(),
+    }
+}
+
+fn room_event(event: proto::room_event::Event) -> RoomEvent {
+    match event {
+        proto::room_event::Event::PeerJoined(peer_id) => RoomEvent::PeerJoined { peer_id },
+        proto::room_event::Event::PeerUpdated(peer_id) => RoomEvent::PeerUpdated { peer_id },
+        proto::room_event::Event::PeerLeft(peer_id) => RoomEvent::PeerLeft { peer_id },
+    }
+}
+
+impl Signaling for GrpcSignaling {
+    async fn announce(
+        &self,
+        room: &RoomConfig,
+        peer_id: &str,
+        args: &BroadcastCandidateArgs,
+    ) -> AResult<()> {
+        let mut args = room.seal(peer_id, args)?;
+        args.room_settings =
+            (room.settings != RoomSettings::default()).then(|| room.settings.clone());
+
+        let request = proto::AnnounceRequest {
+            room: Some(proto_room(room)),
+            peer_id: peer_id.to_owned(),
+            announcement: serde_json::to_vec(&args)?,
+        };
+        self.client.clone().announce(request).await?;
+
+        Ok(())
+    }
+
+    async fn withdraw(&self, room: &RoomConfig, peer_id: &str) -> AResult<()> {
+        self.client
+            .clone()
+            .withdraw(peer_request(room, peer_id))
+            .await?;
+
+        Ok(())
+    }
+
+    async fn peers(&self, room: &RoomConfig) -> AResult<Vec<String>> {
+        let response = self.client.clone().peers(proto_room(room)).await?;
+
+        Ok(response.into_inner().peer_ids)
+    }
+
+    async fn fetch(&self, room: &RoomConfig, peer_id: &str) -> AResult<Option<PeerSignal>> {
+        let response = self
+            .client
+            .clone()
+            .fetch(peer_request(room, peer_id))
+            .await?;
+        let Some(signal) = response.into_inner().signal else {
+            return Ok(None);
+        };
+
+        Ok(Some(room.open(peer_id, serde_json::from_slice(&signal)?)?))
+    }
+
+    /// Streams the room events the server pushes. Servers which don't stream them, or whose
+    /// stream fails or ends, are polled every second instead, which only sees peers join and
+    /// leave
+    fn subscribe<'a>(&'a self, room: &'a RoomConfig) -> impl Stream<Item = RoomEvent> + Send + 'a {
+        futures::stream::unfold(
+            (EventSource::Connecting, HashSet::new()),
+            move |(mut source, mut known)| async move {
+                loop {
+                    match source {
+                        EventSource::Connecting => {
+                            source = match self.client.clone().subscribe(proto_room(room)).await {
+                                Ok(events) => EventSource::Streaming(Box::new(events.into_inner())),
+                                Err(_) => self.poll(room, std::mem::take(&mut known)),
+                            };
+                        }
+                        EventSource::Streaming(ref mut events) => {
+                            let message = events.message().await;
+                            let event = match message {
+                                Ok(Some(event)) => event.event.map(room_event),
+                                Ok(None) | Err(_) => {
+                                    source = self.poll(room, std::mem::take(&mut known));
+                                    continue;
+                                }
+                            };
+                            match &event {
+                                Some(
+                                    RoomEvent::PeerJoined { peer_id }
+                                    | RoomEvent::PeerUpdated { peer_id },
+                                ) => {
+                                    known.insert(peer_id.clone());
+                                }
+                                Some(RoomEvent::PeerLeft { peer_id }) => {
+                                    known.remove(peer_id);
+                                }
+                                None => {}
+                            }
+                            if let Some(event) = event {
+                                return Some((event, (source, known)));
+                            }
+                        }
+                        EventSource::Polling(ref mut events) => {
+                            let event = events.next().await?;
+                            return Some((event, (source, known)));
+                        }
+                    }
+                }
+            },
+        )
+    }
+}
+
+/// Where the room events of a subscription come from
+enum EventSource<'a> {
+    Connecting,
+    Streaming(Box<tonic::Streaming<proto::RoomEvent>>),
+    Polling(BoxStream<'a, RoomEvent>),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::proto::signaling_service_server::{SignalingService, SignalingServiceServer};
+    use super::*;
+    use crate::signaling::tests::{announce_through, free_port};
+    use std::collections::HashMap;
+    use std::net::Ipv4Addr;
+    use std::sync::Mutex;
+    use std::time::Duration;
+    use tonic::{Request, Response, Status};
+    use uuid::Uuid;
+
+    /// The announcement of every peer, as the JSON of a `PeerSignal`, by room and peer id
+    type Rooms = HashMap<(String, String), HashMap<String, Vec<u8>>>;
+
+    /// A gRPC signaling server keeping the latest announcement of every peer. Unless it
+    /// `streams`, it doesn't stream room events, and when it does, the stream ends right after
+    /// the peers already in the room
+    #[derive(Default)]
+    struct MemoryService {
+        rooms: Mutex<Rooms>,
+        streams: bool,
+    }
+
+    // Services fail with tonic's `Status`, however large it is
+    #[allow(clippy::result_large_err)]
+    fn room_key(room: Option<proto::Room>) -> Result<(String, String), Status> {
+        let room = room.ok_or_else(|| Status::invalid_argument("No room given"))?;
+        Ok((room.channel, room.room))
+    }
+
+    #[tonic::async_trait]
+    impl SignalingService for MemoryService {
+        type SubscribeStream =
+            futures::stream::Iter<std::vec::IntoIter<Result<proto::RoomEvent, Status>>>;
+
+        async fn announce(
+            &self,
+            request: Request<proto::AnnounceRequest>,
+        ) -> Result<Response<proto::AnnounceResponse>, Status> {
+            let request = request.into_inner();
+            let args: BroadcastCandidateArgs = serde_json::from_slice(&request.announcement)
+                .map_err(|err| Status::invalid_argument(err.to_string()))?;
+            let signal = serde_json::to_vec(&PeerSignal {
+                candidates: args.candidates,
+                session_description: args.session_description,
+                sealed: args.sealed,
+                metadata: args.metadata,
+                signature: args.signature,
+                cursor: 0,
+                verified: false,
+            })
+            .map_err(|err| Status::internal(err.to_string()))?;
+
+            self.rooms
+                .lock()
+                .unwrap()
+                .entry(room_key(request.room)?)
+                .or_default()
+                .insert(request.peer_id, signal);
+            Ok(Response::new(proto::AnnounceResponse {}))
+        }
+
+        async fn withdraw(
+            &self,
+            request: Request<proto::PeerRequest>,
+        ) -> Result<Response<proto::WithdrawResponse>, Status> {
+            let request = request.into_inner();
+            if let Some(peers) = self.rooms.lock().unwrap().get_mut(&room_key(request.room)?) {
+                peers.remove(&request.peer_id);
+            }
+            Ok(Response::new(proto::WithdrawResponse {}))
+        }
+
+        async fn peers(
+            &self,
+            request: Request<proto::Room>,
+        ) -> Result<Response<proto::PeersResponse>, Status> {
+            let key = room_key(Some(request.into_inner()))?;
+            let peer_ids = self
+                .rooms
+                .lock()
+                .unwrap()
+                .get(&key)
+                .map(|peers| peers.keys().cloned().collect())
+                .unwrap_or_default();
+            Ok(Response::new(proto::PeersResponse { peer_ids }))
+        }
+
+        async fn fetch(
+            &self,
+            request: Request<proto::PeerRequest>,
+        ) -> Result<Response<proto::FetchResponse>, Status> {
+            let request = request.into_inner();
+            let signal = self
+                .rooms
+                .lock()
+                .unwrap()
+                .get(&room_key(request.room)?)
+                .and_then(|peers| peers.get(&request.peer_id).cloned());
+            Ok(Response::new(proto::FetchResponse { signal }))
+        }
+
+        #[allow(clippy::result_large_err)]
+        async fn subscribe(
+            &self,
+            request: Request<proto::Room>,
+        ) -> Result<Response<Self::SubscribeStream>, Status> {
+            if !self.streams {
+                return Err(Status::unimplemented("Room events aren't streamed"));
+            }
+
+            let key = room_key(Some(request.into_inner()))?;
+            let events = self
+                .rooms
+                .lock()
+                .unwrap()
+                .get(&key)
+                .map(|peers| {
+                    peers
+                        .keys()
+                        .map(|peer_id| {
+                            Ok(proto::RoomEvent {
+                                event: Some(proto::room_event::Event::PeerJoined(peer_id.clone())),
+                            })
+                        })
+                        .collect::<Vec<_>>()
+                })
+                .unwrap_or_default();
+            Ok(Response::new(futures::stream::iter(events)))
+        }
+    }
+
+    /// Serves `service` on a free port, returning a client connected to it
+    async fn serve(service: MemoryService) -> AResult<GrpcSignaling> {
+        let port = free_port()?;
+        let server = tonic::transport::Server::builder()
+            .add_service(SignalingServiceServer::new(service))
+            .serve((Ipv4Addr::LOCALHOST, port).into());
+        tokio::spawn(server);
+
+        let url = format!("http://127.0.0.1:{port}");
+        let signaling = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                if let Ok(signaling) = GrpcSignaling::connect(url.as_str()).await {
+                    break signaling;
+                }
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        })
+        .await?;
+        Ok(signaling)
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_grpc_signaling_is_signaling() -> AResult<()> {
+        let signaling = serve(MemoryService::default()).await?;
+
+        let room = RoomConfig::new("test", Uuid::new_v4().to_string());
+        announce_through(&signaling, &room).await?;
+
+        let secret_room = RoomConfig::new("test", Uuid::new_v4().to_string()).with_secret("grpc");
+        announce_through(&signaling, &secret_room).await
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_ended_event_streams_fall_back_to_polling() -> AResult<()> {
+        let signaling = serve(MemoryService {
+            streams: true,
+            ..Default::default()
+        })
+        .await?;
+        let room = RoomConfig::new("test", Uuid::new_v4().to_string());
+        let (first, second) = (Uuid::new_v4().to_string(), Uuid::new_v4().to_string());
+        let args = BroadcastCandidateArgs {
+            candidates: Vec::new(),
+            session_description: None,
+            sealed: None,
+            room_settings: None,
+            metadata: std::collections::BTreeMap::new(),
+            signature: None,
+        };
+        signaling.announce(&room, &first, &args).await?;
+
+        let events = signaling.subscribe(&room);
+        futures::pin_mut!(events);
+        let timeout = Duration::from_secs(5);
+        assert_eq!(
+            tokio::time::timeout(timeout, events.next()).await?,
+            Some(RoomEvent::PeerJoined {
+                peer_id: first.clone()
+            })
+        );
+
+        // The stream has ended, so the room is polled, without yielding the first peer again
+        signaling.announce(&room, &second, &args).await?;
+        assert_eq!(
+            tokio::time::timeout(timeout, events.next()).await?,
+            Some(RoomEvent::PeerJoined { peer_id: second })
+        );
+
+        signaling.withdraw(&room, &first).await?;
+        assert_eq!(
+            tokio::time::timeout(timeout, events.next()).await?,
+            Some(RoomEvent::PeerLeft { peer_id: first })
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,349 @@
+use crate::signaling::{
+    merge_announcement, BroadcastCandidateArgs, PeerSignal, RoomConfig, Signaling,
+};
+use anyhow::Result as AResult;
+use serde::{Deserialize, Serialize};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::collections::{BTreeMap, HashMap};
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+use tokio::task::JoinHandle;
+
+/// The multicast group `LanSignaling` announces to by default
+pub const DEFAULT_GROUP: Ipv4Addr = Ipv4Addr::new(239, 255, 47, 47);
+pub const DEFAULT_PORT: u16 = 47474;
+
+/// How often every instance re-sends its own announcements, so peers joining later hear them
+const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Peers which weren't heard from for this long are forgotten, like the `signal_server` does
+const PEER_TTL: Duration = Duration::from_secs(60);
+
+/// What the instances on the network multicast to each other, as JSON
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum LanMessage {
+    Announce {
+        channel: String,
+        room: String,
+        peer_id: String,
+        args: Box<BroadcastCandidateArgs>,
+    },
+    Withdraw {
+        channel: String,
+        room: String,
+        peer_id: String,
+    },
+}
+
+#[derive(Default)]
+struct LanState {
+    /// What each peer announced in each room, keyed by channel and room, and when it was last
+    /// heard from
+    rooms: HashMap<(String, String), HashMap<String, (PeerSignal, Instant)>>,
+    /// What was announced through this instance, keyed by channel, room and peer id
+    own: HashMap<(String, String, String), BroadcastCandidateArgs>,
+}
+
+impl LanState {
+    fn apply(&mut self, message: LanMessage) {
+        match message {
+            LanMessage::Announce {
+                channel,
+                room,
+                peer_id,
+                args,
+            } => {
+                let peers = self.rooms.entry((channel, room)).or_default();
+                let (signal, last_seen) = peers.entry(peer_id).or_insert_with(|| {
+                    let signal = PeerSignal {
+                        candidates: Vec::new(),
+                        session_description: None,
+                        sealed: None,
+                        metadata: BTreeMap::new(),
+                        signature: None,
+                        cursor: 0,
+                        verified: false,
+                    };
+                    (signal, Instant::now())
+                });
+                merge_announcement(signal, *args);
+                *last_seen = Instant::now();
+            }
+            LanMessage::Withdraw {
+                channel,
+                room,
+                peer_id,
+            } => {
+                let key = (channel, room);
+                if let Some(peers) = self.rooms.get_mut(&key) {
+                    peers.remove(&peer_id);
+                    if peers.is_empty() {
+                        self.rooms.remove(&key);
+                    }
+                }
+            }
+        }
+    }
+
+    fn forget_stale_peers(&mut self) {
+        for peers in self.rooms.values_mut() {
+            peers.retain(|_, (_, last_seen)| last_seen.elapsed() < PEER_TTL);
+        }
+        self.rooms.retain(|_, peers| !peers.is_empty());
+    }
+}
+
+/// Signals through UDP multicast on the local network, so peers on the same LAN can connect
+/// without any signaling server. Every instance remembers what it hears announced, and re-sends
+/// its own announcements every second so peers joining later hear them too.
+///
+/// Anyone on the network can read and forge announcements, so use rooms with a secret, see
+/// `RoomConfig::with_secret`. Room passwords and peer limits aren't enforced, since there is no
+/// server to enforce them
+pub struct LanSignaling {
+    socket: Arc<UdpSocket>,
+    group: SocketAddrV4,
+    state: Arc<Mutex<LanState>>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl LanSignaling {
+    /// Joins the `DEFAULT_GROUP` on the `DEFAULT_PORT`
+    pub fn bind() -> AResult<Self> {
+        Self::bind_to(DEFAULT_GROUP, DEFAULT_PORT)
+    }
+
+    /// Joins the multicast `group` on `port`. Several instances may join the same group on the
+    /// same host, such as several applications signaling through it
+    pub fn bind_to(group: Ipv4Addr, port: u16) -> AResult<Self> {
+        let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+        socket.set_reuse_address(true)?;
+        socket.set_nonblocking(true)?;
+        socket.bind(&SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, port).into())?;
+        socket.join_multicast_v4(&group, &Ipv4Addr::UNSPECIFIED)?;
+        socket.set_multicast_loop_v4(true)?;
+        let socket = Arc::new(UdpSocket::from_std(socket.into())?);
+
+        let group = SocketAddrV4::new(group, port);
+        let state = Arc::new(Mutex::new(LanState::default()));
+        let tasks = vec![
+            tokio::spawn(receive(socket.clone(), state.clone())),
+            tokio::spawn(reannounce(socket.clone(), group, state.clone())),
+        ];
+
+        Ok(Self {
+            socket,
+            group,
+            state,
+            tasks,
+        })
+    }
+
+    async fn send(&self, message: &LanMessage) -> AResult<()> {
+        self.socket
+            .send_to(&serde_json::to_vec(message)?, self.group)
+            .await?;
+        Ok(())
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, LanState> {
+        lock(&self.state)
+    }
+}
+
+fn lock(state: &Mutex<LanState>) -> std::sync::MutexGuard<'_, LanState> {
+    state.lock().expect("Unable to aquire LAN signaling lock")
+}
+
+/// Takes in what the other instances multicast, ignoring anything which isn't understood
+async fn receive(socket: Arc<UdpSocket>, state: Arc<Mutex<LanState>>) {
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+        let Ok((len, _)) = socket.recv_from(&mut buffer).await else {
+            continue;
+        };
+        if let Ok(message) = serde_json::from_slice(&buffer[..len]) {
+            lock(&state).apply(message);
+        }
+    }
+}
+
+/// Re-sends the announcements made through this instance every `ANNOUNCE_INTERVAL`
+async fn reannounce(socket: Arc<UdpSocket>, group: SocketAddrV4, state: Arc<Mutex<LanState>>) {
+    loop {
+        tokio::time::sleep(ANNOUNCE_INTERVAL).await;
+
+        let datagrams = {
+            let mut state = lock(&state);
+            state.forget_stale_peers();
+            state
+                .own
+                .iter()
+                .filter_map(|((channel, room, peer_id), args)| {
+                    serde_json::to_vec(&LanMessage::Announce {
+                        channel: channel.clone(),
+                        room: room.clone(),
+                        peer_id: peer_id.clone(),
+                        args: Box::new(args.clone()),
+                    })
+                    .ok()
+                })
+                .collect::<Vec<_>>()
+        };
+        for datagram in datagrams {
+            let _ = socket.send_to(&datagram, group).await;
+        }
+    }
+}
+
+impl Signaling for LanSignaling {
+    async fn announce(
+        &self,
+        room: &RoomConfig,
+        peer_id: &str,
+        args: &BroadcastCandidateArgs,
+    ) -> AResult<()> {
+        let args = room.seal(peer_id, args)?;
+        let message = LanMessage::Announce {
+            channel: room.channel.clone(),
+            room: room.room.clone(),
+            peer_id: peer_id.to_owned(),
+            args: Box::new(args.clone()),
+        };
+
+        {
+            let mut state = self.lock();
+            let key = (room.channel.clone(), room.room.clone(), peer_id.to_owned());
+            match state.own.get_mut(&key) {
+                Some(own) => {
+                    for candidate in args.candidates.iter() {
+                        if !own.candidates.contains(candidate) {
+                            own.candidates.push(candidate.clone());
+                        }
+                    }
+                    own.session_description = args.session_description.clone();
+                    own.sealed = args.sealed.clone();
+                    own.metadata = args.metadata.clone();
+                    own.signature = args.signature.clone();
+                }
+                None => {
+                    state.own.insert(key, args);
+                }
+            }
+        }
+
+        self.send(&message).await?;
+        // Heard right away, even if multicast doesn't loop back to this host
+        self.lock().apply(message);
+        Ok(())
+    }
+
+    async fn withdraw(&self, room: &RoomConfig, peer_id: &str) -> AResult<()> {
+        let message = LanMessage::Withdraw {
+            channel: room.channel.clone(),
+            room: room.room.clone(),
+            peer_id: peer_id.to_owned(),
+        };
+        self.lock()
+            .own
+            .remove(&(room.channel.clone(), room.room.clone(), peer_id.to_owned()));
+
+        self.send(&message).await?;
+        self.lock().apply(message);
+        Ok(())
+    }
+
+    async fn peers(&self, room: &RoomConfig) -> AResult<Vec<String>> {
+        Ok(self
+            .lock()
+            .rooms
+            .get(&(room.channel.clone(), room.room.clone()))
+            .map(|peers| peers.keys().cloned().collect())
+            .unwrap_or_default())
+    }
+
+    async fn fetch(&self, room: &RoomConfig, peer_id: &str) -> AResult<Option<PeerSignal>> {
+        let signal = self
+            .lock()
+            .rooms
+            .get(&(room.channel.clone(), room.room.clone()))
+            .and_then(|peers| peers.get(peer_id))
+            .map(|(signal, _)| signal.clone());
+
+        signal.map(|signal| room.open(peer_id, signal)).transpose()
+    }
+}
+
+impl Drop for LanSignaling {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::signaling::tests::announce_through;
+    use uuid::Uuid;
+
+    /// A port no other test signals on
+    fn free_port() -> AResult<u16> {
+        Ok(std::net::UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))?
+            .local_addr()?
+            .port())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_lan_signaling_is_signaling() -> AResult<()> {
+        let signaling = LanSignaling::bind_to(DEFAULT_GROUP, free_port()?)?;
+        let room = RoomConfig::new("test", Uuid::new_v4().to_string());
+
+        announce_through(&signaling, &room).await
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_peers_hear_each_other() -> AResult<()> {
+        let port = free_port()?;
+        let local = LanSignaling::bind_to(DEFAULT_GROUP, port)?;
+        let room = RoomConfig::new("test", Uuid::new_v4().to_string()).with_secret("lan party");
+        let peer_id = Uuid::new_v4().to_string();
+
+        let args = BroadcastCandidateArgs {
+            candidates: Vec::new(),
+            session_description: None,
+            sealed: None,
+            room_settings: None,
+            metadata: BTreeMap::new(),
+            signature: None,
+        };
+        local.announce(&room, &peer_id, &args).await?;
+
+        // Joins after the announcement was first sent, so only hears it re-sent
+        let remote = LanSignaling::bind_to(DEFAULT_GROUP, port)?;
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while remote.peers(&room).await?.is_empty() {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+            AResult::<()>::Ok(())
+        })
+        .await??;
+        assert_eq!(remote.peers(&room).await?, std::slice::from_ref(&peer_id));
+        assert!(remote.fetch(&room, &peer_id).await?.is_some());
+
+        local.withdraw(&room, &peer_id).await?;
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while !remote.peers(&room).await?.is_empty() {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+            AResult::<()>::Ok(())
+        })
+        .await??;
+
+        Ok(())
+    }
+}
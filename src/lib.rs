@@ -1,2 +1,28 @@
+pub mod certificate;
+pub mod channel;
+pub mod codec;
+mod compression;
+pub mod encryption;
+pub mod error;
+pub mod fault;
+mod framing;
+#[cfg(feature = "grpc")]
+pub mod grpc_signaling;
+pub mod lan_signaling;
+pub mod lobby;
+pub mod media;
+pub mod memory_signaling;
+pub mod mux;
+mod outbox;
 pub mod p2p_client;
-mod p2p_connection;
+pub mod p2p_connection;
+pub mod quality;
+pub mod rate_limit;
+mod receipt;
+mod receive_buffer;
+mod rpc;
+pub mod signal_socket;
+pub mod signaling;
+pub mod stats;
+pub mod stream;
+mod topic;
@@ -0,0 +1,794 @@
+use crate::error::ClientError;
+use crate::p2p_client::P2PClient;
+use crate::p2p_connection::{ConnectionState, P2PConnection};
+use crate::signaling::{RoomConfig, RoomHandle, RoomSettings, SignalServer, Signaling};
+use anyhow::{anyhow, Result as AResult};
+use futures::future::BoxFuture;
+use futures::stream::FuturesUnordered;
+use futures::{FutureExt, Stream, StreamExt};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use webrtc::ice_transport::ice_candidate::RTCIceCandidate;
+use webrtc::peer_connection::sdp::sdp_type::RTCSdpType;
+
+/// How often `Lobby::run` re-announces the local peer, well within the 60 seconds after which
+/// the signaling server purges peers which haven't announced themselves
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(20);
+
+/// A change in the membership of a `Lobby`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LobbyEvent {
+    /// A connection to the peer has been established
+    MemberJoined(String),
+    /// The connection to the peer has been lost, or could not be established
+    MemberLeft(String),
+}
+
+/// A group of peers in a room, where every peer connects to every other peer.
+/// Created with `P2PClient::join_lobby`, and driven by polling the stream returned by
+/// `Lobby::run`. Signals through a `SignalServer` unless given another backend
+pub struct Lobby<'a, S: Signaling = SignalServer> {
+    client: &'a P2PClient,
+    handle: RoomHandle<'a, S>,
+    require_reliable_transmission: bool,
+    members: Mutex<HashSet<String>>,
+    /// When the connection to each member which is reconnecting dropped
+    down_since: Mutex<HashMap<String, Instant>>,
+    /// The members whose connection has an ICE restart in flight
+    restarting: Mutex<HashSet<String>>,
+}
+
+impl<'a, S: Signaling> Lobby<'a, S> {
+    pub(crate) fn new(
+        client: &'a P2PClient,
+        handle: RoomHandle<'a, S>,
+        require_reliable_transmission: bool,
+    ) -> Self {
+        Self {
+            client,
+            handle,
+            require_reliable_transmission,
+            members: Mutex::new(HashSet::new()),
+            down_since: Mutex::new(HashMap::new()),
+            restarting: Mutex::new(HashSet::new()),
+        }
+    }
+
+    /// Announces `value` under `key` along with the local peer, such as its display name. Peers
+    /// answering the local peer's offer hand it to their `P2PClient::on_incoming` handler
+    pub fn with_metadata(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.handle = self.handle.with_metadata(key, value);
+        self
+    }
+
+    pub fn room(&self) -> &RoomConfig {
+        self.handle.room()
+    }
+
+    /// The ids of every peer currently connected through this lobby
+    pub fn members(&self) -> Vec<String> {
+        self.members
+            .lock()
+            .expect("Unable to aquire members lock")
+            .iter()
+            .cloned()
+            .collect()
+    }
+
+    fn is_member(&self, peer_id: &str) -> bool {
+        self.members
+            .lock()
+            .expect("Unable to aquire members lock")
+            .contains(peer_id)
+    }
+
+    /// Sends `data` to every member of the lobby
+    pub async fn broadcast(&self, data: &[u8]) -> AResult<()> {
+        for peer_id in self.members() {
+            let connection = self
+                .client
+                .get_connection(&peer_id)
+                .await
+                .ok_or_else(|| ClientError::UnknownPeer(peer_id.clone()))?;
+            connection.send(data).await?;
+        }
+        Ok(())
+    }
+
+    /// Announces the local peer in the lobby, then connects to every peer which is or becomes
+    /// present in it, yielding each change in membership. The announcement is renewed while the
+    /// stream is polled, so the local peer stays in the lobby.
+    /// Nothing happens unless the stream is polled. Dropping the stream and calling `run` again
+    /// keeps the current members
+    pub fn run(&self) -> impl Stream<Item = LobbyEvent> + '_ {
+        let state = RunState {
+            announced: false,
+            discovered: self.handle.discovered_peers().boxed(),
+            handshakes: FuturesUnordered::new(),
+            renegotiations: FuturesUnordered::new(),
+            health_check: tokio::time::interval(self.handle.poll_interval()),
+            heartbeat: self.handle.start_heartbeat(HEARTBEAT_INTERVAL).boxed(),
+        };
+
+        futures::stream::unfold(state, move |mut state| async move {
+            if !state.announced {
+                state.announced = true;
+                let _ = self.handle.announce_presence().await;
+            }
+
+            loop {
+                tokio::select! {
+                    Some(peer) = state.discovered.next() => {
+                        if !self.is_member(&peer.peer_id) {
+                            state.handshakes.push(self.handshake(peer.peer_id).boxed());
+                        }
+                    }
+                    Some((peer_id, result)) = state.handshakes.next(), if !state.handshakes.is_empty() => {
+                        let event = match result {
+                            Ok(()) => {
+                                self.members
+                                    .lock()
+                                    .expect("Unable to aquire members lock")
+                                    .insert(peer_id.clone());
+                                LobbyEvent::MemberJoined(peer_id)
+                            }
+                            Err(_) => LobbyEvent::MemberLeft(peer_id),
+                        };
+                        return Some((event, state));
+                    }
+                    Some(()) = state.renegotiations.next(), if !state.renegotiations.is_empty() => {}
+                    // Never completes, re-announcing the local peer on every beat
+                    _ = &mut state.heartbeat => {}
+                    _ = state.health_check.tick() => {
+                        if let Some(peer_id) = self.departed_member().await {
+                            return Some((LobbyEvent::MemberLeft(peer_id), state));
+                        }
+                        state.renegotiations.extend(self.renegotiate_members().await);
+                    }
+                }
+            }
+        })
+    }
+
+    /// Removes and returns a member whose connection is gone, has failed, or has been closed.
+    /// A merely disconnected member is kept, as ICE can still recover or be restarted. When the
+    /// client reconnects, a failed member is kept as well, until it has been down for as long
+    /// as the connect timeout
+    async fn departed_member(&self) -> Option<String> {
+        let reconnects = self.client.reconnect_buffer.is_some();
+        for peer_id in self.members() {
+            let state = self
+                .client
+                .get_connection(&peer_id)
+                .await
+                .map(|connection| connection.state());
+
+            let departed = match state {
+                None | Some(ConnectionState::Closed) => true,
+                Some(ConnectionState::Connected) => {
+                    self.down_since
+                        .lock()
+                        .expect("Unable to aquire down since lock")
+                        .remove(&peer_id);
+                    false
+                }
+                Some(_) if reconnects => {
+                    self.down_since
+                        .lock()
+                        .expect("Unable to aquire down since lock")
+                        .entry(peer_id.clone())
+                        .or_insert_with(Instant::now)
+                        .elapsed()
+                        > self.client.connect_timeout
+                }
+                Some(state) => state == ConnectionState::Failed,
+            };
+
+            if departed {
+                self.members
+                    .lock()
+                    .expect("Unable to aquire members lock")
+                    .remove(&peer_id);
+                self.down_since
+                    .lock()
+                    .expect("Unable to aquire down since lock")
+                    .remove(&peer_id);
+                return Some(peer_id);
+            }
+        }
+        None
+    }
+
+    /// Connects to `peer_id` through a room private to the two peers. The peer with the lower id
+    /// makes the offer, and the other one answers it
+    async fn handshake(&self, peer_id: String) -> (String, AResult<()>) {
+        let result = self.try_handshake(&peer_id).await;
+        (peer_id, result)
+    }
+
+    async fn try_handshake(&self, peer_id: &str) -> AResult<()> {
+        let local_id = self.handle.peer_id();
+        let signal_server = self.handle.signal_server();
+        let pair_room = pair_room(self.room(), local_id, peer_id);
+
+        let connection = if local_id < peer_id {
+            let connection = self
+                .client
+                .create_connection(peer_id, self.require_reliable_transmission)
+                .await?;
+            connection.get_offer().await?;
+
+            loop {
+                self.ensure_tracked(peer_id, &connection).await?;
+                self.handle.announce_to(&pair_room, &connection).await?;
+
+                let answer = signal_server
+                    .fetch(&pair_room, peer_id)
+                    .await?
+                    .and_then(|signal| signal.session_description)
+                    .filter(|sdp| sdp.sdp_type == RTCSdpType::Answer);
+                if let Some(answer) = answer {
+                    connection.set_answer(answer).await?;
+                    break connection;
+                }
+
+                tokio::time::sleep(self.handle.handshake_poll_interval()).await;
+            }
+        } else {
+            // A peer which left before making its offer is given up on like a connection which
+            // never completes
+            let (offer, metadata) = tokio::time::timeout(self.client.connect_timeout, async {
+                loop {
+                    let signal = signal_server.fetch(&pair_room, peer_id).await?;
+                    if let Some(signal) = signal {
+                        if let Some(offer) = signal
+                            .session_description
+                            .filter(|sdp| sdp.sdp_type == RTCSdpType::Offer)
+                        {
+                            return Ok::<_, anyhow::Error>((offer, signal.metadata));
+                        }
+                    }
+
+                    tokio::time::sleep(self.handle.handshake_poll_interval()).await;
+                }
+            })
+            .await
+            .map_err(|_| ClientError::ConnectTimeout(peer_id.to_string()))??;
+
+            let (connection, _) = self
+                .client
+                .answer_connection(
+                    peer_id,
+                    &metadata,
+                    offer,
+                    self.require_reliable_transmission,
+                )
+                .await?;
+            connection
+        };
+
+        self.exchange_candidates(peer_id, &connection).await?;
+        self.client.assign_room(peer_id, self.room().clone()).await
+    }
+
+    /// Restarts ICE on the connection to `peer_id`, such as after a network change, exchanging
+    /// the new offer, answer and candidates through the signal server. The data channels stay
+    /// open throughout. The peer picks up the restart while its `Lobby::run` stream is polled.
+    /// Resolves to `ClientError::ConnectTimeout` if the connection doesn't recover within the
+    /// client's connect timeout
+    pub async fn restart_ice(&self, peer_id: &str) -> AResult<()> {
+        self.reoffer(peer_id, true).await
+    }
+
+    /// Renegotiates the connection to `peer_id` after it changed, such as after
+    /// `P2PConnection::add_track`, exchanging the new offer and answer through the signal server.
+    /// `Lobby::run` does this by itself for members whose connection needs it
+    pub async fn renegotiate(&self, peer_id: &str) -> AResult<()> {
+        self.reoffer(peer_id, false).await
+    }
+
+    /// Makes a new offer to the established connection to `peer_id`, and waits for its answer
+    async fn reoffer(&self, peer_id: &str, ice_restart: bool) -> AResult<()> {
+        let connection = self
+            .client
+            .get_connection(peer_id)
+            .await
+            .ok_or_else(|| ClientError::UnknownPeer(peer_id.to_string()))?;
+
+        let local_id = self.handle.peer_id();
+        let signal_server = self.handle.signal_server();
+        let pair_room = pair_room(self.room(), local_id, peer_id);
+
+        // Neither the current answer, nor one left over from an offer which was given up on,
+        // answers the new offer
+        let previous = connection.remote_description().await.map(|sdp| sdp.sdp);
+        let stale = signal_server
+            .fetch(&pair_room, peer_id)
+            .await?
+            .and_then(|signal| signal.session_description)
+            .map(|sdp| sdp.sdp);
+        if ice_restart {
+            connection.restart_ice().await?;
+        } else {
+            connection.get_offer().await?;
+        }
+
+        tokio::time::timeout(self.client.connect_timeout, async {
+            loop {
+                self.ensure_tracked(peer_id, &connection).await?;
+                self.handle.announce_to(&pair_room, &connection).await?;
+
+                let answer = signal_server
+                    .fetch(&pair_room, peer_id)
+                    .await?
+                    .and_then(|signal| signal.session_description)
+                    .filter(|sdp| sdp.sdp_type == RTCSdpType::Answer)
+                    .filter(|sdp| {
+                        Some(&sdp.sdp) != previous.as_ref() && Some(&sdp.sdp) != stale.as_ref()
+                    });
+                if let Some(answer) = answer {
+                    connection.set_answer(answer).await?;
+                    break;
+                }
+
+                tokio::time::sleep(self.handle.handshake_poll_interval()).await;
+            }
+
+            self.exchange_candidates(peer_id, &connection).await
+        })
+        .await
+        .map_err(|_| ClientError::ConnectTimeout(peer_id.to_string()))?
+    }
+
+    /// Answers every new offer a member has made since the last check, such as for an ICE restart,
+    /// and renegotiates every member connection which needs it. When the client reconnects, the
+    /// peer with the lower id restarts ICE on member connections which dropped. Returns the
+    /// exchanges which complete them
+    async fn renegotiate_members(&self) -> Vec<BoxFuture<'_, ()>> {
+        let local_id = self.handle.peer_id();
+        let signal_server = self.handle.signal_server();
+        let mut renegotiations = Vec::new();
+
+        for peer_id in self.members() {
+            let Some(connection) = self.client.get_connection(&peer_id).await else {
+                continue;
+            };
+            if local_id < peer_id.as_str() && connection.is_reconnecting() {
+                if let Some(restarting) = Restarting::start(&self.restarting, &peer_id) {
+                    renegotiations.push(self.reconnect(restarting, connection).boxed());
+                    continue;
+                }
+            }
+            if connection.is_negotiation_needed() {
+                renegotiations.push(
+                    async move {
+                        let _ = self.renegotiate(&peer_id).await;
+                    }
+                    .boxed(),
+                );
+                continue;
+            }
+            let pair_room = pair_room(self.room(), local_id, &peer_id);
+
+            let current = connection.remote_description().await.map(|sdp| sdp.sdp);
+            let offer = signal_server
+                .fetch(&pair_room, &peer_id)
+                .await
+                .ok()
+                .flatten()
+                .and_then(|signal| signal.session_description)
+                .filter(|sdp| sdp.sdp_type == RTCSdpType::Offer)
+                .filter(|sdp| Some(&sdp.sdp) != current.as_ref());
+            let Some(offer) = offer else {
+                continue;
+            };
+
+            if connection.get_answer(offer).await.is_err()
+                || self
+                    .handle
+                    .announce_to(&pair_room, &connection)
+                    .await
+                    .is_err()
+            {
+                continue;
+            }
+
+            let timeout = self.client.connect_timeout;
+            renegotiations.push(
+                async move {
+                    let _ = tokio::time::timeout(
+                        timeout,
+                        self.exchange_candidates(&peer_id, &connection),
+                    )
+                    .await;
+                }
+                .boxed(),
+            );
+        }
+        renegotiations
+    }
+
+    /// Restarts ICE on the dropped connection to a member. A restart which fails as well is
+    /// given up on early, so the next health check can try again
+    async fn reconnect(&self, restarting: Restarting<'_>, connection: Arc<P2PConnection>) {
+        let mut states = connection.state_changes();
+        states.borrow_and_update();
+        let failed = async {
+            if states.changed().await.is_ok() {
+                let _ = states
+                    .wait_for(|state| *state == ConnectionState::Failed)
+                    .await;
+            }
+        };
+
+        tokio::select! {
+            _ = self.restart_ice(&restarting.1) => {}
+            _ = failed => {}
+        }
+    }
+
+    /// Trades ICE candidates with `peer_id` through the signal server until the connection is
+    /// established
+    async fn exchange_candidates(
+        &self,
+        peer_id: &str,
+        connection: &Arc<P2PConnection>,
+    ) -> AResult<()> {
+        let local_id = self.handle.peer_id();
+        let signal_server = self.handle.signal_server();
+        let pair_room = pair_room(self.room(), local_id, peer_id);
+
+        let mut added_candidates: Vec<RTCIceCandidate> = Vec::new();
+        while !connection.get_is_connected_to_peer() {
+            self.ensure_tracked(peer_id, connection).await?;
+            self.handle.announce_to(&pair_room, connection).await?;
+
+            if let Some(signal) = signal_server.fetch(&pair_room, peer_id).await? {
+                let new_candidates = signal
+                    .candidates
+                    .into_iter()
+                    .filter(|candidate| !added_candidates.contains(candidate))
+                    .collect::<Vec<_>>();
+
+                connection
+                    .set_candidates(
+                        new_candidates
+                            .iter()
+                            .map(|candidate| candidate.to_json())
+                            .collect::<Result<Vec<_>, _>>()?
+                            .into_iter(),
+                    )
+                    .await?;
+                added_candidates.extend(new_candidates);
+            }
+
+            tokio::time::sleep(self.handle.handshake_poll_interval()).await;
+        }
+
+        Ok(())
+    }
+
+    /// Fails if the client has stopped tracking `connection`, such as when it timed out
+    async fn ensure_tracked(&self, peer_id: &str, connection: &Arc<P2PConnection>) -> AResult<()> {
+        match self.client.get_connection(peer_id).await {
+            Some(tracked) if Arc::ptr_eq(&tracked, connection) => Ok(()),
+            _ => Err(anyhow!("Connection to {peer_id} is no longer tracked")),
+        }
+    }
+}
+
+struct RunState<'a> {
+    announced: bool,
+    discovered: futures::stream::BoxStream<'a, crate::signaling::PeerInfo>,
+    handshakes: FuturesUnordered<BoxFuture<'a, (String, AResult<()>)>>,
+    renegotiations: FuturesUnordered<BoxFuture<'a, ()>>,
+    health_check: tokio::time::Interval,
+    heartbeat: BoxFuture<'a, ()>,
+}
+
+/// Marks a member as having an ICE restart in flight, until dropped along with the restart,
+/// which may happen before it completes when the `Lobby::run` stream is dropped
+struct Restarting<'a>(&'a Mutex<HashSet<String>>, String);
+
+impl<'a> Restarting<'a> {
+    /// Marks `peer_id`, unless it already has a restart in flight
+    fn start(restarting: &'a Mutex<HashSet<String>>, peer_id: &str) -> Option<Self> {
+        restarting
+            .lock()
+            .expect("Unable to aquire restarting lock")
+            .insert(peer_id.to_owned())
+            .then(|| Self(restarting, peer_id.to_owned()))
+    }
+}
+
+impl Drop for Restarting<'_> {
+    fn drop(&mut self) {
+        self.0
+            .lock()
+            .expect("Unable to aquire restarting lock")
+            .remove(&self.1);
+    }
+}
+
+/// The room two peers of a lobby use to exchange their session descriptions and candidates
+fn pair_room(lobby: &RoomConfig, local_id: &str, remote_id: &str) -> RoomConfig {
+    let (first, second) = if local_id < remote_id {
+        (local_id, remote_id)
+    } else {
+        (remote_id, local_id)
+    };
+
+    RoomConfig {
+        // The pair room is encrypted, signed and locked like the lobby
+        secret: lobby.secret.clone(),
+        signer: lobby.signer.clone(),
+        trusted_signers: lobby.trusted_signers.clone(),
+        settings: RoomSettings {
+            password: lobby.settings.password.clone(),
+            ..Default::default()
+        },
+        ..RoomConfig::new(
+            lobby.channel.as_str(),
+            format!("{}/{first}/{second}", lobby.room),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory_signaling::MemorySignaling;
+    use crate::p2p_client::IncomingDecision;
+    use crate::signaling::tests::spawn_signal_server;
+    use crate::signaling::SignalServer;
+    use std::time::Duration;
+    use uuid::Uuid;
+    use webrtc::api::media_engine::MIME_TYPE_OPUS;
+    use webrtc::rtp_transceiver::rtp_codec::RTCRtpCodecCapability;
+    use webrtc::track::track_local::track_local_static_sample::TrackLocalStaticSample;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_lobby_connects_members() -> AResult<()> {
+        let server = SignalServer::new(spawn_signal_server().await?);
+        let room = RoomConfig::new("test", Uuid::new_v4().to_string());
+
+        let client1 = P2PClient::default();
+        let client2 = P2PClient::default();
+        let lobby1 = client1.join_lobby(&server, room.clone(), true);
+        let lobby2 = client2.join_lobby(&server, room.clone(), true);
+
+        let (event1, event2) = tokio::time::timeout(Duration::from_secs(20), async {
+            let (mut run1, mut run2) = (lobby1.run().boxed(), lobby2.run().boxed());
+            tokio::join!(run1.next(), run2.next())
+        })
+        .await?;
+
+        assert_eq!(event1, Some(LobbyEvent::MemberJoined(client2.peer_id())));
+        assert_eq!(event2, Some(LobbyEvent::MemberJoined(client1.peer_id())));
+        assert_eq!(lobby1.members(), vec![client2.peer_id()]);
+        assert_eq!(client2.room_of(&client1.peer_id()).await, Some(room));
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_lobby_connects_members_through_memory_signaling() -> AResult<()> {
+        let signaling = MemorySignaling::new();
+        let room = RoomConfig::new("test", Uuid::new_v4().to_string());
+
+        let client1 = P2PClient::default();
+        let client2 = P2PClient::default();
+        let lobby1 = client1.join_lobby(&signaling, room.clone(), true);
+        let lobby2 = client2.join_lobby(&signaling, room.clone(), true);
+
+        let (event1, event2) = tokio::time::timeout(Duration::from_secs(20), async {
+            let (mut run1, mut run2) = (lobby1.run().boxed(), lobby2.run().boxed());
+            tokio::join!(run1.next(), run2.next())
+        })
+        .await?;
+
+        assert_eq!(event1, Some(LobbyEvent::MemberJoined(client2.peer_id())));
+        assert_eq!(event2, Some(LobbyEvent::MemberJoined(client1.peer_id())));
+
+        let connection = client1
+            .get_connection(&client2.peer_id())
+            .await
+            .ok_or_else(|| anyhow!("Missing connection"))?;
+        assert!(connection.get_is_connected_to_peer());
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_lobby_hands_metadata_to_on_incoming() -> AResult<()> {
+        let signaling = MemorySignaling::new();
+        let room = RoomConfig::new("test", Uuid::new_v4().to_string());
+
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let mut client1 = P2PClient::default();
+        let mut client2 = P2PClient::default();
+        for client in [&mut client1, &mut client2] {
+            let seen = seen.clone();
+            client.on_incoming(move |_, metadata| {
+                seen.lock()
+                    .expect("Unable to aquire seen lock")
+                    .push(metadata.get("name").cloned());
+                IncomingDecision::Accept
+            });
+        }
+        let lobby1 = client1
+            .join_lobby(&signaling, room.clone(), true)
+            .with_metadata("name", "first");
+        let lobby2 = client2
+            .join_lobby(&signaling, room.clone(), true)
+            .with_metadata("name", "second");
+
+        tokio::time::timeout(Duration::from_secs(20), async {
+            let (mut run1, mut run2) = (lobby1.run().boxed(), lobby2.run().boxed());
+            tokio::join!(run1.next(), run2.next())
+        })
+        .await?;
+
+        // Only the peer with the higher id answers, seeing what the offering peer announced
+        let offerer = if client1.peer_id() < client2.peer_id() {
+            "first"
+        } else {
+            "second"
+        };
+        assert_eq!(
+            *seen.lock().expect("Unable to aquire seen lock"),
+            vec![Some(offerer.to_owned())]
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_answerer_gives_up_without_an_offer() -> AResult<()> {
+        let signaling = MemorySignaling::new();
+        let room = RoomConfig::new("test", Uuid::new_v4().to_string());
+        let client = P2PClient::default().with_connect_timeout(Duration::from_millis(200));
+        let lobby = client.join_lobby(&signaling, room, true);
+
+        // Sorts before every other id, so the local peer waits for its offer
+        let result = tokio::time::timeout(Duration::from_secs(5), lobby.try_handshake("0")).await?;
+        assert!(matches!(
+            result.unwrap_err().downcast_ref::<ClientError>(),
+            Some(ClientError::ConnectTimeout(peer_id)) if peer_id == "0"
+        ));
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_lobby_restarts_ice() -> AResult<()> {
+        let server = SignalServer::new(spawn_signal_server().await?);
+        let room = RoomConfig::new("test", Uuid::new_v4().to_string());
+
+        let client1 = P2PClient::default();
+        let client2 = P2PClient::default();
+        let lobby1 = client1.join_lobby(&server, room.clone(), true);
+        let lobby2 = client2.join_lobby(&server, room.clone(), true);
+        let (mut run1, mut run2) = (lobby1.run().boxed(), lobby2.run().boxed());
+
+        tokio::time::timeout(Duration::from_secs(20), async {
+            tokio::join!(run1.next(), run2.next())
+        })
+        .await?;
+
+        // The restart is driven by the peer which answered the original offer
+        let (restarting, other, mut other_run, other_id, restarting_id) =
+            if client1.peer_id() > client2.peer_id() {
+                (
+                    &lobby1,
+                    &client2,
+                    run2,
+                    client2.peer_id(),
+                    client1.peer_id(),
+                )
+            } else {
+                (
+                    &lobby2,
+                    &client1,
+                    run1,
+                    client1.peer_id(),
+                    client2.peer_id(),
+                )
+            };
+
+        let connection = other
+            .get_connection(&restarting_id)
+            .await
+            .ok_or_else(|| anyhow!("Missing connection"))?;
+        let previous = connection.local_description().await.map(|sdp| sdp.sdp);
+
+        tokio::time::timeout(Duration::from_secs(30), async {
+            tokio::select! {
+                result = restarting.restart_ice(&other_id) => result,
+                _ = async { while other_run.next().await.is_some() {} } => {
+                    Err(anyhow!("Lobby stopped running"))
+                }
+            }
+        })
+        .await??;
+
+        assert_ne!(
+            connection.local_description().await.map(|sdp| sdp.sdp),
+            previous
+        );
+        assert!(connection.get_is_connected_to_peer());
+        assert_eq!(restarting.members(), vec![other_id]);
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_lobby_renegotiates_members() -> AResult<()> {
+        let server = SignalServer::new(spawn_signal_server().await?);
+        let room = RoomConfig::new("test", Uuid::new_v4().to_string());
+
+        let client1 = P2PClient::default();
+        let client2 = P2PClient::default();
+        let lobby1 = client1.join_lobby(&server, room.clone(), true);
+        let lobby2 = client2.join_lobby(&server, room.clone(), true);
+        let (mut run1, mut run2) = (lobby1.run().boxed(), lobby2.run().boxed());
+
+        tokio::time::timeout(Duration::from_secs(20), async {
+            tokio::join!(run1.next(), run2.next())
+        })
+        .await?;
+
+        let (connection1, connection2) = (
+            client1
+                .get_connection(&client2.peer_id())
+                .await
+                .ok_or_else(|| anyhow!("Missing connection"))?,
+            client2
+                .get_connection(&client1.peer_id())
+                .await
+                .ok_or_else(|| anyhow!("Missing connection"))?,
+        );
+        connection2
+            .add_track(Arc::new(TrackLocalStaticSample::new(
+                RTCRtpCodecCapability {
+                    mime_type: MIME_TYPE_OPUS.to_owned(),
+                    ..Default::default()
+                },
+                "audio".to_owned(),
+                "stream".to_owned(),
+            )))
+            .await?;
+
+        tokio::time::timeout(Duration::from_secs(30), async {
+            let drive = async {
+                futures::future::join(async { while run1.next().await.is_some() {} }, async {
+                    while run2.next().await.is_some() {}
+                })
+                .await
+            };
+            let renegotiated = async {
+                loop {
+                    let received = connection1
+                        .remote_description()
+                        .await
+                        .is_some_and(|sdp| sdp.sdp.contains("m=audio"));
+                    if received && !connection2.is_negotiation_needed() {
+                        break;
+                    }
+                    tokio::time::sleep(Duration::from_millis(50)).await;
+                }
+            };
+            tokio::select! {
+                _ = drive => Err(anyhow!("Lobby stopped running")),
+                _ = renegotiated => Ok(()),
+            }
+        })
+        .await??;
+
+        assert!(connection1.get_is_connected_to_peer());
+        assert_eq!(lobby1.members(), vec![client2.peer_id()]);
+
+        Ok(())
+    }
+}
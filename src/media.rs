@@ -0,0 +1,234 @@
+use bytes::Bytes;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::sync::Mutex;
+use webrtc::api::media_engine::{
+    MIME_TYPE_G722, MIME_TYPE_H264, MIME_TYPE_OPUS, MIME_TYPE_PCMA, MIME_TYPE_PCMU, MIME_TYPE_VP8,
+    MIME_TYPE_VP9,
+};
+use webrtc::media::io::sample_builder::SampleBuilder;
+use webrtc::rtp::codecs::{h264::H264Packet, vp8::Vp8Packet, vp9::Vp9Packet};
+use webrtc::rtp::packetizer::Depacketizer;
+use webrtc::rtp_transceiver::rtp_codec::{RTCRtpCodecCapability, RTPCodecType};
+use webrtc::track::track_remote::TrackRemote;
+
+/// The clock rate of every video codec
+const VIDEO_CLOCK_RATE: u32 = 90000;
+
+/// How many packets a video frame may trail the newest one by before it is given up on
+const MAX_LATE_PACKETS: u16 = 256;
+
+/// The codecs an audio track can be sent with. The samples handed to
+/// `P2PConnection::add_audio_track` must already be encoded with it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AudioCodec {
+    Opus,
+    G722,
+    Pcmu,
+    Pcma,
+}
+
+impl AudioCodec {
+    pub(crate) fn capability(&self) -> RTCRtpCodecCapability {
+        let (mime_type, clock_rate, channels, sdp_fmtp_line) = match self {
+            Self::Opus => (MIME_TYPE_OPUS, 48000, 2, "minptime=10;useinbandfec=1"),
+            Self::G722 => (MIME_TYPE_G722, 8000, 0, ""),
+            Self::Pcmu => (MIME_TYPE_PCMU, 8000, 0, ""),
+            Self::Pcma => (MIME_TYPE_PCMA, 8000, 0, ""),
+        };
+
+        RTCRtpCodecCapability {
+            mime_type: mime_type.to_owned(),
+            clock_rate,
+            channels,
+            sdp_fmtp_line: sdp_fmtp_line.to_owned(),
+            rtcp_feedback: Vec::new(),
+        }
+    }
+}
+
+/// The codecs a video track can be sent with. The samples handed to
+/// `P2PConnection::add_video_track` must already be encoded with it, one frame per sample
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VideoCodec {
+    Vp8,
+    Vp9,
+    /// Frames are in Annex B format, with start codes between the NAL units
+    H264,
+}
+
+impl VideoCodec {
+    pub(crate) fn capability(&self) -> RTCRtpCodecCapability {
+        let (mime_type, sdp_fmtp_line) = match self {
+            Self::Vp8 => (MIME_TYPE_VP8, ""),
+            Self::Vp9 => (MIME_TYPE_VP9, "profile-id=0"),
+            Self::H264 => (
+                MIME_TYPE_H264,
+                "level-asymmetry-allowed=1;packetization-mode=1;profile-level-id=42001f",
+            ),
+        };
+
+        RTCRtpCodecCapability {
+            mime_type: mime_type.to_owned(),
+            clock_rate: VIDEO_CLOCK_RATE,
+            channels: 0,
+            sdp_fmtp_line: sdp_fmtp_line.to_owned(),
+            rtcp_feedback: Vec::new(),
+        }
+    }
+
+    fn from_mime_type(mime_type: &str) -> Option<Self> {
+        [Self::Vp8, Self::Vp9, Self::H264]
+            .into_iter()
+            .find(|codec| mime_type.eq_ignore_ascii_case(&codec.capability().mime_type))
+    }
+}
+
+/// Takes the payload back out of the packets of a video codec
+enum VideoDepacketizer {
+    Vp8(Vp8Packet),
+    Vp9(Vp9Packet),
+    H264(H264Packet),
+}
+
+impl VideoDepacketizer {
+    fn new(codec: VideoCodec) -> Self {
+        match codec {
+            VideoCodec::Vp8 => Self::Vp8(Vp8Packet::default()),
+            VideoCodec::Vp9 => Self::Vp9(Vp9Packet::default()),
+            VideoCodec::H264 => Self::H264(H264Packet::default()),
+        }
+    }
+}
+
+impl Depacketizer for VideoDepacketizer {
+    fn depacketize(&mut self, payload: &Bytes) -> Result<Bytes, webrtc::rtp::Error> {
+        match self {
+            Self::Vp8(packet) => packet.depacketize(payload),
+            Self::Vp9(packet) => packet.depacketize(payload),
+            Self::H264(packet) => packet.depacketize(payload),
+        }
+    }
+
+    fn is_partition_head(&self, payload: &Bytes) -> bool {
+        match self {
+            Self::Vp8(packet) => packet.is_partition_head(payload),
+            Self::Vp9(packet) => packet.is_partition_head(payload),
+            Self::H264(packet) => packet.is_partition_head(payload),
+        }
+    }
+
+    fn is_partition_tail(&self, marker: bool, payload: &Bytes) -> bool {
+        match self {
+            Self::Vp8(packet) => packet.is_partition_tail(marker, payload),
+            Self::Vp9(packet) => packet.is_partition_tail(marker, payload),
+            Self::H264(packet) => packet.is_partition_tail(marker, payload),
+        }
+    }
+}
+
+/// A chunk of encoded media, such as one Opus or VP8 frame, and how long it plays for
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MediaSample {
+    pub data: Bytes,
+    pub duration: Duration,
+}
+
+/// Whether a track carries audio or video
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MediaKind {
+    Audio,
+    Video,
+}
+
+/// A media track the peer sends over the connection, from `P2PConnection::track_events`
+#[derive(Clone)]
+pub struct RemoteTrack {
+    track: Arc<TrackRemote>,
+    /// Puts video frames back together from their packets
+    frames: Option<Arc<Mutex<SampleBuilder<VideoDepacketizer>>>>,
+}
+
+impl RemoteTrack {
+    fn new(track: Arc<TrackRemote>) -> Self {
+        let frames = VideoCodec::from_mime_type(&track.codec().capability.mime_type).map(|codec| {
+            let depacketizer = VideoDepacketizer::new(codec);
+            Arc::new(Mutex::new(SampleBuilder::new(
+                MAX_LATE_PACKETS,
+                depacketizer,
+                VIDEO_CLOCK_RATE,
+            )))
+        });
+        Self { track, frames }
+    }
+
+    pub fn id(&self) -> String {
+        self.track.id()
+    }
+
+    pub fn kind(&self) -> MediaKind {
+        match self.track.kind() {
+            RTPCodecType::Video => MediaKind::Video,
+            _ => MediaKind::Audio,
+        }
+    }
+
+    /// The mime type of the codec the track was negotiated with, such as `audio/opus`
+    pub fn mime_type(&self) -> String {
+        self.track.codec().capability.mime_type
+    }
+
+    /// Waits for the next sample of the track, returning the encoded media it carries. With the
+    /// audio codecs every packet holds a whole sample, while video frames are put back together
+    /// from their packets. Returns `None` once the track has ended
+    pub async fn recv_sample(&self) -> Option<Bytes> {
+        let Some(frames) = &self.frames else {
+            let (packet, _) = self.track.read_rtp().await.ok()?;
+            return Some(packet.payload);
+        };
+
+        let mut frames = frames.lock().await;
+        loop {
+            if let Some(frame) = frames.pop() {
+                return Some(frame.data);
+            }
+            let (packet, _) = self.track.read_rtp().await.ok()?;
+            frames.push(packet);
+        }
+    }
+}
+
+impl std::fmt::Debug for RemoteTrack {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&format!("RemoteTrack: {}", self.id()))
+    }
+}
+
+/// The tracks the peer added, and who is listening for new ones. Tracks which arrive before
+/// anyone listens are held for the first listener
+#[derive(Default)]
+pub(crate) struct IncomingTracks {
+    pending: Vec<RemoteTrack>,
+    listeners: Vec<UnboundedSender<RemoteTrack>>,
+}
+
+impl IncomingTracks {
+    pub(crate) fn push(&mut self, track: Arc<TrackRemote>) {
+        let track = RemoteTrack::new(track);
+        self.listeners
+            .retain(|listener| listener.send(track.clone()).is_ok());
+        if self.listeners.is_empty() {
+            self.pending.push(track);
+        }
+    }
+
+    pub(crate) fn subscribe(&mut self) -> UnboundedReceiver<RemoteTrack> {
+        let (sx, rx) = unbounded_channel();
+        for track in self.pending.drain(..) {
+            let _ = sx.send(track);
+        }
+        self.listeners.push(sx);
+        rx
+    }
+}
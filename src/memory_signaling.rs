@@ -0,0 +1,341 @@
+use crate::signaling::{
+    merge_announcement, BroadcastCandidateArgs, PeerSignal, RoomConfig, RoomEvent, Signaling,
+};
+use anyhow::Result as AResult;
+use futures::Stream;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast;
+
+/// The announcements of every peer, keyed by channel and room, then by peer id
+type Rooms = HashMap<(String, String), HashMap<String, PeerSignal>>;
+
+/// A room event, with the channel and room it happened in
+type Notice = (String, String, RoomEvent);
+
+/// Signals between clients in the same process, without any network or signaling server, such
+/// as for tests. Clones share the same rooms, so hand one to every client which should find the
+/// others. Room events are pushed as they happen, including re-announcements. Room passwords
+/// and peer limits aren't enforced
+#[derive(Clone)]
+pub struct MemorySignaling {
+    rooms: Arc<Mutex<Rooms>>,
+    notices: broadcast::Sender<Notice>,
+}
+
+impl Default for MemorySignaling {
+    fn default() -> Self {
+        Self {
+            rooms: Arc::new(Mutex::new(HashMap::new())),
+            notices: broadcast::channel(256).0,
+        }
+    }
+}
+
+impl MemorySignaling {
+    /// Creates a backend without any rooms, shared by its clones
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Rooms> {
+        self.rooms
+            .lock()
+            .expect("Unable to aquire memory signaling lock")
+    }
+
+    fn notify(&self, room: &RoomConfig, event: RoomEvent) {
+        let _ = self
+            .notices
+            .send((room.channel.clone(), room.room.clone(), event));
+    }
+
+    /// The events which take a subscriber knowing `known` to be in `room` to what is in it now,
+    /// updating `known` to match
+    fn resync(&self, room: &RoomConfig, known: &mut HashSet<String>) -> VecDeque<RoomEvent> {
+        let present = self
+            .lock()
+            .get(&(room.channel.clone(), room.room.clone()))
+            .map(|peers| peers.keys().cloned().collect::<HashSet<_>>())
+            .unwrap_or_default();
+
+        let left = known
+            .difference(&present)
+            .map(|peer_id| RoomEvent::PeerLeft {
+                peer_id: peer_id.clone(),
+            })
+            .collect::<VecDeque<_>>();
+        let joined = present
+            .difference(known)
+            .map(|peer_id| RoomEvent::PeerJoined {
+                peer_id: peer_id.clone(),
+            })
+            .collect::<Vec<_>>();
+
+        *known = present;
+        left.into_iter().chain(joined).collect()
+    }
+}
+
+impl Signaling for MemorySignaling {
+    async fn announce(
+        &self,
+        room: &RoomConfig,
+        peer_id: &str,
+        args: &BroadcastCandidateArgs,
+    ) -> AResult<()> {
+        let args = room.seal(peer_id, args)?;
+
+        let known = {
+            let mut rooms = self.lock();
+            let peers = rooms
+                .entry((room.channel.clone(), room.room.clone()))
+                .or_default();
+            let known = peers.contains_key(peer_id);
+            let signal = peers.entry(peer_id.to_owned()).or_insert(PeerSignal {
+                candidates: Vec::new(),
+                session_description: None,
+                sealed: None,
+                metadata: BTreeMap::new(),
+                signature: None,
+                cursor: 0,
+                verified: false,
+            });
+            merge_announcement(signal, args);
+            known
+        };
+
+        let peer_id = peer_id.to_owned();
+        self.notify(
+            room,
+            if known {
+                RoomEvent::PeerUpdated { peer_id }
+            } else {
+                RoomEvent::PeerJoined { peer_id }
+            },
+        );
+        Ok(())
+    }
+
+    async fn withdraw(&self, room: &RoomConfig, peer_id: &str) -> AResult<()> {
+        let removed = {
+            let mut rooms = self.lock();
+            let key = (room.channel.clone(), room.room.clone());
+            let removed = rooms
+                .get_mut(&key)
+                .and_then(|peers| peers.remove(peer_id))
+                .is_some();
+            if rooms.get(&key).is_some_and(HashMap::is_empty) {
+                rooms.remove(&key);
+            }
+            removed
+        };
+
+        if removed {
+            let peer_id = peer_id.to_owned();
+            self.notify(room, RoomEvent::PeerLeft { peer_id });
+        }
+        Ok(())
+    }
+
+    async fn peers(&self, room: &RoomConfig) -> AResult<Vec<String>> {
+        Ok(self
+            .lock()
+            .get(&(room.channel.clone(), room.room.clone()))
+            .map(|peers| peers.keys().cloned().collect())
+            .unwrap_or_default())
+    }
+
+    async fn fetch(&self, room: &RoomConfig, peer_id: &str) -> AResult<Option<PeerSignal>> {
+        let signal = self
+            .lock()
+            .get(&(room.channel.clone(), room.room.clone()))
+            .and_then(|peers| peers.get(peer_id))
+            .cloned();
+
+        signal.map(|signal| room.open(peer_id, signal)).transpose()
+    }
+
+    /// Yields the peers already in the room as having joined, then every event as it happens.
+    /// If the subscriber falls so far behind that events are dropped, the room is looked at
+    /// again, and the peers which joined or left in the meantime are yielded instead
+    fn subscribe<'a>(&'a self, room: &'a RoomConfig) -> impl Stream<Item = RoomEvent> + Send + 'a {
+        // Subscribed before the room is looked at, so no event falls in between
+        let notices = self.notices.subscribe();
+        let mut known = HashSet::new();
+        let present = self.resync(room, &mut known);
+
+        futures::stream::unfold(
+            (known, present, notices),
+            move |(mut known, mut pending, mut notices)| async move {
+                loop {
+                    if let Some(event) = pending.pop_front() {
+                        return Some((event, (known, pending, notices)));
+                    }
+
+                    match notices.recv().await {
+                        Ok((channel, name, event))
+                            if channel == room.channel && name == room.room =>
+                        {
+                            match &event {
+                                RoomEvent::PeerJoined { peer_id }
+                                | RoomEvent::PeerUpdated { peer_id } => {
+                                    known.insert(peer_id.clone());
+                                }
+                                RoomEvent::PeerLeft { peer_id } => {
+                                    known.remove(peer_id);
+                                }
+                            }
+                            return Some((event, (known, pending, notices)));
+                        }
+                        Ok(_) => {}
+                        Err(broadcast::error::RecvError::Lagged(_)) => {
+                            pending = self.resync(room, &mut known);
+                        }
+                        Err(broadcast::error::RecvError::Closed) => return None,
+                    }
+                }
+            },
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::signaling::tests::announce_through;
+    use crate::signaling::RoomHandle;
+    use futures::StreamExt;
+    use std::time::Duration;
+    use uuid::Uuid;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_memory_signaling_is_signaling() -> AResult<()> {
+        let signaling = MemorySignaling::new();
+
+        let room = RoomConfig::new("test", Uuid::new_v4().to_string());
+        announce_through(&signaling, &room).await?;
+
+        let secret_room = RoomConfig::new("test", Uuid::new_v4().to_string()).with_secret("mem");
+        announce_through(&signaling, &secret_room).await
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_clones_signal_each_other() -> AResult<()> {
+        let (local, remote) = (MemorySignaling::new(), MemorySignaling::new());
+        let shared = local.clone();
+        let room = RoomConfig::new("test", Uuid::new_v4().to_string());
+        let peer_id = Uuid::new_v4().to_string();
+        let args = BroadcastCandidateArgs {
+            candidates: Vec::new(),
+            session_description: None,
+            sealed: None,
+            room_settings: None,
+            metadata: BTreeMap::new(),
+            signature: None,
+        };
+
+        let events = shared.subscribe(&room);
+        futures::pin_mut!(events);
+        local.announce(&room, &peer_id, &args).await?;
+        local.announce(&room, &peer_id, &args).await?;
+        local.withdraw(&room, &peer_id).await?;
+
+        let timeout = Duration::from_secs(1);
+        for event in [
+            RoomEvent::PeerJoined {
+                peer_id: peer_id.clone(),
+            },
+            RoomEvent::PeerUpdated {
+                peer_id: peer_id.clone(),
+            },
+            RoomEvent::PeerLeft {
+                peer_id: peer_id.clone(),
+            },
+        ] {
+            assert_eq!(
+                tokio::time::timeout(timeout, events.next()).await?,
+                Some(event)
+            );
+        }
+
+        // Separate instances don't share rooms
+        local.announce(&room, &peer_id, &args).await?;
+        assert!(remote.peers(&room).await?.is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_rooms_are_joined_through_memory_signaling() -> AResult<()> {
+        let signaling = MemorySignaling::new();
+        let room = RoomConfig::new("test", Uuid::new_v4().to_string());
+        let (local_id, remote_id) = (Uuid::new_v4().to_string(), Uuid::new_v4().to_string());
+        let local = RoomHandle::new(&signaling, room.clone(), local_id.as_str());
+        let remote = RoomHandle::new(&signaling, room, remote_id.as_str());
+
+        let events = local.subscribe_room_events();
+        futures::pin_mut!(events);
+        local.announce_presence().await?;
+        remote.announce_presence().await?;
+
+        let timeout = Duration::from_secs(1);
+        let peer = tokio::time::timeout(timeout, local.wait_for_peer()).await?;
+        assert_eq!(peer.peer_id, remote_id);
+
+        // The local peer's own announcement is left out
+        assert_eq!(
+            tokio::time::timeout(timeout, events.next()).await?,
+            Some(RoomEvent::PeerJoined { peer_id: remote_id })
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_lagging_subscribers_resync() -> AResult<()> {
+        let signaling = MemorySignaling::new();
+        let (room, other) = (
+            RoomConfig::new("test", Uuid::new_v4().to_string()),
+            RoomConfig::new("test", Uuid::new_v4().to_string()),
+        );
+        let (gone, joined) = (Uuid::new_v4().to_string(), Uuid::new_v4().to_string());
+        let args = BroadcastCandidateArgs {
+            candidates: Vec::new(),
+            session_description: None,
+            sealed: None,
+            room_settings: None,
+            metadata: BTreeMap::new(),
+            signature: None,
+        };
+        signaling.announce(&room, &gone, &args).await?;
+
+        let events = signaling.subscribe(&room);
+        futures::pin_mut!(events);
+        let timeout = Duration::from_secs(1);
+        assert_eq!(
+            tokio::time::timeout(timeout, events.next()).await?,
+            Some(RoomEvent::PeerJoined {
+                peer_id: gone.clone()
+            })
+        );
+
+        signaling.withdraw(&room, &gone).await?;
+        signaling.announce(&room, &joined, &args).await?;
+        // Drops the events above from the subscriber's backlog
+        for _ in 0..300 {
+            signaling.announce(&other, &gone, &args).await?;
+        }
+
+        assert_eq!(
+            tokio::time::timeout(timeout, events.next()).await?,
+            Some(RoomEvent::PeerLeft { peer_id: gone })
+        );
+        assert_eq!(
+            tokio::time::timeout(timeout, events.next()).await?,
+            Some(RoomEvent::PeerJoined { peer_id: joined })
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,324 @@
+use crate::channel::Outbound;
+use crate::framing::{self, FrameKind};
+use anyhow::{anyhow, Result as AResult};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::sync::{Mutex, Notify};
+
+/// How many bytes a stream may have in flight before its reader catches up
+pub(crate) const INITIAL_WINDOW: u32 = 256 * 1024;
+
+/// stream id (4) + op (1)
+const HEADER_SIZE: usize = 5;
+
+/// The most data a single frame of a stream carries, so large writes on one stream don't hold
+/// up the others for long
+const MAX_DATA_SIZE: usize = framing::MAX_CHUNK_SIZE - HEADER_SIZE;
+
+/// Set on the op of frames sent by the end which opened the stream, as both ends number the
+/// streams they open on their own
+const FROM_OPENER: u8 = 0x80;
+
+/// How many streams the peer opened may be open at once, whether accepted yet or not. Any more
+/// are closed right away, so a peer can't grow the stream table without bound
+pub(crate) const MAX_PEER_STREAMS: usize = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Op {
+    Open = 0,
+    Data = 1,
+    /// Hands the sender more of the window, once the reader has taken data out of it
+    WindowUpdate = 2,
+    /// The sender won't write to the stream anymore
+    Close = 3,
+}
+
+impl TryFrom<u8> for Op {
+    type Error = anyhow::Error;
+
+    fn try_from(value: u8) -> AResult<Self> {
+        match value {
+            0 => Ok(Self::Open),
+            1 => Ok(Self::Data),
+            2 => Ok(Self::WindowUpdate),
+            3 => Ok(Self::Close),
+            _ => Err(anyhow!("Unknown stream op {value}")),
+        }
+    }
+}
+
+/// Which stream a frame belongs to: whether we opened it, and the id its opener gave it
+type StreamKey = (bool, u32);
+
+/// The state of a stream shared between the `MuxStream` and the channel's message handler
+struct StreamState {
+    /// How many more bytes may be sent before the peer hands out more of the window
+    send_window: std::sync::Mutex<u32>,
+    window_opened: Notify,
+    /// How much of the window handed to the peer its data takes up, until it is read
+    unread: AtomicU32,
+    /// Where data from the peer goes, until the peer closes its end
+    data: std::sync::Mutex<Option<UnboundedSender<Bytes>>>,
+}
+
+/// The streams multiplexed over a channel
+pub(crate) struct Mux {
+    streams: std::sync::Mutex<HashMap<StreamKey, Arc<StreamState>>>,
+    next_stream_id: AtomicU32,
+    accepted_sender: UnboundedSender<MuxStream>,
+    accepted: Mutex<UnboundedReceiver<MuxStream>>,
+}
+
+impl Mux {
+    pub(crate) fn new() -> Self {
+        let (accepted_sender, accepted) = unbounded_channel();
+        Self {
+            streams: std::sync::Mutex::new(HashMap::new()),
+            next_stream_id: AtomicU32::new(0),
+            accepted_sender,
+            accepted: Mutex::new(accepted),
+        }
+    }
+
+    pub(crate) async fn open(self: &Arc<Self>, outbound: Arc<Outbound>) -> AResult<MuxStream> {
+        outbound.ensure_open()?;
+        let id = self.next_stream_id.fetch_add(1, Ordering::Relaxed);
+        let stream = self.insert((true, id), outbound);
+        stream.send_frame(Op::Open, &[]).await?;
+        Ok(stream)
+    }
+
+    pub(crate) async fn accept(&self) -> Option<MuxStream> {
+        self.accepted.lock().await.recv().await
+    }
+
+    /// Takes in a frame of a stream from the peer
+    pub(crate) fn receive(self: &Arc<Self>, mut frame: Bytes, outbound: &Arc<Outbound>) {
+        if frame.len() < HEADER_SIZE {
+            return;
+        }
+        let id = frame.get_u32();
+        let op = frame.get_u8();
+        let key = (op & FROM_OPENER == 0, id);
+        let Ok(op) = Op::try_from(op & !FROM_OPENER) else {
+            return;
+        };
+
+        if op == Op::Open {
+            if key.0 {
+                return;
+            }
+            let peer_streams = self
+                .lock()
+                .keys()
+                .filter(|(opened_locally, _)| !opened_locally)
+                .count();
+            if peer_streams >= MAX_PEER_STREAMS {
+                let outbound = outbound.clone();
+                tokio::spawn(async move { send_frame(&outbound, key, Op::Close, &[]).await });
+                return;
+            }
+            let stream = self.insert(key, outbound.clone());
+            let _ = self.accepted_sender.send(stream);
+            return;
+        }
+
+        // Frames of streams which were already dropped are ignored
+        let Some(state) = self.lock().get(&key).cloned() else {
+            return;
+        };
+        match op {
+            Op::Data => {
+                let len = u32::try_from(frame.len()).unwrap_or(u32::MAX);
+                let unread = state.unread.fetch_add(len, Ordering::Relaxed);
+                if unread.saturating_add(len) > INITIAL_WINDOW {
+                    // The peer sent past the window it was handed, so the stream is closed
+                    // rather than buffering whatever the peer sends
+                    state.lock_data().take();
+                    self.lock().remove(&key);
+                    let outbound = outbound.clone();
+                    tokio::spawn(async move { send_frame(&outbound, key, Op::Close, &[]).await });
+                    return;
+                }
+                if let Some(data) = state.lock_data().as_ref() {
+                    let _ = data.send(frame);
+                }
+            }
+            Op::WindowUpdate if frame.len() >= 4 => {
+                let mut window = state.lock_send_window();
+                *window = window.saturating_add(frame.get_u32());
+                state.window_opened.notify_waiters();
+            }
+            Op::Close => {
+                state.lock_data().take();
+            }
+            _ => {}
+        }
+    }
+
+    fn insert(self: &Arc<Self>, key: StreamKey, outbound: Arc<Outbound>) -> MuxStream {
+        let (sx, rx) = unbounded_channel();
+        let state = Arc::new(StreamState {
+            send_window: std::sync::Mutex::new(INITIAL_WINDOW),
+            window_opened: Notify::new(),
+            unread: AtomicU32::new(0),
+            data: std::sync::Mutex::new(Some(sx)),
+        });
+        self.lock().insert(key, state.clone());
+
+        MuxStream {
+            key,
+            state,
+            data: Mutex::new(rx),
+            mux: Arc::downgrade(self),
+            outbound,
+            closed: AtomicBool::new(false),
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<StreamKey, Arc<StreamState>>> {
+        self.streams.lock().expect("Unable to aquire streams lock")
+    }
+}
+
+impl StreamState {
+    fn lock_send_window(&self) -> std::sync::MutexGuard<'_, u32> {
+        self.send_window
+            .lock()
+            .expect("Unable to aquire send window lock")
+    }
+
+    fn lock_data(&self) -> std::sync::MutexGuard<'_, Option<UnboundedSender<Bytes>>> {
+        self.data.lock().expect("Unable to aquire stream data lock")
+    }
+}
+
+/// One of many independent streams carried by a single channel, from `Channel::open_stream`
+/// or `Channel::accept_stream`. Each stream has its own flow control, so a stream whose reader
+/// falls behind only holds up its own sender
+pub struct MuxStream {
+    key: StreamKey,
+    state: Arc<StreamState>,
+    data: Mutex<UnboundedReceiver<Bytes>>,
+    mux: Weak<Mux>,
+    outbound: Arc<Outbound>,
+    closed: AtomicBool,
+}
+
+impl MuxStream {
+    /// The id the end which opened the stream gave it
+    pub fn id(&self) -> u32 {
+        self.key.1
+    }
+
+    /// Sends `data` to the peer over the stream, waiting whenever the peer's reader has fallen
+    /// a full window behind
+    pub async fn send(&self, mut data: &[u8]) -> AResult<()> {
+        if self.closed.load(Ordering::Relaxed) {
+            return Err(anyhow!("Stream {} was closed", self.id()));
+        }
+
+        while !data.is_empty() {
+            let len = self.reserve(data.len().min(MAX_DATA_SIZE)).await?;
+            self.send_frame(Op::Data, &data[..len]).await?;
+            data = &data[len..];
+        }
+        Ok(())
+    }
+
+    /// Sends `data` without waiting for the peer's window, like a peer which ignores it
+    #[cfg(test)]
+    pub(crate) async fn send_ignoring_window(&self, mut data: &[u8]) -> AResult<()> {
+        while !data.is_empty() {
+            let len = data.len().min(MAX_DATA_SIZE);
+            self.send_frame(Op::Data, &data[..len]).await?;
+            data = &data[len..];
+        }
+        Ok(())
+    }
+
+    /// Waits for the next piece of data from the peer, as large sends arrive in several pieces.
+    /// Returns `None` once the peer closed its end of the stream, or once the peer sent more than
+    /// the window allows
+    pub async fn recv(&self) -> Option<Bytes> {
+        let data = self.data.lock().await.recv().await?;
+        // The window only reopens once the data has been read
+        self.state
+            .unread
+            .fetch_sub(data.len() as u32, Ordering::Relaxed);
+        let _ = self
+            .send_frame(Op::WindowUpdate, &(data.len() as u32).to_be_bytes())
+            .await;
+        Some(data)
+    }
+
+    /// Tells the peer nothing more will be sent over the stream. Data from the peer can still
+    /// be received
+    pub async fn close(&self) -> AResult<()> {
+        if self.closed.swap(true, Ordering::Relaxed) {
+            return Ok(());
+        }
+        self.send_frame(Op::Close, &[]).await
+    }
+
+    /// Takes up to `len` bytes of the send window, waiting for it to reopen if it is used up
+    async fn reserve(&self, len: usize) -> AResult<usize> {
+        loop {
+            let opened = self.state.window_opened.notified();
+            {
+                let mut window = self.state.lock_send_window();
+                if *window > 0 {
+                    let len = len.min(*window as usize);
+                    *window -= len as u32;
+                    return Ok(len);
+                }
+            }
+
+            // The window never reopens if the channel closes in the meantime
+            let _ = tokio::time::timeout(Duration::from_millis(100), opened).await;
+            self.outbound.ensure_open()?;
+        }
+    }
+
+    async fn send_frame(&self, op: Op, body: &[u8]) -> AResult<()> {
+        send_frame(&self.outbound, self.key, op, body).await
+    }
+}
+
+async fn send_frame(outbound: &Outbound, key: StreamKey, op: Op, body: &[u8]) -> AResult<()> {
+    let (opened_locally, id) = key;
+    let flags = if opened_locally { FROM_OPENER } else { 0 };
+
+    let mut frame = BytesMut::with_capacity(HEADER_SIZE + body.len());
+    frame.put_u32(id);
+    frame.put_u8(op as u8 | flags);
+    frame.put_slice(body);
+    outbound.send_frames(FrameKind::Mux, &frame, false).await
+}
+
+impl std::fmt::Debug for MuxStream {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&format!("MuxStream: {}", self.id()))
+    }
+}
+
+impl Drop for MuxStream {
+    fn drop(&mut self) {
+        if let Some(mux) = self.mux.upgrade() {
+            mux.lock().remove(&self.key);
+        }
+        if self.closed.swap(true, Ordering::Relaxed) {
+            return;
+        }
+
+        // Without a runtime the peer's end simply never sees the stream close
+        let (outbound, key) = (self.outbound.clone(), self.key);
+        if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+            runtime.spawn(async move { send_frame(&outbound, key, Op::Close, &[]).await });
+        }
+    }
+}
@@ -0,0 +1,107 @@
+use crate::channel::Message;
+use crate::error::ConnectionError;
+use anyhow::Result as AResult;
+use std::collections::VecDeque;
+use std::time::Instant;
+
+/// The messages sent over the default channel while the connection was reconnecting, which go
+/// out once it is back
+pub(crate) struct Outbox {
+    /// Each with when it goes stale, if it was sent with a time to live
+    messages: VecDeque<(Message, Option<Instant>)>,
+    /// How many bytes the held messages add up to
+    held: usize,
+    capacity: usize,
+}
+
+impl Outbox {
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            messages: VecDeque::new(),
+            held: 0,
+            capacity,
+        }
+    }
+
+    /// Holds `message` until the connection is back, failing if it doesn't fit in the capacity.
+    /// It is dropped instead of sent if it goes stale at `expires_at` first
+    pub(crate) fn hold(&mut self, message: Message, expires_at: Option<Instant>) -> AResult<()> {
+        let len = message.as_bytes().len();
+        if self.held + len > self.capacity {
+            return Err(ConnectionError::ReconnectBufferFull(self.capacity).into());
+        }
+        self.held += len;
+        self.messages.push_back((message, expires_at));
+        Ok(())
+    }
+
+    /// The oldest held message which hasn't gone stale, which has to be sent before any other
+    pub(crate) fn front(&mut self) -> Option<&Message> {
+        let now = Instant::now();
+        while self
+            .messages
+            .front()
+            .is_some_and(|(_, expires_at)| expires_at.is_some_and(|expires_at| expires_at <= now))
+        {
+            self.pop_front();
+        }
+        self.messages.front().map(|(message, _)| message)
+    }
+
+    /// Forgets the oldest held message once it has been sent
+    pub(crate) fn pop_front(&mut self) {
+        if let Some((message, _)) = self.messages.pop_front() {
+            self.held -= message.as_bytes().len();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+
+    #[test]
+    fn test_hold_within_capacity() -> AResult<()> {
+        let mut outbox = Outbox::new(8);
+        outbox.hold(Message::Binary(Bytes::from_static(b"abcd")), None)?;
+        outbox.hold(Message::Text("efg".to_owned()), None)?;
+
+        let full = outbox.hold(Message::Binary(Bytes::from_static(b"hi")), None);
+        assert!(matches!(
+            full.map_err(|err| err.downcast::<ConnectionError>()),
+            Err(Ok(ConnectionError::ReconnectBufferFull(8)))
+        ));
+
+        // Sent messages make room again, and go out in the order they were held
+        assert_eq!(
+            outbox.front(),
+            Some(&Message::Binary(Bytes::from_static(b"abcd")))
+        );
+        outbox.pop_front();
+        outbox.hold(Message::Binary(Bytes::from_static(b"hi")), None)?;
+        assert_eq!(outbox.front(), Some(&Message::Text("efg".to_owned())));
+        outbox.pop_front();
+        outbox.pop_front();
+        assert_eq!(outbox.front(), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_stale_messages_are_dropped() -> AResult<()> {
+        let mut outbox = Outbox::new(8);
+        outbox.hold(
+            Message::Binary(Bytes::from_static(b"old")),
+            Some(Instant::now()),
+        )?;
+        outbox.hold(Message::Binary(Bytes::from_static(b"new")), None)?;
+
+        assert_eq!(
+            outbox.front(),
+            Some(&Message::Binary(Bytes::from_static(b"new")))
+        );
+        // The stale message no longer takes up room
+        outbox.hold(Message::Binary(Bytes::from_static(b"fits!")), None)?;
+        Ok(())
+    }
+}
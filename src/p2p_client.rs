@@ -1,9 +1,25 @@
-use crate::p2p_connection::P2PConnection;
-use std::{collections::HashMap, fmt::Debug};
+use crate::certificate::Certificate;
+use crate::channel::OverflowPolicy;
+use crate::encryption::Encryption;
+use crate::error::ClientError;
+use crate::lobby::Lobby;
+use crate::p2p_connection::{ConnectionState, GatheringState, IceConnectionState, P2PConnection};
+use crate::quality::{Quality, QualityMonitor};
+use crate::rate_limit::RateLimit;
+use crate::signaling::{RoomConfig, RoomHandle, Signaling, SignalingErrorKind};
+use anyhow::Result as AResult;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant};
+use tokio::sync::{broadcast, watch, RwLock};
 use uuid::Uuid;
+use webrtc::api::media_engine::MediaEngine;
+use webrtc::api::setting_engine::SettingEngine;
 use webrtc::api::{APIBuilder, API};
+use webrtc::ice_transport::ice_candidate_type::RTCIceCandidateType;
+use webrtc::peer_connection::sdp::session_description::RTCSessionDescription;
 
-pub(crate) trait IntoId {
+pub(crate) trait IntoId: Send + Sync {
     fn id(&self) -> String;
 }
 
@@ -19,35 +35,829 @@ impl IntoId for String {
     }
 }
 
+/// How statically mapped NAT 1:1 addresses are advertised to the remote peer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NatMappingType {
+    /// Replaces the local host candidate addresses with the mapped addresses
+    Host,
+    /// Advertises the mapped addresses as server reflexive candidates, in addition to the local
+    /// host candidates. This removes the need for a STUN round trip
+    ServerReflexive,
+}
+
+impl From<NatMappingType> for RTCIceCandidateType {
+    fn from(value: NatMappingType) -> Self {
+        match value {
+            NatMappingType::Host => RTCIceCandidateType::Host,
+            NatMappingType::ServerReflexive => RTCIceCandidateType::Srflx,
+        }
+    }
+}
+
+const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_SEND_HIGH_WATER_MARK: usize = 1024 * 1024;
+const DEFAULT_RECEIVE_BUFFER: usize = 128;
+
+/// Free-form information a peer shares about itself, such as a display name or an invite code
+pub type PeerMetadata = BTreeMap<String, String>;
+
+/// Whether an incoming connection is allowed to complete
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IncomingDecision {
+    Accept,
+    Reject,
+}
+
+type IncomingHandler = Arc<dyn Fn(&str, &PeerMetadata) -> IncomingDecision + Send + Sync>;
+
+/// A connection owned by the client, along with the room it was made through
+struct TrackedConnection {
+    connection: Arc<P2PConnection>,
+    room: Option<RoomConfig>,
+}
+
+type ConnectionMap = Arc<RwLock<HashMap<String, TrackedConnection>>>;
+
+/// Events emitted by the `P2PClient` to every subscriber of `P2PClient::events`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ClientEvent {
+    /// The connection to `peer_id` was not established within the connect timeout, and is being
+    /// torn down
+    ConnectTimeout { peer_id: String },
+    /// A call to the signaling server for `room` failed. If the call is retried automatically,
+    /// `retry_in` is how long until the next attempt
+    SignalingError {
+        room: RoomConfig,
+        kind: SignalingErrorKind,
+        retry_in: Option<Duration>,
+    },
+    /// Enough consecutive signaling calls failed for signaling to be considered degraded.
+    /// Established connections are kept alive, and discovery follows the `OutagePolicy`
+    SignalingDegraded,
+    /// A signaling call succeeded after signaling was degraded
+    SignalingRestored,
+    /// Nothing arrived from `peer_id` for as many keepalive intervals as the client allows to be
+    /// missed. The connection is left open, so it can be closed or have its ICE restarted
+    PeerTimeout { peer_id: String },
+    /// The quality of the connection to `peer_id` went up or down, as rated by the client's
+    /// quality monitor
+    QualityChanged { peer_id: String, quality: Quality },
+    /// The established connection to `peer_id` dropped, and sends over it are held until it is
+    /// back. Only emitted by clients created with `P2PClient::with_reconnect`
+    Reconnecting { peer_id: String },
+    /// The connection to `peer_id` is back after `ClientEvent::Reconnecting`, and the sends held
+    /// in the meantime have gone out
+    Reconnected { peer_id: String },
+    /// ICE moved on in finding a working pair of candidates for the connection to `peer_id`.
+    /// Only emitted by clients created with `P2PClient::with_ice_events`
+    IceStateChanged {
+        peer_id: String,
+        state: IceConnectionState,
+    },
+    /// The connection to `peer_id` moved on in gathering its local ICE candidates. Only
+    /// emitted by clients created with `P2PClient::with_ice_events`
+    GatheringStateChanged {
+        peer_id: String,
+        state: GatheringState,
+    },
+}
+
 /// A wrapper around the webrtc connections.
 /// Has a `Default` impl which passes stun:stun.l.google.com:19302 to the `P2PClient::new`
 /// constructor
-pub struct P2PClient<'a> {
+pub struct P2PClient {
     pub(crate) id: Box<dyn IntoId>,
     pub(crate) api: API,
-    connections: HashMap<String, P2PConnection<'a>>,
+    setting_engine: SettingEngine,
+    connections: ConnectionMap,
     pub(crate) ice_servers: Vec<String>,
+    pub(crate) nat_1to1_ips: Vec<String>,
+    pub(crate) nat_mapping_type: NatMappingType,
+    pub(crate) connect_timeout: Duration,
+    pub(crate) send_high_water_mark: usize,
+    pub(crate) send_rate_limit: Option<RateLimit>,
+    pub(crate) compression_threshold: Option<usize>,
+    pub(crate) encryption: Option<Encryption>,
+    pub(crate) paired_channels: bool,
+    pub(crate) channel_protocols: Option<Vec<String>>,
+    pub(crate) receive_buffer: usize,
+    pub(crate) overflow_policy: OverflowPolicy,
+    pub(crate) certificate: Option<Certificate>,
+    pub(crate) reconnect_buffer: Option<usize>,
+    keepalive: Option<(Duration, u32)>,
+    quality_interval: Option<Duration>,
+    ice_events: bool,
+    events: broadcast::Sender<ClientEvent>,
+    on_incoming: Option<IncomingHandler>,
 }
 
-impl<'a> P2PClient<'a> {
+impl P2PClient {
     pub fn new(ice_servers: impl IntoIterator<Item = impl Into<String>>) -> Self {
         let servers = ice_servers
             .into_iter()
             .map(|s| s.into())
             .collect::<Vec<String>>();
 
-        let api = APIBuilder::new().build();
+        let setting_engine = SettingEngine::default();
+        let api = build_api(&setting_engine);
+        let (events, _) = broadcast::channel(64);
 
         Self {
             ice_servers: servers,
             id: Box::new(Uuid::new_v4()),
             connections: Default::default(),
             api,
+            setting_engine,
+            nat_1to1_ips: Vec::new(),
+            nat_mapping_type: NatMappingType::Host,
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            send_high_water_mark: DEFAULT_SEND_HIGH_WATER_MARK,
+            send_rate_limit: None,
+            compression_threshold: None,
+            encryption: None,
+            paired_channels: false,
+            channel_protocols: None,
+            receive_buffer: DEFAULT_RECEIVE_BUFFER,
+            overflow_policy: OverflowPolicy::Block,
+            certificate: None,
+            reconnect_buffer: None,
+            keepalive: None,
+            quality_interval: None,
+            ice_events: false,
+            events,
+            on_incoming: None,
+        }
+    }
+
+    /// Sets how long a connection may take to reach the connected state before it is torn down,
+    /// `P2PConnection::wait_connected` resolves to `ConnectionError::Timeout` and a
+    /// `ClientEvent::ConnectTimeout` is emitted. Defaults to 30 seconds
+    pub fn with_connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = timeout;
+        self
+    }
+
+    /// Sets how many bytes may be queued on a data channel before
+    /// `P2PConnection::send_with_backpressure` waits for it to drain, resuming once half of it has
+    /// been sent. Defaults to 1 MiB
+    pub fn with_send_high_water_mark(mut self, bytes: usize) -> Self {
+        self.send_high_water_mark = bytes;
+        self
+    }
+
+    /// Caps how fast each connection sends, across all of its channels, so replication traffic
+    /// to a peer can't saturate the link. Sends wait once the limit is reached. Connections can
+    /// change their own limit with `P2PConnection::set_send_rate_limit`. Unlimited by default
+    pub fn with_send_rate_limit(mut self, limit: RateLimit) -> Self {
+        self.send_rate_limit = Some(limit);
+        self
+    }
+
+    /// Opts in to LZ4 compressing messages of at least `threshold` bytes, which saves bandwidth
+    /// on repetitive payloads such as JSON state. Each channel tells the peer it compresses along
+    /// with the first message, and messages are only compressed once the peer has opted in as
+    /// well. Off by default
+    pub fn with_compression(mut self, threshold: usize) -> Self {
+        self.compression_threshold = Some(threshold);
+        self
+    }
+
+    /// Encrypts every message end to end on top of DTLS, so they stay unreadable to anything
+    /// relaying the connection. Sends wait for the peer's end of the key exchange to arrive,
+    /// which every channel does afresh. Sends fail with `ConnectionError::PeerUnencrypted` once
+    /// the peer says it doesn't encrypt, and messages from the peer which aren't encrypted are
+    /// dropped. Off by default
+    pub fn with_encryption(mut self, encryption: Encryption) -> Self {
+        self.encryption = Some(encryption);
+        self
+    }
+
+    /// Holds up to `capacity` messages from the peer on each channel until they are received,
+    /// and applies `policy` to the messages arriving while it is full. Defaults to 128 messages
+    /// with `OverflowPolicy::Block`
+    pub fn with_receive_buffer(mut self, capacity: usize, policy: OverflowPolicy) -> Self {
+        self.receive_buffer = capacity;
+        self.overflow_policy = policy;
+        self
+    }
+
+    /// Presents `certificate` in DTLS on every connection, instead of a new one each time, so
+    /// peers see the same fingerprint across runs
+    pub fn with_certificate(mut self, certificate: Certificate) -> Self {
+        self.certificate = Some(certificate);
+        self
+    }
+
+    /// Creates every connection with a reliable and an unreliable channel, like
+    /// `P2PConnection::new_paired`. Their default channels are then reliable whatever
+    /// `require_reliable_transmission` says. Both peers have to opt in
+    pub fn with_paired_channels(mut self) -> Self {
+        self.paired_channels = true;
+        self
+    }
+
+    /// Only takes channels the peer opens with `P2PConnection::open_channel` if they speak one
+    /// of `protocols`, so clients with an incompatible wire protocol are turned away. Other
+    /// channels are closed as they arrive, and never handed out by `P2PConnection::on_channel`.
+    /// Include an empty protocol to take channels opened without one. Every protocol is taken
+    /// by default
+    pub fn with_channel_protocols(
+        mut self,
+        protocols: impl IntoIterator<Item = impl Into<String>>,
+    ) -> Self {
+        self.channel_protocols = Some(protocols.into_iter().map(Into::into).collect());
+        self
+    }
+
+    /// Sends a heartbeat over every established connection each `interval`, which the peer
+    /// echoes back. Once nothing has arrived from a peer for `missed_intervals` intervals, a
+    /// `ClientEvent::PeerTimeout` is emitted, well before the connection state would notice the
+    /// link is dead. Off by default
+    pub fn with_keepalive(mut self, interval: Duration, missed_intervals: u32) -> Self {
+        self.keepalive = Some((interval, missed_intervals));
+        self
+    }
+
+    /// Pings the peer over every established connection each `interval`, and rates the
+    /// connection from rolling averages of the round trip time, unanswered pings and how much
+    /// is queued to send. A `ClientEvent::QualityChanged` is emitted whenever the rating
+    /// changes, so games can adapt their tick rate or warn the player. Off by default
+    pub fn with_quality_monitor(mut self, interval: Duration) -> Self {
+        self.quality_interval = Some(interval);
+        self
+    }
+
+    /// Emits a `ClientEvent::IceStateChanged` and `ClientEvent::GatheringStateChanged` as every
+    /// connection moves through ICE, to find out why one didn't connect, such as gathering
+    /// completing without the checks ever succeeding. Off by default
+    pub fn with_ice_events(mut self) -> Self {
+        self.ice_events = true;
+        self
+    }
+
+    /// Keeps connections behind the same `P2PConnection` through a dropped link, so channel
+    /// wiring doesn't have to be rebuilt. While a connection is reconnecting, up to `buffer`
+    /// bytes sent over its default channel are held and go out once it is back, after which
+    /// sends fail with `ConnectionError::ReconnectBufferFull`. Unreliable sends with
+    /// `P2PConnection::send_as` and `P2PConnection::send_tracked` can't be held, and fail with
+    /// `ConnectionError::Reconnecting` meanwhile. Lobbies restart ICE on members whose connection
+    /// dropped, and only let them go once it has been down for the connect timeout. Off by
+    /// default
+    pub fn with_reconnect(mut self, buffer: usize) -> Self {
+        self.reconnect_buffer = Some(buffer);
+        self
+    }
+
+    /// Statically maps the external IPs of a 1:1 NAT (such as a cloud VM with a public IP) so the
+    /// correct address is advertised to remote peers without needing a STUN round trip.
+    ///
+    /// * `ips` - The external IPs to advertise
+    /// * `mapping_type` - Whether the external IPs replace the host candidates or are advertised
+    ///   as server reflexive candidates
+    pub fn with_nat_1to1_ips(
+        mut self,
+        ips: impl IntoIterator<Item = impl Into<String>>,
+        mapping_type: NatMappingType,
+    ) -> Self {
+        self.nat_1to1_ips = ips.into_iter().map(|ip| ip.into()).collect();
+        self.nat_mapping_type = mapping_type;
+        self.setting_engine
+            .set_nat_1to1_ips(self.nat_1to1_ips.clone(), self.nat_mapping_type.into());
+        self.api = build_api(&self.setting_engine);
+        self
+    }
+
+    /// Gives direct access to the webrtc `SettingEngine` used for every connection of this client,
+    /// for settings which are not exposed by the client itself, such as ICE timeouts or a virtual
+    /// network
+    pub fn with_setting_engine(mut self, configure: impl FnOnce(&mut SettingEngine)) -> Self {
+        configure(&mut self.setting_engine);
+        self.api = build_api(&self.setting_engine);
+        self
+    }
+
+    /// The id this client announces itself as to the signaling server
+    pub fn peer_id(&self) -> String {
+        self.id.id()
+    }
+
+    /// Joins `room` on `signal_server`, or any other signaling backend, as this client. Failed
+    /// signaling calls made through the returned handle are emitted as
+    /// `ClientEvent::SignalingError`
+    pub fn join_room<'s, S: Signaling>(
+        &self,
+        signal_server: &'s S,
+        room: RoomConfig,
+    ) -> RoomHandle<'s, S> {
+        RoomHandle::new(signal_server, room, self.peer_id()).with_events(self.events.clone())
+    }
+
+    /// Joins `room` on `signal_server` as a `Lobby`, which connects to every peer in the room
+    ///
+    /// * `require_reliable_transmission` - if `true`, then we require ordered packets on the
+    ///   connections to the lobby members
+    pub fn join_lobby<'s, S: Signaling>(
+        &'s self,
+        signal_server: &'s S,
+        room: RoomConfig,
+        require_reliable_transmission: bool,
+    ) -> Lobby<'s, S> {
+        Lobby::new(
+            self,
+            self.join_room(signal_server, room),
+            require_reliable_transmission,
+        )
+    }
+
+    /// Subscribes to the events emitted by this client
+    pub fn events(&self) -> broadcast::Receiver<ClientEvent> {
+        self.events.subscribe()
+    }
+
+    /// Sets the handler deciding whether an incoming connection from a peer may complete, given
+    /// the peer's id and metadata. Without a handler, every incoming connection is accepted
+    pub fn on_incoming(
+        &mut self,
+        handler: impl Fn(&str, &PeerMetadata) -> IncomingDecision + Send + Sync + 'static,
+    ) {
+        self.on_incoming = Some(Arc::new(handler));
+    }
+
+    /// Answers an incoming offer from `peer_id`, creating and tracking a connection for it just
+    /// like `P2PClient::create_connection`.
+    /// Resolves to `ClientError::Rejected` if the `on_incoming` handler rejects the peer
+    ///
+    /// * `peer_id` - The id of the remote peer which sent the offer
+    /// * `metadata` - The metadata the remote peer shared about itself
+    /// * `offer` - The offer of the remote peer
+    /// * `require_reliable_transmission` - if `true`, then we require ordered packets
+    pub async fn answer_connection(
+        &self,
+        peer_id: impl Into<String>,
+        metadata: &PeerMetadata,
+        offer: RTCSessionDescription,
+        require_reliable_transmission: bool,
+    ) -> AResult<(Arc<P2PConnection>, RTCSessionDescription)> {
+        let peer_id = peer_id.into();
+
+        if let Some(on_incoming) = &self.on_incoming {
+            if on_incoming(&peer_id, metadata) == IncomingDecision::Reject {
+                return Err(ClientError::Rejected(peer_id).into());
+            }
+        }
+
+        let connection = self
+            .create_connection(peer_id, require_reliable_transmission)
+            .await?;
+        let answer = connection.get_answer(offer).await?;
+
+        Ok((connection, answer))
+    }
+
+    /// Creates a new `P2PConnection` to `peer_id` and tracks it in this client.
+    /// If the connection is not established within the connect timeout, it is closed, removed
+    /// from the client, and a `ClientEvent::ConnectTimeout` is emitted
+    ///
+    /// * `peer_id` - The id of the remote peer this connection is for
+    /// * `require_reliable_transmission` - if `true`, then we require ordered packets
+    pub async fn create_connection(
+        &self,
+        peer_id: impl Into<String>,
+        require_reliable_transmission: bool,
+    ) -> AResult<Arc<P2PConnection>> {
+        let peer_id = peer_id.into();
+        let connection = Arc::new(P2PConnection::new(self, require_reliable_transmission).await?);
+        connection.set_remote_peer_id(peer_id.clone());
+
+        self.connections.write().await.insert(
+            peer_id.clone(),
+            TrackedConnection {
+                connection: connection.clone(),
+                room: None,
+            },
+        );
+
+        if let Some((interval, missed_intervals)) = self.keepalive {
+            tokio::spawn(keepalive(
+                peer_id.clone(),
+                Arc::downgrade(&connection),
+                self.events.clone(),
+                interval,
+                missed_intervals,
+            ));
+        }
+
+        if let Some(interval) = self.quality_interval {
+            tokio::spawn(monitor_quality(
+                peer_id.clone(),
+                Arc::downgrade(&connection),
+                self.events.clone(),
+                interval,
+                self.send_high_water_mark,
+            ));
+        }
+
+        if self.ice_events {
+            // Subscribed before anything can happen, so no change is missed
+            tokio::spawn(report_ice_states(
+                peer_id.clone(),
+                connection.ice_state_changes(),
+                connection.gathering_changes(),
+                self.events.clone(),
+            ));
+        }
+
+        if self.reconnect_buffer.is_some() {
+            tokio::spawn(watch_reconnects(
+                peer_id.clone(),
+                Arc::downgrade(&connection),
+                self.events.clone(),
+            ));
+        }
+
+        tokio::spawn(watch_connect_timeout(
+            peer_id,
+            connection.clone(),
+            self.connections.clone(),
+            self.events.clone(),
+        ));
+
+        Ok(connection)
+    }
+
+    /// Gets the tracked connection to `peer_id`, if there is one
+    pub async fn get_connection(&self, peer_id: &str) -> Option<Arc<P2PConnection>> {
+        self.connections
+            .read()
+            .await
+            .get(peer_id)
+            .map(|tracked| tracked.connection.clone())
+    }
+
+    /// Gets the ids of every peer this client is currently connected to
+    pub async fn connected_peers(&self) -> Vec<String> {
+        self.connections
+            .read()
+            .await
+            .iter()
+            .filter(|(_, tracked)| tracked.connection.get_is_connected_to_peer())
+            .map(|(peer_id, _)| peer_id.clone())
+            .collect()
+    }
+
+    /// Records that the connection to `peer_id` belongs to `room`
+    pub async fn assign_room(&self, peer_id: &str, room: RoomConfig) -> AResult<()> {
+        self.connections
+            .write()
+            .await
+            .get_mut(peer_id)
+            .ok_or_else(|| ClientError::UnknownPeer(peer_id.to_string()))?
+            .room = Some(room);
+
+        Ok(())
+    }
+
+    /// Gets the room the connection to `peer_id` belongs to, if it has been assigned one
+    pub async fn room_of(&self, peer_id: &str) -> Option<RoomConfig> {
+        self.connections
+            .read()
+            .await
+            .get(peer_id)
+            .and_then(|tracked| tracked.room.clone())
+    }
+
+    /// Gets the ids of all the peers whose connections belong to `room`
+    pub async fn peers_in_room(&self, room: &RoomConfig) -> Vec<String> {
+        self.connections
+            .read()
+            .await
+            .iter()
+            .filter(|(_, tracked)| tracked.room.as_ref() == Some(room))
+            .map(|(peer_id, _)| peer_id.clone())
+            .collect()
+    }
+
+    /// Moves the already established connections to `peer_ids` from the room of `handle` into
+    /// `to`, without renegotiating them.
+    /// The local peer is announced in the new room, and the returned handle will not yield the
+    /// migrated peers from `RoomHandle::discovered_peers`
+    pub async fn migrate_room<'s, S: Signaling>(
+        &self,
+        handle: &RoomHandle<'s, S>,
+        to: RoomConfig,
+        peer_ids: &[&str],
+    ) -> AResult<RoomHandle<'s, S>> {
+        let announced = {
+            let mut connections = self.connections.write().await;
+
+            if let Some(missing) = peer_ids
+                .iter()
+                .find(|peer_id| !connections.contains_key(**peer_id))
+            {
+                return Err(ClientError::UnknownPeer(missing.to_string()).into());
+            }
+
+            let mut announced = None;
+            for peer_id in peer_ids {
+                if let Some(tracked) = connections.get_mut(*peer_id) {
+                    tracked.room = Some(to.clone());
+                    announced.get_or_insert_with(|| tracked.connection.clone());
+                }
+            }
+            announced
+        };
+
+        let migrated = handle.migrate(to, peer_ids.iter().map(|peer_id| peer_id.to_string()));
+        if let Some(connection) = announced {
+            migrated.announce(&connection).await?;
+        }
+
+        Ok(migrated)
+    }
+
+    /// Waits for the connection to `peer_id` to be established.
+    /// Resolves to `ClientError::ConnectTimeout` if the connect timeout elapses first
+    pub async fn wait_for_connection(&self, peer_id: &str) -> AResult<Arc<P2PConnection>> {
+        let mut events = self.events();
+
+        loop {
+            let Some(connection) = self.get_connection(peer_id).await else {
+                // The timeout is emitted along with the removal of the connection, so it has to
+                // be queued already if that is why the connection is gone
+                while let Ok(event) = events.try_recv() {
+                    if event
+                        == (ClientEvent::ConnectTimeout {
+                            peer_id: peer_id.to_string(),
+                        })
+                    {
+                        return Err(ClientError::ConnectTimeout(peer_id.to_string()).into());
+                    }
+                }
+                return Err(ClientError::UnknownPeer(peer_id.to_string()).into());
+            };
+
+            match connection.state() {
+                ConnectionState::Connected => return Ok(connection),
+                ConnectionState::Closed => {
+                    return Err(ClientError::UnknownPeer(peer_id.to_string()).into())
+                }
+                _ => {}
+            }
+
+            // Once closed, the connection is looked up again, as it may have been replaced
+            let mut states = connection.state_changes();
+            tokio::select! {
+                Ok(ClientEvent::ConnectTimeout { peer_id: timed_out }) = events.recv() => {
+                    if timed_out == peer_id {
+                        return Err(ClientError::ConnectTimeout(timed_out).into());
+                    }
+                }
+                _ = states.wait_for(|state| {
+                    matches!(state, ConnectionState::Connected | ConnectionState::Closed)
+                }) => {}
+            }
         }
     }
 }
 
-impl<'a> Default for P2PClient<'a> {
+async fn watch_connect_timeout(
+    peer_id: String,
+    connection: Arc<P2PConnection>,
+    connections: ConnectionMap,
+    events: broadcast::Sender<ClientEvent>,
+) {
+    // Closes the connection if it times out
+    if connection.wait_connected().await.is_ok() {
+        return;
+    }
+
+    {
+        // The event is sent while holding the lock, so anyone who sees the connection missing
+        // will also have the event queued
+        let mut connections = connections.write().await;
+        if connections
+            .get(&peer_id)
+            .is_some_and(|tracked| Arc::ptr_eq(&tracked.connection, &connection))
+        {
+            connections.remove(&peer_id);
+        }
+        let _ = events.send(ClientEvent::ConnectTimeout { peer_id });
+    }
+}
+
+/// Reports `connection` dropping and coming back once it is established, sending what was held
+/// while it was down, until it is dropped or closed
+async fn watch_reconnects(
+    peer_id: String,
+    connection: Weak<P2PConnection>,
+    events: broadcast::Sender<ClientEvent>,
+) {
+    let Some(mut states) = connection
+        .upgrade()
+        .map(|connection| connection.state_changes())
+    else {
+        return;
+    };
+
+    loop {
+        if states
+            .wait_for(|state| *state == ConnectionState::Connected)
+            .await
+            .is_err()
+        {
+            return;
+        }
+        let Ok(state) = states
+            .wait_for(|state| *state != ConnectionState::Connected)
+            .await
+            .map(|state| *state)
+        else {
+            return;
+        };
+        if state == ConnectionState::Closed {
+            return;
+        }
+        let _ = events.send(ClientEvent::Reconnecting {
+            peer_id: peer_id.clone(),
+        });
+
+        let Ok(state) = states
+            .wait_for(|state| matches!(state, ConnectionState::Connected | ConnectionState::Closed))
+            .await
+            .map(|state| *state)
+        else {
+            return;
+        };
+        let Some(connection) = connection.upgrade() else {
+            return;
+        };
+        if state == ConnectionState::Closed {
+            return;
+        }
+        let _ = connection.flush_outbox().await;
+        let _ = events.send(ClientEvent::Reconnected {
+            peer_id: peer_id.clone(),
+        });
+    }
+}
+
+/// Reports the ICE and gathering states of a connection as they change, until it is closed
+async fn report_ice_states(
+    peer_id: String,
+    mut ice_states: watch::Receiver<IceConnectionState>,
+    mut gathering_states: watch::Receiver<GatheringState>,
+    events: broadcast::Sender<ClientEvent>,
+) {
+    let mut ice_state = *ice_states.borrow();
+    let mut gathering_state = *gathering_states.borrow();
+    loop {
+        tokio::select! {
+            changed = ice_states.changed() => {
+                if changed.is_err() {
+                    return;
+                }
+                // Repeats of the same state aren't a transition
+                let state = *ice_states.borrow_and_update();
+                if state == ice_state {
+                    continue;
+                }
+                ice_state = state;
+                let peer_id = peer_id.clone();
+                let _ = events.send(ClientEvent::IceStateChanged { peer_id, state });
+                if state == IceConnectionState::Closed {
+                    return;
+                }
+            }
+            changed = gathering_states.changed() => {
+                if changed.is_err() {
+                    return;
+                }
+                let state = *gathering_states.borrow_and_update();
+                if state == gathering_state {
+                    continue;
+                }
+                gathering_state = state;
+                let peer_id = peer_id.clone();
+                let _ = events.send(ClientEvent::GatheringStateChanged { peer_id, state });
+            }
+        }
+    }
+}
+
+/// Sends heartbeats over `connection` once it is established, until it is dropped, closed or
+/// the peer times out
+async fn keepalive(
+    peer_id: String,
+    connection: Weak<P2PConnection>,
+    events: broadcast::Sender<ClientEvent>,
+    interval: Duration,
+    missed_intervals: u32,
+) {
+    let Some(mut states) = connection
+        .upgrade()
+        .map(|connection| connection.state_changes())
+    else {
+        return;
+    };
+    if states
+        .wait_for(|state| *state == ConnectionState::Connected)
+        .await
+        .is_err()
+    {
+        return;
+    }
+
+    // Only silence after the connection was established counts
+    let connected_at = Instant::now();
+    let mut ticks = tokio::time::interval(interval);
+    loop {
+        ticks.tick().await;
+        let Some(connection) = connection.upgrade() else {
+            return;
+        };
+        if connection.state() == ConnectionState::Closed {
+            return;
+        }
+
+        let channel = connection.channel();
+        let heard_from = channel.last_received().max(connected_at);
+        if heard_from.elapsed() > interval * missed_intervals {
+            let _ = events.send(ClientEvent::PeerTimeout { peer_id });
+            return;
+        }
+        let _ = channel.send_heartbeat().await;
+    }
+}
+
+/// Rates the quality of `connection` each `interval` once it is established, until it is
+/// dropped or closed
+async fn monitor_quality(
+    peer_id: String,
+    connection: Weak<P2PConnection>,
+    events: broadcast::Sender<ClientEvent>,
+    interval: Duration,
+    high_water_mark: usize,
+) {
+    let Some(mut states) = connection
+        .upgrade()
+        .map(|connection| connection.state_changes())
+    else {
+        return;
+    };
+    if states
+        .wait_for(|state| *state == ConnectionState::Connected)
+        .await
+        .is_err()
+    {
+        return;
+    }
+
+    // Pings only go through once the channel has opened as well
+    let Some(opened) = connection.upgrade() else {
+        return;
+    };
+    if opened.channel().wait_open().await.is_err() {
+        return;
+    }
+    drop(opened);
+
+    let mut monitor = QualityMonitor::new(high_water_mark);
+    let mut ticks = tokio::time::interval(interval);
+    loop {
+        ticks.tick().await;
+        let Some(connection) = connection.upgrade() else {
+            return;
+        };
+        if connection.state() == ConnectionState::Closed {
+            return;
+        }
+
+        // A ping which isn't answered before the next one is due counts as lost
+        let rtt = tokio::time::timeout(interval, connection.channel().ping())
+            .await
+            .ok()
+            .and_then(Result::ok);
+        if let Some(quality) = monitor.sample(rtt, connection.queued_bytes().await) {
+            let peer_id = peer_id.clone();
+            let _ = events.send(ClientEvent::QualityChanged { peer_id, quality });
+        }
+    }
+}
+
+fn build_api(setting_engine: &SettingEngine) -> API {
+    // The default codecs let tracks be added to connections
+    let mut media_engine = MediaEngine::default();
+    media_engine
+        .register_default_codecs()
+        .expect("Unable to register the default codecs");
+
+    APIBuilder::new()
+        .with_setting_engine(setting_engine.clone())
+        .with_media_engine(media_engine)
+        .build()
+}
+
+impl Default for P2PClient {
     fn default() -> Self {
         Self::new(["stun:stun.l.google.com:19302"])
     }
@@ -56,6 +866,12 @@ impl<'a> Default for P2PClient<'a> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::signaling::tests::spawn_signal_server;
+    use crate::signaling::SignalServer;
+    use futures::StreamExt;
+    use std::time::Duration;
+    use tokio::time::{sleep, Instant};
+    use webrtc::peer_connection::sdp::sdp_type::RTCSdpType;
 
     const DEFAULT_SERVER: &str = "stun:stun.l.google.com:19302";
 
@@ -75,4 +891,270 @@ mod tests {
         assert_eq!(client.ice_servers[0], DEFAULT_SERVER);
         Ok(())
     }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_nat_1to1_ips_advertised() -> anyhow::Result<()> {
+        let public_ip = "203.0.113.7";
+        let client = P2PClient::new(Vec::<String>::new())
+            .with_nat_1to1_ips([public_ip], NatMappingType::Host);
+
+        assert_eq!(client.nat_1to1_ips, vec![public_ip.to_string()]);
+
+        let connection = P2PConnection::new(&client, true).await?;
+        connection.get_offer().await?;
+
+        let now = Instant::now();
+        while now.elapsed() < Duration::from_secs(10) {
+            if connection
+                .get_pending_candidates()?
+                .iter()
+                .any(|candidate| candidate.candidate.contains(&public_ip.to_string()))
+            {
+                return Ok(());
+            }
+            sleep(Duration::from_millis(10)).await;
+        }
+
+        Err(anyhow::anyhow!("NAT 1:1 address was never advertised"))
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_connect_timeout() -> anyhow::Result<()> {
+        let peer_id = Uuid::new_v4().to_string();
+        let client =
+            P2PClient::new([DEFAULT_SERVER]).with_connect_timeout(Duration::from_millis(100));
+        let mut events = client.events();
+
+        client.create_connection(peer_id.as_str(), true).await?;
+
+        let err = client
+            .wait_for_connection(&peer_id)
+            .await
+            .expect_err("Connection should not have been established");
+        assert!(matches!(
+            err.downcast_ref::<ClientError>(),
+            Some(ClientError::ConnectTimeout(id)) if *id == peer_id
+        ));
+
+        assert_eq!(
+            events.recv().await?,
+            ClientEvent::ConnectTimeout {
+                peer_id: peer_id.clone()
+            }
+        );
+        assert!(client.get_connection(&peer_id).await.is_none());
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_migrate_room_keeps_connection() -> anyhow::Result<()> {
+        let server = SignalServer::new(spawn_signal_server().await?);
+        let lobby = RoomConfig::new("test", Uuid::new_v4().to_string());
+        let game = RoomConfig::new("test", Uuid::new_v4().to_string());
+
+        let client = P2PClient::default();
+        let local_id = Uuid::new_v4().to_string();
+        let remote_id = Uuid::new_v4().to_string();
+
+        let connection = client.create_connection(remote_id.as_str(), true).await?;
+        assert_eq!(connection.remote_peer_id(), Some(remote_id.as_str()));
+        connection.get_offer().await?;
+        client.assign_room(&remote_id, lobby.clone()).await?;
+
+        let handle = server
+            .join(lobby.clone(), local_id.as_str())
+            .with_poll_interval(Duration::from_millis(20));
+        server
+            .join(game.clone(), remote_id.as_str())
+            .announce(&connection)
+            .await?;
+
+        let migrated = client
+            .migrate_room(&handle, game.clone(), &[remote_id.as_str()])
+            .await?;
+
+        assert_eq!(migrated.room(), &game);
+        assert_eq!(client.room_of(&remote_id).await, Some(game.clone()));
+        assert_eq!(client.peers_in_room(&game).await, vec![remote_id.clone()]);
+        assert!(client.peers_in_room(&lobby).await.is_empty());
+        assert!(Arc::ptr_eq(
+            &client
+                .get_connection(&remote_id)
+                .await
+                .expect("Connection was dropped"),
+            &connection
+        ));
+        assert!(server.get_peers(&game).await?.contains(&local_id));
+
+        let peers = migrated.discovered_peers();
+        futures::pin_mut!(peers);
+        assert!(
+            tokio::time::timeout(Duration::from_millis(200), peers.next())
+                .await
+                .is_err(),
+            "Migrated peers should not be rediscovered"
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_on_incoming_gates_connections() -> anyhow::Result<()> {
+        let remote = P2PClient::default();
+        let remote_connection = P2PConnection::new(&remote, true).await?;
+        let offer = remote_connection.get_offer().await?;
+
+        let mut client = P2PClient::default();
+        client.on_incoming(|_, metadata| match metadata.get("invite") {
+            Some(invite) if invite == "secret" => IncomingDecision::Accept,
+            _ => IncomingDecision::Reject,
+        });
+
+        let uninvited = Uuid::new_v4().to_string();
+        let err = client
+            .answer_connection(
+                uninvited.as_str(),
+                &PeerMetadata::new(),
+                offer.clone(),
+                true,
+            )
+            .await
+            .expect_err("Peer without an invite should be rejected");
+        assert!(matches!(
+            err.downcast_ref::<ClientError>(),
+            Some(ClientError::Rejected(id)) if *id == uninvited
+        ));
+        assert!(client.get_connection(&uninvited).await.is_none());
+
+        let invited = Uuid::new_v4().to_string();
+        let metadata = PeerMetadata::from([("invite".to_string(), "secret".to_string())]);
+        let (_, answer) = client
+            .answer_connection(invited.as_str(), &metadata, offer, true)
+            .await?;
+        assert_eq!(answer.sdp_type, RTCSdpType::Answer);
+        assert!(client.get_connection(&invited).await.is_some());
+
+        Ok(())
+    }
+
+    /// Connects a tracked connection of `client1` to one of `client2`
+    async fn connect_clients(
+        client1: &P2PClient,
+        client2: &P2PClient,
+    ) -> anyhow::Result<(Arc<P2PConnection>, Arc<P2PConnection>)> {
+        let (peer1, peer2) = (client1.peer_id(), client2.peer_id());
+        let connection1 = client1.create_connection(peer2.as_str(), true).await?;
+        let offer = connection1.get_offer().await?;
+        let (connection2, answer) = client2
+            .answer_connection(peer1, &PeerMetadata::new(), offer, true)
+            .await?;
+        connection1.set_answer(answer).await?;
+
+        let mut candidates1 = connection1.candidate_events()?;
+        let mut candidates2 = connection2.candidate_events()?;
+        let wait = async {
+            loop {
+                tokio::select! {
+                    Some(candidate) = candidates1.recv() => {
+                        connection2.set_candidates(std::iter::once(candidate)).await?;
+                    }
+                    Some(candidate) = candidates2.recv() => {
+                        connection1.set_candidates(std::iter::once(candidate)).await?;
+                    }
+                    connected = client1.wait_for_connection(&peer2) => {
+                        connected?;
+                        return anyhow::Ok(());
+                    }
+                }
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(10), wait).await??;
+        Ok((connection1, connection2))
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_keepalive_detects_dead_peer() -> anyhow::Result<()> {
+        let client1 =
+            P2PClient::new([DEFAULT_SERVER]).with_keepalive(Duration::from_millis(100), 3);
+        let client2 = P2PClient::new([DEFAULT_SERVER]);
+        let peer2 = client2.peer_id();
+        let mut events = client1.events();
+
+        let (_connection1, connection2) = connect_clients(&client1, &client2).await?;
+
+        // The peer keeps answering the heartbeats
+        sleep(Duration::from_millis(600)).await;
+        assert!(events.try_recv().is_err());
+
+        connection2.close().await?;
+        let timed_out = tokio::time::timeout(Duration::from_secs(5), events.recv()).await??;
+        assert_eq!(timed_out, ClientEvent::PeerTimeout { peer_id: peer2 });
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_ice_events() -> anyhow::Result<()> {
+        let client1 = P2PClient::new([DEFAULT_SERVER]).with_ice_events();
+        let client2 = P2PClient::new([DEFAULT_SERVER]);
+        let peer2 = client2.peer_id();
+        let mut events = client1.events();
+
+        connect_clients(&client1, &client2).await?;
+
+        let mut ice_states = Vec::new();
+        let mut gathering_states = Vec::new();
+        let collect = async {
+            while !ice_states.contains(&IceConnectionState::Connected)
+                || !gathering_states.contains(&GatheringState::Complete)
+            {
+                match events.recv().await? {
+                    ClientEvent::IceStateChanged { peer_id, state } if peer_id == peer2 => {
+                        ice_states.push(state);
+                    }
+                    ClientEvent::GatheringStateChanged { peer_id, state } if peer_id == peer2 => {
+                        gathering_states.push(state);
+                    }
+                    _ => {}
+                }
+            }
+            anyhow::Ok(())
+        };
+        tokio::time::timeout(Duration::from_secs(10), collect).await??;
+
+        // Changes in quick succession can be seen as just the last of them
+        assert_eq!(ice_states.last(), Some(&IceConnectionState::Connected));
+        assert!(!ice_states.contains(&IceConnectionState::Failed));
+        assert_eq!(gathering_states.last(), Some(&GatheringState::Complete));
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_quality_monitor() -> anyhow::Result<()> {
+        let client1 =
+            P2PClient::new([DEFAULT_SERVER]).with_quality_monitor(Duration::from_millis(100));
+        let client2 = P2PClient::new([DEFAULT_SERVER]);
+        let peer2 = client2.peer_id();
+        let mut events = client1.events();
+
+        let (_connection1, connection2) = connect_clients(&client1, &client2).await?;
+
+        // The link stays good while the peer answers quickly
+        sleep(Duration::from_millis(600)).await;
+        assert!(events.try_recv().is_err());
+
+        connection2.close().await?;
+        let changed = tokio::time::timeout(Duration::from_secs(5), events.recv()).await??;
+        assert_eq!(
+            changed,
+            ClientEvent::QualityChanged {
+                peer_id: peer2,
+                quality: Quality::Bad
+            }
+        );
+
+        Ok(())
+    }
 }
@@ -1,48 +1,249 @@
-use crate::p2p_client::{IntoId, P2PClient};
+pub use crate::channel::Message;
+
+use crate::certificate::Certificate;
+use crate::channel::{
+    CancellationToken, Channel, ChannelEvent, ChannelOptions, ChannelSettings, Delivery,
+};
+use crate::codec::{Bincode, Codec};
+use crate::error::ConnectionError;
+use crate::fault::FaultInjector;
+#[cfg(any(test, feature = "fault-injection"))]
+use crate::fault::Faults;
+use crate::framing::DEFAULT_MAX_MESSAGE_SIZE;
+use crate::media::{AudioCodec, IncomingTracks, MediaSample, RemoteTrack, VideoCodec};
+use crate::mux::MuxStream;
+use crate::outbox::Outbox;
+use crate::p2p_client::P2PClient;
+use crate::rate_limit::{RateLimit, RateLimiter};
+use crate::stats::{CandidatePair, ConnectionStats, StatsSnapshot};
+use crate::stream::P2PStream;
 use anyhow::{anyhow, Result as AResult};
-use core::task;
-use std::sync::atomic::AtomicBool;
-use std::sync::{Arc, RwLock};
-use tokio::sync::mpsc::error::TryRecvError;
-use tokio::sync::mpsc::{channel, Receiver};
+use bytes::Bytes;
+use futures::{Stream, StreamExt};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock, Weak};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::sync::{broadcast, watch, Notify};
 use webrtc::data_channel::data_channel_init::RTCDataChannelInit;
-use webrtc::data_channel::data_channel_message::DataChannelMessage;
 use webrtc::data_channel::RTCDataChannel;
+use webrtc::dtls_transport::dtls_transport_state::RTCDtlsTransportState;
+use webrtc::dtls_transport::RTCDtlsTransport;
 use webrtc::ice_transport::ice_candidate::{RTCIceCandidate, RTCIceCandidateInit};
+use webrtc::ice_transport::ice_connection_state::RTCIceConnectionState;
+use webrtc::ice_transport::ice_gatherer_state::RTCIceGathererState;
 use webrtc::ice_transport::ice_server::RTCIceServer;
+use webrtc::ice_transport::ice_transport_state::RTCIceTransportState;
+use webrtc::media::Sample;
 use webrtc::peer_connection::configuration::RTCConfiguration;
+use webrtc::peer_connection::offer_answer_options::{RTCAnswerOptions, RTCOfferOptions};
+use webrtc::peer_connection::peer_connection_state::RTCPeerConnectionState;
 use webrtc::peer_connection::sdp::session_description::RTCSessionDescription;
 use webrtc::peer_connection::RTCPeerConnection;
+use webrtc::rtp_transceiver::rtp_codec::RTCRtpCodecCapability;
+use webrtc::rtp_transceiver::rtp_sender::RTCRtpSender;
+use webrtc::track::track_local::track_local_static_sample::TrackLocalStaticSample;
+use webrtc::track::track_local::TrackLocal;
+
+/// Where a `P2PConnection` is in its lifecycle
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionState {
+    New,
+    Connecting,
+    Connected,
+    Disconnected,
+    Failed,
+    Closed,
+}
+
+impl From<RTCPeerConnectionState> for ConnectionState {
+    fn from(state: RTCPeerConnectionState) -> Self {
+        match state {
+            RTCPeerConnectionState::Unspecified | RTCPeerConnectionState::New => Self::New,
+            RTCPeerConnectionState::Connecting => Self::Connecting,
+            RTCPeerConnectionState::Connected => Self::Connected,
+            RTCPeerConnectionState::Disconnected => Self::Disconnected,
+            RTCPeerConnectionState::Failed => Self::Failed,
+            RTCPeerConnectionState::Closed => Self::Closed,
+        }
+    }
+}
+
+/// Which of paired channels a message goes over, from `P2PConnection::send_as`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeliveryMode {
+    /// Arrives, and in the order it was sent, such as for game events
+    Reliable,
+    /// May be dropped or arrive out of order, but never waits on a lost message, such as for
+    /// state which is sent again soon anyway
+    Unreliable,
+}
+
+/// Where ICE is in finding a working pair of candidates for a `P2PConnection`, which is more
+/// telling than the `ConnectionState` when a connection can't be established
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IceConnectionState {
+    New,
+    /// Candidate pairs are being checked
+    Checking,
+    /// A working pair was found, though checking may go on for a better one
+    Connected,
+    /// Checking is done, and a pair was picked
+    Completed,
+    Disconnected,
+    /// Every candidate pair failed, such as when neither peer can reach the other
+    Failed,
+    Closed,
+}
+
+impl From<RTCIceConnectionState> for IceConnectionState {
+    fn from(state: RTCIceConnectionState) -> Self {
+        match state {
+            RTCIceConnectionState::Unspecified | RTCIceConnectionState::New => Self::New,
+            RTCIceConnectionState::Checking => Self::Checking,
+            RTCIceConnectionState::Connected => Self::Connected,
+            RTCIceConnectionState::Completed => Self::Completed,
+            RTCIceConnectionState::Disconnected => Self::Disconnected,
+            RTCIceConnectionState::Failed => Self::Failed,
+            RTCIceConnectionState::Closed => Self::Closed,
+        }
+    }
+}
+
+/// How far a `P2PConnection` is in gathering its local ICE candidates
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GatheringState {
+    /// Gathering starts once a local description is set, such as by `get_offer`
+    New,
+    Gathering,
+    /// Every local candidate is in the local description from here on, until ICE restarts
+    Complete,
+}
+
+pub struct P2PConnection {
+    connection: Arc<RTCPeerConnection>,
+    channel: Channel,
+    /// The unreliable half of a connection created with paired channels
+    unreliable: Option<Channel>,
+    local_id: String,
+    remote_id: std::sync::OnceLock<String>,
+    connect_timeout: Duration,
+    /// The DTLS fingerprint the peer has to present, when pinned
+    expected_fingerprint: std::sync::Mutex<Option<String>>,
+    channel_settings: ChannelSettings,
+    incoming_channels: Arc<std::sync::Mutex<HashMap<String, Channel>>>,
+    incoming_channel_opened: Arc<Notify>,
+    /// The channels handed out by `open_channel` and `on_channel`, which `close` closes too
+    handed_out_channels: std::sync::Mutex<Vec<Weak<RTCDataChannel>>>,
+    negotiation_needed: Arc<AtomicBool>,
+    negotiation_needed_changed: Arc<Notify>,
+    closed: AtomicBool,
+    ice_candidates: Arc<RwLock<GatheredCandidates>>,
+    incoming_tracks: Arc<std::sync::Mutex<IncomingTracks>>,
+    state: Arc<watch::Sender<ConnectionState>>,
+    gathering: Arc<watch::Sender<GatheringState>>,
+    ice_state: Arc<watch::Sender<IceConnectionState>>,
+    /// Where sends over the default channel wait while reconnecting, when the client reconnects
+    outbox: Option<tokio::sync::Mutex<Outbox>>,
+}
+
+/// The local ICE candidates gathered so far, and who is listening for new ones
+#[derive(Default)]
+struct GatheredCandidates {
+    candidates: Vec<RTCIceCandidate>,
+    /// How many of `candidates` have already been handed out, through `candidate_events` or
+    /// `get_pending_candidates`
+    consumed: usize,
+    listeners: Vec<UnboundedSender<RTCIceCandidateInit>>,
+}
+
+impl GatheredCandidates {
+    fn push(&mut self, candidate: RTCIceCandidate) {
+        if self
+            .candidates
+            .iter()
+            .any(|gathered| is_same_candidate(gathered, &candidate))
+        {
+            return;
+        }
+        // A candidate which can't be described for the peer is of no use to it
+        if let Ok(init) = candidate.to_json() {
+            self.listeners
+                .retain(|listener| listener.send(init.clone()).is_ok());
+        }
+        self.candidates.push(candidate);
+        if !self.listeners.is_empty() {
+            self.consumed = self.candidates.len();
+        }
+    }
+
+    fn take_pending(&mut self) -> Vec<RTCIceCandidate> {
+        let pending = self.candidates[self.consumed..].to_vec();
+        self.consumed = self.candidates.len();
+        pending
+    }
+
+    fn clear(&mut self) {
+        self.candidates.clear();
+        self.consumed = 0;
+    }
+}
 
-pub struct P2PConnection<'a> {
-    connection: RTCPeerConnection,
-    data_channel: Arc<RTCDataChannel>,
-    local_id: &'a Box<dyn IntoId>,
-    remote_id: Option<Box<dyn IntoId>>,
-    message_reciever: Receiver<DataChannelMessage>,
-    ice_candidates: Arc<RwLock<Vec<RTCIceCandidate>>>,
-    connected: Arc<AtomicBool>,
+/// Whether two candidates describe the same transport address, which ICE may report more than
+/// once under different stats ids
+fn is_same_candidate(a: &RTCIceCandidate, b: &RTCIceCandidate) -> bool {
+    RTCIceCandidate {
+        stats_id: b.stats_id.clone(),
+        ..a.clone()
+    } == *b
 }
 
-impl<'a> std::fmt::Debug for P2PConnection<'a> {
+impl std::fmt::Debug for P2PConnection {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.write_str(&format!("P2PConnection: {}", self.local_id.id()))
+        match self.remote_id.get() {
+            Some(remote_id) => f.write_str(&format!(
+                "P2PConnection: {} -> {}",
+                self.local_id, remote_id
+            )),
+            None => f.write_str(&format!("P2PConnection: {}", self.local_id)),
+        }
     }
 }
 
-impl<'a> P2PConnection<'a> {
+impl P2PConnection {
     /// Creates a new `P2PConnection` from a `&P2PClient`.
     /// This is an async function, and expects the client to have at least one valid STUN server
     /// already setup
     ///
     /// * `client` - The P2P Client which will take control of this struct
     /// * `require_reliable_transmission` - if `true`, then we require ordered packets. This makes
-    /// our packets more reliable, but at the potential cost of network performance as we do not
-    /// allow dropped packets
-    pub async fn new(
-        client: &'a P2PClient<'a>,
-        require_reliable_transmission: bool,
-    ) -> AResult<Self> {
+    ///   our packets more reliable, but at the potential cost of network performance as we do not
+    ///   allow dropped packets
+    pub async fn new(client: &P2PClient, require_reliable_transmission: bool) -> AResult<Self> {
+        Self::create(
+            client,
+            require_reliable_transmission,
+            client.paired_channels,
+        )
+        .await
+    }
+
+    /// Creates a new `P2PConnection` with two channels negotiated up front: the default one,
+    /// which is ordered and reliable, and an unordered one which never retransmits. They are
+    /// reached through `reliable` and `unreliable`, which suits games sending events alongside
+    /// state which is stale by the time it could be resent. The peer has to create its
+    /// connection with paired channels as well
+    pub async fn new_paired(client: &P2PClient) -> AResult<Self> {
+        Self::create(client, true, true).await
+    }
+
+    async fn create(client: &P2PClient, reliable: bool, paired: bool) -> AResult<Self> {
         let config = RTCConfiguration {
             ice_servers: client
                 .ice_servers
@@ -53,46 +254,180 @@ impl<'a> P2PConnection<'a> {
                     ..Default::default()
                 })
                 .collect::<Vec<_>>(),
+            certificates: client
+                .certificate
+                .iter()
+                .map(Certificate::rtc_certificate)
+                .collect(),
             ..Default::default()
         };
 
-        let connection = client.api.new_peer_connection(config).await?;
+        let connection = Arc::new(client.api.new_peer_connection(config).await?);
         let data_channel = connection
             .create_data_channel(
                 &format!("data_channel_{}", client.id.id()),
                 Some(RTCDataChannelInit {
-                    ordered: Some(require_reliable_transmission),
+                    ordered: Some(reliable || paired),
+                    // Both peers create the same channel, so each one receives what the other sends
+                    negotiated: Some(0),
                     ..Default::default()
                 }),
             )
             .await?;
+        let unreliable_data_channel = if paired {
+            let options = ChannelOptions::unreliable(0);
+            let data_channel = connection
+                .create_data_channel(
+                    &format!("unreliable_channel_{}", client.id.id()),
+                    Some(RTCDataChannelInit {
+                        negotiated: Some(1),
+                        ..options.into()
+                    }),
+                )
+                .await?;
+            Some(data_channel)
+        } else {
+            None
+        };
 
-        let (sx, rx) = channel(128);
+        let channel_settings = ChannelSettings {
+            high_water_mark: client.send_high_water_mark,
+            rate_limiter: Arc::new(RateLimiter::new(client.send_rate_limit)),
+            compression_threshold: client.compression_threshold,
+            encryption: client.encryption.clone(),
+            receive_buffer: client.receive_buffer,
+            overflow_policy: client.overflow_policy,
+            max_message_size: Arc::new(AtomicUsize::new(DEFAULT_MAX_MESSAGE_SIZE)),
+            faults: Arc::new(FaultInjector::default()),
+            ended: CancellationToken::new(),
+        };
+        let channel = Channel::new(data_channel, channel_settings.clone()).await;
+        let unreliable = match unreliable_data_channel {
+            Some(data_channel) => Some(Channel::new(data_channel, channel_settings.clone()).await),
+            None => None,
+        };
 
-        data_channel.on_message(Box::new(move |msg| {
-            let sx = sx.clone();
-            Box::pin(async move {
-                let _ = sx.send(msg).await;
-            })
-        }));
+        // Channels the peer opens with `open_channel` are held until `on_channel` asks for them.
+        // The handler runs before the channel opens, so no message is missed
+        let incoming_channels = Arc::new(std::sync::Mutex::new(HashMap::new()));
+        let incoming_channel_opened = Arc::new(Notify::new());
+        {
+            let (channels, opened) = (incoming_channels.clone(), incoming_channel_opened.clone());
+            let settings = channel_settings.clone();
+            let protocols = Arc::new(client.channel_protocols.clone());
+            connection.on_data_channel(Box::new(move |data_channel| {
+                let (channels, opened) = (channels.clone(), opened.clone());
+                let (settings, protocols) = (settings.clone(), protocols.clone());
+                Box::pin(async move {
+                    let refused = protocols.as_deref().is_some_and(|protocols| {
+                        !protocols
+                            .iter()
+                            .any(|protocol| protocol == data_channel.protocol())
+                    }) || {
+                        // A channel already held under the label isn't replaced, as it would
+                        // never be closed
+                        let channels = channels
+                            .lock()
+                            .expect("Unable to aquire incoming channels lock");
+                        channels.len() >= MAX_HELD_CHANNELS
+                            || channels.contains_key(data_channel.label())
+                    };
+                    if refused {
+                        // The channel can only be closed once it has opened
+                        let weak_data_channel = Arc::downgrade(&data_channel);
+                        data_channel.on_open(Box::new(move || {
+                            let weak_data_channel = weak_data_channel.clone();
+                            Box::pin(async move {
+                                if let Some(data_channel) = weak_data_channel.upgrade() {
+                                    let _ = data_channel.close().await;
+                                }
+                            })
+                        }));
+                        return;
+                    }
+                    let channel = Channel::new(data_channel, settings).await;
+                    channels
+                        .lock()
+                        .expect("Unable to aquire incoming channels lock")
+                        .insert(channel.label().to_owned(), channel);
+                    opened.notify_waiters();
+                })
+            }));
+        }
 
-        let connected = Arc::new(AtomicBool::new(false));
-        let connected_clone = connected.clone();
-        connection.on_peer_connection_state_change(Box::new(move |state| {
-            match state {
-                webrtc::peer_connection::peer_connection_state::RTCPeerConnectionState::Connected => {
-                    connected_clone.store(true, std::sync::atomic::Ordering::Relaxed);
-                },
-                _ => {
-                    connected_clone.store(false, std::sync::atomic::Ordering::Relaxed);
+        // webrtc-rs can leave the peer connection state at `New` once ICE reconnects, whether by
+        // itself or after a restart, so ICE reconnecting over a live DTLS transport counts as
+        // connected as well
+        let state = Arc::new(watch::Sender::new(ConnectionState::New));
+        {
+            // A failed connection can come back with an ICE restart, which a client that
+            // reconnects waits for
+            let (state, ended) = (state.clone(), channel_settings.ended.clone());
+            let reconnects = client.reconnect_buffer.is_some();
+            let dtls_transport = connection.sctp().transport();
+            connection.on_peer_connection_state_change(Box::new(move |new_state| {
+                if new_state == RTCPeerConnectionState::Closed
+                    || (new_state == RTCPeerConnectionState::Failed && !reconnects)
+                {
+                    ended.cancel();
+                }
+                if new_state != RTCPeerConnectionState::New || !is_reconnected(&dtls_transport) {
+                    state.send_replace(new_state.into());
                 }
-            };
+                Box::pin(async {})
+            }));
+        }
+        let ice_state = Arc::new(watch::Sender::new(IceConnectionState::New));
+        {
+            let (state, ice_state) = (state.clone(), ice_state.clone());
+            let dtls_transport = connection.sctp().transport();
+            connection.on_ice_connection_state_change(Box::new(move |new_state| {
+                ice_state.send_replace(new_state.into());
+                if is_reconnected(&dtls_transport) {
+                    state.send_replace(ConnectionState::Connected);
+                }
+                Box::pin(async {})
+            }));
+        }
 
+        // The first negotiation is driven by the initial offer and answer, so only changes made
+        // after it count
+        let negotiation_needed = Arc::new(AtomicBool::new(false));
+        let negotiation_needed_changed = Arc::new(Notify::new());
+        {
+            let (needed, changed) = (
+                negotiation_needed.clone(),
+                negotiation_needed_changed.clone(),
+            );
+            let weak_connection = Arc::downgrade(&connection);
+            connection.on_negotiation_needed(Box::new(move || {
+                let (needed, changed) = (needed.clone(), changed.clone());
+                let weak_connection = weak_connection.clone();
+                Box::pin(async move {
+                    let Some(connection) = weak_connection.upgrade() else {
+                        return;
+                    };
+                    if connection.current_remote_description().await.is_some() {
+                        needed.store(true, Ordering::Relaxed);
+                        changed.notify_waiters();
+                    }
+                })
+            }));
+        }
 
-            Box::pin(async {})
-        }));
+        let incoming_tracks = Arc::new(std::sync::Mutex::new(IncomingTracks::default()));
+        {
+            let incoming_tracks = incoming_tracks.clone();
+            connection.on_track(Box::new(move |track, _, _| {
+                incoming_tracks
+                    .lock()
+                    .expect("Unable to aquire incoming tracks lock")
+                    .push(track);
+                Box::pin(async {})
+            }));
+        }
 
-        let ice_candidates = Arc::new(RwLock::new(Vec::new()));
+        let ice_candidates = Arc::new(RwLock::new(GatheredCandidates::default()));
 
         let candidates_clone = ice_candidates.clone();
 
@@ -101,28 +436,84 @@ impl<'a> P2PConnection<'a> {
 
             Box::pin(async move {
                 if let Some(candidate) = candidate {
-                    let mut candidates = cloned.write().expect("Unable to aquire write lock");
-                    candidates.push(candidate);
+                    cloned
+                        .write()
+                        .expect("Unable to aquire write lock")
+                        .push(candidate);
                 }
             })
         }));
 
+        let gathering = Arc::new(watch::Sender::new(GatheringState::New));
+        {
+            let gathering = gathering.clone();
+            connection.on_ice_gathering_state_change(Box::new(move |state| {
+                match state {
+                    RTCIceGathererState::Gathering => {
+                        gathering.send_replace(GatheringState::Gathering);
+                    }
+                    RTCIceGathererState::Complete => {
+                        gathering.send_replace(GatheringState::Complete);
+                    }
+                    _ => {}
+                }
+                Box::pin(async {})
+            }));
+        }
+
         Ok(Self {
-            local_id: &client.id,
-            data_channel,
+            local_id: client.id.id(),
+            channel,
+            unreliable,
             connection,
-            remote_id: None,
-            message_reciever: rx,
+            remote_id: std::sync::OnceLock::new(),
+            connect_timeout: client.connect_timeout,
+            expected_fingerprint: std::sync::Mutex::new(None),
+            channel_settings,
+            incoming_channels,
+            incoming_channel_opened,
+            handed_out_channels: std::sync::Mutex::new(Vec::new()),
+            negotiation_needed,
+            negotiation_needed_changed,
+            closed: AtomicBool::new(false),
             ice_candidates,
-            connected,
+            incoming_tracks,
+            state,
+            gathering,
+            ice_state,
+            outbox: client
+                .reconnect_buffer
+                .map(|capacity| tokio::sync::Mutex::new(Outbox::new(capacity))),
         })
     }
 
+    /// The id of the peer on the other end, once the connection has been handed to one by
+    /// `P2PClient::create_connection`, `P2PClient::answer_connection` or a lobby
+    pub fn remote_peer_id(&self) -> Option<&str> {
+        self.remote_id.get().map(String::as_str)
+    }
+
+    /// Records the id of the peer on the other end. A connection only ever belongs to one peer,
+    /// so later ids are ignored
+    pub(crate) fn set_remote_peer_id(&self, peer_id: impl Into<String>) {
+        let _ = self.remote_id.set(peer_id.into());
+    }
+
     /// Gets the offer for use with the signaling server
     /// Will also trickle ICE candidates and automatically send them to the signaling server so the
     /// other peer can add them in turn
-    pub(crate) async fn get_offer(&self) -> AResult<RTCSessionDescription> {
-        let offer = self.connection.create_offer(None).await?;
+    pub async fn get_offer(&self) -> AResult<RTCSessionDescription> {
+        self.get_offer_with(RTCOfferOptions::default()).await
+    }
+
+    /// Gets an offer like `get_offer`, created with `options`. An offer with
+    /// `RTCOfferOptions::ice_restart` set restarts ICE like `restart_ice`
+    pub async fn get_offer_with(&self, options: RTCOfferOptions) -> AResult<RTCSessionDescription> {
+        self.negotiation_needed.store(false, Ordering::Relaxed);
+        if options.ice_restart {
+            self.clear_candidates()?;
+        }
+        let offer = self.connection.create_offer(Some(options)).await?;
         self.connection.set_local_description(offer).await?;
 
         let local_description = self
@@ -134,19 +525,30 @@ impl<'a> P2PConnection<'a> {
         Ok(local_description)
     }
 
-    pub(crate) async fn set_answer(&self, offer: RTCSessionDescription) -> AResult<()> {
-        self.connection.set_remote_description(offer).await?;
-        Ok(())
+    pub async fn set_answer(&self, offer: RTCSessionDescription) -> AResult<()> {
+        self.set_remote_description(offer).await
     }
 
     /// Used to set the remote answer to the connection
-    pub(crate) async fn get_answer(
+    pub async fn get_answer(&self, offer: RTCSessionDescription) -> AResult<RTCSessionDescription> {
+        self.get_answer_with(offer, RTCAnswerOptions::default())
+            .await
+    }
+
+    /// Answers `offer` like `get_answer`, with the answer created with `options`
+    pub async fn get_answer_with(
         &self,
         offer: RTCSessionDescription,
+        options: RTCAnswerOptions,
     ) -> AResult<RTCSessionDescription> {
-        self.connection.set_remote_description(offer).await?;
+        // An offer with new ICE credentials is an ICE restart, which gathers candidates anew
+        let previous = self.connection.remote_description().await;
+        if previous.is_some_and(|previous| ice_ufrag(&previous) != ice_ufrag(&offer)) {
+            self.clear_candidates()?;
+        }
+        self.set_remote_description(offer).await?;
 
-        let answer = self.connection.create_answer(None).await?;
+        let answer = self.connection.create_answer(Some(options)).await?;
 
         self.connection.set_local_description(answer).await?;
 
@@ -159,7 +561,165 @@ impl<'a> P2PConnection<'a> {
         Ok(local_description)
     }
 
-    pub(crate) async fn set_candidates(
+    /// The SHA-256 fingerprint of the certificate this connection presents in DTLS, in the
+    /// colon separated hex form used in session descriptions. Hand it to the peer out of band
+    /// for `expect_remote_fingerprint`
+    pub fn local_fingerprint(&self) -> AResult<String> {
+        self.connection
+            .sctp()
+            .transport()
+            .get_local_parameters()?
+            .fingerprints
+            .into_iter()
+            .find(|fingerprint| fingerprint.algorithm == FINGERPRINT_ALGORITHM)
+            .map(|fingerprint| fingerprint.value.to_uppercase())
+            .ok_or(anyhow!(
+                "The connection has no {FINGERPRINT_ALGORITHM} fingerprint"
+            ))
+    }
+
+    /// The SHA-256 fingerprint the peer gave for its DTLS certificate in its session
+    /// description, once one has been applied. DTLS fails unless the certificate matches it
+    pub async fn remote_fingerprint(&self) -> Option<String> {
+        let description = self.connection.remote_description().await?;
+        remote_fingerprint(&description).map(str::to_owned)
+    }
+
+    /// Pins the DTLS fingerprint the peer has to present, as learned out of band, so a
+    /// signaling server can't put itself in the middle. A session description from the peer
+    /// with another fingerprint is refused with `ConnectionError::FingerprintMismatch`, and
+    /// closes the connection
+    pub fn expect_remote_fingerprint(&self, fingerprint: impl Into<String>) {
+        *self
+            .expected_fingerprint
+            .lock()
+            .expect("Unable to aquire expected fingerprint lock") = Some(fingerprint.into());
+    }
+
+    /// Adds a media track to send to the peer. The connection then needs renegotiating, which
+    /// `negotiation_needed` reports
+    pub async fn add_track(
+        &self,
+        track: Arc<dyn TrackLocal + Send + Sync>,
+    ) -> AResult<Arc<RTCRtpSender>> {
+        let sender = self.connection.add_track(track).await?;
+
+        // webrtc-rs doesn't reliably fire `on_negotiation_needed` on an established connection
+        if self.connection.current_remote_description().await.is_some() {
+            self.negotiation_needed.store(true, Ordering::Relaxed);
+            self.negotiation_needed_changed.notify_waiters();
+        }
+        Ok(sender)
+    }
+
+    /// Adds an audio track to send to the peer, fed with the already encoded `samples` until the
+    /// stream ends or the connection closes. The stream sets the pace, so it should yield each
+    /// sample as it is captured. Like `add_track`, the connection then needs renegotiating
+    pub async fn add_audio_track(
+        &self,
+        codec: AudioCodec,
+        samples: impl Stream<Item = MediaSample> + Send + 'static,
+    ) -> AResult<Arc<RTCRtpSender>> {
+        self.add_sample_track("audio", codec.capability(), samples)
+            .await
+    }
+
+    /// Adds a video track to send to the peer, such as a camera feed or a shared screen, fed with
+    /// the already encoded `samples`, one frame each, like `add_audio_track`. Frames are split
+    /// into packets to send, and the peer's `RemoteTrack::recv_sample` puts them back together
+    pub async fn add_video_track(
+        &self,
+        codec: VideoCodec,
+        samples: impl Stream<Item = MediaSample> + Send + 'static,
+    ) -> AResult<Arc<RTCRtpSender>> {
+        self.add_sample_track("video", codec.capability(), samples)
+            .await
+    }
+
+    async fn add_sample_track(
+        &self,
+        kind: &str,
+        capability: RTCRtpCodecCapability,
+        samples: impl Stream<Item = MediaSample> + Send + 'static,
+    ) -> AResult<Arc<RTCRtpSender>> {
+        let track = Arc::new(TrackLocalStaticSample::new(
+            capability,
+            format!("{kind}_{}", uuid::Uuid::new_v4()),
+            self.local_id.clone(),
+        ));
+        let sender = self.add_track(track.clone()).await?;
+
+        // RTCP has to be read for the interceptors, such as NACK handling, to see it
+        {
+            let sender = sender.clone();
+            tokio::spawn(async move { while sender.read_rtcp().await.is_ok() {} });
+        }
+
+        let weak_connection = Arc::downgrade(&self.connection);
+        tokio::spawn(async move {
+            let mut samples = std::pin::pin!(samples);
+            while let Some(sample) = samples.next().await {
+                let open = weak_connection.upgrade().is_some_and(|connection| {
+                    connection.connection_state() != RTCPeerConnectionState::Closed
+                });
+                if !open {
+                    break;
+                }
+
+                let sample = Sample {
+                    data: sample.data,
+                    duration: sample.duration,
+                    ..Default::default()
+                };
+                if track.write_sample(&sample).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        Ok(sender)
+    }
+
+    /// Subscribes to the media tracks the peer adds. Tracks which arrived before anyone
+    /// subscribed go to the first subscriber
+    pub fn track_events(&self) -> UnboundedReceiver<RemoteTrack> {
+        self.incoming_tracks
+            .lock()
+            .expect("Unable to aquire incoming tracks lock")
+            .subscribe()
+    }
+
+    /// Waits until the established connection has changed in a way the peer has to agree to,
+    /// such as after `add_track`. It is renegotiated by handing a fresh `get_offer` to the peer's
+    /// `get_answer`, and its answer to `set_answer`, while the data channels stay open
+    pub async fn negotiation_needed(&self) {
+        loop {
+            let changed = self.negotiation_needed_changed.notified();
+            if self.is_negotiation_needed() {
+                return;
+            }
+            changed.await;
+        }
+    }
+
+    /// Whether the connection has changed since it was last negotiated, see `negotiation_needed`
+    pub fn is_negotiation_needed(&self) -> bool {
+        self.negotiation_needed.load(Ordering::Relaxed)
+    }
+
+    /// Restarts ICE, such as after a network change, returning an offer with fresh ICE
+    /// credentials for the peer to answer with `get_answer`. The data channels stay open while
+    /// new candidates are gathered and exchanged, and `get_pending_candidates` only returns the
+    /// new ones
+    pub async fn restart_ice(&self) -> AResult<RTCSessionDescription> {
+        self.get_offer_with(RTCOfferOptions {
+            ice_restart: true,
+            ..Default::default()
+        })
+        .await
+    }
+
+    pub async fn set_candidates(
         &self,
         candidates: impl Iterator<Item = RTCIceCandidateInit>,
     ) -> AResult<()> {
@@ -169,142 +729,2525 @@ impl<'a> P2PConnection<'a> {
         Ok(())
     }
 
-    /// Gets all of the not-yet-gotten ICE Candidates from the queue, for use with sending through
-    /// the signaling server
-    pub(crate) fn get_pending_candidates(&self) -> AResult<Vec<RTCIceCandidate>> {
+    /// Takes the ICE candidates which haven't been handed out yet, ready to be sent to the peer
+    /// and added with `set_candidates`. Each candidate is only returned once
+    pub fn get_pending_candidates(&self) -> AResult<Vec<RTCIceCandidateInit>> {
+        let pending = self
+            .ice_candidates
+            .write()
+            .map_err(|_| anyhow!("Unable to aquire write lock guard"))?
+            .take_pending();
+        Ok(pending
+            .iter()
+            .map(RTCIceCandidate::to_json)
+            .collect::<Result<_, _>>()?)
+    }
+
+    /// Every ICE candidate gathered since the connection was created or ICE was last restarted,
+    /// whether or not it has been handed out
+    pub(crate) fn gathered_candidates(&self) -> AResult<Vec<RTCIceCandidate>> {
         Ok(self
             .ice_candidates
             .read()
             .map_err(|_| anyhow!("Unable to aquire read lock guard"))?
+            .candidates
             .clone())
     }
 
-    pub(crate) fn get_is_connected_to_peer(&self) -> bool {
-        self.connected.load(std::sync::atomic::Ordering::Relaxed)
+    /// Trickles the local ICE candidates as they are gathered, so each one can be sent to the
+    /// peer exactly once. The receiver first gets the candidates no earlier receiver has been
+    /// handed, then every new one
+    pub fn candidate_events(&self) -> AResult<UnboundedReceiver<RTCIceCandidateInit>> {
+        let mut gathered = self
+            .ice_candidates
+            .write()
+            .map_err(|_| anyhow!("Unable to aquire write lock guard"))?;
+
+        let (sx, rx) = unbounded_channel();
+        for candidate in &gathered.candidates[gathered.consumed..] {
+            if let Ok(init) = candidate.to_json() {
+                let _ = sx.send(init);
+            }
+        }
+        gathered.consumed = gathered.candidates.len();
+        gathered.listeners.push(sx);
+
+        Ok(rx)
     }
-}
 
-impl<'a> Drop for P2PConnection<'a> {
-    fn drop(&mut self) {
-        futures::executor::block_on(async move {
-            let _ = self.data_channel.close().await;
-            println!("Data Channel has been closed");
-            let _ = self.connection.close().await;
-            println!("Connection has been closed");
-        });
+    pub fn get_is_connected_to_peer(&self) -> bool {
+        self.state() == ConnectionState::Connected
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use std::time::Duration;
+    /// Waits for the connection to be established. If it isn't within the client's connect
+    /// timeout, such as when ICE fails without ever reporting it, the connection is closed and
+    /// this resolves to `ConnectionError::Timeout`
+    pub async fn wait_connected(&self) -> AResult<()> {
+        let mut states = self.state_changes();
+        let connected = tokio::time::timeout(
+            self.connect_timeout,
+            states.wait_for(|state| *state == ConnectionState::Connected),
+        )
+        .await
+        .is_ok_and(|connected| connected.is_ok());
 
-    use super::*;
-    use tokio::time::{sleep, Instant};
-    use webrtc::peer_connection::sdp::sdp_type::RTCSdpType;
+        if connected {
+            return Ok(());
+        }
 
-    const STUN_SERVERS: [&str; 1] = ["stun:stun.l.google.com:19302"];
+        let _ = self.close().await;
+        Err(ConnectionError::Timeout.into())
+    }
 
-    async fn wait_for_condition<'a>(
-        condition: Box<dyn Fn() -> AResult<bool> + 'a>,
-        timeout: Duration,
-    ) -> AResult<()> {
-        let now = Instant::now();
+    /// Where ICE is in finding a working pair of candidates
+    pub fn ice_state(&self) -> IceConnectionState {
+        *self.ice_state.borrow()
+    }
 
-        while now.elapsed() < timeout {
-            if condition()? {
-                return Ok(());
-            }
-            sleep(Duration::from_millis(10)).await;
+    /// Watches ICE finding a working pair of candidates. The receiver sees every change from
+    /// here on, though changes made in quick succession may be seen as just the last of them
+    pub fn ice_state_changes(&self) -> watch::Receiver<IceConnectionState> {
+        self.ice_state.subscribe()
+    }
+
+    /// How far the connection is in gathering its local ICE candidates
+    pub fn gathering_state(&self) -> GatheringState {
+        *self.gathering.borrow()
+    }
+
+    /// Watches the gathering of local ICE candidates. The receiver sees every change from here
+    /// on, including gathering starting over when ICE restarts
+    pub fn gathering_changes(&self) -> watch::Receiver<GatheringState> {
+        self.gathering.subscribe()
+    }
+
+    /// Waits for every local ICE candidate to be gathered, then returns the local description
+    /// with all of them in it. Call it after `get_offer` or `get_answer` to send the peer a
+    /// single complete description instead of trickling candidates, for signaling backends
+    /// which only pass session descriptions along. Fails with `ConnectionError::Timeout` if
+    /// gathering doesn't complete within the client's connect timeout
+    pub async fn wait_for_gathering_complete(&self) -> AResult<RTCSessionDescription> {
+        let mut gathering = self.gathering_changes();
+        let complete = tokio::time::timeout(
+            self.connect_timeout,
+            gathering.wait_for(|state| *state == GatheringState::Complete),
+        )
+        .await
+        .is_ok_and(|complete| complete.is_ok());
+        if !complete {
+            return Err(ConnectionError::Timeout.into());
         }
-        return Err(anyhow!("Unable to validate condition"));
+
+        self.connection
+            .local_description()
+            .await
+            .ok_or(anyhow!("Unable to get local description"))
     }
 
-    #[tokio::test]
-    async fn test_new_p2p_connection() -> AResult<()> {
-        let client = P2PClient::new(STUN_SERVERS);
-        let _ = P2PConnection::new(&client, true).await?;
-        Ok(())
+    /// The current state of the connection
+    pub fn state(&self) -> ConnectionState {
+        *self.state.borrow()
     }
 
-    #[tokio::test]
-    async fn test_get_local_description() -> AResult<()> {
-        let client = P2PClient::new(STUN_SERVERS);
-        let connection = P2PConnection::new(&client, true).await?;
+    /// Watches the state of the connection. The receiver sees every change from here on, and
+    /// can `wait_for` a given state instead of polling `get_is_connected_to_peer`
+    pub fn state_changes(&self) -> watch::Receiver<ConnectionState> {
+        self.state.subscribe()
+    }
 
-        let offer = connection.get_offer().await?;
-        assert_eq!(offer.sdp_type, RTCSdpType::Offer);
-        Ok(())
+    /// The default channel every connection is created with, which the methods below send and
+    /// receive over
+    pub fn channel(&self) -> &Channel {
+        &self.channel
     }
 
-    #[tokio::test]
-    async fn test_facilitate_p2p_connection() -> AResult<()> {
-        let client1 = P2PClient::new(STUN_SERVERS);
-        let client2 = P2PClient::new(STUN_SERVERS);
+    /// Opens a new stream over the default channel, see `Channel::open_stream`
+    pub async fn open_stream(&self) -> AResult<MuxStream> {
+        self.channel.open_stream().await
+    }
 
-        let connection1 = Arc::new(P2PConnection::new(&client1, true).await?);
-        let connection2 = Arc::new(P2PConnection::new(&client2, true).await?);
+    /// Waits for the next stream the peer opens over the default channel
+    pub async fn accept_stream(&self) -> Option<MuxStream> {
+        self.channel.accept_stream().await
+    }
 
-        let offer = connection1.get_offer().await?;
-        assert_eq!(offer.sdp_type, RTCSdpType::Offer);
+    /// The ordered, reliable channel of a connection created with paired channels, which is its
+    /// default channel. `None` on other connections
+    pub fn reliable(&self) -> Option<&Channel> {
+        self.unreliable.as_ref().map(|_| &self.channel)
+    }
 
-        let answer = connection2.get_answer(offer).await?;
-        assert_eq!(answer.sdp_type, RTCSdpType::Answer);
+    /// The unordered channel of a connection created with paired channels, which drops a
+    /// message rather than resend it. `None` on other connections
+    pub fn unreliable(&self) -> Option<&Channel> {
+        self.unreliable.as_ref()
+    }
 
-        connection1.set_answer(answer).await?;
+    /// Stops taking in messages over the default channel, and the unreliable one of paired
+    /// channels, until `resume`. See `Channel::pause`
+    pub fn pause(&self) {
+        self.channel.pause();
+        if let Some(unreliable) = &self.unreliable {
+            unreliable.pause();
+        }
+    }
 
-        {
-            let con_clone = connection1.clone();
-            wait_for_condition(
-                Box::new(move || Ok(con_clone.get_pending_candidates()?.len() > 0)),
-                Duration::from_secs(10),
-            )
-            .await?;
+    /// Takes in messages again after `pause`
+    pub fn resume(&self) {
+        self.channel.resume();
+        if let Some(unreliable) = &self.unreliable {
+            unreliable.resume();
         }
-        {
-            let con_clone = connection2.clone();
-            wait_for_condition(
-                Box::new(move || Ok(con_clone.get_pending_candidates()?.len() > 0)),
-                Duration::from_secs(10),
-            )
-            .await?;
+    }
+
+    /// Sends `data` to the peer over the reliable or unreliable one of paired channels, so the
+    /// application doesn't have to pick the channel itself. Without paired channels, both go
+    /// over the default channel. Unreliable sends aren't held while reconnecting, and fail with
+    /// `ConnectionError::Reconnecting` instead
+    pub async fn send_as(&self, data: &[u8], mode: DeliveryMode) -> AResult<()> {
+        match (mode, &self.unreliable) {
+            (DeliveryMode::Unreliable, Some(unreliable)) => {
+                self.flush_unless_reconnecting().await?;
+                unreliable.send(data).await
+            }
+            _ => self.send(data).await,
         }
+    }
 
-        let con1_candidates = connection1.get_pending_candidates()?;
-        let con2_candidates = connection2.get_pending_candidates()?;
+    /// Waits for the next message over either of paired channels, along with which one it came
+    /// over. Without paired channels, every message comes over the default channel as
+    /// `DeliveryMode::Reliable`. Returns `None` once both channels are gone
+    pub async fn recv_any(&self) -> Option<(DeliveryMode, Message)> {
+        let Some(unreliable) = &self.unreliable else {
+            return self
+                .channel
+                .recv()
+                .await
+                .map(|message| (DeliveryMode::Reliable, message));
+        };
+        tokio::select! {
+            Some(message) = self.channel.recv() => Some((DeliveryMode::Reliable, message)),
+            Some(message) = unreliable.recv() => Some((DeliveryMode::Unreliable, message)),
+            else => None,
+        }
+    }
 
-        connection1
-            .set_candidates(con2_candidates.iter().map(|can| {
-                can.to_json()
-                    .expect("Unable to convert RTCIceCandidate to RTCIceCandidateInit")
-            }))
-            .await?;
+    /// Turns the connection into a byte stream over its default channel, which implements
+    /// tokio's `AsyncRead` and `AsyncWrite`. See `P2PStream`
+    pub fn into_stream(self: Arc<Self>) -> P2PStream {
+        P2PStream::new(self)
+    }
 
-        connection2
-            .set_candidates(con1_candidates.iter().map(|can| {
-                can.to_json()
-                    .expect("Unable to convert RTCIceCandidate to RTCIceCandidateInit")
-            }))
+    /// The number of bytes queued on the default channel which haven't been sent yet, along with
+    /// those queued on the unreliable one of paired channels
+    pub async fn queued_bytes(&self) -> usize {
+        let queued = self.channel.buffered_amount().await;
+        match &self.unreliable {
+            Some(unreliable) => queued + unreliable.buffered_amount().await,
+            None => queued,
+        }
+    }
+
+    /// The largest message the peer takes in one piece, as negotiated in the session
+    /// descriptions. Every channel splits its messages into frames which fit it, so this only
+    /// matters to sizing frames of your own. Assumed to be 64 KiB until the peer's session
+    /// description has been applied
+    pub fn max_message_size(&self) -> usize {
+        self.channel_settings
+            .max_message_size
+            .load(Ordering::Relaxed)
+    }
+
+    /// Subscribes to the events of the default channel, such as it going over its send
+    /// high-water mark. See `Channel::events`
+    pub fn channel_events(&self) -> broadcast::Receiver<ChannelEvent> {
+        self.channel.events()
+    }
+
+    /// Opens a new channel labeled `label` alongside the default one. The peer picks it up with
+    /// `on_channel`, unless it doesn't take the channel's protocol or already holds 64 channels
+    /// it hasn't picked up, in which case the channel is closed
+    pub async fn open_channel(&self, label: &str, options: ChannelOptions) -> AResult<Channel> {
+        let data_channel = self
+            .connection
+            .create_data_channel(label, Some(options.into()))
             .await?;
+        let channel = Channel::new(data_channel, self.channel_settings.clone()).await;
+        self.hand_out(&channel);
+        Ok(channel)
+    }
+
+    /// Waits for the peer to open a channel labeled `label` with `open_channel`
+    pub async fn on_channel(&self, label: &str) -> Channel {
+        loop {
+            let opened = self.incoming_channel_opened.notified();
+            if let Some(channel) = self
+                .incoming_channels
+                .lock()
+                .expect("Unable to aquire incoming channels lock")
+                .remove(label)
+            {
+                self.hand_out(&channel);
+                return channel;
+            }
+            opened.await;
+        }
+    }
+
+    fn hand_out(&self, channel: &Channel) {
+        let mut channels = self
+            .handed_out_channels
+            .lock()
+            .expect("Unable to aquire handed out channels lock");
+        // Channels which are gone don't need closing
+        channels.retain(|data_channel| data_channel.strong_count() > 0);
+        channels.push(Arc::downgrade(channel.data_channel()));
+    }
+
+    /// The limit on how fast this connection sends, if there is one
+    pub fn send_rate_limit(&self) -> Option<RateLimit> {
+        self.channel_settings.rate_limiter.limit()
+    }
+
+    /// Caps how fast this connection sends across all of its channels, replacing the limit it
+    /// got from `P2PClient::with_send_rate_limit`. `None` lifts the limit
+    pub fn set_send_rate_limit(&self, limit: Option<RateLimit>) {
+        self.channel_settings.rate_limiter.set_limit(limit);
+    }
+
+    /// Injects `faults` into every message sent or received over the connection's channels,
+    /// replacing the ones injected before. Only available with the `fault-injection` feature
+    #[cfg(any(test, feature = "fault-injection"))]
+    pub fn inject_faults(&self, faults: Faults) {
+        self.channel_settings.faults.set(faults);
+    }
+
+    /// Cuts the connection off from the peer until `end_disconnect`, dropping every message
+    /// either way as if the network went down, so keepalives time out on both ends. Only
+    /// available with the `fault-injection` feature
+    #[cfg(any(test, feature = "fault-injection"))]
+    pub fn force_disconnect(&self) {
+        self.channel_settings.faults.set_disconnected(true);
+    }
+
+    /// Lets messages through again after `force_disconnect`
+    #[cfg(any(test, feature = "fault-injection"))]
+    pub fn end_disconnect(&self) {
+        self.channel_settings.faults.set_disconnected(false);
+    }
+
+    /// The public key of the peer's `Identity`, once it has arrived over the default channel.
+    /// Compare it to the identity you expect the peer to have before trusting its messages
+    pub fn peer_identity(&self) -> Option<[u8; 32]> {
+        self.channel.peer_identity()
+    }
 
+    /// Sends `data` to the peer over the default channel.
+    /// Fails with `ConnectionError::ChannelNotOpen` if the channel hasn't opened yet, or has
+    /// been closed. While the connection is reconnecting, the message is held until it is back
+    /// if the client was created with `P2PClient::with_reconnect`
+    pub async fn send(&self, data: &[u8]) -> AResult<()> {
+        if self
+            .hold(|| Message::Binary(Bytes::copy_from_slice(data)), None)
+            .await?
         {
-            let (con1, con2) = (connection1.clone(), connection2.clone());
+            return Ok(());
+        }
+        self.channel.send(data).await
+    }
 
-            wait_for_condition(
-                Box::new(move || Ok(con1.get_is_connected_to_peer())),
-                Duration::from_secs(10),
-            )
-            .await?;
-            wait_for_condition(
-                Box::new(move || Ok(con2.get_is_connected_to_peer())),
-                Duration::from_secs(10),
-            )
-            .await?;
+    /// Sends `data` to the peer over the default channel, returning a `Delivery` which resolves
+    /// once the peer has received it. See `Channel::send_tracked`. It isn't held while
+    /// reconnecting, and fails with `ConnectionError::Reconnecting` instead
+    pub async fn send_tracked(&self, data: &[u8]) -> AResult<Delivery> {
+        self.flush_unless_reconnecting().await?;
+        self.channel.send_tracked(data).await
+    }
+
+    /// Sends `data` to the peer like `send`, but first waits for the default channel's queue to
+    /// drain whenever more than the client's send high-water mark is waiting to go out
+    pub async fn send_with_backpressure(&self, data: &[u8]) -> AResult<()> {
+        if self
+            .hold(|| Message::Binary(Bytes::copy_from_slice(data)), None)
+            .await?
+        {
+            return Ok(());
         }
+        self.channel.send_with_backpressure(data).await
+    }
 
-        assert!(connection1.get_is_connected_to_peer());
-        assert!(connection2.get_is_connected_to_peer());
+    /// Sends `data` to the peer over the default channel, giving up if it hasn't gone out within
+    /// `timeout`. See `Channel::send_with_timeout`
+    pub async fn send_with_timeout(&self, data: &[u8], timeout: Duration) -> AResult<()> {
+        if self
+            .hold(|| Message::Binary(Bytes::copy_from_slice(data)), None)
+            .await?
+        {
+            return Ok(());
+        }
+        self.channel.send_with_timeout(data, timeout).await
+    }
 
-        Ok(())
+    /// Sends `data` to the peer over the default channel, giving up once `cancel` is cancelled
+    /// if it hasn't gone out yet. See `Channel::send_cancellable`
+    pub async fn send_cancellable(&self, data: &[u8], cancel: &CancellationToken) -> AResult<()> {
+        if self
+            .hold(|| Message::Binary(Bytes::copy_from_slice(data)), None)
+            .await?
+        {
+            return Ok(());
+        }
+        self.channel.send_cancellable(data, cancel).await
+    }
+
+    /// Sends `data` to the peer over the default channel, dropping it if it goes stale within
+    /// `ttl` first. See `Channel::send_with_ttl`. While reconnecting it is held until then, and
+    /// silently dropped if the connection isn't back in time
+    pub async fn send_with_ttl(&self, data: &[u8], ttl: Duration) -> AResult<()> {
+        let expires_at = Instant::now() + ttl;
+        if self
+            .hold(
+                || Message::Binary(Bytes::copy_from_slice(data)),
+                Some(expires_at),
+            )
+            .await?
+        {
+            return Ok(());
+        }
+        self.channel
+            .send_with_ttl(data, expires_at.saturating_duration_since(Instant::now()))
+            .await
+    }
+
+    /// Sends `text` to the peer over the default channel as a text message.
+    /// Fails with `ConnectionError::ChannelNotOpen` if the channel hasn't opened yet, or has
+    /// been closed. Held while reconnecting like `send`
+    pub async fn send_text(&self, text: &str) -> AResult<()> {
+        if self.hold(|| Message::Text(text.to_owned()), None).await? {
+            return Ok(());
+        }
+        self.channel.send_text(text).await
+    }
+
+    /// Waits for the next message on the default channel. Returns `None` once the channel is gone,
+    /// or the connection has failed or closed. See `Channel::recv`
+    pub async fn recv(&self) -> Option<Message> {
+        self.channel.recv().await
+    }
+
+    /// Waits for messages on the default channel, moving up to `limit` of them into `buffer`.
+    /// See `Channel::recv_many`
+    pub async fn recv_many(&self, buffer: &mut Vec<Message>, limit: usize) -> usize {
+        self.channel.recv_many(buffer, limit).await
+    }
+
+    /// Gets the next message on the default channel, if one has already arrived
+    pub fn try_recv(&self) -> Option<Message> {
+        self.channel.try_recv()
+    }
+
+    /// Encodes `message` with the default `Bincode` codec and sends it to the peer
+    pub async fn send_msg<T: Serialize>(&self, message: &T) -> AResult<()> {
+        self.send_msg_with(&Bincode, message).await
+    }
+
+    /// Encodes `message` with `codec` and sends it to the peer
+    pub async fn send_msg_with<C: Codec, T: Serialize>(
+        &self,
+        codec: &C,
+        message: &T,
+    ) -> AResult<()> {
+        self.send(&codec.encode(message)?).await
+    }
+
+    /// Waits for the next message on the default channel and decodes it with the default
+    /// `Bincode` codec. Returns `None` once the channel is gone
+    pub async fn recv_msg<T: DeserializeOwned>(&self) -> AResult<Option<T>> {
+        self.channel.recv_msg().await
+    }
+
+    /// Waits for the next message on the default channel and decodes it with `codec`.
+    /// Returns `None` once the channel is gone
+    pub async fn recv_msg_with<C: Codec, T: DeserializeOwned>(
+        &self,
+        codec: &C,
+    ) -> AResult<Option<T>> {
+        self.channel.recv_msg_with(codec).await
+    }
+
+    /// Measures the round trip time to the peer over the default channel, see `Channel::ping`
+    pub async fn ping(&self) -> AResult<Duration> {
+        self.channel.ping().await
+    }
+
+    /// The rolling average of the round trip times measured by `ping`
+    pub fn latency(&self) -> Option<Duration> {
+        self.channel.latency()
+    }
+
+    /// Calls `method` on the peer over the default channel, see `Channel::call`
+    pub async fn call(&self, method: &str, payload: &[u8]) -> AResult<Bytes> {
+        self.channel.call(method, payload).await
+    }
+
+    /// Calls `method` on the peer over the default channel, failing with `RpcError::Timeout` if
+    /// no response arrives within `timeout`
+    pub async fn call_with_timeout(
+        &self,
+        method: &str,
+        payload: &[u8],
+        timeout: Duration,
+    ) -> AResult<Bytes> {
+        self.channel
+            .call_with_timeout(method, payload, timeout)
+            .await
+    }
+
+    /// Answers the peer's calls to `method` over the default channel with `handler`, see
+    /// `Channel::register_handler`
+    pub fn register_handler<F, Fut>(&self, method: &str, handler: F)
+    where
+        F: Fn(Bytes) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = AResult<Bytes>> + Send + 'static,
+    {
+        self.channel.register_handler(method, handler)
+    }
+
+    /// Gathers the current statistics of the connection, such as its round trip time and the
+    /// candidate pair it is sending over
+    pub async fn get_stats(&self) -> ConnectionStats {
+        ConnectionStats::from(&self.connection.get_stats().await)
+    }
+
+    /// Gathers the current statistics of the connection like `get_stats`, marked with the peer
+    /// and the time they are for, such as to send periodic telemetry to a backend
+    pub async fn snapshot_stats(&self) -> StatsSnapshot {
+        StatsSnapshot {
+            peer_id: self.remote_peer_id().map(str::to_owned),
+            taken_at: SystemTime::now(),
+            stats: self.get_stats().await,
+        }
+    }
+
+    /// The candidate pair ICE selected to send over, showing whether traffic goes directly to
+    /// the peer, through a NAT mapping, or through a TURN relay. `None` until one is selected
+    pub async fn selected_candidate_pair(&self) -> Option<CandidatePair> {
+        CandidatePair::from_report(&self.connection.get_stats().await)
+    }
+
+    /// Gets the local session description, if an offer or answer has been created
+    pub(crate) async fn local_description(&self) -> Option<RTCSessionDescription> {
+        self.connection.local_description().await
+    }
+
+    /// Gets the remote session description, if an offer or answer has been applied
+    pub(crate) async fn remote_description(&self) -> Option<RTCSessionDescription> {
+        self.connection.remote_description().await
+    }
+
+    fn clear_candidates(&self) -> AResult<()> {
+        self.ice_candidates
+            .write()
+            .map_err(|_| anyhow!("Unable to aquire write lock guard"))?
+            .clear();
+        // A restarted ICE agent gathers anew without its gatherer reporting it
+        self.gathering.send_replace(GatheringState::Gathering);
+        Ok(())
+    }
+
+    /// Closes every data channel and the underlying peer connection, so the peer sees it close
+    /// right away instead of waiting for ICE to time out. Besides the default channel, this
+    /// covers the paired unreliable one, those from `open_channel` and `on_channel`, and those
+    /// the peer opened which weren't asked for yet. Each of them stops yielding messages.
+    /// Closing an already closed connection does nothing. Dropping the connection closes it in
+    /// the background instead
+    pub async fn close(&self) -> AResult<()> {
+        if self.closed.swap(true, Ordering::Relaxed) {
+            return Ok(());
+        }
+        // Ends the receive buffer of every channel made with the connection's settings
+        self.channel_settings.ended.cancel();
+
+        let mut data_channels = vec![self.channel.data_channel().clone()];
+        data_channels.extend(
+            self.unreliable
+                .iter()
+                .map(|unreliable| unreliable.data_channel().clone()),
+        );
+        data_channels.extend(
+            self.handed_out_channels
+                .lock()
+                .expect("Unable to aquire handed out channels lock")
+                .drain(..)
+                .filter_map(|data_channel| data_channel.upgrade()),
+        );
+        data_channels.extend(
+            self.incoming_channels
+                .lock()
+                .expect("Unable to aquire incoming channels lock")
+                .drain()
+                .map(|(_, channel)| channel.data_channel().clone()),
+        );
+        for data_channel in data_channels {
+            data_channel.close().await?;
+        }
+        self.connection.close().await?;
+        Ok(())
+    }
+
+    /// Whether the connection dropped after its default channel opened, and is waiting to be
+    /// re-established, such as by an ICE restart. Sends are only held while it is
+    pub fn is_reconnecting(&self) -> bool {
+        self.outbox.is_some()
+            && !matches!(
+                self.state(),
+                ConnectionState::Connected | ConnectionState::Closed
+            )
+            && self.channel.is_open()
+    }
+
+    /// Sends the messages held while reconnecting, in the order they were sent
+    pub(crate) async fn flush_outbox(&self) -> AResult<()> {
+        let Some(outbox) = &self.outbox else {
+            return Ok(());
+        };
+        Self::flush(&mut *outbox.lock().await, &self.channel).await
+    }
+
+    /// Holds the message made by `message` while reconnecting, until `expires_at` if given,
+    /// returning whether it was held. Otherwise, the messages held before it are sent first, so
+    /// the peer sees them in order
+    async fn hold(
+        &self,
+        message: impl FnOnce() -> Message,
+        expires_at: Option<Instant>,
+    ) -> AResult<bool> {
+        let Some(outbox) = &self.outbox else {
+            return Ok(false);
+        };
+        let mut outbox = outbox.lock().await;
+        if self.is_reconnecting() {
+            outbox.hold(message(), expires_at)?;
+            return Ok(true);
+        }
+        Self::flush(&mut outbox, &self.channel).await?;
+        Ok(false)
+    }
+
+    /// Fails with `ConnectionError::Reconnecting` for sends which can't be held while
+    /// reconnecting. Otherwise, the messages held before are sent first, like `hold`
+    async fn flush_unless_reconnecting(&self) -> AResult<()> {
+        let Some(outbox) = &self.outbox else {
+            return Ok(());
+        };
+        let mut outbox = outbox.lock().await;
+        if self.is_reconnecting() {
+            return Err(ConnectionError::Reconnecting.into());
+        }
+        Self::flush(&mut outbox, &self.channel).await
+    }
+
+    async fn flush(outbox: &mut Outbox, channel: &Channel) -> AResult<()> {
+        while let Some(message) = outbox.front() {
+            match message {
+                Message::Binary(data) => channel.send(data).await?,
+                Message::Text(text) => channel.send_text(text).await?,
+            }
+            outbox.pop_front();
+        }
+        Ok(())
+    }
+
+    /// Applies a session description from the peer, once its fingerprint checks out
+    async fn set_remote_description(&self, description: RTCSessionDescription) -> AResult<()> {
+        self.verify_fingerprint(&description).await?;
+        let max_message_size = negotiated_max_message_size(&description);
+        self.connection.set_remote_description(description).await?;
+        self.channel_settings
+            .max_message_size
+            .store(max_message_size, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Checks the fingerprint in a session description from the peer against the pinned one,
+    /// closing the connection if they differ
+    async fn verify_fingerprint(&self, description: &RTCSessionDescription) -> AResult<()> {
+        let expected = self
+            .expected_fingerprint
+            .lock()
+            .expect("Unable to aquire expected fingerprint lock")
+            .clone();
+        let Some(expected) = expected else {
+            return Ok(());
+        };
+
+        let actual = remote_fingerprint(description);
+        if actual.is_some_and(|actual| actual.eq_ignore_ascii_case(&expected)) {
+            return Ok(());
+        }
+        let err = ConnectionError::FingerprintMismatch {
+            actual: actual.map(str::to_owned),
+            expected,
+        };
+        let _ = self.close().await;
+        Err(err.into())
+    }
+}
+
+/// The hash function fingerprints are compared with, as named in session descriptions
+const FINGERPRINT_ALGORITHM: &str = "sha-256";
+
+/// How many channels the peer opened are held until `on_channel` picks them up. Any more are
+/// closed, so a peer can't make the connection hold on to an unbounded number of them
+pub(crate) const MAX_HELD_CHANNELS: usize = 64;
+
+/// The SHA-256 fingerprint of the DTLS certificate given in `description`
+fn remote_fingerprint(description: &RTCSessionDescription) -> Option<&str> {
+    description.sdp.lines().find_map(|line| {
+        let (algorithm, value) = line.strip_prefix("a=fingerprint:")?.split_once(' ')?;
+        algorithm
+            .eq_ignore_ascii_case(FINGERPRINT_ALGORITHM)
+            .then_some(value.trim())
+    })
+}
+
+/// The largest message which can be sent to a peer which gave `description`: the smaller of
+/// what it advertises and what webrtc-rs takes. Peers which don't advertise a size are assumed
+/// to take 64 KiB, and 0 means they take messages of any size (RFC 8841)
+fn negotiated_max_message_size(description: &RTCSessionDescription) -> usize {
+    let advertised = description
+        .sdp
+        .lines()
+        .find_map(|line| {
+            line.strip_prefix("a=max-message-size:")?
+                .trim()
+                .parse()
+                .ok()
+        })
+        .unwrap_or(DEFAULT_MAX_MESSAGE_SIZE);
+    match advertised {
+        0 => DEFAULT_MAX_MESSAGE_SIZE,
+        advertised => advertised.min(DEFAULT_MAX_MESSAGE_SIZE),
+    }
+}
+
+/// The ICE username fragment of `description`, which changes whenever ICE is restarted
+fn ice_ufrag(description: &RTCSessionDescription) -> Option<&str> {
+    description
+        .sdp
+        .lines()
+        .find_map(|line| line.strip_prefix("a=ice-ufrag:"))
+}
+
+/// Whether ICE is connected over a DTLS transport which is still up
+fn is_reconnected(dtls_transport: &RTCDtlsTransport) -> bool {
+    dtls_transport.state() == RTCDtlsTransportState::Connected
+        && matches!(
+            dtls_transport.ice_transport().state(),
+            RTCIceTransportState::Connected | RTCIceTransportState::Completed
+        )
+}
+
+impl Stream for P2PConnection {
+    type Item = Message;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.get_mut().channel).poll_next(cx)
+    }
+}
+
+impl Drop for P2PConnection {
+    fn drop(&mut self) {
+        if self.closed.load(Ordering::Relaxed) {
+            return;
+        }
+
+        let name = format!("{self:?}");
+        let data_channel = self.channel.data_channel().clone();
+        let connection = self.connection.clone();
+        let cleanup = async move {
+            let _ = data_channel.close().await;
+            tracing::debug!(connection = %name, "Data channel closed on drop");
+            let _ = connection.close().await;
+            tracing::debug!(connection = %name, "Connection closed on drop");
+        };
+
+        // Blocking in Drop stalls the runtime worker it runs on, and webrtc can't close without a
+        // runtime at all, so the cleanup is only ever spawned. Without a runtime the peer notices
+        // once ICE times out
+        if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+            runtime.spawn(cleanup);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::channel::OverflowPolicy;
+    use crate::codec::Bincode;
+    use crate::encryption::{Encryption, Identity};
+    use crate::error::{RpcError, SendError};
+    use crate::framing;
+    use crate::media::MediaKind;
+    use crate::stats::{PathKind, TransportProtocol};
+    use bytes::Bytes;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::time::{sleep, Instant};
+    use webrtc::api::media_engine::{MIME_TYPE_OPUS, MIME_TYPE_VP8};
+    use webrtc::data_channel::data_channel_state::RTCDataChannelState;
+    use webrtc::peer_connection::sdp::sdp_type::RTCSdpType;
+    use webrtc::track::track_local::track_local_static_sample::TrackLocalStaticSample;
+
+    const STUN_SERVERS: [&str; 1] = ["stun:stun.l.google.com:19302"];
+
+    async fn wait_for_condition<'a>(
+        condition: Box<dyn Fn() -> AResult<bool> + 'a>,
+        timeout: Duration,
+    ) -> AResult<()> {
+        let now = Instant::now();
+
+        while now.elapsed() < timeout {
+            if condition()? {
+                return Ok(());
+            }
+            sleep(Duration::from_millis(10)).await;
+        }
+        Err(anyhow!("Unable to validate condition"))
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_new_p2p_connection() -> AResult<()> {
+        let client = P2PClient::new(STUN_SERVERS);
+        let _ = P2PConnection::new(&client, true).await?;
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_get_local_description() -> AResult<()> {
+        let client = P2PClient::new(STUN_SERVERS);
+        let connection = P2PConnection::new(&client, true).await?;
+
+        let offer = connection.get_offer().await?;
+        assert_eq!(offer.sdp_type, RTCSdpType::Offer);
+        Ok(())
+    }
+
+    /// Takes the pending candidates of both connections until each has gathered at least one
+    async fn pending_candidates(
+        connection1: &P2PConnection,
+        connection2: &P2PConnection,
+    ) -> AResult<(Vec<RTCIceCandidateInit>, Vec<RTCIceCandidateInit>)> {
+        let mut candidates = (Vec::new(), Vec::new());
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while candidates.0.is_empty() || candidates.1.is_empty() {
+                candidates.0.extend(connection1.get_pending_candidates()?);
+                candidates.1.extend(connection2.get_pending_candidates()?);
+                sleep(Duration::from_millis(10)).await;
+            }
+            Ok::<_, anyhow::Error>(())
+        })
+        .await??;
+        Ok(candidates)
+    }
+
+    /// Connects two fresh connections of the given clients by handing their offer, answer and
+    /// candidates to each other directly
+    async fn connected_pair(
+        client1: &P2PClient,
+        client2: &P2PClient,
+    ) -> AResult<(Arc<P2PConnection>, Arc<P2PConnection>)> {
+        let connection1 = Arc::new(P2PConnection::new(client1, true).await?);
+        let connection2 = Arc::new(P2PConnection::new(client2, true).await?);
+        connect(connection1, connection2).await
+    }
+
+    /// Connects a pair like `connected_pair`, then waits for the channels of both to open
+    async fn open_pair(
+        client1: &P2PClient,
+        client2: &P2PClient,
+    ) -> AResult<(Arc<P2PConnection>, Arc<P2PConnection>)> {
+        let (connection1, connection2) = connected_pair(client1, client2).await?;
+        for connection in [&connection1, &connection2] {
+            tokio::time::timeout(Duration::from_secs(10), connection.channel.wait_open()).await??;
+        }
+        Ok((connection1, connection2))
+    }
+
+    /// Connects two connections which haven't been negotiated yet
+    async fn connect(
+        connection1: Arc<P2PConnection>,
+        connection2: Arc<P2PConnection>,
+    ) -> AResult<(Arc<P2PConnection>, Arc<P2PConnection>)> {
+        let offer = connection1.get_offer().await?;
+        assert_eq!(offer.sdp_type, RTCSdpType::Offer);
+
+        let answer = connection2.get_answer(offer).await?;
+        assert_eq!(answer.sdp_type, RTCSdpType::Answer);
+
+        connection1.set_answer(answer).await?;
+
+        let (con1_candidates, con2_candidates) =
+            pending_candidates(&connection1, &connection2).await?;
+
+        connection1
+            .set_candidates(con2_candidates.into_iter())
+            .await?;
+
+        connection2
+            .set_candidates(con1_candidates.into_iter())
+            .await?;
+
+        connection1.wait_connected().await?;
+        connection2.wait_connected().await?;
+
+        Ok((connection1, connection2))
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_facilitate_p2p_connection() -> AResult<()> {
+        let client1 = P2PClient::new(STUN_SERVERS);
+        let client2 = P2PClient::new(STUN_SERVERS);
+
+        let (connection1, connection2) = connected_pair(&client1, &client2).await?;
+
+        assert!(connection1.get_is_connected_to_peer());
+        assert!(connection2.get_is_connected_to_peer());
+        assert_eq!(connection1.state(), ConnectionState::Connected);
+
+        let mut states = connection2.state_changes();
+        assert_eq!(*states.borrow_and_update(), ConnectionState::Connected);
+        connection2.close().await?;
+        tokio::time::timeout(
+            Duration::from_secs(10),
+            states.wait_for(|state| *state == ConnectionState::Closed),
+        )
+        .await??;
+        assert!(!connection2.get_is_connected_to_peer());
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_wait_connected() -> AResult<()> {
+        let client1 = P2PClient::new(STUN_SERVERS);
+        let client2 = P2PClient::new(STUN_SERVERS);
+
+        let (connection1, _connection2) = connected_pair(&client1, &client2).await?;
+        connection1.wait_connected().await?;
+
+        let client = P2PClient::new(STUN_SERVERS).with_connect_timeout(Duration::from_millis(100));
+        let connection = P2PConnection::new(&client, true).await?;
+        connection.get_offer().await?;
+
+        let err = connection.wait_connected().await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<ConnectionError>(),
+            Some(ConnectionError::Timeout)
+        ));
+        assert!(connection.send(b"hello").await.is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_send_requires_open_channel() -> AResult<()> {
+        let client = P2PClient::new(STUN_SERVERS);
+        let connection = P2PConnection::new(&client, true).await?;
+
+        let err = connection.send(b"hello").await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<ConnectionError>(),
+            Some(ConnectionError::ChannelNotOpen(_))
+        ));
+        assert!(connection.send_text("hello").await.is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_send_over_open_channel() -> AResult<()> {
+        let client1 = P2PClient::new(STUN_SERVERS);
+        let client2 = P2PClient::new(STUN_SERVERS);
+
+        let (connection1, _connection2) = open_pair(&client1, &client2).await?;
+
+        connection1.send(b"hello").await?;
+        connection1.send_text("hello").await?;
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_recv_messages() -> AResult<()> {
+        let client1 = P2PClient::new(STUN_SERVERS);
+        let client2 = P2PClient::new(STUN_SERVERS);
+
+        let (connection1, connection2) = open_pair(&client1, &client2).await?;
+        assert_eq!(connection2.try_recv(), None);
+
+        connection1.send(b"hello").await?;
+        connection1.send_text("world").await?;
+        connection1.send(b"!").await?;
+
+        let received = tokio::time::timeout(Duration::from_secs(10), connection2.recv()).await?;
+        assert_eq!(
+            received,
+            Some(Message::Binary(Bytes::from_static(b"hello")))
+        );
+
+        let mut connection2 = Arc::try_unwrap(connection2).expect("Connection is still shared");
+        let received = tokio::time::timeout(Duration::from_secs(10), async {
+            futures::StreamExt::next(&mut connection2).await
+        })
+        .await?;
+        assert_eq!(received, Some(Message::Text("world".to_owned())));
+
+        let pending = tokio::time::timeout(Duration::from_secs(10), async {
+            loop {
+                if let Some(message) = connection2.try_recv() {
+                    break message;
+                }
+                sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await?;
+        assert_eq!(pending, Message::Binary(Bytes::from_static(b"!")));
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_typed_messages() -> AResult<()> {
+        #[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+        enum Command {
+            Move { x: i32, y: i32 },
+            Chat(String),
+        }
+
+        let client1 = P2PClient::new(STUN_SERVERS);
+        let client2 = P2PClient::new(STUN_SERVERS);
+
+        let (connection1, connection2) = open_pair(&client1, &client2).await?;
+
+        connection1.send_msg(&Command::Move { x: 3, y: -4 }).await?;
+        connection1
+            .send_msg_with(&Bincode, &Command::Chat("gg".to_owned()))
+            .await?;
+
+        let (first, second) = tokio::time::timeout(Duration::from_secs(10), async {
+            (
+                connection2.recv_msg::<Command>().await,
+                connection2.recv_msg_with::<_, Command>(&Bincode).await,
+            )
+        })
+        .await?;
+        assert_eq!(first?, Some(Command::Move { x: 3, y: -4 }));
+        assert_eq!(second?, Some(Command::Chat("gg".to_owned())));
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_large_messages_are_chunked() -> AResult<()> {
+        let client1 = P2PClient::new(STUN_SERVERS);
+        let client2 = P2PClient::new(STUN_SERVERS);
+
+        let (connection1, connection2) = open_pair(&client1, &client2).await?;
+
+        let payload = (0..200 * 1024).map(|i| i as u8).collect::<Vec<_>>();
+        let text = "ü".repeat(50 * 1024);
+        connection1.send(&payload).await?;
+        connection1.send_text(&text).await?;
+
+        let (binary, received_text) = tokio::time::timeout(Duration::from_secs(10), async {
+            (connection2.recv().await, connection2.recv().await)
+        })
+        .await?;
+        assert_eq!(binary, Some(Message::Binary(Bytes::from(payload))));
+        assert_eq!(received_text, Some(Message::Text(text)));
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_send_with_backpressure() -> AResult<()> {
+        const HIGH_WATER_MARK: usize = 32 * 1024;
+        let client1 = P2PClient::new(STUN_SERVERS).with_send_high_water_mark(HIGH_WATER_MARK);
+        let client2 = P2PClient::new(STUN_SERVERS);
+
+        let (connection1, connection2) = open_pair(&client1, &client2).await?;
+
+        let payload = vec![7u8; 16 * 1024];
+        for _ in 0..16 {
+            connection1.send_with_backpressure(&payload).await?;
+            assert!(
+                connection1.channel.buffered_amount().await
+                    <= HIGH_WATER_MARK + framing::MAX_CHUNK_SIZE + 64
+            );
+        }
+
+        let received = tokio::time::timeout(Duration::from_secs(20), async {
+            let mut received = 0;
+            while received < 16 {
+                assert_eq!(
+                    connection2.recv().await,
+                    Some(Message::Binary(Bytes::from(payload.clone())))
+                );
+                received += 1;
+            }
+            received
+        })
+        .await?;
+        assert_eq!(received, 16);
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_watermark_events() -> AResult<()> {
+        const HIGH_WATER_MARK: usize = 32 * 1024;
+        let client1 = P2PClient::new(STUN_SERVERS).with_send_high_water_mark(HIGH_WATER_MARK);
+        let client2 = P2PClient::new(STUN_SERVERS);
+
+        let (connection1, connection2) = open_pair(&client1, &client2).await?;
+        assert_eq!(
+            (
+                connection1.channel.high_water_mark(),
+                connection1.channel.low_water_mark()
+            ),
+            (HIGH_WATER_MARK, HIGH_WATER_MARK / 2)
+        );
+        assert_eq!(connection1.queued_bytes().await, 0);
+
+        let mut events = connection1.channel_events();
+        let payload = vec![7u8; 4 * HIGH_WATER_MARK];
+        connection1.send(&payload).await?;
+        assert!(connection1.queued_bytes().await <= payload.len() + 1024);
+
+        for expected in [ChannelEvent::HighWaterMark, ChannelEvent::LowWaterMark] {
+            let event = tokio::time::timeout(Duration::from_secs(10), events.recv()).await??;
+            assert_eq!(event, expected);
+        }
+        assert_eq!(
+            tokio::time::timeout(Duration::from_secs(10), connection2.recv()).await?,
+            Some(Message::Binary(Bytes::from(payload)))
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_compression() -> AResult<()> {
+        let client1 = P2PClient::new(STUN_SERVERS).with_compression(1024);
+        let client2 = P2PClient::new(STUN_SERVERS).with_compression(1024);
+        let client3 = P2PClient::new(STUN_SERVERS);
+
+        let (connection1, connection2) = open_pair(&client1, &client2).await?;
+
+        let recv = |connection: Arc<P2PConnection>| async move {
+            tokio::time::timeout(Duration::from_secs(10), connection.recv()).await
+        };
+
+        // The capabilities go out along with the first message
+        connection1.send(b"hello").await?;
+        assert_eq!(
+            recv(connection2.clone()).await?,
+            Some(Message::Binary(Bytes::from_static(b"hello")))
+        );
+        for connection in [connection1.clone(), connection2.clone()] {
+            wait_for_condition(
+                Box::new(move || Ok(connection.channel.is_compressing())),
+                Duration::from_secs(10),
+            )
+            .await?;
+        }
+
+        let state = r#"{"position":[1.0,2.0,3.0],"velocity":[0.0,0.0,0.0]}"#.repeat(1024);
+        connection1.send_text(&state).await?;
+        connection1.send(b"small").await?;
+        connection2.send(state.as_bytes()).await?;
+
+        assert_eq!(
+            recv(connection2.clone()).await?,
+            Some(Message::Text(state.clone()))
+        );
+        assert_eq!(
+            recv(connection2.clone()).await?,
+            Some(Message::Binary(Bytes::from_static(b"small")))
+        );
+        assert_eq!(
+            recv(connection1.clone()).await?,
+            Some(Message::Binary(Bytes::from(state.clone())))
+        );
+
+        // Neither side compresses unless both opted in
+        let (connection1, connection3) = open_pair(&client1, &client3).await?;
+        connection1.send_text(&state).await?;
+        assert_eq!(recv(connection3.clone()).await?, Some(Message::Text(state)));
+        assert!(!connection1.channel.is_compressing());
+        assert!(!connection3.channel.is_compressing());
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_encryption() -> AResult<()> {
+        let (identity1, identity2) = (Identity::generate(), Identity::generate());
+        let client1 = P2PClient::new(STUN_SERVERS)
+            .with_encryption(Encryption::Identity(identity1.clone()))
+            .with_compression(1024);
+        let client2 = P2PClient::new(STUN_SERVERS)
+            .with_encryption(Encryption::Identity(identity2.clone()))
+            .with_compression(1024);
+
+        let (connection1, connection2) = open_pair(&client1, &client2).await?;
+
+        let recv = |connection: Arc<P2PConnection>| async move {
+            tokio::time::timeout(Duration::from_secs(10), connection.recv()).await
+        };
+
+        // The first send waits for the peer's key
+        connection1.send_text("secret").await?;
+        assert_eq!(
+            recv(connection2.clone()).await?,
+            Some(Message::Text("secret".to_string()))
+        );
+        assert!(connection1.channel.is_encrypted());
+        assert_eq!(connection1.peer_identity(), Some(identity2.public_key()));
+        assert_eq!(connection2.peer_identity(), Some(identity1.public_key()));
+
+        let state = r#"{"position":[1.0,2.0,3.0]}"#.repeat(1024);
+        connection2.send(state.as_bytes()).await?;
+        assert_eq!(
+            recv(connection1.clone()).await?,
+            Some(Message::Binary(Bytes::from(state)))
+        );
+        assert!(connection2.channel.is_compressing());
+
+        // A peer which doesn't encrypt answers with its capabilities, so sends fail rather than
+        // wait forever
+        let client3 = P2PClient::new(STUN_SERVERS);
+        let (connection1, _connection3) = open_pair(&client1, &client3).await?;
+        let err = tokio::time::timeout(Duration::from_secs(10), connection1.send(b"secret"))
+            .await?
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<ConnectionError>(),
+            Some(ConnectionError::PeerUnencrypted)
+        ));
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_into_stream() -> AResult<()> {
+        let client1 = P2PClient::new(STUN_SERVERS);
+        let client2 = P2PClient::new(STUN_SERVERS);
+
+        let (connection1, connection2) = open_pair(&client1, &client2).await?;
+        let mut stream1 = connection1.into_stream();
+        let mut stream2 = connection2.into_stream();
+
+        // Larger than a single frame, and written in pieces which don't line up with the reads
+        let data = (0..100_000).map(|i| i as u8).collect::<Vec<_>>();
+        for piece in data.chunks(30_000) {
+            stream1.write_all(piece).await?;
+        }
+        stream1.shutdown().await?;
+
+        let mut received = Vec::new();
+        tokio::time::timeout(Duration::from_secs(10), stream2.read_to_end(&mut received)).await??;
+        assert_eq!(received, data);
+        assert!(stream1.write_all(b"late").await.is_err());
+
+        // The other direction is still open
+        stream2.write_all(b"reply").await?;
+        stream2.flush().await?;
+        let mut reply = [0u8; 5];
+        tokio::time::timeout(Duration::from_secs(10), stream1.read_exact(&mut reply)).await??;
+        assert_eq!(&reply, b"reply");
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_mux_streams() -> AResult<()> {
+        let client1 = P2PClient::new(STUN_SERVERS);
+        let client2 = P2PClient::new(STUN_SERVERS);
+
+        let (connection1, connection2) = open_pair(&client1, &client2).await?;
+        let accept = || async {
+            tokio::time::timeout(Duration::from_secs(10), connection2.accept_stream())
+                .await
+                .ok()
+                .flatten()
+                .ok_or_else(|| anyhow!("No stream was accepted"))
+        };
+
+        let bulk1 = connection1.open_stream().await?;
+        let bulk2 = accept().await?;
+        let chat1 = connection1.open_stream().await?;
+        let chat2 = accept().await?;
+        assert_eq!((bulk2.id(), chat2.id()), (bulk1.id(), chat1.id()));
+
+        // Twice the window, which can't all go out while nothing on the other end reads it
+        let bulk = vec![7u8; 2 * crate::mux::INITIAL_WINDOW as usize];
+        let sent = Arc::new(AtomicBool::new(false));
+        let sender = {
+            let (bulk, sent) = (bulk.clone(), sent.clone());
+            tokio::spawn(async move {
+                bulk1.send(&bulk).await?;
+                bulk1.close().await?;
+                sent.store(true, Ordering::Relaxed);
+                AResult::<()>::Ok(())
+            })
+        };
+
+        // The stalled stream doesn't hold up the others, in either direction
+        chat1.send(b"hello").await?;
+        async fn recv(stream: &MuxStream) -> AResult<Option<Bytes>> {
+            Ok(tokio::time::timeout(Duration::from_secs(10), stream.recv()).await?)
+        }
+        assert_eq!(recv(&chat2).await?, Some(Bytes::from_static(b"hello")));
+        chat2.send(b"hi").await?;
+        assert_eq!(recv(&chat1).await?, Some(Bytes::from_static(b"hi")));
+        assert!(!sent.load(Ordering::Relaxed));
+
+        let mut received = Vec::new();
+        while let Some(data) = recv(&bulk2).await? {
+            received.extend_from_slice(&data);
+        }
+        assert_eq!(received, bulk);
+        sender.await??;
+
+        // Dropping a stream closes it on the other end
+        drop(chat1);
+        assert_eq!(recv(&chat2).await?, None);
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_streams_overrunning_the_window_are_closed() -> AResult<()> {
+        let client1 = P2PClient::new(STUN_SERVERS);
+        let client2 = P2PClient::new(STUN_SERVERS);
+
+        let (connection1, connection2) = open_pair(&client1, &client2).await?;
+        let opened = connection1.open_stream().await?;
+        let accepted = tokio::time::timeout(Duration::from_secs(10), connection2.accept_stream())
+            .await?
+            .ok_or_else(|| anyhow!("No stream was accepted"))?;
+
+        // Nothing reads on the other end, so the last byte overruns the window
+        let window = crate::mux::INITIAL_WINDOW as usize;
+        opened.send_ignoring_window(&vec![7u8; window + 1]).await?;
+        assert_eq!(
+            tokio::time::timeout(Duration::from_secs(10), opened.recv()).await?,
+            None
+        );
+
+        let mut received = 0;
+        while let Some(data) =
+            tokio::time::timeout(Duration::from_secs(10), accepted.recv()).await?
+        {
+            received += data.len();
+        }
+        assert!(received <= window);
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_peer_streams_are_capped() -> AResult<()> {
+        let client1 = P2PClient::new(STUN_SERVERS);
+        let client2 = P2PClient::new(STUN_SERVERS);
+
+        let (connection1, connection2) = open_pair(&client1, &client2).await?;
+        let mut streams = Vec::new();
+        for _ in 0..crate::mux::MAX_PEER_STREAMS {
+            streams.push(connection1.open_stream().await?);
+        }
+        let refused = connection1.open_stream().await?;
+        assert_eq!(
+            tokio::time::timeout(Duration::from_secs(10), refused.recv()).await?,
+            None
+        );
+
+        // Streams which were accepted and dropped make room again
+        let accepted = tokio::time::timeout(Duration::from_secs(10), connection2.accept_stream())
+            .await?
+            .ok_or_else(|| anyhow!("No stream was accepted"))?;
+        assert_eq!(accepted.id(), streams[0].id());
+        drop(accepted);
+        let admitted = connection1.open_stream().await?;
+        admitted.send(b"hello").await?;
+        assert!(
+            tokio::time::timeout(Duration::from_millis(500), admitted.recv())
+                .await
+                .is_err()
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_paired_channels() -> AResult<()> {
+        let client1 = P2PClient::new(STUN_SERVERS).with_paired_channels();
+        let client2 = P2PClient::new(STUN_SERVERS).with_paired_channels();
+
+        let (connection1, connection2) = open_pair(&client1, &client2).await?;
+        for connection in [&connection1, &connection2] {
+            let unreliable = connection
+                .unreliable()
+                .expect("Paired channels were asked for");
+            tokio::time::timeout(Duration::from_secs(10), unreliable.wait_open()).await??;
+        }
+
+        let (reliable1, unreliable1) = (
+            connection1.reliable().unwrap(),
+            connection1.unreliable().unwrap(),
+        );
+        let (reliable2, unreliable2) = (
+            connection2.reliable().unwrap(),
+            connection2.unreliable().unwrap(),
+        );
+        assert!(std::ptr::eq(reliable1, connection1.channel()));
+        let data_channel = unreliable1.data_channel();
+        assert_eq!(
+            (data_channel.ordered(), data_channel.max_retransmits()),
+            (false, 0)
+        );
+
+        reliable1.send(b"event").await?;
+        unreliable1.send(b"state").await?;
+        assert_eq!(
+            tokio::time::timeout(Duration::from_secs(10), unreliable2.recv()).await?,
+            Some(Message::Binary(Bytes::from_static(b"state")))
+        );
+        assert_eq!(
+            tokio::time::timeout(Duration::from_secs(10), reliable2.recv()).await?,
+            Some(Message::Binary(Bytes::from_static(b"event")))
+        );
+
+        // Without picking the channel
+        connection1
+            .send_as(b"state", DeliveryMode::Unreliable)
+            .await?;
+        assert_eq!(
+            tokio::time::timeout(Duration::from_secs(10), connection2.recv_any()).await?,
+            Some((
+                DeliveryMode::Unreliable,
+                Message::Binary(Bytes::from_static(b"state"))
+            ))
+        );
+        connection1
+            .send_as(b"event", DeliveryMode::Reliable)
+            .await?;
+        assert_eq!(
+            tokio::time::timeout(Duration::from_secs(10), connection2.recv_any()).await?,
+            Some((
+                DeliveryMode::Reliable,
+                Message::Binary(Bytes::from_static(b"event"))
+            ))
+        );
+
+        // Connections made without paired channels only have the default one
+        let client3 = P2PClient::new(STUN_SERVERS);
+        let connection3 = P2PConnection::new(&client3, true).await?;
+        assert!(connection3.reliable().is_none() && connection3.unreliable().is_none());
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_receive_buffer_overflow() -> AResult<()> {
+        let client1 = P2PClient::new(STUN_SERVERS);
+        let client2 =
+            P2PClient::new(STUN_SERVERS).with_receive_buffer(2, OverflowPolicy::DropOldest);
+        let client3 = P2PClient::new(STUN_SERVERS).with_receive_buffer(2, OverflowPolicy::Close);
+
+        for (receiving_client, policy) in [
+            (&client2, OverflowPolicy::DropOldest),
+            (&client3, OverflowPolicy::Close),
+        ] {
+            let (connection1, connection2) = open_pair(&client1, receiving_client).await?;
+
+            for byte in 0..5u8 {
+                connection1.send(&[byte]).await?;
+            }
+            let received = |byte: u8| Some(Message::Binary(Bytes::copy_from_slice(&[byte])));
+
+            if policy == OverflowPolicy::DropOldest {
+                // The echo comes back once every message before it was handled
+                tokio::time::timeout(Duration::from_secs(10), connection1.ping()).await??;
+                assert_eq!(connection2.try_recv(), received(3));
+                assert_eq!(connection2.try_recv(), received(4));
+                assert!(!connection2.channel.overflowed());
+            } else {
+                let connection = connection2.clone();
+                wait_for_condition(
+                    Box::new(move || Ok(connection.channel.overflowed())),
+                    Duration::from_secs(10),
+                )
+                .await?;
+                let recv = || tokio::time::timeout(Duration::from_secs(10), connection2.recv());
+                assert_eq!(recv().await?, received(0));
+                assert_eq!(recv().await?, received(1));
+                assert_eq!(recv().await?, None);
+            }
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_channel_events() -> AResult<()> {
+        let client1 = P2PClient::new(STUN_SERVERS);
+        let client2 = P2PClient::new(STUN_SERVERS);
+
+        let connection1 = Arc::new(P2PConnection::new(&client1, true).await?);
+        let connection2 = Arc::new(P2PConnection::new(&client2, true).await?);
+        let mut events = connection1.channel().events();
+        assert!(!connection1.channel().is_open());
+
+        let (connection1, connection2) = connect(connection1, connection2).await?;
+        async fn recv_event(
+            events: &mut broadcast::Receiver<ChannelEvent>,
+        ) -> AResult<ChannelEvent> {
+            Ok(tokio::time::timeout(Duration::from_secs(10), events.recv()).await??)
+        }
+        assert_eq!(recv_event(&mut events).await?, ChannelEvent::Opened);
+        connection1.channel().wait_open().await?;
+        connection1.send(b"hello").await?;
+
+        let channel1 = connection1
+            .open_channel("events", ChannelOptions::default())
+            .await?;
+        let channel2 = connection2.on_channel("events").await;
+        for channel in [&channel1, &channel2] {
+            tokio::time::timeout(Duration::from_secs(10), channel.wait_open()).await??;
+        }
+
+        // Closing either end closes both
+        let mut events = channel1.events();
+        channel2.data_channel().close().await?;
+        assert_eq!(recv_event(&mut events).await?, ChannelEvent::Closed);
+        assert!(channel1.wait_open().await.is_err());
+        assert!(channel1.send(b"late").await.is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_rpc() -> AResult<()> {
+        // Calls go through the same encryption as messages
+        let encryption = Encryption::PreSharedKey([3; 32]);
+        let client1 = P2PClient::new(STUN_SERVERS).with_encryption(encryption.clone());
+        let client2 = P2PClient::new(STUN_SERVERS).with_encryption(encryption);
+
+        let (connection1, connection2) = open_pair(&client1, &client2).await?;
+
+        connection2.register_handler("reverse", |payload: Bytes| async move {
+            Ok(payload.iter().rev().copied().collect::<Vec<_>>().into())
+        });
+        connection2.register_handler("fail", |_| async { Err(anyhow!("Not today")) });
+        connection2.register_handler("stall", |payload| async move {
+            sleep(Duration::from_secs(5)).await;
+            Ok(payload)
+        });
+
+        // Calls in flight at the same time each get their own response
+        let (reversed, stalled) = tokio::join!(
+            async {
+                sleep(Duration::from_millis(100)).await;
+                connection1.call("reverse", b"hello").await
+            },
+            connection1.call_with_timeout("stall", &[], Duration::from_millis(500)),
+        );
+        assert_eq!(reversed?, Bytes::from_static(b"olleh"));
+        assert!(matches!(
+            stalled.unwrap_err().downcast_ref::<RpcError>(),
+            Some(RpcError::Timeout)
+        ));
+
+        let err = connection1.call("fail", &[]).await.unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<RpcError>(),
+            Some(&RpcError::Failed("Not today".to_owned()))
+        );
+        let err = connection1.call("missing", &[]).await.unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<RpcError>(),
+            Some(&RpcError::UnknownMethod("missing".to_owned()))
+        );
+
+        // Calls don't show up among the messages
+        connection1.send(b"after").await?;
+        assert_eq!(
+            tokio::time::timeout(Duration::from_secs(10), connection2.recv()).await?,
+            Some(Message::Binary(Bytes::from_static(b"after")))
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_send_rate_limit() -> AResult<()> {
+        let client1 =
+            P2PClient::new(STUN_SERVERS).with_send_rate_limit(RateLimit::MessagesPerSecond(10));
+        let client2 = P2PClient::new(STUN_SERVERS);
+
+        let (connection1, connection2) = open_pair(&client1, &client2).await?;
+        assert_eq!(
+            connection1.send_rate_limit(),
+            Some(RateLimit::MessagesPerSecond(10))
+        );
+
+        // The first 10 go out in a burst, and the other 5 wait for the budget to refill. How long
+        // they wait is covered by the rate limiter's own tests, on a paused clock
+        for i in 0..15u8 {
+            connection1.send(&[i]).await?;
+        }
+
+        for i in 0..15u8 {
+            assert_eq!(
+                tokio::time::timeout(Duration::from_secs(10), connection2.recv()).await?,
+                Some(Message::Binary(Bytes::from(vec![i])))
+            );
+        }
+
+        connection1.set_send_rate_limit(None);
+        assert_eq!(connection1.send_rate_limit(), None);
+        for i in 0..15u8 {
+            connection1.send(&[i]).await?;
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_send_gives_up() -> AResult<()> {
+        let client1 =
+            P2PClient::new(STUN_SERVERS).with_send_rate_limit(RateLimit::MessagesPerSecond(1));
+        let client2 = P2PClient::new(STUN_SERVERS);
+
+        let (connection1, connection2) = open_pair(&client1, &client2).await?;
+
+        // The burst is spent, so the next sends are held back by the rate limit
+        connection1.send(b"first").await?;
+        let timed_out = connection1
+            .send_with_timeout(b"late", Duration::from_millis(100))
+            .await
+            .map_err(|err| err.downcast::<SendError>());
+        assert!(matches!(
+            timed_out,
+            Err(Ok(SendError::Timeout(payload))) if payload == Bytes::from_static(b"late")
+        ));
+
+        let cancel = CancellationToken::new();
+        tokio::spawn({
+            let cancel = cancel.clone();
+            async move {
+                sleep(Duration::from_millis(100)).await;
+                cancel.cancel();
+            }
+        });
+        let cancelled = connection1
+            .send_cancellable(b"cancelled", &cancel)
+            .await
+            .map_err(|err| err.downcast::<SendError>());
+        assert!(matches!(
+            cancelled,
+            Err(Ok(SendError::Cancelled(payload))) if payload == Bytes::from_static(b"cancelled")
+        ));
+
+        let expired = connection1
+            .send_with_ttl(b"stale", Duration::from_millis(100))
+            .await
+            .map_err(|err| err.downcast::<SendError>());
+        assert!(matches!(
+            expired,
+            Err(Ok(SendError::Expired(payload))) if payload == Bytes::from_static(b"stale")
+        ));
+
+        connection1
+            .send_with_timeout(b"on time", Duration::from_secs(5))
+            .await?;
+        for expected in [&b"first"[..], b"on time"] {
+            assert_eq!(
+                tokio::time::timeout(Duration::from_secs(10), connection2.recv()).await?,
+                Some(Message::Binary(Bytes::from_static(expected)))
+            );
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_injected_faults() -> AResult<()> {
+        let client1 = P2PClient::new(STUN_SERVERS);
+        let client2 = P2PClient::new(STUN_SERVERS);
+
+        let (connection1, connection2) = open_pair(&client1, &client2).await?;
+        let next = |connection: Arc<P2PConnection>| async move {
+            tokio::time::timeout(Duration::from_millis(500), connection.recv())
+                .await
+                .ok()
+                .flatten()
+        };
+
+        connection1.inject_faults(Faults {
+            latency: Duration::from_millis(200),
+            loss: 1.0,
+            seed: 0,
+        });
+        let started = Instant::now();
+        connection1.send(b"lost").await?;
+        assert!(started.elapsed() >= Duration::from_millis(200));
+        assert_eq!(next(connection2.clone()).await, None);
+
+        connection1.inject_faults(Faults::default());
+        connection2.force_disconnect();
+        connection1.send(b"cut off").await?;
+        assert_eq!(next(connection2.clone()).await, None);
+        connection2.send(b"cut off").await?;
+        assert_eq!(next(connection1.clone()).await, None);
+
+        connection2.end_disconnect();
+        connection1.send(b"back").await?;
+        assert_eq!(
+            next(connection2.clone()).await,
+            Some(Message::Binary(Bytes::from_static(b"back")))
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_pause() -> AResult<()> {
+        let client1 = P2PClient::new(STUN_SERVERS);
+        let client2 = P2PClient::new(STUN_SERVERS);
+
+        let (connection1, connection2) = open_pair(&client1, &client2).await?;
+
+        connection2.pause();
+        assert!(connection2.channel().is_paused());
+        connection1.send(b"while loading").await?;
+        sleep(Duration::from_millis(200)).await;
+        assert_eq!(connection2.try_recv(), None);
+
+        connection2.resume();
+        assert_eq!(
+            tokio::time::timeout(Duration::from_secs(10), connection2.recv()).await?,
+            Some(Message::Binary(Bytes::from_static(b"while loading")))
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_channel_protocols() -> AResult<()> {
+        let client1 = P2PClient::new(STUN_SERVERS);
+        let client2 = P2PClient::new(STUN_SERVERS).with_channel_protocols(["game/2"]);
+
+        let (connection1, connection2) = connected_pair(&client1, &client2).await?;
+
+        let outdated = connection1
+            .open_channel(
+                "outdated",
+                ChannelOptions::default().with_protocol("game/1"),
+            )
+            .await?;
+        let current = connection1
+            .open_channel("current", ChannelOptions::default().with_protocol("game/2"))
+            .await?;
+
+        let remote_current =
+            tokio::time::timeout(Duration::from_secs(10), connection2.on_channel("current"))
+                .await?;
+        assert_eq!(remote_current.protocol(), "game/2");
+        current.wait_open().await?;
+
+        // The peer closes the channel it doesn't take
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while outdated.wait_open().await.is_ok() {
+                sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await?;
+        assert!(connection2
+            .incoming_channels
+            .lock()
+            .unwrap()
+            .get("outdated")
+            .is_none());
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_held_channels_are_capped() -> AResult<()> {
+        let client1 = P2PClient::new(STUN_SERVERS);
+        let client2 = P2PClient::new(STUN_SERVERS);
+
+        let (connection1, connection2) = open_pair(&client1, &client2).await?;
+        let mut channels = Vec::new();
+        for i in 0..MAX_HELD_CHANNELS {
+            let channel = connection1
+                .open_channel(&format!("held {i}"), ChannelOptions::default())
+                .await?;
+            tokio::time::timeout(Duration::from_secs(10), channel.wait_open()).await??;
+            channels.push(channel);
+        }
+
+        // The peer closes the channel it has no room for
+        let refused = connection1
+            .open_channel("refused", ChannelOptions::default())
+            .await?;
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while refused.wait_open().await.is_ok() {
+                sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await?;
+
+        // Picking one up makes room again
+        let held =
+            tokio::time::timeout(Duration::from_secs(10), connection2.on_channel("held 0")).await?;
+        assert_eq!(held.label(), "held 0");
+        let admitted = connection1
+            .open_channel("admitted", ChannelOptions::default())
+            .await?;
+        tokio::time::timeout(Duration::from_secs(10), connection2.on_channel("admitted")).await?;
+        tokio::time::timeout(Duration::from_secs(10), admitted.wait_open()).await??;
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_duplicate_channel_labels_are_refused() -> AResult<()> {
+        let client1 = P2PClient::new(STUN_SERVERS);
+        let client2 = P2PClient::new(STUN_SERVERS);
+
+        let (connection1, connection2) = open_pair(&client1, &client2).await?;
+        let first = connection1
+            .open_channel("chat", ChannelOptions::default())
+            .await?;
+        tokio::time::timeout(Duration::from_secs(10), first.wait_open()).await??;
+
+        // The peer closes the channel whose label it already holds one under
+        let duplicate = connection1
+            .open_channel("chat", ChannelOptions::default())
+            .await?;
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while duplicate.wait_open().await.is_ok() {
+                sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await?;
+
+        // The channel held first is the one handed out
+        let held =
+            tokio::time::timeout(Duration::from_secs(10), connection2.on_channel("chat")).await?;
+        first.send(b"hello").await?;
+        assert_eq!(
+            tokio::time::timeout(Duration::from_secs(10), held.recv()).await?,
+            Some(Message::Binary(Bytes::from_static(b"hello")))
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_labeled_channels() -> AResult<()> {
+        let client1 = P2PClient::new(STUN_SERVERS);
+        let client2 = P2PClient::new(STUN_SERVERS);
+
+        let (connection1, connection2) = connected_pair(&client1, &client2).await?;
+
+        let chat = connection1
+            .open_channel("chat", ChannelOptions::default())
+            .await?;
+        let state = connection1
+            .open_channel("state", ChannelOptions::unreliable(0))
+            .await?;
+        assert_eq!(chat.label(), "chat");
+
+        let (remote_state, remote_chat) = tokio::time::timeout(Duration::from_secs(10), async {
+            (
+                connection2.on_channel("state").await,
+                connection2.on_channel("chat").await,
+            )
+        })
+        .await?;
+        assert_eq!(remote_chat.label(), "chat");
+        for channel in [&chat, &state, &remote_chat, &remote_state] {
+            tokio::time::timeout(Duration::from_secs(10), channel.wait_open()).await??;
+        }
+
+        chat.send_text("hi").await?;
+        state.send(b"position").await?;
+        connection1.send(b"default").await?;
+        remote_chat.send_text("hello").await?;
+
+        let received = tokio::time::timeout(Duration::from_secs(10), async {
+            (
+                remote_chat.recv().await,
+                remote_state.recv().await,
+                connection2.recv().await,
+                chat.recv().await,
+            )
+        })
+        .await?;
+        assert_eq!(
+            received,
+            (
+                Some(Message::Text("hi".to_owned())),
+                Some(Message::Binary(Bytes::from_static(b"position"))),
+                Some(Message::Binary(Bytes::from_static(b"default"))),
+                Some(Message::Text("hello".to_owned())),
+            )
+        );
+        assert_eq!(state.try_recv(), None);
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_without_trickle() -> AResult<()> {
+        let client1 = P2PClient::new(STUN_SERVERS);
+        let client2 = P2PClient::new(STUN_SERVERS);
+        let connection1 = P2PConnection::new(&client1, true).await?;
+        let connection2 = P2PConnection::new(&client2, true).await?;
+        assert_eq!(connection1.gathering_state(), GatheringState::New);
+
+        // Only the complete descriptions are exchanged, without any candidates on the side
+        connection1.get_offer().await?;
+        let offer = connection1.wait_for_gathering_complete().await?;
+        assert_eq!(connection1.gathering_state(), GatheringState::Complete);
+        assert!(offer.sdp.contains("a=candidate"));
+
+        connection2.get_answer(offer).await?;
+        let answer = connection2.wait_for_gathering_complete().await?;
+        connection1.set_answer(answer).await?;
+
+        connection1.wait_connected().await?;
+        connection2.wait_connected().await?;
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_restart_ice() -> AResult<()> {
+        let client1 = P2PClient::new(STUN_SERVERS);
+        let client2 = P2PClient::new(STUN_SERVERS);
+
+        let (connection1, connection2) = connected_pair(&client1, &client2).await?;
+        let previous = connection1.local_description().await;
+
+        let offer = connection1.restart_ice().await?;
+        assert_eq!(offer.sdp_type, RTCSdpType::Offer);
+        assert_ne!(ice_ufrag(&offer), previous.as_ref().and_then(ice_ufrag));
+
+        let answer = connection2.get_answer(offer).await?;
+        connection1.set_answer(answer).await?;
+        // Gathering starts over along with ICE, and completes again
+        connection1.wait_for_gathering_complete().await?;
+
+        let candidates = pending_candidates(&connection1, &connection2).await?;
+        connection1.set_candidates(candidates.1.into_iter()).await?;
+        connection2.set_candidates(candidates.0.into_iter()).await?;
+
+        connection1.wait_connected().await?;
+        tokio::time::timeout(Duration::from_secs(10), connection1.channel.wait_open()).await??;
+        connection1.send_text("still here").await?;
+        let received = tokio::time::timeout(Duration::from_secs(10), connection2.recv()).await?;
+        assert_eq!(received, Some(Message::Text("still here".to_owned())));
+        assert_eq!(connection1.state(), ConnectionState::Connected);
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_renegotiation() -> AResult<()> {
+        let client1 = P2PClient::new(STUN_SERVERS);
+        let client2 = P2PClient::new(STUN_SERVERS);
+
+        let (connection1, connection2) = connected_pair(&client1, &client2).await?;
+        assert!(!connection1.is_negotiation_needed());
+
+        let track = Arc::new(TrackLocalStaticSample::new(
+            RTCRtpCodecCapability {
+                mime_type: MIME_TYPE_OPUS.to_owned(),
+                ..Default::default()
+            },
+            "audio".to_owned(),
+            "stream".to_owned(),
+        ));
+        connection1.add_track(track).await?;
+        tokio::time::timeout(Duration::from_secs(10), connection1.negotiation_needed()).await?;
+
+        let offer = connection1.get_offer().await?;
+        assert!(!connection1.is_negotiation_needed());
+        let answer = connection2.get_answer(offer).await?;
+        connection1.set_answer(answer).await?;
+
+        assert!(connection2
+            .remote_description()
+            .await
+            .is_some_and(|offer| offer.sdp.contains("m=audio")));
+        assert!(!connection2.gathered_candidates()?.is_empty());
+
+        tokio::time::timeout(Duration::from_secs(10), connection1.channel.wait_open()).await??;
+        connection1.send_text("renegotiated").await?;
+        let received = tokio::time::timeout(Duration::from_secs(10), connection2.recv()).await?;
+        assert_eq!(received, Some(Message::Text("renegotiated".to_owned())));
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_audio_track() -> AResult<()> {
+        let client1 = P2PClient::new(STUN_SERVERS);
+        let client2 = P2PClient::new(STUN_SERVERS);
+
+        let (connection1, connection2) = connected_pair(&client1, &client2).await?;
+        let mut tracks = connection2.track_events();
+
+        // An Opus frame of silence every 20ms
+        const SILENCE: &[u8] = &[0xf8, 0xff, 0xfe];
+        let samples = futures::stream::unfold(
+            tokio::time::interval(Duration::from_millis(20)),
+            |mut ticks| async move {
+                ticks.tick().await;
+                let sample = MediaSample {
+                    data: Bytes::from_static(SILENCE),
+                    duration: Duration::from_millis(20),
+                };
+                Some((sample, ticks))
+            },
+        );
+        connection1
+            .add_audio_track(AudioCodec::Opus, samples)
+            .await?;
+
+        tokio::time::timeout(Duration::from_secs(10), connection1.negotiation_needed()).await?;
+        let offer = connection1.get_offer().await?;
+        let answer = connection2.get_answer(offer).await?;
+        connection1.set_answer(answer).await?;
+
+        let track = tokio::time::timeout(Duration::from_secs(10), tracks.recv())
+            .await?
+            .ok_or(anyhow!("No track arrived"))?;
+        assert_eq!(track.kind(), MediaKind::Audio);
+        assert_eq!(track.mime_type(), MIME_TYPE_OPUS);
+
+        let sample = tokio::time::timeout(Duration::from_secs(10), track.recv_sample()).await?;
+        assert_eq!(sample, Some(Bytes::from_static(SILENCE)));
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_video_track() -> AResult<()> {
+        let client1 = P2PClient::new(STUN_SERVERS);
+        let client2 = P2PClient::new(STUN_SERVERS);
+
+        let (connection1, connection2) = connected_pair(&client1, &client2).await?;
+        let mut tracks = connection2.track_events();
+
+        // Frames larger than a packet, so they are split up and put back together
+        let frame = Bytes::from((0..3000).map(|i| i as u8).collect::<Vec<_>>());
+        let samples = futures::stream::unfold(
+            (
+                tokio::time::interval(Duration::from_millis(33)),
+                frame.clone(),
+            ),
+            |(mut ticks, frame)| async move {
+                ticks.tick().await;
+                let sample = MediaSample {
+                    data: frame.clone(),
+                    duration: Duration::from_millis(33),
+                };
+                Some((sample, (ticks, frame)))
+            },
+        );
+        connection1
+            .add_video_track(VideoCodec::Vp8, samples)
+            .await?;
+
+        tokio::time::timeout(Duration::from_secs(10), connection1.negotiation_needed()).await?;
+        let offer = connection1.get_offer().await?;
+        let answer = connection2.get_answer(offer).await?;
+        connection1.set_answer(answer).await?;
+
+        let track = tokio::time::timeout(Duration::from_secs(10), tracks.recv())
+            .await?
+            .ok_or(anyhow!("No track arrived"))?;
+        assert_eq!(track.kind(), MediaKind::Video);
+        assert_eq!(track.mime_type(), MIME_TYPE_VP8);
+
+        let sample = tokio::time::timeout(Duration::from_secs(10), track.recv_sample()).await?;
+        assert_eq!(sample, Some(frame));
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_get_stats() -> AResult<()> {
+        let client1 = P2PClient::new(STUN_SERVERS);
+        let client2 = P2PClient::new(STUN_SERVERS);
+
+        let (connection1, connection2) = open_pair(&client1, &client2).await?;
+        connection1.send(&[1u8; 4096]).await?;
+        tokio::time::timeout(Duration::from_secs(10), connection2.recv()).await?;
+
+        let stats = connection1.get_stats().await;
+        assert!(stats.bytes_sent >= 4096);
+        assert!(stats.bytes_received > 0);
+        assert!(stats.local_candidate.is_some());
+        assert!(stats
+            .remote_candidate
+            .as_ref()
+            .is_some_and(|candidate| candidate.port != 0 && !candidate.address.is_empty()));
+
+        // Both peers are on this host, so they talk over UDP between their host candidates
+        let pair = connection1
+            .selected_candidate_pair()
+            .await
+            .expect("A candidate pair should have been selected");
+        assert_eq!(Some(&pair.local), stats.local_candidate.as_ref());
+        assert_eq!(Some(&pair.remote), stats.remote_candidate.as_ref());
+        assert_eq!(pair.protocol, TransportProtocol::Udp);
+        assert_ne!(pair.path(), PathKind::Relayed);
+
+        // The statistics sit alongside the peer and time in a snapshot
+        let snapshot = serde_json::to_value(connection1.snapshot_stats().await)?;
+        assert_eq!(snapshot["peer_id"], serde_json::Value::Null);
+        assert!(snapshot["bytes_sent"].as_u64() >= Some(4096));
+        assert!(snapshot["local_candidate"]["port"].is_u64());
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_fingerprint_pinning() -> AResult<()> {
+        let client1 = P2PClient::new(STUN_SERVERS);
+        let client2 = P2PClient::new(STUN_SERVERS);
+
+        let connection1 = Arc::new(P2PConnection::new(&client1, true).await?);
+        let connection2 = Arc::new(P2PConnection::new(&client2, true).await?);
+        let fingerprint2 = connection2.local_fingerprint()?;
+        assert_eq!(fingerprint2.split(':').count(), 32);
+        connection1.expect_remote_fingerprint(fingerprint2.to_lowercase());
+        connection2.expect_remote_fingerprint(connection1.local_fingerprint()?);
+
+        let (connection1, connection2) = connect(connection1, connection2).await?;
+        assert_eq!(connection1.remote_fingerprint().await, Some(fingerprint2));
+
+        // A peer which isn't the one pinned is refused before anything is exchanged with it
+        let connection3 = P2PConnection::new(&client1, true).await?;
+        let connection4 = P2PConnection::new(&client2, true).await?;
+        connection4.expect_remote_fingerprint(connection2.local_fingerprint()?);
+        let err = connection4
+            .get_answer(connection3.get_offer().await?)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<ConnectionError>(),
+            Some(ConnectionError::FingerprintMismatch {
+                actual: Some(_),
+                ..
+            })
+        ));
+        assert!(connection4.remote_fingerprint().await.is_none());
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_stable_certificate() -> AResult<()> {
+        let certificate = Certificate::from_pem(&Certificate::generate()?.to_pem())?;
+        let client1 = P2PClient::new(STUN_SERVERS).with_certificate(certificate.clone());
+        let client2 = P2PClient::new(STUN_SERVERS);
+
+        let connection1 = Arc::new(P2PConnection::new(&client1, true).await?);
+        let connection2 = Arc::new(P2PConnection::new(&client2, true).await?);
+        assert_eq!(connection1.local_fingerprint()?, certificate.fingerprint()?);
+        assert_ne!(connection2.local_fingerprint()?, certificate.fingerprint()?);
+        assert_eq!(
+            P2PConnection::new(&client1, true)
+                .await?
+                .local_fingerprint()?,
+            certificate.fingerprint()?
+        );
+
+        connection2.expect_remote_fingerprint(certificate.fingerprint()?);
+        let (_, connection2) = connect(connection1, connection2).await?;
+        assert_eq!(
+            connection2.remote_fingerprint().await,
+            Some(certificate.fingerprint()?)
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_send_in_topic() -> AResult<()> {
+        let client1 = P2PClient::new(STUN_SERVERS);
+        let client2 = P2PClient::new(STUN_SERVERS);
+        let (connection1, connection2) = connected_pair(&client1, &client2).await?;
+
+        let topics = connection1
+            .open_channel("topics", ChannelOptions::per_topic())
+            .await?;
+        let remote_topics =
+            tokio::time::timeout(Duration::from_secs(10), connection2.on_channel("topics")).await?;
+        topics.wait_open().await?;
+
+        for index in 0..50u8 {
+            topics.send_in_topic(u16::from(index % 2), &[index]).await?;
+        }
+
+        let mut received = [Vec::new(), Vec::new()];
+        for _ in 0..50 {
+            let message = tokio::time::timeout(Duration::from_secs(10), remote_topics.recv())
+                .await?
+                .unwrap();
+            let index = message.as_bytes()[0];
+            received[usize::from(index % 2)].push(index);
+        }
+        assert_eq!(received[0], (0..50).step_by(2).collect::<Vec<_>>());
+        assert_eq!(received[1], (1..50).step_by(2).collect::<Vec<_>>());
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_send_tracked() -> AResult<()> {
+        let client1 = P2PClient::new(STUN_SERVERS);
+        let client2 = P2PClient::new(STUN_SERVERS).with_receive_buffer(1, OverflowPolicy::Block);
+        let (connection1, connection2) = connected_pair(&client1, &client2).await?;
+        connection1.channel.wait_open().await?;
+        connection2.channel.wait_open().await?;
+
+        let delivery = connection1.send_tracked(b"first").await?;
+        tokio::time::timeout(Duration::from_secs(5), delivery).await??;
+
+        // Not delivered while the peer's receive buffer is full
+        let mut delivery = connection1.send_tracked(b"second").await?;
+        assert!(
+            tokio::time::timeout(Duration::from_millis(300), &mut delivery)
+                .await
+                .is_err()
+        );
+        assert_eq!(
+            connection2.recv().await,
+            Some(Message::Binary(Bytes::from_static(b"first")))
+        );
+        tokio::time::timeout(Duration::from_secs(5), delivery).await??;
+
+        // Never delivered once the channel closes
+        let delivery = connection1.send_tracked(b"third").await?;
+        connection1.close().await?;
+        assert!(tokio::time::timeout(Duration::from_secs(5), delivery)
+            .await?
+            .is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_max_message_size() -> AResult<()> {
+        let client = P2PClient::new(STUN_SERVERS);
+        let connection1 = Arc::new(P2PConnection::new(&client, true).await?);
+        let connection2 = Arc::new(P2PConnection::new(&client, true).await?);
+        assert_eq!(connection1.max_message_size(), DEFAULT_MAX_MESSAGE_SIZE);
+
+        let offer = connection1.get_offer().await?;
+        assert_eq!(
+            negotiated_max_message_size(&offer),
+            DEFAULT_MAX_MESSAGE_SIZE
+        );
+        for (advertised, negotiated) in [
+            (1024, 1024),
+            (0, DEFAULT_MAX_MESSAGE_SIZE),
+            (1 << 30, DEFAULT_MAX_MESSAGE_SIZE),
+        ] {
+            let mut offer = offer.clone();
+            offer
+                .sdp
+                .push_str(&format!("a=max-message-size:{advertised}\r\n"));
+            assert_eq!(negotiated_max_message_size(&offer), negotiated);
+        }
+
+        let mut offer = offer;
+        offer.sdp.push_str("a=max-message-size:1024\r\n");
+        connection1
+            .set_answer(connection2.get_answer(offer).await?)
+            .await?;
+        assert_eq!(connection2.max_message_size(), 1024);
+        assert_eq!(connection1.max_message_size(), DEFAULT_MAX_MESSAGE_SIZE);
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_close() -> AResult<()> {
+        let client1 = P2PClient::new(STUN_SERVERS);
+        let client2 = P2PClient::new(STUN_SERVERS);
+
+        let (connection1, _connection2) = connected_pair(&client1, &client2).await?;
+        let mut states = connection1.state_changes();
+
+        connection1.close().await?;
+        connection1.close().await?;
+        tokio::time::timeout(
+            Duration::from_secs(10),
+            states.wait_for(|state| *state == ConnectionState::Closed),
+        )
+        .await??;
+
+        let err = connection1.send(b"hello").await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<ConnectionError>(),
+            Some(ConnectionError::ConnectionClosed)
+        ));
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_close_closes_every_channel() -> AResult<()> {
+        let client1 = P2PClient::new(STUN_SERVERS).with_paired_channels();
+        let client2 = P2PClient::new(STUN_SERVERS).with_paired_channels();
+
+        let (connection1, connection2) = open_pair(&client1, &client2).await?;
+        let opened = connection1
+            .open_channel("opened", ChannelOptions::default())
+            .await?;
+        let _claimed_by_peer = connection2
+            .open_channel("claimed", ChannelOptions::default())
+            .await?;
+        let _held_by_peer = connection2
+            .open_channel("held", ChannelOptions::default())
+            .await?;
+        let claimed =
+            tokio::time::timeout(Duration::from_secs(10), connection1.on_channel("claimed"))
+                .await?;
+        wait_for_condition(
+            Box::new(|| {
+                Ok(connection1
+                    .incoming_channels
+                    .lock()
+                    .unwrap()
+                    .contains_key("held"))
+            }),
+            Duration::from_secs(10),
+        )
+        .await?;
+        let held = connection1.incoming_channels.lock().unwrap()["held"]
+            .data_channel()
+            .clone();
+        let unreliable = connection1
+            .unreliable()
+            .expect("Paired channels were asked for");
+        for channel in [&opened, &claimed, unreliable] {
+            tokio::time::timeout(Duration::from_secs(10), channel.wait_open()).await??;
+        }
+
+        connection1.close().await?;
+        for channel in [connection1.channel(), unreliable, &opened, &claimed] {
+            assert!(matches!(
+                channel.data_channel().ready_state(),
+                RTCDataChannelState::Closing | RTCDataChannelState::Closed
+            ));
+            assert_eq!(
+                tokio::time::timeout(Duration::from_secs(5), channel.recv()).await?,
+                None
+            );
+        }
+        assert!(matches!(
+            held.ready_state(),
+            RTCDataChannelState::Closing | RTCDataChannelState::Closed
+        ));
+        assert!(connection1.incoming_channels.lock().unwrap().is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_rate_limited_send_fails_once_the_channel_closes() -> AResult<()> {
+        let client1 =
+            P2PClient::new(STUN_SERVERS).with_send_rate_limit(RateLimit::MessagesPerSecond(1));
+        let client2 = P2PClient::new(STUN_SERVERS);
+
+        let (connection1, connection2) = open_pair(&client1, &client2).await?;
+        let channel1 = connection1
+            .open_channel("limited", ChannelOptions::default())
+            .await?;
+        let channel2 = connection2.on_channel("limited").await;
+        for channel in [&channel1, &channel2] {
+            tokio::time::timeout(Duration::from_secs(10), channel.wait_open()).await??;
+        }
+
+        // The burst is spent, so the next send waits on the rate limit while the peer closes
+        channel1.send(b"first").await?;
+        let send = tokio::spawn(async move { channel1.send(b"second").await });
+        channel2.data_channel().close().await?;
+
+        let err = tokio::time::timeout(Duration::from_secs(5), send)
+            .await??
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<ConnectionError>(),
+            Some(ConnectionError::ChannelNotOpen(_))
+        ));
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_close_ends_pending_operations() -> AResult<()> {
+        let client1 =
+            P2PClient::new(STUN_SERVERS).with_send_rate_limit(RateLimit::MessagesPerSecond(1));
+        let client2 = P2PClient::new(STUN_SERVERS);
+
+        let (connection1, _connection2) = connected_pair(&client1, &client2).await?;
+        connection1.channel.wait_open().await?;
+
+        // The burst is spent, so the next send waits on the rate limit
+        connection1.send(b"first").await?;
+        let send = tokio::spawn({
+            let connection1 = connection1.clone();
+            async move { connection1.send(b"second").await }
+        });
+        let recv = tokio::spawn({
+            let connection1 = connection1.clone();
+            async move { connection1.recv().await }
+        });
+        sleep(Duration::from_millis(100)).await;
+
+        connection1.close().await?;
+        let err = tokio::time::timeout(Duration::from_secs(5), send)
+            .await??
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<ConnectionError>(),
+            Some(ConnectionError::ConnectionClosed)
+        ));
+        assert_eq!(
+            tokio::time::timeout(Duration::from_secs(5), recv).await??,
+            None
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_drop_outside_runtime() -> AResult<()> {
+        let runtime = tokio::runtime::Runtime::new()?;
+        let connection = runtime.block_on(async {
+            let client = P2PClient::new(STUN_SERVERS);
+            P2PConnection::new(&client, true).await
+        })?;
+
+        // Neither blocks nor panics without a runtime to spawn the cleanup on
+        drop(connection);
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_ping() -> AResult<()> {
+        let client1 = P2PClient::new(STUN_SERVERS);
+        let client2 = P2PClient::new(STUN_SERVERS);
+
+        let (connection1, connection2) = open_pair(&client1, &client2).await?;
+        assert_eq!(connection1.latency(), None);
+
+        for _ in 0..3 {
+            let round_trip =
+                tokio::time::timeout(Duration::from_secs(10), connection1.ping()).await??;
+            assert!(round_trip < Duration::from_secs(5));
+        }
+        assert!(connection1.latency().is_some());
+
+        // Pings are answered without showing up as messages
+        connection1.send_text("after").await?;
+        let received = tokio::time::timeout(Duration::from_secs(10), connection2.recv()).await?;
+        assert_eq!(received, Some(Message::Text("after".to_owned())));
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_candidate_events() -> AResult<()> {
+        let client1 = P2PClient::new(STUN_SERVERS);
+        let client2 = P2PClient::new(STUN_SERVERS);
+
+        let connection1 = Arc::new(P2PConnection::new(&client1, true).await?);
+        let connection2 = Arc::new(P2PConnection::new(&client2, true).await?);
+        let mut events1 = connection1.candidate_events()?;
+        let mut events2 = connection2.candidate_events()?;
+
+        let offer = connection1.get_offer().await?;
+        let answer = connection2.get_answer(offer).await?;
+        connection1.set_answer(answer).await?;
+
+        let mut trickled = Vec::new();
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while !(connection1.get_is_connected_to_peer()
+                && connection2.get_is_connected_to_peer())
+            {
+                tokio::select! {
+                    Some(candidate) = events1.recv() => {
+                        trickled.push(candidate.clone());
+                        connection2.set_candidates(std::iter::once(candidate)).await?;
+                    }
+                    Some(candidate) = events2.recv() => {
+                        connection1.set_candidates(std::iter::once(candidate)).await?;
+                    }
+                    _ = tokio::time::sleep(Duration::from_millis(50)) => {}
+                }
+            }
+            Ok::<_, anyhow::Error>(())
+        })
+        .await??;
+
+        // Every candidate is trickled once, and a later receiver only gets the ones to come
+        for (i, candidate) in trickled.iter().enumerate() {
+            assert!(!trickled[..i].contains(candidate));
+        }
+        assert!(connection1.candidate_events()?.try_recv().is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_pending_candidates_drain_without_duplicates() {
+        let candidate = RTCIceCandidate {
+            stats_id: "first".to_owned(),
+            address: "192.0.2.1".to_owned(),
+            port: 5000,
+            ..Default::default()
+        };
+        let mut gathered = GatheredCandidates::default();
+        gathered.push(candidate.clone());
+        gathered.push(RTCIceCandidate {
+            stats_id: "second".to_owned(),
+            ..candidate.clone()
+        });
+        assert_eq!(gathered.take_pending(), vec![candidate.clone()]);
+        assert!(gathered.take_pending().is_empty());
+
+        let other = RTCIceCandidate {
+            port: 5001,
+            ..candidate.clone()
+        };
+        gathered.push(other.clone());
+        assert_eq!(gathered.take_pending(), vec![other.clone()]);
+        assert_eq!(gathered.candidates, vec![candidate, other]);
     }
 }
@@ -0,0 +1,110 @@
+use std::time::Duration;
+
+/// How much each new sample moves the rolling averages
+const SMOOTHING: f64 = 0.25;
+
+/// How well a connection is doing, from `ClientEvent::QualityChanged`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum Quality {
+    #[default]
+    Good,
+    /// Noticeably laggy or lossy, such as when a game should lower its tick rate
+    Degraded,
+    /// Barely usable, such as when a game should warn the player
+    Bad,
+}
+
+impl Quality {
+    /// The worse quality of the two, going by how far `value` is past each threshold
+    fn rate(value: f64, degraded: f64, bad: f64) -> Self {
+        if value >= bad {
+            Self::Bad
+        } else if value >= degraded {
+            Self::Degraded
+        } else {
+            Self::Good
+        }
+    }
+}
+
+/// Rolling averages of the round trip time, ping loss and send queue of a connection, which
+/// its quality is rated from
+pub(crate) struct QualityMonitor {
+    rtt: Option<f64>,
+    loss: f64,
+    /// The queued bytes as a share of the send high-water mark
+    queued: f64,
+    high_water_mark: usize,
+    quality: Quality,
+}
+
+impl QualityMonitor {
+    pub(crate) fn new(high_water_mark: usize) -> Self {
+        Self {
+            rtt: None,
+            loss: 0.0,
+            queued: 0.0,
+            high_water_mark: high_water_mark.max(1),
+            quality: Quality::Good,
+        }
+    }
+
+    /// Takes in the round trip time of the latest ping, `None` if it went unanswered, and how
+    /// many bytes are queued, returning the new quality if it changed
+    pub(crate) fn sample(&mut self, rtt: Option<Duration>, queued: usize) -> Option<Quality> {
+        let average = |average: f64, sample: f64| average + (sample - average) * SMOOTHING;
+
+        if let Some(rtt) = rtt {
+            let rtt = rtt.as_secs_f64();
+            self.rtt = Some(
+                self.rtt
+                    .map_or(rtt, |average_rtt| average(average_rtt, rtt)),
+            );
+        }
+        self.loss = average(self.loss, if rtt.is_some() { 0.0 } else { 1.0 });
+        self.queued = average(self.queued, queued as f64 / self.high_water_mark as f64);
+
+        let quality = [
+            Quality::rate(self.rtt.unwrap_or_default(), 0.15, 0.4),
+            Quality::rate(self.loss, 0.02, 0.1),
+            Quality::rate(self.queued, 0.5, 1.0),
+        ]
+        .into_iter()
+        .max()
+        .unwrap_or_default();
+
+        if quality == self.quality {
+            return None;
+        }
+        self.quality = quality;
+        Some(quality)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_quality_follows_samples() {
+        let mut monitor = QualityMonitor::new(1000);
+        let fast = Some(Duration::from_millis(20));
+        assert_eq!(monitor.sample(fast, 0), None);
+
+        // A single lost ping is enough to notice, and it takes a while to recover from
+        assert_eq!(monitor.sample(None, 0), Some(Quality::Bad));
+        assert_eq!(monitor.sample(fast, 0), None);
+        let recovered = (0..20).find_map(|_| monitor.sample(fast, 0));
+        assert_eq!(recovered, Some(Quality::Degraded));
+        let recovered = (0..20).find_map(|_| monitor.sample(fast, 0));
+        assert_eq!(recovered, Some(Quality::Good));
+
+        let slow = Some(Duration::from_millis(250));
+        let degraded = (0..20).find_map(|_| monitor.sample(slow, 0));
+        assert_eq!(degraded, Some(Quality::Degraded));
+
+        // A send queue which doesn't drain
+        let bad = (0..20).find_map(|_| monitor.sample(fast, 5000));
+        assert_eq!(bad, Some(Quality::Bad));
+    }
+}
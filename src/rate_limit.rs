@@ -0,0 +1,182 @@
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Caps how fast a `P2PConnection` sends, across all of its channels. Up to one second's worth
+/// may be sent in a burst, after which sends wait for the budget to refill
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RateLimit {
+    /// At most this many payload bytes per second
+    BytesPerSecond(u64),
+    /// At most this many messages per second, however large they are
+    MessagesPerSecond(u32),
+}
+
+impl RateLimit {
+    /// How many tokens are refilled each second, which is also the size of a burst
+    fn rate(&self) -> f64 {
+        match *self {
+            Self::BytesPerSecond(bytes) => bytes.max(1) as f64,
+            Self::MessagesPerSecond(messages) => messages.max(1) as f64,
+        }
+    }
+
+    /// How many tokens a message of `len` bytes costs
+    fn cost(&self, len: usize) -> f64 {
+        match self {
+            Self::BytesPerSecond(_) => len as f64,
+            Self::MessagesPerSecond(_) => 1.0,
+        }
+    }
+}
+
+/// A token bucket shared by every channel of a connection
+#[derive(Debug)]
+pub(crate) struct RateLimiter(Mutex<Bucket>);
+
+#[derive(Debug)]
+struct Bucket {
+    limit: Option<RateLimit>,
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(limit: Option<RateLimit>) -> Self {
+        Self(Mutex::new(Bucket {
+            limit,
+            tokens: limit.map_or(0.0, |limit| limit.rate()),
+            refilled_at: Instant::now(),
+        }))
+    }
+
+    pub(crate) fn limit(&self) -> Option<RateLimit> {
+        self.lock().limit
+    }
+
+    /// Replaces the limit, starting over with a full burst
+    pub(crate) fn set_limit(&self, limit: Option<RateLimit>) {
+        *self.lock() = Bucket {
+            limit,
+            tokens: limit.map_or(0.0, |limit| limit.rate()),
+            refilled_at: Instant::now(),
+        };
+    }
+
+    /// Waits until a message of `len` bytes fits in the limit. Callers check again whether they
+    /// can still send once it returns, since the wait can take a while
+    pub(crate) async fn acquire(&self, len: usize) {
+        let wait = self.reserve(len, Instant::now());
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    /// Gives back the tokens taken for a message of `len` bytes which was given up on
+    pub(crate) fn refund(&self, len: usize) {
+        let mut bucket = self.lock();
+        if let Some(limit) = bucket.limit {
+            bucket.tokens = (bucket.tokens + limit.cost(len)).min(limit.rate());
+        }
+    }
+
+    /// Takes the tokens for a message of `len` bytes right away, going into debt if there aren't
+    /// enough, and returns how long until the debt is paid off. Concurrent senders queue up
+    /// behind each other this way
+    fn reserve(&self, len: usize, now: Instant) -> Duration {
+        let mut bucket = self.lock();
+        let Some(limit) = bucket.limit else {
+            return Duration::ZERO;
+        };
+
+        let rate = limit.rate();
+        let refilled = now
+            .saturating_duration_since(bucket.refilled_at)
+            .as_secs_f64()
+            * rate;
+        bucket.tokens = (bucket.tokens + refilled).min(rate) - limit.cost(len);
+        bucket.refilled_at = now;
+
+        if bucket.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-bucket.tokens / rate)
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Bucket> {
+        self.0.lock().expect("Unable to aquire rate limiter lock")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unlimited_never_waits() {
+        let limiter = RateLimiter::new(None);
+        let now = Instant::now();
+        assert_eq!(limiter.reserve(usize::MAX, now), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_bytes_per_second() {
+        let limiter = RateLimiter::new(Some(RateLimit::BytesPerSecond(1000)));
+        let now = Instant::now();
+
+        assert_eq!(limiter.reserve(1000, now), Duration::ZERO);
+        assert_eq!(limiter.reserve(500, now), Duration::from_millis(500));
+        // Half a second later the debt is paid off, with nothing left over
+        assert_eq!(
+            limiter.reserve(250, now + Duration::from_millis(500)),
+            Duration::from_millis(250)
+        );
+        // The budget never refills past a one second burst
+        assert_eq!(
+            limiter.reserve(1000, now + Duration::from_secs(10)),
+            Duration::ZERO
+        );
+    }
+
+    #[test]
+    fn test_messages_per_second() {
+        let limiter = RateLimiter::new(Some(RateLimit::MessagesPerSecond(4)));
+        let now = Instant::now();
+
+        for _ in 0..4 {
+            assert_eq!(limiter.reserve(1024 * 1024, now), Duration::ZERO);
+        }
+        assert_eq!(limiter.reserve(1, now), Duration::from_millis(250));
+        // A message given up on doesn't hold up the next one
+        limiter.refund(1);
+        assert_eq!(limiter.reserve(1, now), Duration::from_millis(250));
+
+        limiter.set_limit(None);
+        assert_eq!(limiter.reserve(1, now), Duration::ZERO);
+        assert_eq!(limiter.limit(), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_acquire_waits_for_the_budget() {
+        let limiter = RateLimiter::new(Some(RateLimit::MessagesPerSecond(10)));
+
+        // The first 10 go out in a burst, and the other 5 wait for the budget to refill
+        let started = Instant::now();
+        for _ in 0..10 {
+            limiter.acquire(1).await;
+        }
+        assert_eq!(started.elapsed(), Duration::ZERO);
+        for _ in 0..5 {
+            limiter.acquire(1).await;
+        }
+        assert_eq!(started.elapsed().as_millis(), 500);
+
+        limiter.set_limit(None);
+        let started = Instant::now();
+        for _ in 0..100 {
+            limiter.acquire(1).await;
+        }
+        assert_eq!(started.elapsed(), Duration::ZERO);
+    }
+}
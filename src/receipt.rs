@@ -0,0 +1,151 @@
+use crate::channel::Outbound;
+use crate::framing::FrameKind;
+use anyhow::{anyhow, Result as AResult};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use futures::future::BoxFuture;
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::sync::oneshot;
+
+/// Tracked messages sent over a channel which the peer hasn't acknowledged yet
+#[derive(Default)]
+pub(crate) struct Receipts {
+    pending: Mutex<HashMap<u32, oneshot::Sender<()>>>,
+    next_id: AtomicU32,
+}
+
+impl Receipts {
+    /// Starts tracking a message, returning its id, the frame to send the peer and where its
+    /// acknowledgement arrives
+    pub(crate) fn start(
+        &self,
+        kind: FrameKind,
+        data: &[u8],
+    ) -> (u32, Bytes, oneshot::Receiver<()>) {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+
+        let mut tracked = BytesMut::with_capacity(5 + data.len());
+        tracked.put_u32(id);
+        tracked.put_u8(kind as u8);
+        tracked.put_slice(data);
+
+        let (sx, rx) = oneshot::channel();
+        self.lock().insert(id, sx);
+        (id, tracked.freeze(), rx)
+    }
+
+    /// Forgets a message which is no longer waited on
+    pub(crate) fn cancel(&self, id: u32) {
+        self.lock().remove(&id);
+    }
+
+    /// Hands an acknowledgement from the peer to the delivery waiting for it
+    pub(crate) fn acknowledge(&self, ack: &[u8]) {
+        let Ok(id) = <[u8; 4]>::try_from(ack) else {
+            return;
+        };
+        if let Some(sx) = self.lock().remove(&u32::from_be_bytes(id)) {
+            let _ = sx.send(());
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<u32, oneshot::Sender<()>>> {
+        self.pending
+            .lock()
+            .expect("Unable to aquire pending receipts lock")
+    }
+}
+
+/// Takes a tracked message from the peer apart into the acknowledgement to send back once it
+/// has been delivered, and the kind and data of the message
+pub(crate) fn open(mut tracked: Bytes) -> AResult<(Bytes, FrameKind, Bytes)> {
+    if tracked.len() < 5 {
+        return Err(anyhow!("Tracked message is too short"));
+    }
+    let ack = tracked.split_to(4);
+    let kind = FrameKind::try_from(tracked.get_u8())?;
+    Ok((ack, kind, tracked))
+}
+
+/// Resolves once the peer has received the message sent with `Channel::send_tracked`, failing
+/// if the channel closes first. On a channel which may drop messages, wrap it in a timeout, as
+/// the message or its acknowledgement may never arrive
+pub struct Delivery(BoxFuture<'static, AResult<()>>);
+
+impl Delivery {
+    pub(crate) fn new(
+        id: u32,
+        mut ack: oneshot::Receiver<()>,
+        receipts: Arc<Receipts>,
+        outbound: Arc<Outbound>,
+    ) -> Self {
+        // Stops tracking the message however the wait ends, including the delivery being dropped
+        let cancel = CancelOnDrop(id, receipts);
+        Self(Box::pin(async move {
+            let _cancel = cancel;
+            // The acknowledgement never arrives if the channel closes in the meantime
+            loop {
+                match tokio::time::timeout(Duration::from_millis(100), &mut ack).await {
+                    Ok(delivered) => return Ok(delivered?),
+                    Err(_) => outbound.ensure_open()?,
+                }
+            }
+        }))
+    }
+}
+
+impl Future for Delivery {
+    type Output = AResult<()>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.0.as_mut().poll(cx)
+    }
+}
+
+impl std::fmt::Debug for Delivery {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Delivery")
+    }
+}
+
+struct CancelOnDrop(u32, Arc<Receipts>);
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        self.1.cancel(self.0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_acknowledge() -> AResult<()> {
+        let receipts = Receipts::default();
+        let (_, tracked, mut first) = receipts.start(FrameKind::Text, b"hello");
+        let (second_id, _, mut second) = receipts.start(FrameKind::Binary, &[]);
+
+        let (ack, kind, data) = open(tracked)?;
+        assert_eq!(
+            (kind, data),
+            (FrameKind::Text, Bytes::from_static(b"hello"))
+        );
+        assert!(open(Bytes::from_static(&[0, 0, 0, 1])).is_err());
+
+        receipts.acknowledge(&ack);
+        assert_eq!(first.try_recv(), Ok(()));
+        // Acknowledged twice, or for a message which was given up on
+        receipts.acknowledge(&ack);
+        receipts.cancel(second_id);
+        receipts.acknowledge(&second_id.to_be_bytes());
+        assert!(second.try_recv().is_err());
+        assert!(receipts.lock().is_empty());
+        Ok(())
+    }
+}
@@ -0,0 +1,289 @@
+use crate::channel::Message;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::task::{Context, Poll, Waker};
+use tokio::sync::Notify;
+
+/// What a channel does with a message from the peer once its receive buffer is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OverflowPolicy {
+    /// Stops reading from the channel until there is room, which pushes back on the peer once
+    /// the transport's own buffers fill up
+    #[default]
+    Block,
+    /// Makes room by dropping the oldest message which hasn't been received yet
+    DropOldest,
+    /// Closes the channel, see `Channel::overflowed`
+    Close,
+}
+
+/// What became of a message handed to `ReceiveBuffer::push`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Pushed {
+    Queued,
+    /// The buffer was full under `OverflowPolicy::Close`, so it was closed instead
+    Overflowed,
+    Closed,
+}
+
+/// The messages from the peer which haven't been received yet
+pub(crate) struct ReceiveBuffer {
+    state: Mutex<State>,
+    capacity: usize,
+    policy: OverflowPolicy,
+    /// Wakes blocked pushes once a message has been taken out
+    space: Notify,
+}
+
+#[derive(Default)]
+struct State {
+    messages: VecDeque<Message>,
+    closed: bool,
+    overflowed: bool,
+    /// Takes nothing in until resumed, see `Channel::pause`
+    paused: bool,
+    receivers: Vec<Waker>,
+}
+
+impl ReceiveBuffer {
+    pub(crate) fn new(capacity: usize, policy: OverflowPolicy) -> Self {
+        Self {
+            state: Mutex::new(State::default()),
+            capacity: capacity.max(1),
+            policy,
+            space: Notify::new(),
+        }
+    }
+
+    pub(crate) async fn push(&self, message: Message) -> Pushed {
+        loop {
+            let space = self.space.notified();
+            {
+                let mut state = self.lock();
+                if state.closed {
+                    return Pushed::Closed;
+                }
+                if state.paused {
+                    // Waits for `resume`
+                } else if state.messages.len() < self.capacity {
+                    return self.queue(&mut state, message);
+                } else {
+                    match self.policy {
+                        OverflowPolicy::Block => {}
+                        OverflowPolicy::DropOldest => {
+                            state.messages.pop_front();
+                            return self.queue(&mut state, message);
+                        }
+                        OverflowPolicy::Close => {
+                            state.overflowed = true;
+                            Self::close_locked(&mut state);
+                            return Pushed::Overflowed;
+                        }
+                    }
+                }
+            }
+            space.await;
+        }
+    }
+
+    /// Holds every push until `resume`, whatever the overflow policy
+    pub(crate) fn pause(&self) {
+        self.lock().paused = true;
+    }
+
+    pub(crate) fn resume(&self) {
+        self.lock().paused = false;
+        self.space.notify_waiters();
+    }
+
+    pub(crate) fn is_paused(&self) -> bool {
+        self.lock().paused
+    }
+
+    /// Ends the buffer once the messages already in it have been received
+    pub(crate) fn close(&self) {
+        Self::close_locked(&mut self.lock());
+        self.space.notify_waiters();
+    }
+
+    pub(crate) fn poll_recv(&self, cx: &mut Context<'_>) -> Poll<Option<Message>> {
+        let mut state = self.lock();
+        if let Some(message) = state.messages.pop_front() {
+            self.space.notify_waiters();
+            return Poll::Ready(Some(message));
+        }
+        if state.closed {
+            return Poll::Ready(None);
+        }
+
+        Self::wait(&mut state, cx)
+    }
+
+    pub(crate) async fn recv(&self) -> Option<Message> {
+        std::future::poll_fn(|cx| self.poll_recv(cx)).await
+    }
+
+    /// Waits for at least one message, then moves up to `limit` of them into `buffer`,
+    /// returning how many. 0 once the buffer is closed and empty, or if `limit` is 0
+    pub(crate) async fn recv_many(&self, buffer: &mut Vec<Message>, limit: usize) -> usize {
+        if limit == 0 {
+            return 0;
+        }
+        std::future::poll_fn(|cx| {
+            let mut state = self.lock();
+            if !state.messages.is_empty() {
+                let count = limit.min(state.messages.len());
+                buffer.extend(state.messages.drain(..count));
+                self.space.notify_waiters();
+                return Poll::Ready(count);
+            }
+            if state.closed {
+                return Poll::Ready(0);
+            }
+
+            Self::wait(&mut state, cx)
+        })
+        .await
+    }
+
+    pub(crate) fn try_recv(&self) -> Option<Message> {
+        let message = self.lock().messages.pop_front();
+        if message.is_some() {
+            self.space.notify_waiters();
+        }
+        message
+    }
+
+    pub(crate) fn overflowed(&self) -> bool {
+        self.lock().overflowed
+    }
+
+    fn queue(&self, state: &mut State, message: Message) -> Pushed {
+        state.messages.push_back(message);
+        for waker in state.receivers.drain(..) {
+            waker.wake();
+        }
+        Pushed::Queued
+    }
+
+    /// Wakes the receiver polling with `cx` once a message arrives
+    fn wait<T>(state: &mut State, cx: &mut Context<'_>) -> Poll<T> {
+        if !state
+            .receivers
+            .iter()
+            .any(|waker| waker.will_wake(cx.waker()))
+        {
+            state.receivers.push(cx.waker().clone());
+        }
+        Poll::Pending
+    }
+
+    fn close_locked(state: &mut State) {
+        state.closed = true;
+        for waker in state.receivers.drain(..) {
+            waker.wake();
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state
+            .lock()
+            .expect("Unable to aquire receive buffer lock")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+    use std::time::Duration;
+
+    fn message(byte: u8) -> Message {
+        Message::Binary(Bytes::copy_from_slice(&[byte]))
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_drop_oldest() {
+        let buffer = ReceiveBuffer::new(2, OverflowPolicy::DropOldest);
+        for byte in 0..3 {
+            assert_eq!(buffer.push(message(byte)).await, Pushed::Queued);
+        }
+        assert_eq!(buffer.try_recv(), Some(message(1)));
+        assert_eq!(buffer.recv().await, Some(message(2)));
+        assert_eq!(buffer.try_recv(), None);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_recv_many() {
+        let buffer = ReceiveBuffer::new(8, OverflowPolicy::Block);
+        for byte in 0..3 {
+            buffer.push(message(byte)).await;
+        }
+
+        let mut received = vec![message(9)];
+        assert_eq!(buffer.recv_many(&mut received, 2).await, 2);
+        assert_eq!(buffer.recv_many(&mut received, 0).await, 0);
+        assert_eq!(buffer.recv_many(&mut received, 8).await, 1);
+        assert_eq!(
+            received,
+            vec![message(9), message(0), message(1), message(2)]
+        );
+
+        buffer.close();
+        assert_eq!(buffer.recv_many(&mut received, 8).await, 0);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_block() {
+        let buffer = std::sync::Arc::new(ReceiveBuffer::new(1, OverflowPolicy::Block));
+        buffer.push(message(0)).await;
+
+        let blocked = tokio::spawn({
+            let buffer = buffer.clone();
+            async move { buffer.push(message(1)).await }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!blocked.is_finished());
+
+        assert_eq!(buffer.recv().await, Some(message(0)));
+        assert_eq!(blocked.await.unwrap(), Pushed::Queued);
+        assert_eq!(buffer.recv().await, Some(message(1)));
+
+        buffer.close();
+        assert_eq!(buffer.recv().await, None);
+        assert_eq!(buffer.push(message(2)).await, Pushed::Closed);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_pause() {
+        let buffer = std::sync::Arc::new(ReceiveBuffer::new(4, OverflowPolicy::DropOldest));
+        buffer.push(message(0)).await;
+        buffer.pause();
+
+        let held = tokio::spawn({
+            let buffer = buffer.clone();
+            async move { buffer.push(message(1)).await }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!held.is_finished());
+        // What arrived before the pause can still be received
+        assert_eq!(buffer.try_recv(), Some(message(0)));
+        assert_eq!(buffer.try_recv(), None);
+
+        buffer.resume();
+        assert_eq!(held.await.unwrap(), Pushed::Queued);
+        assert_eq!(buffer.recv().await, Some(message(1)));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_close_on_overflow() {
+        let buffer = ReceiveBuffer::new(1, OverflowPolicy::Close);
+        buffer.push(message(0)).await;
+        assert_eq!(buffer.push(message(1)).await, Pushed::Overflowed);
+        assert!(buffer.overflowed());
+
+        // What was buffered before the overflow is still delivered
+        assert_eq!(buffer.recv().await, Some(message(0)));
+        assert_eq!(buffer.recv().await, None);
+    }
+}
@@ -0,0 +1,183 @@
+use crate::error::RpcError;
+use anyhow::{anyhow, Result as AResult};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use futures::future::BoxFuture;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::oneshot;
+
+/// How long `Channel::call` waits for the peer to respond
+pub(crate) const DEFAULT_CALL_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+const STATUS_OK: u8 = 0;
+const STATUS_FAILED: u8 = 1;
+const STATUS_UNKNOWN_METHOD: u8 = 2;
+
+pub(crate) type Handler = Arc<dyn Fn(Bytes) -> BoxFuture<'static, AResult<Bytes>> + Send + Sync>;
+
+type Response = Result<Bytes, RpcError>;
+
+type PendingCalls = HashMap<u32, oneshot::Sender<Response>>;
+
+/// The calls a channel made which are waiting for their response, and the handlers which answer
+/// the peer's calls
+#[derive(Default)]
+pub(crate) struct Rpc {
+    handlers: Mutex<HashMap<String, Handler>>,
+    pending: Mutex<PendingCalls>,
+    next_call_id: AtomicU32,
+}
+
+impl Rpc {
+    /// Answers calls to `method` with `handler`, replacing any handler it already had
+    pub(crate) fn register(&self, method: &str, handler: Handler) {
+        self.lock_handlers().insert(method.to_owned(), handler);
+    }
+
+    /// Starts a call, returning its id, the request to send the peer and where its response
+    /// arrives
+    pub(crate) fn start(
+        &self,
+        method: &str,
+        payload: &[u8],
+    ) -> AResult<(u32, Bytes, oneshot::Receiver<Response>)> {
+        let method_len = u16::try_from(method.len())
+            .map_err(|_| anyhow!("Method names are limited to {} bytes", u16::MAX))?;
+        let id = self.next_call_id.fetch_add(1, Ordering::Relaxed);
+
+        let mut request = BytesMut::with_capacity(6 + method.len() + payload.len());
+        request.put_u32(id);
+        request.put_u16(method_len);
+        request.put_slice(method.as_bytes());
+        request.put_slice(payload);
+
+        let (sx, rx) = oneshot::channel();
+        self.lock_pending().insert(id, sx);
+        Ok((id, request.freeze(), rx))
+    }
+
+    /// Forgets a call which is no longer waited on
+    pub(crate) fn cancel(&self, id: u32) {
+        self.lock_pending().remove(&id);
+    }
+
+    /// Hands a response from the peer to the call waiting for it
+    pub(crate) fn complete(&self, mut response: Bytes) {
+        if response.len() < 5 {
+            return;
+        }
+        let id = response.get_u32();
+        let status = response.get_u8();
+
+        let Some(sx) = self.lock_pending().remove(&id) else {
+            return;
+        };
+        let text = || String::from_utf8_lossy(&response).into_owned();
+        let _ = sx.send(match status {
+            STATUS_OK => Ok(response.clone()),
+            STATUS_UNKNOWN_METHOD => Err(RpcError::UnknownMethod(text())),
+            _ => Err(RpcError::Failed(text())),
+        });
+    }
+
+    /// Runs the handler for a request from the peer, returning the response to send back.
+    /// `None` if the request is malformed
+    pub(crate) async fn answer(&self, mut request: Bytes) -> Option<Bytes> {
+        if request.len() < 6 {
+            return None;
+        }
+        let id = request.get_u32();
+        let method_len = request.get_u16() as usize;
+        if request.len() < method_len {
+            return None;
+        }
+        let method = String::from_utf8_lossy(&request.split_to(method_len)).into_owned();
+
+        let handler = self.lock_handlers().get(&method).cloned();
+        let (status, payload) = match handler {
+            Some(handler) => match handler(request).await {
+                Ok(payload) => (STATUS_OK, payload),
+                Err(err) => (STATUS_FAILED, Bytes::from(err.to_string())),
+            },
+            None => (STATUS_UNKNOWN_METHOD, Bytes::from(method)),
+        };
+
+        let mut response = BytesMut::with_capacity(5 + payload.len());
+        response.put_u32(id);
+        response.put_u8(status);
+        response.put_slice(&payload);
+        Some(response.freeze())
+    }
+
+    fn lock_handlers(&self) -> std::sync::MutexGuard<'_, HashMap<String, Handler>> {
+        self.handlers
+            .lock()
+            .expect("Unable to aquire rpc handlers lock")
+    }
+
+    fn lock_pending(&self) -> std::sync::MutexGuard<'_, PendingCalls> {
+        self.pending
+            .lock()
+            .expect("Unable to aquire pending calls lock")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn echo() -> Handler {
+        Arc::new(|payload| Box::pin(async move { Ok(payload) }))
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_round_trip() -> AResult<()> {
+        let (caller, callee) = (Rpc::default(), Rpc::default());
+        callee.register("echo", echo());
+        callee.register(
+            "fail",
+            Arc::new(|_| Box::pin(async { Err(anyhow!("Nope")) })),
+        );
+
+        let (_, request, response) = caller.start("echo", b"hello")?;
+        caller.complete(callee.answer(request).await.unwrap());
+        assert_eq!(response.await?, Ok(Bytes::from_static(b"hello")));
+
+        let (_, request, response) = caller.start("fail", &[])?;
+        caller.complete(callee.answer(request).await.unwrap());
+        assert_eq!(response.await?, Err(RpcError::Failed("Nope".to_owned())));
+
+        let (_, request, response) = caller.start("missing", &[])?;
+        caller.complete(callee.answer(request).await.unwrap());
+        assert_eq!(
+            response.await?,
+            Err(RpcError::UnknownMethod("missing".to_owned()))
+        );
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_responses_find_their_call() -> AResult<()> {
+        let (caller, callee) = (Rpc::default(), Rpc::default());
+        callee.register("echo", echo());
+
+        let (_, first_request, first) = caller.start("echo", b"first")?;
+        let (second_id, second_request, second) = caller.start("echo", b"second")?;
+        assert!(callee.answer(Bytes::from_static(&[0, 1])).await.is_none());
+
+        // Answered out of order
+        caller.complete(callee.answer(second_request).await.unwrap());
+        caller.complete(callee.answer(first_request).await.unwrap());
+        assert_eq!(first.await?, Ok(Bytes::from_static(b"first")));
+        assert_eq!(second.await?, Ok(Bytes::from_static(b"second")));
+
+        // A response to a call which was given up on is ignored
+        let (id, request, _) = caller.start("echo", &[])?;
+        caller.cancel(id);
+        caller.complete(callee.answer(request).await.unwrap());
+        assert!(caller.lock_pending().is_empty());
+        assert_ne!(id, second_id);
+        Ok(())
+    }
+}
@@ -0,0 +1,209 @@
+use crate::signaling::{Capability, RoomConfig, SignalServer};
+use anyhow::Result as AResult;
+use futures::StreamExt;
+use signal_server::{ROOM_PASSWORD_HEADER, SESSION_TOKEN_HEADER};
+use tokio::net::TcpStream;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::Message as WsMessage;
+use tokio_tungstenite::{Connector, MaybeTlsStream, WebSocketStream};
+
+pub use signal_server::RoomNotice;
+
+/// A WebSocket kept open to the signaling server for a room, which has the server push peers
+/// announcing themselves and the offers and answers left for the local peer, instead of them
+/// being polled for like `RoomHandle::discovered_peers` does. Announcing and sending offers and
+/// answers still goes through the `SignalServer` and its `RoomHandle`
+pub struct SignalSocket {
+    stream: WebSocketStream<MaybeTlsStream<TcpStream>>,
+    room: RoomConfig,
+    peer_id: String,
+}
+
+impl SignalSocket {
+    /// Opens a socket to the signaling server of `signal_server` for `peer_id` in `room`, which
+    /// has to be announced to it first, presenting its session token. The server's certificate
+    /// is verified according to the `TlsConfig` of `signal_server`. Fails with
+    /// `SignalError::Unsupported` if the server is too old to serve sockets
+    pub async fn connect(
+        signal_server: &SignalServer,
+        room: RoomConfig,
+        peer_id: impl Into<String>,
+    ) -> AResult<Self> {
+        signal_server.require(Capability::WebSocket).await?;
+
+        let peer_id = peer_id.into();
+        // http:// becomes ws://, and https:// becomes wss://
+        let mut url = reqwest::Url::parse(&format!(
+            "{}/ws",
+            signal_server.url().replacen("http", "ws", 1)
+        ))?;
+        url.query_pairs_mut()
+            .append_pair("channel", &room.channel)
+            .append_pair("room", &room.room)
+            .append_pair("peer_id", &peer_id);
+        let mut request = url.as_str().into_client_request()?;
+        if let Some((name, value)) = signal_server.auth() {
+            request.headers_mut().insert(name.clone(), value.clone());
+        }
+        if let Some(password) = &room.settings.password {
+            request
+                .headers_mut()
+                .insert(ROOM_PASSWORD_HEADER, password.parse()?);
+        }
+        if let Some(token) = signal_server.session_token(&room, &peer_id) {
+            request
+                .headers_mut()
+                .insert(SESSION_TOKEN_HEADER, token.parse()?);
+        }
+        let connector = Connector::NativeTls(signal_server.tls().connector()?);
+        let (stream, _) =
+            tokio_tungstenite::connect_async_tls_with_config(request, None, false, Some(connector))
+                .await?;
+
+        Ok(Self {
+            stream,
+            room,
+            peer_id,
+        })
+    }
+
+    pub fn room(&self) -> &RoomConfig {
+        &self.room
+    }
+
+    /// Waits for the next notice about another peer in the room. Offers and answers are checked
+    /// like `RoomHandle::fetch_offers_for_me` does. Returns `None` once the server has closed
+    /// the socket
+    pub async fn recv(&mut self) -> AResult<Option<RoomNotice>> {
+        while let Some(message) = self.stream.next().await {
+            let WsMessage::Text(text) = message? else {
+                continue;
+            };
+            let notice = serde_json::from_str(&text)?;
+            // The server tells us about ourselves like any other peer
+            match notice {
+                RoomNotice::PeerAnnounced { ref peer_id }
+                | RoomNotice::PeerUpdated { ref peer_id }
+                | RoomNotice::PeerWithdrawn { ref peer_id }
+                    if *peer_id == self.peer_id => {}
+                RoomNotice::Offer(signal) => {
+                    let signal = self.room.open_directed("offer", &self.peer_id, signal)?;
+                    return Ok(Some(RoomNotice::Offer(signal)));
+                }
+                RoomNotice::Answer(signal) => {
+                    let signal = self.room.open_directed("answer", &self.peer_id, signal)?;
+                    return Ok(Some(RoomNotice::Answer(signal)));
+                }
+                notice => return Ok(Some(notice)),
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Closes the socket, after which the server stops pushing notices
+    pub async fn close(mut self) -> AResult<()> {
+        self.stream.close(None).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::p2p_client::P2PClient;
+    use crate::p2p_connection::P2PConnection;
+    use crate::signaling::tests::{spawn_signal_server, spawn_tls_proxy};
+    use crate::signaling::TlsConfig;
+    use std::time::Duration;
+    use uuid::Uuid;
+
+    async fn recv(socket: &mut SignalSocket) -> AResult<RoomNotice> {
+        tokio::time::timeout(Duration::from_secs(5), socket.recv())
+            .await??
+            .ok_or(anyhow::anyhow!("The socket was closed"))
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_notices_are_pushed() -> AResult<()> {
+        let url = spawn_signal_server().await?;
+        let server = SignalServer::new(url.as_str());
+        let room = RoomConfig::new("test", Uuid::new_v4().to_string());
+        let (local_id, early_id, late_id) = (
+            Uuid::new_v4().to_string(),
+            Uuid::new_v4().to_string(),
+            Uuid::new_v4().to_string(),
+        );
+
+        let early = server.join(room.clone(), early_id.as_str());
+        early.announce_presence().await?;
+
+        let local = server.join(room.clone(), local_id.as_str());
+        local.announce_presence().await?;
+        let mut socket = SignalSocket::connect(&server, room.clone(), local_id.as_str()).await?;
+        // Only the client holding the session of the peer gets its offers and answers
+        let spoofer = SignalServer::new(url.as_str());
+        assert!(
+            SignalSocket::connect(&spoofer, room.clone(), local_id.as_str())
+                .await
+                .is_err()
+        );
+        let late = server.join(room, late_id.as_str());
+        late.announce_presence().await?;
+
+        let client = P2PClient::default();
+        let connection = P2PConnection::new(&client, true).await?;
+        late.send_offer_to(&local_id, &connection.get_offer().await?)
+            .await?;
+        early.withdraw().await?;
+
+        assert!(matches!(
+            recv(&mut socket).await?,
+            RoomNotice::PeerAnnounced { peer_id } if peer_id == early_id
+        ));
+        assert!(matches!(
+            recv(&mut socket).await?,
+            RoomNotice::PeerAnnounced { peer_id } if peer_id == late_id
+        ));
+        assert!(matches!(
+            recv(&mut socket).await?,
+            RoomNotice::Offer(signal) if signal.from == late_id
+        ));
+        assert!(matches!(
+            recv(&mut socket).await?,
+            RoomNotice::PeerWithdrawn { peer_id } if peer_id == early_id
+        ));
+
+        socket.close().await?;
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_sockets_verify_the_certificate() -> AResult<()> {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()])?;
+        let url = spawn_tls_proxy(&spawn_signal_server().await?, &cert).await?;
+        let room = RoomConfig::new("test", Uuid::new_v4().to_string());
+        let peer_id = Uuid::new_v4().to_string();
+
+        let server = SignalServer::new(url.as_str())
+            .with_tls(TlsConfig::pinned(cert.cert.pem().as_bytes())?)?;
+        server
+            .join(room.clone(), peer_id.as_str())
+            .announce_presence()
+            .await?;
+        let socket = SignalSocket::connect(&server, room.clone(), peer_id.as_str()).await?;
+        socket.close().await?;
+
+        // The socket refuses a certificate the pin doesn't match, even once the capabilities
+        // of the server are known
+        let other = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()])?
+            .cert
+            .pem();
+        let server = server.with_tls(TlsConfig::pinned(other.as_bytes())?)?;
+        assert!(SignalSocket::connect(&server, room, peer_id.as_str())
+            .await
+            .is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,3957 @@
+use crate::encryption::{Cipher, Identity};
+use crate::error::SignalError;
+use crate::framing::FrameKind;
+use crate::p2p_client::ClientEvent;
+use crate::p2p_connection::P2PConnection;
+use anyhow::Result as AResult;
+use argon2::Argon2;
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+use futures::{Stream, StreamExt};
+
+use reqwest::header::{
+    HeaderName, HeaderValue, ACCEPT_ENCODING, AUTHORIZATION, CONTENT_ENCODING, CONTENT_TYPE,
+};
+use signal_server::{
+    AnnounceResponse, SignalRejection, PROTOCOL_VERSION, PROTOCOL_VERSION_HEADER,
+    ROOM_PASSWORD_HEADER, SESSION_TOKEN_HEADER,
+};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::{broadcast, OnceCell};
+use webrtc::ice_transport::ice_candidate::RTCIceCandidate;
+use webrtc::peer_connection::sdp::session_description::RTCSessionDescription;
+
+pub use signal_server::{
+    AnnouncementSignature, BroadcastCandidateArgs, Capability, DirectedArgs, DirectedSignal,
+    PeerSignal, RoomEvent, RoomInfo, RoomSettings, ServerInfo,
+};
+
+pub(crate) const DEFAULT_POLL_INTERVAL: Duration = Duration::from_secs(1);
+const DEFAULT_HANDSHAKE_POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Identifies a room on the signaling server. Rooms are grouped into channels, so that several
+/// applications can share a single signaling server
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct RoomConfig {
+    pub channel: String,
+    pub room: String,
+    /// Encrypts what is announced in the room, see `with_secret`
+    pub(crate) secret: Option<RoomSecret>,
+    /// Signs what is announced in the room, see `with_signing_identity`
+    pub(crate) signer: Option<RoomSigner>,
+    /// The key each peer has to sign what it announces with, see `with_trusted_signer`
+    pub(crate) trusted_signers: BTreeMap<String, [u8; 32]>,
+    /// Sent along with every announcement, see `with_password`, `with_max_peers` and
+    /// `with_metadata`
+    pub(crate) settings: RoomSettings,
+}
+
+impl RoomConfig {
+    pub fn new(channel: impl Into<String>, room: impl Into<String>) -> Self {
+        Self {
+            channel: channel.into(),
+            room: room.into(),
+            secret: None,
+            signer: None,
+            trusted_signers: BTreeMap::new(),
+            settings: RoomSettings::default(),
+        }
+    }
+
+    /// Requires every peer announcing itself in the room, or reading from it, to know
+    /// `password`, if the room is created by this peer. Otherwise `password` has to match the one
+    /// the room was created with, or the request is refused with `SignalError::WrongPassword`
+    pub fn with_password(mut self, password: impl Into<String>) -> Self {
+        self.settings.password = Some(password.into());
+        self
+    }
+
+    /// Limits the room to `max_peers` peers, if it is created by this peer. Peers announcing
+    /// themselves in a full room are refused with `SignalError::RoomFull`
+    pub fn with_max_peers(mut self, max_peers: usize) -> Self {
+        self.settings.max_peers = Some(max_peers);
+        self
+    }
+
+    /// Lists the room with `value` under `key` in its `RoomInfo::metadata`, if the room is
+    /// created by this peer
+    pub fn with_metadata(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.settings.metadata.insert(key.into(), value.into());
+        self
+    }
+
+    /// Encrypts the session descriptions and candidates announced in the room, and the offers
+    /// and answers left for other peers, under a key derived from `secret`, so the operator of
+    /// the signaling server can't read them or harvest the peers' addresses. Every peer in the
+    /// room has to use the same secret. The key is stretched with Argon2id, which takes tens of
+    /// milliseconds, so the config is best built once
+    pub fn with_secret(mut self, secret: impl AsRef<str>) -> Self {
+        self.secret = Some(RoomSecret::derive(
+            secret.as_ref(),
+            &self.channel,
+            &self.room,
+        ));
+        self
+    }
+
+    /// Signs everything announced in the room and the offers and answers left for other peers
+    /// with the Ed25519 key of `identity`. Peers which trust its `Identity::signing_public_key`
+    /// for this peer id, see `with_trusted_signer`, can tell if the signaling server or anyone
+    /// else replaced them. Signed announcements always carry everything, rather than only the
+    /// candidates which are new
+    pub fn with_signing_identity(mut self, identity: &Identity) -> Self {
+        self.signer = Some(RoomSigner(Arc::new(identity.signing_key())));
+        self
+    }
+
+    /// Trusts only `signing_public_key` to sign what `peer_id` announces and leaves for this
+    /// peer, failing the fetch with `SignalError::UntrustedSigner` if it is unsigned or signed
+    /// with another key, and with `SignalError::InvalidSignature` if it was changed after it was
+    /// signed. What peers without a trusted key send is handed out with `verified` unset, since
+    /// anyone could have signed it
+    pub fn with_trusted_signer(
+        mut self,
+        peer_id: impl Into<String>,
+        signing_public_key: [u8; 32],
+    ) -> Self {
+        self.trusted_signers
+            .insert(peer_id.into(), signing_public_key);
+        self
+    }
+
+    /// Encrypts `args` if the room has a secret, then signs them if it has a signing identity
+    pub(crate) fn seal(
+        &self,
+        peer_id: &str,
+        args: &BroadcastCandidateArgs,
+    ) -> AResult<BroadcastCandidateArgs> {
+        let mut args = match &self.secret {
+            Some(secret) => secret.seal(args)?,
+            None => args.clone(),
+        };
+        if let Some(signer) = &self.signer {
+            let message = signed_message(
+                self,
+                peer_id,
+                &args.candidates,
+                &args.session_description,
+                &args.sealed,
+                &args.metadata,
+            )?;
+            args.signature = Some(signer.sign(&message));
+        }
+        Ok(args)
+    }
+
+    /// Reverses `seal`, failing if `peer_id` has a trusted key which the announcement isn't
+    /// validly signed with, or if the announcement can't be decrypted
+    pub(crate) fn open(&self, peer_id: &str, mut signal: PeerSignal) -> AResult<PeerSignal> {
+        signal.verified = self.verify_announcement(peer_id, &signal)?;
+        match &self.secret {
+            Some(secret) => secret.open(signal),
+            None => Ok(signal),
+        }
+    }
+
+    /// Checks the signature of what `peer_id` announced, returning whether it was verified
+    pub(crate) fn verify_announcement(&self, peer_id: &str, signal: &PeerSignal) -> AResult<bool> {
+        self.verify(peer_id, signal.signature.as_ref(), || {
+            signed_message(
+                self,
+                peer_id,
+                &signal.candidates,
+                &signal.session_description,
+                &signal.sealed,
+                &signal.metadata,
+            )
+        })
+    }
+
+    /// Signs the session description `from` leaves for `to` on the mailbox at `path` if the
+    /// room has a signing identity, and encrypts it if the room has a secret
+    pub(crate) fn seal_directed(
+        &self,
+        path: &str,
+        from: &str,
+        to: &str,
+        description: &RTCSessionDescription,
+    ) -> AResult<DirectedArgs> {
+        let signature = match &self.signer {
+            Some(signer) => {
+                Some(signer.sign(&directed_message(self, path, from, to, description)?))
+            }
+            None => None,
+        };
+        Ok(match &self.secret {
+            Some(secret) => DirectedArgs {
+                session_description: None,
+                sealed: Some(secret.seal_description(description)?),
+                signature,
+            },
+            None => DirectedArgs {
+                session_description: Some(description.clone()),
+                sealed: None,
+                signature,
+            },
+        })
+    }
+
+    /// Reverses `seal_directed` for what `signal.from` left for `to` on the mailbox at `path`,
+    /// failing like `open` does
+    pub(crate) fn open_directed(
+        &self,
+        path: &str,
+        to: &str,
+        mut signal: DirectedSignal,
+    ) -> AResult<DirectedSignal> {
+        let description = match (&self.secret, signal.sealed.take()) {
+            (Some(secret), Some(sealed)) => secret.open_description(&sealed)?,
+            (Some(_), None) => {
+                return Err(anyhow::anyhow!("The peer's {path} isn't encrypted"));
+            }
+            (None, _) => signal
+                .session_description
+                .take()
+                .ok_or(anyhow::anyhow!("The peer's {path} is encrypted"))?,
+        };
+        signal.verified = self.verify(&signal.from, signal.signature.as_ref(), || {
+            directed_message(self, path, &signal.from, to, &description)
+        })?;
+        signal.session_description = Some(description);
+        Ok(signal)
+    }
+
+    /// Checks `signature` over `message` against the key trusted for `peer_id`, returning
+    /// whether it was verified. Nothing is verified for peers without a trusted key, since the
+    /// signature may just as well be the signaling server's
+    fn verify(
+        &self,
+        peer_id: &str,
+        signature: Option<&AnnouncementSignature>,
+        message: impl FnOnce() -> AResult<Vec<u8>>,
+    ) -> AResult<bool> {
+        let Some(trusted) = self.trusted_signers.get(peer_id) else {
+            return Ok(false);
+        };
+        let Some(signature) = signature else {
+            return Err(SignalError::UntrustedSigner.into());
+        };
+        if signature.public_key.as_slice() != trusted.as_slice() {
+            return Err(SignalError::UntrustedSigner.into());
+        }
+
+        let verifying_key =
+            VerifyingKey::from_bytes(trusted).map_err(|_| SignalError::InvalidSignature)?;
+        let signature = Signature::from_slice(&signature.signature)
+            .map_err(|_| SignalError::InvalidSignature)?;
+        verifying_key
+            .verify_strict(&message()?, &signature)
+            .map_err(|_| SignalError::InvalidSignature)?;
+        Ok(true)
+    }
+
+    /// Whether every announcement has to be sent whole, as encrypted and signed ones do
+    fn announces_whole(&self) -> bool {
+        self.secret.is_some() || self.signer.is_some()
+    }
+}
+
+/// What a signature over the announcement of `peer_id` covers. The candidates are sorted, so the
+/// order the server hands them out in doesn't matter
+fn signed_message(
+    room: &RoomConfig,
+    peer_id: &str,
+    candidates: &[RTCIceCandidate],
+    session_description: &Option<RTCSessionDescription>,
+    sealed: &Option<Vec<u8>>,
+    metadata: &BTreeMap<String, String>,
+) -> AResult<Vec<u8>> {
+    let mut candidates = candidates
+        .iter()
+        .map(serde_json::to_string)
+        .collect::<Result<Vec<_>, _>>()?;
+    candidates.sort();
+    candidates.dedup();
+
+    let signed = serde_json::to_vec(&(
+        &room.channel,
+        &room.room,
+        peer_id,
+        candidates,
+        session_description,
+        sealed,
+        metadata,
+    ))?;
+    Ok([b"rust_p2p announcement".as_slice(), &signed].concat())
+}
+
+/// What a signature over the session description `from` leaves for `to` on the mailbox at
+/// `path` covers, so it can't be passed off as meant for another peer or as the other kind
+fn directed_message(
+    room: &RoomConfig,
+    path: &str,
+    from: &str,
+    to: &str,
+    description: &RTCSessionDescription,
+) -> AResult<Vec<u8>> {
+    let signed = serde_json::to_vec(&(&room.channel, &room.room, path, from, to, description))?;
+    Ok([b"rust_p2p directed signal".as_slice(), &signed].concat())
+}
+
+/// The key what is announced in a room is signed with
+#[derive(Clone)]
+pub(crate) struct RoomSigner(Arc<SigningKey>);
+
+impl RoomSigner {
+    fn sign(&self, message: &[u8]) -> AnnouncementSignature {
+        AnnouncementSignature {
+            public_key: self.0.verifying_key().to_bytes().to_vec(),
+            signature: self.0.sign(message).to_bytes().to_vec(),
+        }
+    }
+}
+
+impl PartialEq for RoomSigner {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.verifying_key() == other.0.verifying_key()
+    }
+}
+
+impl Eq for RoomSigner {}
+
+impl std::hash::Hash for RoomSigner {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.0.verifying_key().hash(state);
+    }
+}
+
+impl std::fmt::Debug for RoomSigner {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&format!(
+            "RoomSigner: {:?}",
+            self.0.verifying_key().to_bytes()
+        ))
+    }
+}
+
+/// The key what is announced in a room with a secret is encrypted under
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub(crate) struct RoomSecret([u8; 32]);
+
+impl RoomSecret {
+    fn derive(secret: &str, channel: &str, room: &str) -> Self {
+        // Every peer in the room has to derive the same key, so the salt can only be the room
+        let salt = [
+            b"rust_p2p room secret/".as_slice(),
+            channel.as_bytes(),
+            b"/",
+            room.as_bytes(),
+        ]
+        .concat();
+        let mut key = [0u8; 32];
+        Argon2::default()
+            .hash_password_into(secret.as_bytes(), &salt, &mut key)
+            .expect("The salt and a 32 byte key are within Argon2's limits");
+        Self(key)
+    }
+
+    /// Encrypts a session description left for a single peer
+    fn seal_description(&self, description: &RTCSessionDescription) -> AResult<Vec<u8>> {
+        let plain = serde_json::to_vec(description)?;
+        Cipher::new(&self.0).seal(FrameKind::Binary, &plain)
+    }
+
+    /// Reverses `seal_description`
+    fn open_description(&self, sealed: &[u8]) -> AResult<RTCSessionDescription> {
+        let (_, plain) = Cipher::new(&self.0).open(sealed)?;
+        Ok(serde_json::from_slice(&plain)?)
+    }
+
+    /// Moves the candidates and session description of `args` into its encrypted part
+    pub(crate) fn seal(&self, args: &BroadcastCandidateArgs) -> AResult<BroadcastCandidateArgs> {
+        let plain = serde_json::to_vec(&PeerSignal {
+            candidates: args.candidates.clone(),
+            session_description: args.session_description.clone(),
+            sealed: None,
+            metadata: args.metadata.clone(),
+            signature: None,
+            cursor: 0,
+            verified: false,
+        })?;
+
+        Ok(BroadcastCandidateArgs {
+            candidates: Vec::new(),
+            session_description: None,
+            sealed: Some(Cipher::new(&self.0).seal(FrameKind::Binary, &plain)?),
+            room_settings: args.room_settings.clone(),
+            metadata: BTreeMap::new(),
+            signature: None,
+        })
+    }
+
+    /// Reverses `seal`, failing if the peer announced itself unencrypted or under another secret
+    pub(crate) fn open(&self, signal: PeerSignal) -> AResult<PeerSignal> {
+        let sealed = signal
+            .sealed
+            .ok_or(anyhow::anyhow!("The peer's announcement isn't encrypted"))?;
+        let (_, plain) = Cipher::new(&self.0).open(&sealed)?;
+        Ok(PeerSignal {
+            signature: signal.signature,
+            cursor: signal.cursor,
+            ..serde_json::from_slice(&plain)?
+        })
+    }
+}
+
+impl std::fmt::Debug for RoomSecret {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("RoomSecret")
+    }
+}
+
+/// A peer which has announced itself in a room
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerInfo {
+    pub peer_id: String,
+    pub room: RoomConfig,
+    /// When this peer was first seen by the local client
+    pub discovered_at: SystemTime,
+}
+
+/// Why a call to the signaling server failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SignalingErrorKind {
+    /// The signaling server could not be reached
+    Network,
+    /// The signaling server responded with an error status code
+    Status(u16),
+    /// The response of the signaling server could not be understood
+    InvalidResponse,
+    /// The signaling server refused the request, see `SignalError`
+    Rejected,
+}
+
+impl From<&anyhow::Error> for SignalingErrorKind {
+    fn from(err: &anyhow::Error) -> Self {
+        if err.is::<SignalError>() {
+            return Self::Rejected;
+        }
+
+        match err.downcast_ref::<reqwest::Error>() {
+            Some(err) if err.is_decode() => Self::InvalidResponse,
+            Some(err) => err
+                .status()
+                .map_or(Self::Network, |status| Self::Status(status.as_u16())),
+            None => Self::InvalidResponse,
+        }
+    }
+}
+
+impl SignalingErrorKind {
+    /// Whether the signaling server found fault with the request, responding with a 4xx status
+    /// code or refusing it
+    pub fn is_client_error(&self) -> bool {
+        match self {
+            Self::Status(status) => (400..500).contains(status),
+            Self::Rejected => true,
+            _ => false,
+        }
+    }
+
+    /// Whether the signaling server failed to handle the request, responding with a 5xx status
+    /// code
+    pub fn is_server_error(&self) -> bool {
+        matches!(self, Self::Status(status) if (500..600).contains(status))
+    }
+
+    /// Whether the same call may succeed if it is made again
+    pub fn is_retryable(&self) -> bool {
+        matches!(self, Self::Network | Self::Status(429)) || self.is_server_error()
+    }
+}
+
+/// How a `SignalServer` retries announcements which fail with a retryable error, see
+/// `SignalingErrorKind::is_retryable`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// How many times a failed announcement is retried before its error is returned
+    pub max_retries: u32,
+    /// How long to wait before the first retry. Every retry after it waits twice as long as the
+    /// one before
+    pub initial_backoff: Duration,
+    /// The longest wait between two retries
+    pub max_backoff: Duration,
+}
+
+impl RetryPolicy {
+    /// Never retries
+    pub fn none() -> Self {
+        Self {
+            max_retries: 0,
+            ..Default::default()
+        }
+    }
+
+    /// How long to wait before retry number `retry`, counting from 0. Jittered to between half
+    /// and all of the backoff, so clients failing together don't retry together
+    fn backoff(&self, retry: u32) -> Duration {
+        let backoff = self
+            .initial_backoff
+            .saturating_mul(2u32.saturating_pow(retry))
+            .min(self.max_backoff);
+        backoff / 2 + backoff / 2 * random_permille() / 1000
+    }
+}
+
+/// A random number below 1000, for jitter
+fn random_permille() -> u32 {
+    // A random uuid is plenty for jitter and needs no dependency
+    (uuid::Uuid::new_v4().as_u128() % 1000) as u32
+}
+
+/// Jitters `interval` by up to a quarter either way, so clients which started polling together
+/// don't keep hitting the signaling server at the same moment
+fn jittered(interval: Duration) -> Duration {
+    interval * 3 / 4 + interval / 2 * random_permille() / 1000
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 3,
+            initial_backoff: Duration::from_millis(200),
+            max_backoff: Duration::from_secs(5),
+        }
+    }
+}
+
+/// How a `SignalServer` behaves when the signaling server becomes unavailable mid-session.
+/// Established connections never depend on the signaling server, so they are always kept alive
+/// through an outage
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OutagePolicy {
+    /// How many consecutive signaling calls have to fail before signaling is considered degraded
+    pub failure_threshold: u32,
+    /// If `true`, discovery only probes the signaling server every `probe_interval` while
+    /// signaling is degraded, instead of polling at its usual interval
+    pub pause_discovery: bool,
+    pub probe_interval: Duration,
+    /// If `true`, withdrawals from rooms made while signaling is degraded are queued and sent once
+    /// it is restored, instead of failing
+    pub buffer_withdrawals: bool,
+}
+
+impl Default for OutagePolicy {
+    fn default() -> Self {
+        Self {
+            failure_threshold: 3,
+            pause_discovery: true,
+            probe_interval: Duration::from_secs(5),
+            buffer_withdrawals: true,
+        }
+    }
+}
+
+/// How a `SignalServer` verifies the certificate of a signaling server served over https, for
+/// self-hosted servers with a private CA or a self-signed certificate. Applies to its
+/// `SignalSocket`s as well
+#[derive(Debug, Clone, Default)]
+pub struct TlsConfig {
+    /// The PEM of every trusted certificate, kept for the `SignalSocket` connector and the
+    /// `GrpcSignaling` channel
+    root_certificates: Vec<Vec<u8>>,
+    /// Whether only `root_certificates` are trusted, instead of them and the system's
+    pinned: bool,
+}
+
+impl TlsConfig {
+    /// Trusts the certificates issued by the CA in `pem`, besides the ones the system trusts
+    pub fn with_root_certificate(mut self, pem: &[u8]) -> AResult<Self> {
+        reqwest::Certificate::from_pem(pem)?;
+        self.root_certificates.push(pem.to_vec());
+        Ok(self)
+    }
+
+    /// Trusts nothing but the self-signed server certificate in `pem`, so a server presenting
+    /// any other certificate is refused, even one issued by a CA the system trusts
+    pub fn pinned(pem: &[u8]) -> AResult<Self> {
+        Ok(Self {
+            pinned: true,
+            ..Self::default().with_root_certificate(pem)?
+        })
+    }
+
+    fn configure(&self, mut builder: reqwest::ClientBuilder) -> AResult<reqwest::ClientBuilder> {
+        for pem in &self.root_certificates {
+            builder = builder.add_root_certificate(reqwest::Certificate::from_pem(pem)?);
+        }
+        Ok(builder.tls_built_in_root_certs(!self.pinned))
+    }
+
+    /// The connector verifying the signaling server's certificate the same way for a
+    /// `SignalSocket`
+    pub(crate) fn connector(&self) -> AResult<native_tls::TlsConnector> {
+        let mut builder = native_tls::TlsConnector::builder();
+        for pem in &self.root_certificates {
+            builder.add_root_certificate(native_tls::Certificate::from_pem(pem)?);
+        }
+        builder.disable_built_in_roots(self.pinned);
+        Ok(builder.build()?)
+    }
+
+    /// The TLS config verifying the signaling server's certificate the same way for a
+    /// `GrpcSignaling`
+    #[cfg(feature = "grpc")]
+    pub(crate) fn client_tls_config(&self) -> tonic::transport::ClientTlsConfig {
+        let config = tonic::transport::ClientTlsConfig::new().ca_certificates(
+            self.root_certificates
+                .iter()
+                .map(tonic::transport::Certificate::from_pem),
+        );
+        if self.pinned {
+            config
+        } else {
+            config.with_native_roots()
+        }
+    }
+}
+
+/// How long calls to the signaling server may take before they fail, so a hung signaling server
+/// fails fast instead of stalling the connection flow
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timeouts {
+    /// How long connecting to the signaling server may take
+    pub connect: Duration,
+    /// How long a call may take, from sending the request to reading the whole response. Doesn't
+    /// apply to the requests the signaling server holds open, made by
+    /// `RoomHandle::wait_for_peer` and `RoomHandle::subscribe_room_events`
+    pub request: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            connect: Duration::from_secs(10),
+            request: Duration::from_secs(30),
+        }
+    }
+}
+
+/// How a `SignalServer` keeps its connections to the signaling server open between calls. Every
+/// announcement and poll reuses an idle connection if there is one, so a client heartbeating
+/// every few seconds pays for connecting, and for the TLS handshake, only once
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PoolConfig {
+    /// How long an idle connection is kept open for the next call, or `None` to keep it open
+    /// until the signaling server closes it
+    pub idle_timeout: Option<Duration>,
+    /// How many idle connections are kept open to the signaling server. 0 opens a new
+    /// connection for every call
+    pub max_idle: usize,
+    /// How often TCP keep-alive probes are sent on open connections, so NATs and proxies don't
+    /// drop them while they are idle, or `None` to not send any
+    pub tcp_keepalive: Option<Duration>,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        Self {
+            idle_timeout: Some(Duration::from_secs(90)),
+            max_idle: usize::MAX,
+            tcp_keepalive: Some(Duration::from_secs(30)),
+        }
+    }
+}
+
+fn build_client(
+    tls: &TlsConfig,
+    timeouts: &Timeouts,
+    pool: &PoolConfig,
+) -> AResult<reqwest::Client> {
+    let builder = reqwest::Client::builder()
+        .connect_timeout(timeouts.connect)
+        .pool_idle_timeout(pool.idle_timeout)
+        .pool_max_idle_per_host(pool.max_idle)
+        .tcp_keepalive(pool.tcp_keepalive);
+    Ok(tls.configure(builder)?.build()?)
+}
+
+/// How many peers the candidates already announced are remembered for before they are all
+/// forgotten, for clients which leave rooms without withdrawing from them
+const MAX_ANNOUNCED_PEERS: usize = 256;
+
+/// The candidates each peer announced, keyed by channel, room and peer id
+type AnnouncedCandidates = HashMap<(String, String, String), Vec<RTCIceCandidate>>;
+
+/// The session token of each peer, keyed by channel, room and peer id
+type SessionTokens = HashMap<(String, String, String), String>;
+
+#[derive(Default)]
+struct SignalingHealth {
+    consecutive_failures: AtomicU32,
+    degraded: AtomicBool,
+    pending_withdrawals: Mutex<Vec<(RoomConfig, String)>>,
+}
+
+/// An HTTP client for the `signal_server`. Clones are cheap and share the HTTP client's
+/// connections, the signaling health and what was announced, so a single client can be used
+/// from several tasks at once
+#[derive(Clone)]
+pub struct SignalServer {
+    client: reqwest::Client,
+    url: String,
+    /// The header carrying the auth token, attached to every request
+    auth: Option<(HeaderName, HeaderValue)>,
+    retry_policy: RetryPolicy,
+    outage_policy: OutagePolicy,
+    /// Whether announcements are deflated, and the peers' announcements asked for deflated
+    compression: bool,
+    tls: TlsConfig,
+    timeouts: Timeouts,
+    pool: PoolConfig,
+    health: Arc<SignalingHealth>,
+    /// The candidates each peer already announced, keyed by channel, room and peer id, so that
+    /// `broadcast_self` only sends new ones
+    announced: Arc<Mutex<AnnouncedCandidates>>,
+    /// The session token the signaling server handed out for each announced peer, keyed by
+    /// channel, room and peer id, presented on every later update of the peer
+    sessions: Arc<Mutex<SessionTokens>>,
+    /// What the signaling server supports, asked for once it is first needed
+    server_info: Arc<OnceCell<ServerInfo>>,
+}
+
+impl SignalServer {
+    /// Creates a new signaling client
+    ///
+    /// * `url` - The base url of the signaling server, e.g. `http://localhost:8000`
+    pub fn new(url: impl Into<String>) -> Self {
+        let (tls, timeouts, pool) = (
+            TlsConfig::default(),
+            Timeouts::default(),
+            PoolConfig::default(),
+        );
+        Self {
+            client: build_client(&tls, &timeouts, &pool)
+                .expect("Unable to initialize the HTTP client"),
+            url: url.into().trim_end_matches('/').to_string(),
+            auth: None,
+            retry_policy: RetryPolicy::default(),
+            outage_policy: OutagePolicy::default(),
+            compression: false,
+            tls,
+            timeouts,
+            pool,
+            health: Arc::new(SignalingHealth::default()),
+            announced: Arc::new(Mutex::new(HashMap::new())),
+            sessions: Arc::new(Mutex::new(HashMap::new())),
+            server_info: Arc::new(OnceCell::new()),
+        }
+    }
+
+    /// Uses a pre-configured `reqwest::Client` (HTTP or SOCKS5 proxies, timeouts, custom TLS, ...)
+    /// for every call to the signaling server, instead of the default client. Its own connect
+    /// timeout and TLS settings are used, while the request timeout of the `Timeouts` still
+    /// applies
+    pub fn with_http_client(mut self, client: reqwest::Client) -> Self {
+        self.client = client;
+        self
+    }
+
+    /// Sends `token` as an `Authorization: Bearer` header with every request, for signaling
+    /// servers which require an auth token. Fails if `token` contains characters which aren't
+    /// allowed in a header value
+    pub fn with_auth(self, token: impl AsRef<str>) -> AResult<Self> {
+        let mut value = HeaderValue::from_str(&format!("Bearer {}", token.as_ref()))?;
+        value.set_sensitive(true);
+        Ok(self.with_auth_header(AUTHORIZATION, value))
+    }
+
+    /// Sends `value` in the `name` header with every request, for signaling servers, or proxies
+    /// in front of them, which expect the auth token somewhere else than the `Authorization`
+    /// header. The `signal_server` also accepts it in its `AUTH_TOKEN_HEADER`
+    pub fn with_auth_header(mut self, name: HeaderName, value: HeaderValue) -> Self {
+        self.auth = Some((name, value));
+        self
+    }
+
+    /// Verifies the signaling server's certificate according to `tls`. Replaces the client
+    /// given with `with_http_client`, so build one with the certificates added to pass there
+    /// instead to configure both
+    pub fn with_tls(mut self, tls: TlsConfig) -> AResult<Self> {
+        self.tls = tls;
+        self.client = build_client(&self.tls, &self.timeouts, &self.pool)?;
+        Ok(self)
+    }
+
+    /// Sets how long calls to the signaling server may take. Defaults to 10 seconds to connect
+    /// and 30 seconds per request. Like `with_tls`, this replaces the client given with
+    /// `with_http_client`, apart from the request timeout
+    pub fn with_timeouts(mut self, timeouts: Timeouts) -> AResult<Self> {
+        self.timeouts = timeouts;
+        self.client = build_client(&self.tls, &self.timeouts, &self.pool)?;
+        Ok(self)
+    }
+
+    /// Sets how connections to the signaling server are kept open between calls. Defaults to
+    /// keeping idle connections for 90 seconds, with TCP keep-alive probes every 30 seconds.
+    /// Like `with_tls`, this replaces the client given with `with_http_client`
+    pub fn with_pool(mut self, pool: PoolConfig) -> AResult<Self> {
+        self.pool = pool;
+        self.client = build_client(&self.tls, &self.timeouts, &self.pool)?;
+        Ok(self)
+    }
+
+    /// Deflates announcements, and asks the signaling server to deflate the announcements of
+    /// other peers, which saves several KB per handshake on constrained links. Only use this with
+    /// a `signal_server` which understands deflated announcements
+    pub fn with_compression(mut self) -> Self {
+        self.compression = true;
+        self
+    }
+
+    /// Sets how failed announcements are retried. Defaults to 3 retries, backing off from 200
+    /// milliseconds
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    /// Sets how this client behaves when the signaling server becomes unavailable
+    pub fn with_outage_policy(mut self, outage_policy: OutagePolicy) -> Self {
+        self.outage_policy = outage_policy;
+        self
+    }
+
+    /// The base url of the signaling server
+    pub(crate) fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// How the signaling server's certificate is verified, see `with_tls`
+    pub(crate) fn tls(&self) -> &TlsConfig {
+        &self.tls
+    }
+
+    /// The header carrying the auth token, if one was set
+    pub(crate) fn auth(&self) -> Option<&(HeaderName, HeaderValue)> {
+        self.auth.as_ref()
+    }
+
+    /// Starts a request to `path` of the signaling server, carrying the auth token, which fails
+    /// if it takes longer than the request timeout
+    fn request(&self, method: reqwest::Method, path: &str) -> reqwest::RequestBuilder {
+        self.held_request(method, path)
+            .timeout(self.timeouts.request)
+    }
+
+    /// Starts a request to `path` which the signaling server may hold open for as long as it
+    /// likes, carrying the auth token
+    fn held_request(&self, method: reqwest::Method, path: &str) -> reqwest::RequestBuilder {
+        let request = self.client.request(method, format!("{}/{path}", self.url));
+        match &self.auth {
+            Some((name, value)) => request.header(name, value),
+            None => request,
+        }
+    }
+
+    /// What the signaling server supports, as answered on its `/version` endpoint. Asked for
+    /// once, then remembered. Servers from before the endpoint are taken to speak the first
+    /// protocol version without any of the newer capabilities
+    pub async fn server_info(&self) -> AResult<ServerInfo> {
+        let info = self
+            .server_info
+            .get_or_try_init(|| async {
+                let response = send(self.request(reqwest::Method::GET, "version")).await?;
+                if response.status() == reqwest::StatusCode::NOT_FOUND {
+                    return AResult::Ok(ServerInfo {
+                        protocol_version: 1,
+                        server_version: String::new(),
+                        capabilities: Vec::new(),
+                    });
+                }
+
+                AResult::<ServerInfo>::Ok(
+                    check_rejection(response)
+                        .await?
+                        .json::<ServerInfo>()
+                        .await?,
+                )
+            })
+            .await?;
+
+        Ok(info.clone())
+    }
+
+    /// Whether the signaling server supports `capability`, see `server_info`
+    pub async fn supports(&self, capability: Capability) -> AResult<bool> {
+        Ok(self.server_info().await?.capabilities.contains(&capability))
+    }
+
+    /// Whether announcements are deflated and fetches ask for deflated announcements. Taken to be
+    /// unsupported if the signaling server can't say, so the call itself goes through anyway
+    async fn compresses(&self) -> bool {
+        self.compression
+            && self
+                .supports(Capability::Compression)
+                .await
+                .unwrap_or(false)
+    }
+
+    /// Fails with `SignalError::Unsupported` if the signaling server lacks `capability`
+    pub(crate) async fn require(&self, capability: Capability) -> AResult<()> {
+        if !self.supports(capability).await? {
+            return Err(SignalError::Unsupported(capability).into());
+        }
+
+        Ok(())
+    }
+
+    /// Whether enough consecutive signaling calls have failed for signaling to be considered
+    /// degraded, according to the `OutagePolicy`
+    pub fn is_degraded(&self) -> bool {
+        self.health.degraded.load(Ordering::Relaxed)
+    }
+
+    /// Announces the local description and the gathered ICE candidates of `connection` to
+    /// everyone in the room. Only the candidates which weren't announced yet are sent, since the
+    /// signaling server adds them to the ones it already knows
+    pub async fn broadcast_self(
+        &self,
+        room: &RoomConfig,
+        peer_id: &str,
+        connection: &P2PConnection,
+    ) -> AResult<()> {
+        let args = BroadcastCandidateArgs {
+            candidates: connection.gathered_candidates()?,
+            session_description: connection.local_description().await,
+            sealed: None,
+            room_settings: None,
+            metadata: BTreeMap::new(),
+            signature: None,
+        };
+
+        self.announce_delta(room, peer_id, &args).await
+    }
+
+    /// Announces `args` like `announce`, leaving out the candidates `peer_id` already announced
+    /// to the room. Rooms with a secret or a signing identity are always sent everything, since
+    /// the server replaces their sealed announcement instead of adding to it, and the signature
+    /// has to cover all of it
+    pub(crate) async fn announce_delta(
+        &self,
+        room: &RoomConfig,
+        peer_id: &str,
+        args: &BroadcastCandidateArgs,
+    ) -> AResult<()> {
+        if room.announces_whole() {
+            return self.announce(room, peer_id, args).await;
+        }
+
+        let key = (room.channel.clone(), room.room.clone(), peer_id.to_owned());
+        let new_candidates = {
+            let announced = self.lock_announced();
+            let known = announced.get(&key);
+            args.candidates
+                .iter()
+                .filter(|candidate| known.is_none_or(|known| !known.contains(candidate)))
+                .cloned()
+                .collect::<Vec<_>>()
+        };
+        let delta = BroadcastCandidateArgs {
+            candidates: new_candidates.clone(),
+            ..args.clone()
+        };
+
+        let known = self.announce_known(room, peer_id, &delta).await?;
+        // The server forgot the peer, such as after its announcement went stale or the server
+        // restarted, along with the candidates which were left out
+        let resent = !known && new_candidates.len() < args.candidates.len();
+        if resent {
+            self.announce(room, peer_id, args).await?;
+        }
+
+        let mut announced = self.lock_announced();
+        if !announced.contains_key(&key) && announced.len() >= MAX_ANNOUNCED_PEERS {
+            // Forgetting only costs sending every candidate again on the next announcement
+            announced.clear();
+        }
+        let candidates = announced.entry(key).or_default();
+        if !known {
+            candidates.clear();
+        }
+        candidates.extend(if resent {
+            args.candidates.clone()
+        } else {
+            new_candidates
+        });
+        Ok(())
+    }
+
+    fn lock_announced(&self) -> std::sync::MutexGuard<'_, AnnouncedCandidates> {
+        self.announced
+            .lock()
+            .expect("Unable to aquire announced candidates lock")
+    }
+
+    fn lock_sessions(&self) -> std::sync::MutexGuard<'_, SessionTokens> {
+        self.sessions
+            .lock()
+            .expect("Unable to aquire session token lock")
+    }
+
+    /// The session token the signaling server handed out when `peer_id` was first announced to
+    /// the room, if the server hands them out. Save it to take the session back up with
+    /// `resume_session` after the client restarts
+    pub fn session_token(&self, room: &RoomConfig, peer_id: &str) -> Option<String> {
+        self.lock_sessions()
+            .get(&(room.channel.clone(), room.room.clone(), peer_id.to_owned()))
+            .cloned()
+    }
+
+    /// Presents `token` whenever the client acts as `peer_id` in the room, such as to announce
+    /// or withdraw it or to leave and take its offers and answers, taking up the session of the
+    /// client which announced it before, such as this one before it restarted. Without it, the
+    /// signaling server refuses to let anyone else act as the peer, with
+    /// `SignalError::SessionMismatch`.
+    ///
+    /// The server derives the token from the room and peer id under a secret of its own, so it
+    /// is still taken after the server restarted with the same secret, and refused with
+    /// `SignalError::SessionMismatch` otherwise. Once the server forgot the announcement, such as
+    /// after it went stale, a client announcing the peer id without a token is handed it too
+    pub fn resume_session(&self, room: &RoomConfig, peer_id: &str, token: impl Into<String>) {
+        self.lock_sessions().insert(
+            (room.channel.clone(), room.room.clone(), peer_id.to_owned()),
+            token.into(),
+        );
+    }
+
+    /// Presents the password of `room` with `request`, if it has one, which the signaling server
+    /// wants for every read of a room with a password
+    fn with_room_password(
+        &self,
+        request: reqwest::RequestBuilder,
+        room: &RoomConfig,
+    ) -> reqwest::RequestBuilder {
+        match &room.settings.password {
+            Some(password) => request.header(ROOM_PASSWORD_HEADER, password),
+            None => request,
+        }
+    }
+
+    /// Presents the session token of `peer_id` with `request`, if the client holds one
+    fn with_session(
+        &self,
+        request: reqwest::RequestBuilder,
+        room: &RoomConfig,
+        peer_id: &str,
+    ) -> reqwest::RequestBuilder {
+        match self.session_token(room, peer_id) {
+            Some(token) => request.header(SESSION_TOKEN_HEADER, token),
+            None => request,
+        }
+    }
+
+    /// Announces `args` to the room along with its settings, encrypted if the room has a secret
+    /// and signed if it has a signing identity, retrying according to the `RetryPolicy`
+    #[tracing::instrument(
+        level = "debug",
+        skip_all,
+        fields(channel = %room.channel, room = %room.room, peer_id = %peer_id)
+    )]
+    pub(crate) async fn announce(
+        &self,
+        room: &RoomConfig,
+        peer_id: &str,
+        args: &BroadcastCandidateArgs,
+    ) -> AResult<()> {
+        self.announce_known(room, peer_id, args).await.map(|_| ())
+    }
+
+    /// Announces `args` like `announce`, returning whether the signaling server already knew the
+    /// peer, in which case it kept the candidates announced before
+    async fn announce_known(
+        &self,
+        room: &RoomConfig,
+        peer_id: &str,
+        args: &BroadcastCandidateArgs,
+    ) -> AResult<bool> {
+        let mut args = room.seal(peer_id, args)?;
+        args.room_settings =
+            (room.settings != RoomSettings::default()).then(|| room.settings.clone());
+
+        let mut retry = 0;
+        loop {
+            match self.announce_once(room, peer_id, &args).await {
+                Err(err)
+                    if retry < self.retry_policy.max_retries
+                        && SignalingErrorKind::from(&err).is_retryable() =>
+                {
+                    tokio::time::sleep(self.retry_policy.backoff(retry)).await;
+                    retry += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    async fn announce_once(
+        &self,
+        room: &RoomConfig,
+        peer_id: &str,
+        args: &BroadcastCandidateArgs,
+    ) -> AResult<bool> {
+        let request = self
+            .request(reqwest::Method::POST, "announce")
+            .query(&[
+                ("channel", room.channel.as_str()),
+                ("room", room.room.as_str()),
+                ("peer_id", peer_id),
+            ])
+            .header(PROTOCOL_VERSION_HEADER, PROTOCOL_VERSION);
+        let request = self.with_session(request, room, peer_id);
+        let request = if self.compresses().await {
+            let json = serde_json::to_vec(args)?;
+            request
+                .header(CONTENT_TYPE, "application/json")
+                .header(CONTENT_ENCODING, "deflate")
+                .body(miniz_oxide::deflate::compress_to_vec_zlib(&json, 6))
+        } else {
+            request.json(args)
+        };
+        let response = send(request).await?;
+
+        // Servers from before session tokens answer with an empty body
+        let body = check_rejection(response).await?.bytes().await?;
+        if body.is_empty() {
+            return Ok(false);
+        }
+        let response: AnnounceResponse = serde_json::from_slice(&body)?;
+        self.resume_session(room, peer_id, response.session_token);
+
+        Ok(response.known)
+    }
+
+    /// Removes the announcement of `peer_id` from the room
+    #[tracing::instrument(
+        level = "debug",
+        skip_all,
+        fields(channel = %room.channel, room = %room.room, peer_id = %peer_id)
+    )]
+    pub async fn withdraw(&self, room: &RoomConfig, peer_id: &str) -> AResult<()> {
+        self.lock_announced().remove(&(
+            room.channel.clone(),
+            room.room.clone(),
+            peer_id.to_owned(),
+        ));
+
+        let request = self.request(reqwest::Method::DELETE, "announce").query(&[
+            ("channel", room.channel.as_str()),
+            ("room", room.room.as_str()),
+            ("peer_id", peer_id),
+        ]);
+        let request = self.with_session(request, room, peer_id);
+        check_rejection(send(request).await?).await?;
+
+        self.lock_sessions()
+            .remove(&(room.channel.clone(), room.room.clone(), peer_id.to_owned()));
+        Ok(())
+    }
+
+    /// Gets the ids of every peer which has announced itself in the room
+    #[tracing::instrument(
+        level = "debug",
+        skip_all,
+        fields(channel = %room.channel, room = %room.room)
+    )]
+    pub async fn get_peers(&self, room: &RoomConfig) -> AResult<Vec<String>> {
+        let request = self
+            .request(reqwest::Method::GET, "all_candidates")
+            .query(&[
+                ("channel", room.channel.as_str()),
+                ("room", room.room.as_str()),
+            ]);
+        let response = send(self.with_room_password(request, room)).await?;
+
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(Vec::new());
+        }
+
+        Ok(check_rejection(response).await?.json().await?)
+    }
+
+    /// Lists the rooms of `channel` which have peers in them, for building a room browser
+    #[tracing::instrument(level = "debug", skip(self))]
+    pub async fn list_rooms(&self, channel: &str) -> AResult<Vec<RoomInfo>> {
+        let response = send(
+            self.request(reqwest::Method::GET, "rooms")
+                .query(&[("channel", channel)]),
+        )
+        .await?;
+
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(Vec::new());
+        }
+
+        Ok(check_rejection(response).await?.json().await?)
+    }
+
+    /// Gets the session description and candidates `peer_id` announced in the room, or `None` if
+    /// it has not announced itself there. The answering side completes the handshake with them.
+    /// If the room has a secret, they are decrypted with it, and if they are signed, the signature
+    /// is checked
+    pub async fn get_peer(&self, room: &RoomConfig, peer_id: &str) -> AResult<Option<PeerSignal>> {
+        self.fetch_signal(room, peer_id, None).await
+    }
+
+    /// Gets only the candidates `peer_id` announced in the room after `since`, along with its
+    /// session description if that changed after `since`, or `None` if it has not announced
+    /// itself there. Pass the `cursor` of the previous fetch as `since`, or 0 to get everything.
+    /// In rooms with a secret, the encrypted announcement is handed out whole whenever it changed.
+    /// Rooms trusting only some signers always get everything, since only that is signed
+    pub async fn fetch_updates(
+        &self,
+        room: &RoomConfig,
+        peer_id: &str,
+        since: u64,
+    ) -> AResult<Option<PeerSignal>> {
+        self.fetch_signal(room, peer_id, Some(since)).await
+    }
+
+    #[tracing::instrument(
+        name = "fetch_peer",
+        level = "debug",
+        skip_all,
+        fields(channel = %room.channel, room = %room.room, peer_id = %peer_id, since = ?since)
+    )]
+    async fn fetch_signal(
+        &self,
+        room: &RoomConfig,
+        peer_id: &str,
+        since: Option<u64>,
+    ) -> AResult<Option<PeerSignal>> {
+        // A partial announcement can't be checked against the signature over all of it
+        let since = since.filter(|_| !room.trusted_signers.contains_key(peer_id));
+        let request = self.request(reqwest::Method::GET, "candidate").query(&[
+            ("channel", room.channel.as_str()),
+            ("room", room.room.as_str()),
+            ("candidate_id", peer_id),
+        ]);
+        let request = self.with_room_password(request, room);
+        let request = match since {
+            Some(since) => request.query(&[("since", since)]),
+            None => request,
+        };
+        let request = if self.compresses().await {
+            request.header(ACCEPT_ENCODING, "deflate")
+        } else {
+            request
+        };
+        let response = send(request).await?;
+
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+
+        let mut signal: PeerSignal = read_json(check_rejection(response).await?).await?;
+        signal.verified = room.verify_announcement(peer_id, &signal)?;
+        match &room.secret {
+            // Nothing changed since the cursor
+            Some(_) if since.is_some() && signal.sealed.is_none() => Ok(Some(signal)),
+            Some(secret) => Ok(Some(secret.open(signal)?)),
+            None => Ok(Some(signal)),
+        }
+    }
+
+    /// Leaves `description` at `path` of the signaling server for `to` alone to fetch
+    #[tracing::instrument(
+        level = "debug",
+        skip_all,
+        fields(channel = %room.channel, room = %room.room, path = %path, from = %from, to = %to)
+    )]
+    async fn send_directed(
+        &self,
+        path: &str,
+        room: &RoomConfig,
+        from: &str,
+        to: &str,
+        description: &RTCSessionDescription,
+    ) -> AResult<()> {
+        self.require(Capability::DirectedMailbox).await?;
+        let args = room.seal_directed(path, from, to, description)?;
+
+        let request = self
+            .request(reqwest::Method::POST, path)
+            .query(&[
+                ("channel", room.channel.as_str()),
+                ("room", room.room.as_str()),
+                ("from", from),
+                ("to", to),
+            ])
+            .json(&args);
+        let request = self.with_room_password(request, room);
+        let response = send(self.with_session(request, room, from)).await?;
+        check_rejection(response).await?;
+
+        Ok(())
+    }
+
+    /// Opens the Server-Sent Events of the room, or `None` if the server doesn't serve them
+    #[tracing::instrument(
+        level = "debug",
+        skip_all,
+        fields(channel = %room.channel, room = %room.room)
+    )]
+    async fn open_room_events(&self, room: &RoomConfig) -> AResult<Option<reqwest::Response>> {
+        let request = self
+            .held_request(reqwest::Method::GET, "events")
+            .query(&[
+                ("channel", room.channel.as_str()),
+                ("room", room.room.as_str()),
+            ])
+            .header(reqwest::header::ACCEPT, "text/event-stream");
+        let response = send(self.with_room_password(request, room)).await?;
+        let response = check_rejection(response).await?;
+
+        let is_event_stream = response
+            .headers()
+            .get(reqwest::header::CONTENT_TYPE)
+            .and_then(|content_type| content_type.to_str().ok())
+            .is_some_and(|content_type| content_type.starts_with("text/event-stream"));
+        Ok(is_event_stream.then_some(response))
+    }
+
+    /// Asks the signaling server for the peers in the room other than `peer_id`, which holds
+    /// the request until there is one, or it gives up and answers with none
+    #[tracing::instrument(
+        level = "debug",
+        skip_all,
+        fields(channel = %room.channel, room = %room.room, peer_id = %peer_id)
+    )]
+    async fn wait_for_peers(&self, room: &RoomConfig, peer_id: &str) -> AResult<Vec<String>> {
+        let request = self
+            .held_request(reqwest::Method::GET, "wait_for_peer")
+            .query(&[
+                ("channel", room.channel.as_str()),
+                ("room", room.room.as_str()),
+                ("peer_id", peer_id),
+            ]);
+        let response = send(self.with_room_password(request, room)).await?;
+
+        Ok(check_rejection(response).await?.json().await?)
+    }
+
+    #[tracing::instrument(
+        level = "debug",
+        skip_all,
+        fields(channel = %room.channel, room = %room.room, peer_id = %peer_id)
+    )]
+    async fn fetch_offers(&self, room: &RoomConfig, peer_id: &str) -> AResult<Vec<DirectedSignal>> {
+        self.require(Capability::DirectedMailbox).await?;
+
+        let request = self.request(reqwest::Method::GET, "offers").query(&[
+            ("channel", room.channel.as_str()),
+            ("room", room.room.as_str()),
+            ("peer_id", peer_id),
+        ]);
+        let request = self.with_room_password(request, room);
+        let response = send(self.with_session(request, room, peer_id)).await?;
+
+        let offers: Vec<DirectedSignal> = check_rejection(response).await?.json().await?;
+        offers
+            .into_iter()
+            .map(|offer| room.open_directed("offer", peer_id, offer))
+            .collect()
+    }
+
+    #[tracing::instrument(
+        level = "debug",
+        skip_all,
+        fields(channel = %room.channel, room = %room.room, from = %from, to = %to)
+    )]
+    async fn fetch_answer(
+        &self,
+        room: &RoomConfig,
+        from: &str,
+        to: &str,
+    ) -> AResult<Option<DirectedSignal>> {
+        self.require(Capability::DirectedMailbox).await?;
+
+        let request = self.request(reqwest::Method::GET, "answer").query(&[
+            ("channel", room.channel.as_str()),
+            ("room", room.room.as_str()),
+            ("from", from),
+            ("to", to),
+        ]);
+        let request = self.with_room_password(request, room);
+        let response = send(self.with_session(request, room, to)).await?;
+
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+
+        let answer: DirectedSignal = check_rejection(response).await?.json().await?;
+        Ok(Some(room.open_directed("answer", to, answer)?))
+    }
+
+    /// Records a failed signaling call, returning `true` if signaling just became degraded
+    fn record_failure(&self) -> bool {
+        let failures = self
+            .health
+            .consecutive_failures
+            .fetch_add(1, Ordering::Relaxed)
+            + 1;
+
+        failures >= self.outage_policy.failure_threshold
+            && !self.health.degraded.swap(true, Ordering::Relaxed)
+    }
+
+    /// Records a successful signaling call, returning `true` if signaling was just restored
+    fn record_success(&self) -> bool {
+        self.health.consecutive_failures.store(0, Ordering::Relaxed);
+        self.health.degraded.swap(false, Ordering::Relaxed)
+    }
+
+    fn buffer_withdrawal(&self, room: RoomConfig, peer_id: String) {
+        self.health
+            .pending_withdrawals
+            .lock()
+            .expect("Unable to aquire withdrawal lock")
+            .push((room, peer_id));
+    }
+
+    /// Sends the withdrawals buffered during an outage, keeping the ones which fail again
+    async fn flush_withdrawals(&self) {
+        let pending = std::mem::take(
+            &mut *self
+                .health
+                .pending_withdrawals
+                .lock()
+                .expect("Unable to aquire withdrawal lock"),
+        );
+
+        for (room, peer_id) in pending {
+            if self.withdraw(&room, &peer_id).await.is_err() {
+                self.buffer_withdrawal(room, peer_id);
+            }
+        }
+    }
+
+    /// Joins a room as `peer_id`, returning a handle used to announce to and discover peers in it
+    pub fn join(&self, room: RoomConfig, peer_id: impl Into<String>) -> RoomHandle<'_> {
+        RoomHandle::new(self, room, peer_id)
+    }
+
+    /// Polls the room every `interval`, yielding the id of every peer in it exactly once, as
+    /// they announce themselves. Failed polls are retried on the next interval. Unlike
+    /// `RoomHandle::discovered_peers`, the stream owns a clone of this client, so it can be moved
+    /// into a task of its own. It yields the local peer too, once it has announced itself
+    pub fn discover(
+        &self,
+        room: RoomConfig,
+        interval: Duration,
+    ) -> impl Stream<Item = String> + Send + 'static {
+        futures::stream::unfold(
+            (self.clone(), room, true, HashSet::new(), VecDeque::new()),
+            move |(server, room, mut first_poll, mut seen, mut pending)| async move {
+                loop {
+                    if let Some(peer_id) = pending.pop_front() {
+                        return Some((peer_id, (server, room, first_poll, seen, pending)));
+                    }
+
+                    if !first_poll {
+                        tokio::time::sleep(interval).await;
+                    }
+                    first_poll = false;
+
+                    let Ok(peers) = server.get_peers(&room).await else {
+                        continue;
+                    };
+                    pending.extend(
+                        peers
+                            .into_iter()
+                            .filter(|peer_id| seen.insert(peer_id.clone())),
+                    );
+                }
+            },
+        )
+    }
+}
+
+/// The largest size a deflated response may inflate to, so the server can't make us allocate
+/// without bound
+const MAX_INFLATED_SIZE: usize = 16 * 1024 * 1024;
+
+/// Sends `request` to the signaling server, logging how long it took to answer and with which
+/// status within the span of the signaling call
+async fn send(request: reqwest::RequestBuilder) -> reqwest::Result<reqwest::Response> {
+    let started = Instant::now();
+    let result = request.send().await;
+    let latency_ms = started.elapsed().as_millis() as u64;
+    match &result {
+        Ok(response) => tracing::debug!(
+            latency_ms,
+            status = response.status().as_u16(),
+            "Signaling server answered"
+        ),
+        Err(err) => tracing::debug!(latency_ms, error = %err, "Signaling request failed"),
+    }
+    result
+}
+
+/// Parses the JSON body of `response`, inflating it first if the server deflated it
+async fn read_json<T: serde::de::DeserializeOwned>(response: reqwest::Response) -> AResult<T> {
+    let deflated = response
+        .headers()
+        .get(CONTENT_ENCODING)
+        .is_some_and(|encoding| encoding.as_bytes().eq_ignore_ascii_case(b"deflate"));
+    if !deflated {
+        return Ok(response.json().await?);
+    }
+
+    let body = response.bytes().await?;
+    let body = miniz_oxide::inflate::decompress_to_vec_zlib_with_limit(&body, MAX_INFLATED_SIZE)
+        .map_err(|_| anyhow::anyhow!("Unable to inflate the response"))?;
+    Ok(serde_json::from_slice(&body)?)
+}
+
+/// Fails if the signaling server didn't answer with a success status. Refusals carrying a
+/// `SignalRejection` become the matching `SignalError`, like `SignalError::Unauthorized`, and
+/// any other failure the status error
+async fn check_rejection(response: reqwest::Response) -> AResult<reqwest::Response> {
+    if response.status().is_success() {
+        return Ok(response);
+    }
+
+    let status_error = response.error_for_status_ref().err();
+    match response.json::<SignalRejection>().await {
+        Ok(rejection) => Err(SignalError::from(rejection).into()),
+        Err(err) => Err(status_error.unwrap_or(err).into()),
+    }
+}
+
+/// Adds `args` to what a peer announced before, like the `signal_server` does: candidates add to
+/// the known ones, while the session description and metadata replace the earlier ones. For
+/// backends keeping announcements themselves
+pub(crate) fn merge_announcement(signal: &mut PeerSignal, args: BroadcastCandidateArgs) {
+    for candidate in args.candidates {
+        if !signal.candidates.contains(&candidate) {
+            signal.candidates.push(candidate);
+        }
+    }
+    signal.session_description = args.session_description;
+    signal.sealed = args.sealed;
+    signal.metadata = args.metadata;
+    signal.signature = args.signature;
+}
+
+/// A backend peers find and signal each other through. `SignalServer` signals through the
+/// `signal_server` over HTTP, implement this to signal through anything else.
+/// The methods return `impl Future`, so the trait can't be used as `dyn Signaling`. Code which
+/// signals through any backend, like `RoomHandle` and `Lobby`, is generic over it instead
+pub trait Signaling: Send + Sync {
+    /// Announces what `peer_id` has to tell the others in the room. Candidates add to the ones
+    /// it announced before, while the session description replaces its earlier one
+    fn announce(
+        &self,
+        room: &RoomConfig,
+        peer_id: &str,
+        args: &BroadcastCandidateArgs,
+    ) -> impl Future<Output = AResult<()>> + Send;
+
+    /// Removes everything `peer_id` announced from the room
+    fn withdraw(
+        &self,
+        room: &RoomConfig,
+        peer_id: &str,
+    ) -> impl Future<Output = AResult<()>> + Send;
+
+    /// The ids of every peer which has announced itself in the room
+    fn peers(&self, room: &RoomConfig) -> impl Future<Output = AResult<Vec<String>>> + Send;
+
+    /// What `peer_id` announced in the room, or `None` if it has not announced itself there
+    fn fetch(
+        &self,
+        room: &RoomConfig,
+        peer_id: &str,
+    ) -> impl Future<Output = AResult<Option<PeerSignal>>> + Send;
+
+    /// Yields the peers joining, re-announcing themselves in and leaving the room. Unless a
+    /// backend can push them, the room is polled every second, which only sees peers join and
+    /// leave
+    fn subscribe<'a>(&'a self, room: &'a RoomConfig) -> impl Stream<Item = RoomEvent> + Send + 'a {
+        room_event_stream(
+            || async { Ok(None) },
+            move || async move { self.peers(room).await.ok() },
+            || DEFAULT_POLL_INTERVAL,
+            "",
+        )
+    }
+
+    /// Announces `args` like `announce`, for backends which can leave out what `peer_id`
+    /// already announced. Announces all of it by default
+    fn announce_changes(
+        &self,
+        room: &RoomConfig,
+        peer_id: &str,
+        args: &BroadcastCandidateArgs,
+    ) -> impl Future<Output = AResult<()>> + Send {
+        self.announce(room, peer_id, args)
+    }
+
+    /// The `SignalServer` this backend is, if it is one. Rooms joined through it follow its
+    /// `OutagePolicy` and wait for peers with its long polls, while other backends never degrade
+    fn as_signal_server(&self) -> Option<&SignalServer> {
+        None
+    }
+}
+
+impl Signaling for SignalServer {
+    async fn announce(
+        &self,
+        room: &RoomConfig,
+        peer_id: &str,
+        args: &BroadcastCandidateArgs,
+    ) -> AResult<()> {
+        SignalServer::announce(self, room, peer_id, args).await
+    }
+
+    async fn withdraw(&self, room: &RoomConfig, peer_id: &str) -> AResult<()> {
+        SignalServer::withdraw(self, room, peer_id).await
+    }
+
+    async fn peers(&self, room: &RoomConfig) -> AResult<Vec<String>> {
+        self.get_peers(room).await
+    }
+
+    async fn fetch(&self, room: &RoomConfig, peer_id: &str) -> AResult<Option<PeerSignal>> {
+        self.get_peer(room, peer_id).await
+    }
+
+    /// Streams the signaling server's Server-Sent Events, polling the room every second when
+    /// it doesn't serve them
+    fn subscribe<'a>(&'a self, room: &'a RoomConfig) -> impl Stream<Item = RoomEvent> + Send + 'a {
+        room_event_stream(
+            move || self.open_room_events(room),
+            move || async move { self.get_peers(room).await.ok() },
+            || DEFAULT_POLL_INTERVAL,
+            "",
+        )
+    }
+
+    async fn announce_changes(
+        &self,
+        room: &RoomConfig,
+        peer_id: &str,
+        args: &BroadcastCandidateArgs,
+    ) -> AResult<()> {
+        self.announce_delta(room, peer_id, args).await
+    }
+
+    fn as_signal_server(&self) -> Option<&SignalServer> {
+        Some(self)
+    }
+}
+
+/// A handle to a room joined through a signaling backend, a `SignalServer` unless given another
+pub struct RoomHandle<'a, S: Signaling = SignalServer> {
+    signal_server: &'a S,
+    room: RoomConfig,
+    peer_id: String,
+    poll_interval: Duration,
+    handshake_poll_interval: Duration,
+    known_peers: HashSet<String>,
+    /// Announced along with the local peer, see `with_metadata`
+    metadata: BTreeMap<String, String>,
+    events: Option<broadcast::Sender<ClientEvent>>,
+    /// What was last announced through this handle, re-announced by `start_heartbeat`
+    announcement: Mutex<Option<BroadcastCandidateArgs>>,
+}
+
+impl<'a, S: Signaling> RoomHandle<'a, S> {
+    /// Joins `room` through `signal_server` as `peer_id`
+    pub fn new(signal_server: &'a S, room: RoomConfig, peer_id: impl Into<String>) -> Self {
+        Self {
+            signal_server,
+            room,
+            peer_id: peer_id.into(),
+            poll_interval: DEFAULT_POLL_INTERVAL,
+            handshake_poll_interval: DEFAULT_HANDSHAKE_POLL_INTERVAL,
+            known_peers: HashSet::new(),
+            metadata: BTreeMap::new(),
+            events: None,
+            announcement: Mutex::new(None),
+        }
+    }
+
+    /// Sets how often the signaling server is polled for newly announced peers. Defaults to 1
+    /// second. Every poll is jittered by up to a quarter of the interval, so large lobbies don't
+    /// poll in lockstep
+    pub fn with_poll_interval(mut self, poll_interval: Duration) -> Self {
+        self.poll_interval = poll_interval;
+        self
+    }
+
+    /// Sets how often the signaling server is polled for the peer's answer and candidates while
+    /// a handshake is pending. Defaults to 200 milliseconds, and is never slower than the poll
+    /// interval. Jittered like the poll interval
+    pub fn with_handshake_poll_interval(mut self, handshake_poll_interval: Duration) -> Self {
+        self.handshake_poll_interval = handshake_poll_interval;
+        self
+    }
+
+    /// Announces `value` under `key` along with the local peer, such as its display name,
+    /// region or version, so others can show who is in the room before connecting. Handed out
+    /// in the `metadata` of the peer's `PeerSignal`
+    pub fn with_metadata(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.metadata.insert(key.into(), value.into());
+        self
+    }
+
+    /// Emits failed signaling calls made through this handle as `ClientEvent::SignalingError`
+    pub(crate) fn with_events(mut self, events: broadcast::Sender<ClientEvent>) -> Self {
+        self.events = Some(events);
+        self
+    }
+
+    pub fn room(&self) -> &RoomConfig {
+        &self.room
+    }
+
+    pub(crate) fn signal_server(&self) -> &'a S {
+        self.signal_server
+    }
+
+    pub(crate) fn peer_id(&self) -> &str {
+        &self.peer_id
+    }
+
+    /// How long to wait before polling again while idle, jittered
+    pub(crate) fn poll_interval(&self) -> Duration {
+        jittered(self.poll_interval)
+    }
+
+    /// How long to wait before polling again while a handshake is pending, jittered
+    pub(crate) fn handshake_poll_interval(&self) -> Duration {
+        jittered(self.handshake_poll_interval.min(self.poll_interval))
+    }
+
+    /// Creates a handle to `to` for the same local peer, treating `known_peers` as already
+    /// discovered so they are never yielded by `discovered_peers`
+    pub(crate) fn migrate(
+        &self,
+        to: RoomConfig,
+        known_peers: impl IntoIterator<Item = String>,
+    ) -> RoomHandle<'a, S> {
+        RoomHandle {
+            signal_server: self.signal_server,
+            room: to,
+            peer_id: self.peer_id.clone(),
+            poll_interval: self.poll_interval,
+            handshake_poll_interval: self.handshake_poll_interval,
+            known_peers: known_peers.into_iter().collect(),
+            metadata: self.metadata.clone(),
+            events: self.events.clone(),
+            announcement: Mutex::new(None),
+        }
+    }
+
+    /// Announces `connection` to the room
+    pub async fn announce(&self, connection: &P2PConnection) -> AResult<()> {
+        let args = BroadcastCandidateArgs {
+            candidates: connection.gathered_candidates()?,
+            session_description: connection.local_description().await,
+            sealed: None,
+            room_settings: None,
+            metadata: self.metadata.clone(),
+            signature: None,
+        };
+
+        self.announce_args(args).await
+    }
+
+    /// Announces the local peer to the room without any session description, so that it can be
+    /// discovered
+    pub async fn announce_presence(&self) -> AResult<()> {
+        let args = BroadcastCandidateArgs {
+            candidates: Vec::new(),
+            session_description: None,
+            sealed: None,
+            room_settings: None,
+            metadata: self.metadata.clone(),
+            signature: None,
+        };
+
+        self.announce_args(args).await
+    }
+
+    async fn announce_args(&self, args: BroadcastCandidateArgs) -> AResult<()> {
+        *self.lock_announcement() = Some(args.clone());
+
+        let result = self
+            .signal_server
+            .announce_changes(&self.room, &self.peer_id, &args)
+            .await;
+        self.track(result, None).await
+    }
+
+    /// Announces `connection` to `room` as the local peer, such as to the room private to it and
+    /// another peer of a lobby
+    pub(crate) async fn announce_to(
+        &self,
+        room: &RoomConfig,
+        connection: &P2PConnection,
+    ) -> AResult<()> {
+        let args = BroadcastCandidateArgs {
+            candidates: connection.gathered_candidates()?,
+            session_description: connection.local_description().await,
+            sealed: None,
+            room_settings: None,
+            metadata: self.metadata.clone(),
+            signature: None,
+        };
+
+        self.signal_server
+            .announce_changes(room, &self.peer_id, &args)
+            .await
+    }
+
+    /// Re-announces what was last announced through this handle every `interval`, so that the
+    /// signaling server, which purges peers 60 seconds after they last announced themselves,
+    /// keeps the local peer in the room. Nothing is re-announced before the first announcement
+    /// or after a withdrawal. Failed re-announcements are tried again on the next beat.
+    /// Runs until the returned future is dropped
+    pub async fn start_heartbeat(&self, interval: Duration) {
+        loop {
+            tokio::time::sleep(interval).await;
+
+            let announcement = self.lock_announcement().clone();
+            if let Some(args) = announcement {
+                let result = self
+                    .signal_server
+                    .announce(&self.room, &self.peer_id, &args)
+                    .await;
+                let _ = self.track(result, Some(interval)).await;
+            }
+        }
+    }
+
+    fn lock_announcement(&self) -> std::sync::MutexGuard<'_, Option<BroadcastCandidateArgs>> {
+        self.announcement
+            .lock()
+            .expect("Unable to aquire announcement lock")
+    }
+
+    /// Withdraws the local peer's announcement from the room.
+    /// While signaling is degraded the withdrawal is buffered instead, if the `OutagePolicy`
+    /// allows it
+    pub async fn withdraw(&self) -> AResult<()> {
+        self.lock_announcement().take();
+
+        if let Some(server) = self.signal_server.as_signal_server() {
+            if server.is_degraded() && server.outage_policy.buffer_withdrawals {
+                server.buffer_withdrawal(self.room.clone(), self.peer_id.clone());
+                return Ok(());
+            }
+        }
+
+        let result = self.signal_server.withdraw(&self.room, &self.peer_id).await;
+        self.track(result, None).await
+    }
+
+    /// Waits until at least one other peer is in the room, returning the first of them. The
+    /// signaling server holds each request until there is one, so nothing is polled in the
+    /// meantime. Failed requests are retried on the poll interval
+    pub async fn wait_for_peer(&self) -> PeerInfo {
+        loop {
+            let (result, holds) = match self.signal_server.as_signal_server() {
+                Some(server) => (server.wait_for_peers(&self.room, &self.peer_id).await, true),
+                None => (self.signal_server.peers(&self.room).await, false),
+            };
+            let retry_in = self.poll_interval();
+            match self.track(result, Some(retry_in)).await {
+                Ok(peers) => {
+                    if let Some(peer_id) = peers.into_iter().find(|peer| *peer != self.peer_id) {
+                        return PeerInfo {
+                            peer_id,
+                            room: self.room.clone(),
+                            discovered_at: SystemTime::now(),
+                        };
+                    }
+                    if !holds {
+                        tokio::time::sleep(retry_in).await;
+                    }
+                }
+                Err(_) => tokio::time::sleep(retry_in).await,
+            }
+        }
+    }
+
+    /// Yields the other peers joining, re-announcing themselves in and leaving the room, as
+    /// pushed by the signaling server's Server-Sent Events. If the server doesn't serve them,
+    /// or the event stream breaks off, the room is polled on the poll interval instead, which
+    /// only sees peers join and leave
+    pub fn subscribe_room_events(&self) -> impl Stream<Item = RoomEvent> + Send + '_ {
+        let Some(server) = self.signal_server.as_signal_server() else {
+            return self
+                .signal_server
+                .subscribe(&self.room)
+                .filter(|event| {
+                    let (RoomEvent::PeerJoined { peer_id }
+                    | RoomEvent::PeerUpdated { peer_id }
+                    | RoomEvent::PeerLeft { peer_id }) = event;
+                    futures::future::ready(*peer_id != self.peer_id)
+                })
+                .right_stream();
+        };
+
+        room_event_stream(
+            move || server.open_room_events(&self.room),
+            move || async move {
+                let result = server.get_peers(&self.room).await;
+                self.track(result, Some(self.discovery_interval()))
+                    .await
+                    .ok()
+            },
+            || self.discovery_interval(),
+            &self.peer_id,
+        )
+        .left_stream()
+    }
+
+    /// Updates the signaling health with the result of a signaling call, emitting the
+    /// corresponding events
+    async fn track<T>(&self, result: AResult<T>, retry_in: Option<Duration>) -> AResult<T> {
+        let server = self.signal_server.as_signal_server();
+        match &result {
+            Ok(_) => {
+                if let Some(server) = server.filter(|server| server.record_success()) {
+                    self.emit(ClientEvent::SignalingRestored);
+                    server.flush_withdrawals().await;
+                }
+            }
+            Err(err) => {
+                self.emit(ClientEvent::SignalingError {
+                    room: self.room.clone(),
+                    kind: err.into(),
+                    retry_in,
+                });
+                if server.is_some_and(|server| server.record_failure()) {
+                    self.emit(ClientEvent::SignalingDegraded);
+                }
+            }
+        }
+
+        result
+    }
+
+    fn emit(&self, event: ClientEvent) {
+        if let Some(events) = &self.events {
+            let _ = events.send(event);
+        }
+    }
+
+    /// How long discovery waits between polls of the signaling server
+    fn discovery_interval(&self) -> Duration {
+        match self.signal_server.as_signal_server() {
+            Some(server) if server.is_degraded() && server.outage_policy.pause_discovery => {
+                jittered(server.outage_policy.probe_interval)
+            }
+            _ => self.poll_interval(),
+        }
+    }
+
+    /// Yields every other peer in the room exactly once, as they announce themselves.
+    /// Failed polls of the signaling server are retried on the next poll interval
+    pub fn discovered_peers(&self) -> impl Stream<Item = PeerInfo> + '_ {
+        futures::stream::unfold(
+            (true, self.known_peers.clone(), VecDeque::new()),
+            move |(mut first_poll, mut seen, mut pending)| async move {
+                loop {
+                    if let Some(peer) = pending.pop_front() {
+                        return Some((peer, (first_poll, seen, pending)));
+                    }
+
+                    if !first_poll {
+                        tokio::time::sleep(self.discovery_interval()).await;
+                    }
+                    first_poll = false;
+
+                    let result = self.signal_server.peers(&self.room).await;
+                    let retry_in = self.discovery_interval();
+                    let Ok(peers) = self.track(result, Some(retry_in)).await else {
+                        continue;
+                    };
+
+                    for peer_id in peers {
+                        if peer_id != self.peer_id && seen.insert(peer_id.clone()) {
+                            pending.push_back(PeerInfo {
+                                peer_id,
+                                room: self.room.clone(),
+                                discovered_at: SystemTime::now(),
+                            });
+                        }
+                    }
+                }
+            },
+        )
+    }
+}
+
+impl RoomHandle<'_, SignalServer> {
+    /// Leaves `offer` for `peer_id` alone, which picks it up with `fetch_offers_for_me`.
+    /// A newer offer replaces one the peer hasn't fetched yet. Like the other mailbox calls, the
+    /// local peer has to be announced first, since the server only lets the client holding its
+    /// session act as it
+    pub async fn send_offer_to(&self, peer_id: &str, offer: &RTCSessionDescription) -> AResult<()> {
+        let result = self
+            .signal_server
+            .send_directed("offer", &self.room, &self.peer_id, peer_id, offer)
+            .await;
+        self.track(result, None).await
+    }
+
+    /// Takes the offers other peers left for the local peer since the last fetch
+    pub async fn fetch_offers_for_me(&self) -> AResult<Vec<DirectedSignal>> {
+        let result = self
+            .signal_server
+            .fetch_offers(&self.room, &self.peer_id)
+            .await;
+        self.track(result, None).await
+    }
+
+    /// Leaves `answer` for `peer_id` alone, which picks it up with `fetch_answer_from`
+    pub async fn send_answer_to(
+        &self,
+        peer_id: &str,
+        answer: &RTCSessionDescription,
+    ) -> AResult<()> {
+        let result = self
+            .signal_server
+            .send_directed("answer", &self.room, &self.peer_id, peer_id, answer)
+            .await;
+        self.track(result, None).await
+    }
+
+    /// Takes the answer `peer_id` left for the local peer, or `None` if it hasn't answered yet
+    pub async fn fetch_answer_from(&self, peer_id: &str) -> AResult<Option<DirectedSignal>> {
+        let result = self
+            .signal_server
+            .fetch_answer(&self.room, peer_id, &self.peer_id)
+            .await;
+        self.track(result, None).await
+    }
+}
+
+/// An event which hasn't ended within this many bytes is taken for a broken stream, rather than
+/// buffered without bound
+const MAX_SSE_EVENT_SIZE: usize = 64 * 1024;
+
+/// How long the room is polled for after its event stream failed to open or broke off, before
+/// it is opened again. Waits twice as long after each failure in a row
+const SSE_REOPEN_POLICY: RetryPolicy = RetryPolicy {
+    max_retries: u32::MAX,
+    initial_backoff: Duration::from_secs(1),
+    max_backoff: Duration::from_secs(60),
+};
+
+/// Yields the events of a room, leaving out the ones about `local_id`. They are streamed from
+/// the Server-Sent Events `open` opens, or, if it can't open them or they break off, found by
+/// calling `poll` for the peers in the room every `interval`, which only sees peers join and
+/// leave. Failed polls yield `None`, and are tried again on the next interval. Events which
+/// failed to open or broke off are opened again after a backoff, while those `open` doesn't
+/// have at all are never tried again
+pub(crate) fn room_event_stream<'a, O, OF, P, PF, I>(
+    open: O,
+    poll: P,
+    interval: I,
+    local_id: &'a str,
+) -> impl Stream<Item = RoomEvent> + Send + 'a
+where
+    O: Fn() -> OF + Send + 'a,
+    OF: Future<Output = AResult<Option<reqwest::Response>>> + Send,
+    P: Fn() -> PF + Send + 'a,
+    PF: Future<Output = Option<Vec<String>>> + Send,
+    I: Fn() -> Duration + Send + 'a,
+{
+    resumed_room_event_stream(HashSet::new(), open, poll, interval, local_id)
+}
+
+/// Yields the events of a room like `room_event_stream`, to a subscriber which already knows
+/// the peers in `known` to be in it, such as from events streamed before. Only changes to them
+/// are yielded
+pub(crate) fn resumed_room_event_stream<'a, O, OF, P, PF, I>(
+    known: HashSet<String>,
+    open: O,
+    poll: P,
+    interval: I,
+    local_id: &'a str,
+) -> impl Stream<Item = RoomEvent> + Send + 'a
+where
+    O: Fn() -> OF + Send + 'a,
+    OF: Future<Output = AResult<Option<reqwest::Response>>> + Send,
+    P: Fn() -> PF + Send + 'a,
+    PF: Future<Output = Option<Vec<String>>> + Send,
+    I: Fn() -> Duration + Send + 'a,
+{
+    let state = RoomEvents {
+        source: EventSource::Connecting,
+        known,
+        pending: VecDeque::new(),
+        reopen_failures: 0,
+    };
+    futures::stream::unfold(
+        (state, open, poll, interval),
+        move |(mut state, open, poll, interval)| async move {
+            loop {
+                if let Some(event) = state.pending.pop_front() {
+                    return Some((event, (state, open, poll, interval)));
+                }
+
+                match &mut state.source {
+                    EventSource::Connecting => match open().await {
+                        Ok(Some(response)) => {
+                            state.source = EventSource::Streaming {
+                                response,
+                                buffer: Vec::new(),
+                            };
+                        }
+                        Ok(None) => {
+                            state.source = EventSource::Polling {
+                                first_poll: true,
+                                reopen_at: None,
+                            };
+                        }
+                        // Polled right away when the stream was never open, so the peers
+                        // already in the room are found without waiting
+                        Err(_) => state.fall_back_to_polling(state.reopen_failures == 0),
+                    },
+                    EventSource::Streaming { response, buffer } => {
+                        let Ok(Some(chunk)) = response.chunk().await else {
+                            state.fall_back_to_polling(false);
+                            continue;
+                        };
+                        buffer.extend_from_slice(&chunk);
+                        let events = drain_sse_events(buffer);
+                        let overflowed = buffer.len() > MAX_SSE_EVENT_SIZE;
+                        for event in events {
+                            state.push(event, local_id);
+                        }
+                        if overflowed {
+                            state.fall_back_to_polling(false);
+                        } else {
+                            state.reopen_failures = 0;
+                        }
+                    }
+                    EventSource::Polling {
+                        first_poll,
+                        reopen_at,
+                    } => {
+                        if !*first_poll {
+                            tokio::time::sleep(interval()).await;
+                        }
+                        *first_poll = false;
+                        let reopen = reopen_at.is_some_and(|at| at <= Instant::now());
+
+                        if let Some(peers) = poll().await {
+                            state.diff(peers, local_id);
+                        }
+                        if reopen {
+                            state.source = EventSource::Connecting;
+                        }
+                    }
+                }
+            }
+        },
+    )
+}
+
+/// Where `RoomHandle::subscribe_room_events` gets its events from
+enum EventSource {
+    Connecting,
+    Streaming {
+        response: reqwest::Response,
+        /// What arrived of an event which isn't complete yet, kept as bytes since a chunk may
+        /// end in the middle of a character
+        buffer: Vec<u8>,
+    },
+    Polling {
+        first_poll: bool,
+        /// When to open the events again, unless they aren't to be
+        reopen_at: Option<Instant>,
+    },
+}
+
+struct RoomEvents {
+    source: EventSource,
+    /// The peers which have joined and not left yet
+    known: HashSet<String>,
+    pending: VecDeque<RoomEvent>,
+    /// How many times in a row the events failed to open or broke off, without anything
+    /// arriving over them
+    reopen_failures: u32,
+}
+
+impl RoomEvents {
+    /// Polls the room until the events are opened again, after a backoff growing with every
+    /// failure in a row
+    fn fall_back_to_polling(&mut self, first_poll: bool) {
+        let reopen_at = Instant::now() + SSE_REOPEN_POLICY.backoff(self.reopen_failures);
+        self.reopen_failures = self.reopen_failures.saturating_add(1);
+        self.source = EventSource::Polling {
+            first_poll,
+            reopen_at: Some(reopen_at),
+        };
+    }
+
+    /// Queues `event`, unless it is about the local peer or doesn't change anything
+    fn push(&mut self, event: RoomEvent, local_id: &str) {
+        let changed = match &event {
+            RoomEvent::PeerJoined { peer_id } => {
+                peer_id != local_id && self.known.insert(peer_id.clone())
+            }
+            RoomEvent::PeerUpdated { peer_id } if peer_id != local_id => {
+                self.known.insert(peer_id.clone());
+                true
+            }
+            RoomEvent::PeerUpdated { .. } => false,
+            RoomEvent::PeerLeft { peer_id } => self.known.remove(peer_id),
+        };
+        if changed {
+            self.pending.push_back(event);
+        }
+    }
+
+    /// Queues the peers which joined or left since the last poll, which found `peers` in the
+    /// room
+    fn diff(&mut self, peers: Vec<String>, local_id: &str) {
+        let peers = peers.into_iter().collect::<HashSet<_>>();
+        let left = self.known.difference(&peers).cloned().collect::<Vec<_>>();
+        for peer_id in left {
+            self.push(RoomEvent::PeerLeft { peer_id }, local_id);
+        }
+        for peer_id in peers {
+            self.push(RoomEvent::PeerJoined { peer_id }, local_id);
+        }
+    }
+}
+
+/// Takes the complete Server-Sent Events out of `buffer`, parsing the JSON in their data. Lines
+/// may end with "\r\n", "\n" or "\r", and an event ends with a blank line
+fn drain_sse_events(buffer: &mut Vec<u8>) -> Vec<RoomEvent> {
+    let mut events = Vec::new();
+    let mut data = Vec::new();
+    // Where the next line starts, and where the last complete event ended
+    let (mut start, mut consumed) = (0, 0);
+    while let Some(end) = buffer[start..]
+        .iter()
+        .position(|byte| matches!(byte, b'\r' | b'\n'))
+        .map(|offset| start + offset)
+    {
+        let next = match (buffer[end], buffer.get(end + 1)) {
+            (b'\r', Some(b'\n')) => end + 2,
+            // The "\n" of a "\r\n" may not have arrived yet
+            (b'\r', None) => break,
+            _ => end + 1,
+        };
+        let line = &buffer[start..end];
+        start = next;
+
+        if line.is_empty() {
+            if let Ok(event) = serde_json::from_slice(&data.join(&b'\n')) {
+                events.push(event);
+            }
+            data.clear();
+            consumed = start;
+        } else if let Some(value) = line.strip_prefix(b"data:") {
+            data.push(value.strip_prefix(b" ").unwrap_or(value));
+        }
+    }
+    buffer.drain(..consumed);
+    events
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::p2p_client::P2PClient;
+    use futures::StreamExt;
+    use signal_server::server::ServerConfig;
+    use std::net::{Ipv4Addr, TcpListener};
+    use std::sync::atomic::AtomicUsize;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use uuid::Uuid;
+    use webrtc::peer_connection::sdp::sdp_type::RTCSdpType;
+
+    pub(crate) fn free_port() -> AResult<u16> {
+        Ok(TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?
+            .local_addr()?
+            .port())
+    }
+
+    /// Launches an in-process `signal_server` on a free local port, returning its base url
+    pub(crate) async fn spawn_signal_server() -> AResult<String> {
+        spawn_signal_server_on(free_port()?).await
+    }
+
+    /// Launches an in-process `signal_server` on `port`, returning its base url
+    pub(crate) async fn spawn_signal_server_on(port: u16) -> AResult<String> {
+        spawn_configured_signal_server(port, ServerConfig::default()).await
+    }
+
+    /// Launches an in-process `signal_server` enforcing `server_config` on `port`, returning its
+    /// base url
+    pub(crate) async fn spawn_configured_signal_server(
+        port: u16,
+        server_config: ServerConfig,
+    ) -> AResult<String> {
+        let mut config = rocket::Config::debug_default();
+        config.address = Ipv4Addr::LOCALHOST.into();
+        config.port = port;
+        config.log_level = rocket::config::LogLevel::Off;
+        config.shutdown.ctrlc = false;
+
+        let rocket = signal_server::server::build_with(server_config).configure(config);
+        tokio::spawn(rocket.launch());
+
+        let url = format!("http://127.0.0.1:{port}");
+        let server = SignalServer::new(url.as_str());
+        let room = RoomConfig::new("ping", "ping");
+        for _ in 0..100 {
+            // Servers requiring an auth token refuse us, but they are up
+            match server.get_peers(&room).await {
+                Ok(_) => return Ok(url),
+                Err(err) if SignalingErrorKind::from(&err) != SignalingErrorKind::Network => {
+                    return Ok(url)
+                }
+                Err(_) => {}
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+
+        Err(anyhow::anyhow!("Signal server never became reachable"))
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_discovered_peers_are_deduplicated() -> AResult<()> {
+        let server = SignalServer::new(spawn_signal_server().await?);
+        let room = RoomConfig::new("test", Uuid::new_v4().to_string());
+
+        let client = P2PClient::default();
+        let connection = P2PConnection::new(&client, true).await?;
+        connection.get_offer().await?;
+
+        let local_id = Uuid::new_v4().to_string();
+        let remote_id = Uuid::new_v4().to_string();
+
+        let local = server
+            .join(room.clone(), local_id.as_str())
+            .with_poll_interval(Duration::from_millis(20));
+        let remote = server.join(room.clone(), remote_id.as_str());
+
+        local.announce(&connection).await?;
+        remote.announce(&connection).await?;
+        remote.announce(&connection).await?;
+
+        let peers = local.discovered_peers();
+        futures::pin_mut!(peers);
+
+        let peer = tokio::time::timeout(Duration::from_secs(5), peers.next())
+            .await?
+            .expect("Stream should not end");
+        assert_eq!(peer.peer_id, remote_id);
+        assert_eq!(peer.room, room);
+
+        assert!(
+            tokio::time::timeout(Duration::from_millis(200), peers.next())
+                .await
+                .is_err(),
+            "Peers should only be yielded once"
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_get_peer() -> AResult<()> {
+        let server = SignalServer::new(spawn_signal_server().await?);
+        let room = RoomConfig::new("test", Uuid::new_v4().to_string());
+        let peer_id = Uuid::new_v4().to_string();
+
+        assert!(server.get_peer(&room, &peer_id).await?.is_none());
+
+        let client = P2PClient::default();
+        let connection = P2PConnection::new(&client, true).await?;
+        connection.get_offer().await?;
+        server.broadcast_self(&room, &peer_id, &connection).await?;
+
+        let signal = server
+            .get_peer(&room, &peer_id)
+            .await?
+            .expect("The peer should have announced itself");
+        assert_eq!(
+            signal
+                .session_description
+                .map(|description| description.sdp_type),
+            Some(RTCSdpType::Offer)
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_peer_metadata_is_handed_out() -> AResult<()> {
+        let server = SignalServer::new(spawn_signal_server().await?);
+        let room = RoomConfig::new("test", Uuid::new_v4().to_string());
+        let secret_room = room.clone().with_secret("hunter2");
+        let peer_id = Uuid::new_v4().to_string();
+
+        for room in [room, secret_room] {
+            server
+                .join(room.clone(), peer_id.clone())
+                .with_metadata("display_name", "Alice")
+                .with_metadata("region", "eu-west")
+                .announce_presence()
+                .await?;
+
+            let signal = server.get_peer(&room, &peer_id).await?.unwrap();
+            assert_eq!(
+                signal.metadata.get("display_name").map(String::as_str),
+                Some("Alice")
+            );
+            assert_eq!(
+                signal.metadata.get("region").map(String::as_str),
+                Some("eu-west")
+            );
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_directed_offer_and_answer() -> AResult<()> {
+        let url = spawn_signal_server().await?;
+        let server = SignalServer::new(url.as_str());
+        let room = RoomConfig::new("test", Uuid::new_v4().to_string());
+        let (offerer_id, answerer_id, bystander_id) = (
+            Uuid::new_v4().to_string(),
+            Uuid::new_v4().to_string(),
+            Uuid::new_v4().to_string(),
+        );
+        let offerer = server.join(room.clone(), offerer_id.as_str());
+        let answerer = server.join(room.clone(), answerer_id.as_str());
+        let bystander = server.join(room.clone(), bystander_id.as_str());
+        for handle in [&offerer, &answerer, &bystander] {
+            handle.announce_presence().await?;
+        }
+
+        let client = P2PClient::default();
+        let connection1 = P2PConnection::new(&client, true).await?;
+        let connection2 = P2PConnection::new(&client, true).await?;
+
+        offerer
+            .send_offer_to(&answerer_id, &connection1.get_offer().await?)
+            .await?;
+        assert!(bystander.fetch_offers_for_me().await?.is_empty());
+        assert!(offerer.fetch_answer_from(&answerer_id).await?.is_none());
+
+        // Nobody else can act as the answerer, such as to take its offers
+        let spoofer = SignalServer::new(url.as_str());
+        let err = spoofer
+            .join(room, answerer_id.as_str())
+            .fetch_offers_for_me()
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<SignalError>(),
+            Some(&SignalError::SessionMismatch)
+        );
+
+        let offers = answerer.fetch_offers_for_me().await?;
+        assert_eq!(offers.len(), 1);
+        assert_eq!(offers[0].from, offerer_id);
+        // Fetching takes the offers out of the mailbox
+        assert!(answerer.fetch_offers_for_me().await?.is_empty());
+
+        let answer = connection2
+            .get_answer(offers[0].session_description.clone().unwrap())
+            .await?;
+        answerer.send_answer_to(&offerer_id, &answer).await?;
+
+        let answer = offerer
+            .fetch_answer_from(&answerer_id)
+            .await?
+            .expect("The peer should have answered");
+        assert_eq!(answer.from, answerer_id);
+        let answer = answer.session_description.unwrap();
+        assert_eq!(answer.sdp_type, RTCSdpType::Answer);
+        connection1.set_answer(answer).await?;
+        assert!(offerer.fetch_answer_from(&answerer_id).await?.is_none());
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_mailboxes_are_capped() -> AResult<()> {
+        let url = spawn_configured_signal_server(
+            free_port()?,
+            ServerConfig {
+                max_mail_per_peer: 1,
+                max_mail_per_room: 2,
+                ..Default::default()
+            },
+        )
+        .await?;
+        let server = SignalServer::new(url.as_str());
+        let room = RoomConfig::new("test", Uuid::new_v4().to_string());
+        let ids = [(); 3].map(|_| Uuid::new_v4().to_string());
+        let [first, second, third] = ids
+            .each_ref()
+            .map(|peer_id| server.join(room.clone(), peer_id.as_str()));
+        for handle in [&first, &second, &third] {
+            handle.announce_presence().await?;
+        }
+
+        let client = P2PClient::default();
+        let offer = P2PConnection::new(&client, true).await?.get_offer().await?;
+        let mailbox_full = |result: AResult<()>| {
+            result.unwrap_err().downcast_ref::<SignalError>() == Some(&SignalError::MailboxFull)
+        };
+
+        first.send_offer_to(&ids[2], &offer).await?;
+        // A newer offer replaces the waiting one instead of adding to it
+        first.send_offer_to(&ids[2], &offer).await?;
+        assert!(mailbox_full(second.send_offer_to(&ids[2], &offer).await));
+
+        second.send_offer_to(&ids[0], &offer).await?;
+        assert!(mailbox_full(third.send_answer_to(&ids[1], &offer).await));
+
+        // Fetching makes room again
+        assert_eq!(third.fetch_offers_for_me().await?.len(), 1);
+        third.send_answer_to(&ids[1], &offer).await?;
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_directed_signals_are_sealed_in_secret_rooms() -> AResult<()> {
+        let server = SignalServer::new(spawn_signal_server().await?);
+        let room = RoomConfig::new("test", Uuid::new_v4().to_string()).with_secret("hunter2");
+        let (offerer_id, answerer_id) = (Uuid::new_v4().to_string(), Uuid::new_v4().to_string());
+        let offerer = server.join(room.clone(), offerer_id.as_str());
+        let answerer = server.join(room.clone(), answerer_id.as_str());
+        offerer.announce_presence().await?;
+        answerer.announce_presence().await?;
+
+        let client = P2PClient::default();
+        let offer = P2PConnection::new(&client, true).await?.get_offer().await?;
+
+        // The signaling server only sees the encrypted offer
+        let sealed = room.seal_directed("offer", &offerer_id, &answerer_id, &offer)?;
+        assert!(sealed.session_description.is_none() && sealed.sealed.is_some());
+
+        offerer.send_offer_to(&answerer_id, &offer).await?;
+        let offers = answerer.fetch_offers_for_me().await?;
+        assert_eq!(offers.len(), 1);
+        assert_eq!(
+            offers[0]
+                .session_description
+                .as_ref()
+                .map(|offer| &offer.sdp),
+            Some(&offer.sdp)
+        );
+
+        // Peers with another secret can't open it
+        offerer.send_offer_to(&answerer_id, &offer).await?;
+        let wrong = server.join(room.with_secret("wrong"), answerer_id.as_str());
+        assert!(wrong.fetch_offers_for_me().await.is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_room_events() -> AResult<()> {
+        let server = SignalServer::new(spawn_signal_server().await?);
+        let room = RoomConfig::new("test", Uuid::new_v4().to_string());
+        let (local_id, remote_id) = (Uuid::new_v4().to_string(), Uuid::new_v4().to_string());
+
+        let local = server.join(room.clone(), local_id.as_str());
+        let remote = server.join(room, remote_id.as_str());
+        local.announce_presence().await?;
+
+        let events = local.subscribe_room_events();
+        futures::pin_mut!(events);
+        let timeout = Duration::from_secs(5);
+
+        remote.announce_presence().await?;
+        assert_eq!(
+            tokio::time::timeout(timeout, events.next()).await?,
+            Some(RoomEvent::PeerJoined {
+                peer_id: remote_id.clone()
+            })
+        );
+        remote.announce_presence().await?;
+        assert_eq!(
+            tokio::time::timeout(timeout, events.next()).await?,
+            Some(RoomEvent::PeerUpdated {
+                peer_id: remote_id.clone()
+            })
+        );
+        remote.withdraw().await?;
+        assert_eq!(
+            tokio::time::timeout(timeout, events.next()).await?,
+            Some(RoomEvent::PeerLeft { peer_id: remote_id })
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_wait_for_peer() -> AResult<()> {
+        let server = SignalServer::new(spawn_signal_server().await?);
+        let room = RoomConfig::new("test", Uuid::new_v4().to_string());
+        let (local_id, remote_id) = (Uuid::new_v4().to_string(), Uuid::new_v4().to_string());
+
+        let local = server.join(room.clone(), local_id.as_str());
+        let remote = server.join(room.clone(), remote_id.as_str());
+        local.announce_presence().await?;
+
+        let waiting = local.wait_for_peer();
+        futures::pin_mut!(waiting);
+        assert!(
+            tokio::time::timeout(Duration::from_millis(200), &mut waiting)
+                .await
+                .is_err(),
+            "Nobody else is in the room yet"
+        );
+
+        remote.announce_presence().await?;
+        let peer = tokio::time::timeout(Duration::from_secs(5), waiting).await?;
+        assert_eq!(peer.peer_id, remote_id);
+        assert_eq!(peer.room, room);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_room_events_from_polls() {
+        let mut events = RoomEvents {
+            source: EventSource::Polling {
+                first_poll: true,
+                reopen_at: None,
+            },
+            known: HashSet::new(),
+            pending: VecDeque::new(),
+            reopen_failures: 0,
+        };
+        let peers = |ids: &[&str]| ids.iter().map(|id| id.to_string()).collect::<Vec<_>>();
+
+        events.diff(peers(&["local", "a"]), "local");
+        events.diff(peers(&["local", "a", "b"]), "local");
+        events.diff(peers(&["local", "b"]), "local");
+        assert_eq!(
+            Vec::from(events.pending),
+            [
+                RoomEvent::PeerJoined {
+                    peer_id: "a".to_owned()
+                },
+                RoomEvent::PeerJoined {
+                    peer_id: "b".to_owned()
+                },
+                RoomEvent::PeerLeft {
+                    peer_id: "a".to_owned()
+                },
+            ]
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_overlong_sse_events_fall_back_to_polling_and_reopen() -> AResult<()> {
+        // Every stream it serves sends more than an event may hold, without ever ending a line
+        let listener = tokio::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+        let url = format!("http://{}", listener.local_addr()?);
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let mut request = [0; 1024];
+                    let _ = socket.read(&mut request).await;
+                    let _ = socket
+                        .write_all(b"HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\n\r\n")
+                        .await;
+                    let _ = socket.write_all(&[b'a'; MAX_SSE_EVENT_SIZE + 1]).await;
+                    tokio::time::sleep(Duration::from_secs(60)).await;
+                });
+            }
+        });
+
+        let opened = AtomicUsize::new(0);
+        let events = room_event_stream(
+            || {
+                opened.fetch_add(1, Ordering::Relaxed);
+                let url = url.clone();
+                async move { Ok(Some(reqwest::get(url).await?)) }
+            },
+            || async { Some(vec!["remote".to_owned()]) },
+            || Duration::from_millis(20),
+            "local",
+        );
+        futures::pin_mut!(events);
+
+        assert_eq!(
+            tokio::time::timeout(Duration::from_secs(5), events.next()).await?,
+            Some(RoomEvent::PeerJoined {
+                peer_id: "remote".to_owned()
+            })
+        );
+        // The events are opened again once the backoff is over, while the room is polled
+        let reopened = tokio::time::timeout(Duration::from_secs(5), async {
+            while opened.load(Ordering::Relaxed) < 2 {
+                let _ = tokio::time::timeout(Duration::from_millis(50), events.next()).await;
+            }
+        })
+        .await;
+        assert!(reopened.is_ok());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_sse_events_split_across_chunks() {
+        let stream = concat!(
+            "data: {\"type\":\"peer_joined\",\"peer_id\":\"пир\"}\r\r",
+            ": keep-alive\r\n\r\n",
+            "data: {\"type\":\"peer_left\",\"peer_id\":\"пир\"}\n\n",
+        );
+        let joined = RoomEvent::PeerJoined {
+            peer_id: "пир".to_owned(),
+        };
+        let left = RoomEvent::PeerLeft {
+            peer_id: "пир".to_owned(),
+        };
+
+        // Split between every byte, in the middle of characters and of "\r\n"s
+        for split in 1..stream.len() {
+            let mut buffer = Vec::new();
+            let mut events = Vec::new();
+            for chunk in [&stream.as_bytes()[..split], &stream.as_bytes()[split..]] {
+                buffer.extend_from_slice(chunk);
+                events.extend(drain_sse_events(&mut buffer));
+            }
+            assert_eq!(events, [joined.clone(), left.clone()], "split at {split}");
+            assert!(buffer.is_empty());
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_signaling_errors_are_emitted() -> AResult<()> {
+        let server = SignalServer::new(format!("http://127.0.0.1:{}", free_port()?))
+            .with_retry_policy(RetryPolicy::none());
+        let room = RoomConfig::new("test", Uuid::new_v4().to_string());
+        let poll_interval = Duration::from_millis(20);
+
+        let client = P2PClient::default();
+        let mut events = client.events();
+        let handle = client
+            .join_room(&server, room.clone())
+            .with_poll_interval(poll_interval);
+
+        let connection = P2PConnection::new(&client, true).await?;
+        assert!(handle.announce(&connection).await.is_err());
+        assert_eq!(
+            events.recv().await?,
+            ClientEvent::SignalingError {
+                room: room.clone(),
+                kind: SignalingErrorKind::Network,
+                retry_in: None,
+            }
+        );
+
+        let peers = handle.discovered_peers();
+        futures::pin_mut!(peers);
+        let _ = tokio::time::timeout(Duration::from_millis(100), peers.next()).await;
+        let ClientEvent::SignalingError {
+            room: failed_room,
+            kind,
+            retry_in: Some(retry_in),
+        } = events.recv().await?
+        else {
+            panic!("Expected a signaling error with a retry");
+        };
+        assert_eq!(failed_room, room);
+        assert_eq!(kind, SignalingErrorKind::Network);
+        // The poll interval is jittered by up to a quarter either way
+        assert!(retry_in >= poll_interval * 3 / 4 && retry_in <= poll_interval * 5 / 4);
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_outage_degrades_and_restores() -> AResult<()> {
+        let port = free_port()?;
+        let server = SignalServer::new(format!("http://127.0.0.1:{port}"))
+            .with_retry_policy(RetryPolicy::none())
+            .with_outage_policy(OutagePolicy {
+                failure_threshold: 1,
+                probe_interval: Duration::from_millis(50),
+                ..Default::default()
+            });
+        let room = RoomConfig::new("test", Uuid::new_v4().to_string());
+
+        let client = P2PClient::default();
+        let mut events = client.events();
+        let handle = client.join_room(&server, room.clone());
+
+        let connection = P2PConnection::new(&client, true).await?;
+        connection.get_offer().await?;
+
+        assert!(handle.announce(&connection).await.is_err());
+        assert!(matches!(
+            events.recv().await?,
+            ClientEvent::SignalingError { .. }
+        ));
+        assert_eq!(events.recv().await?, ClientEvent::SignalingDegraded);
+        assert!(server.is_degraded());
+
+        // Buffered until signaling is restored
+        handle.withdraw().await?;
+
+        // The same peer is announced from elsewhere meanwhile, so both share its session
+        let url = spawn_signal_server_on(port).await?;
+        let healthy = SignalServer::new(url);
+        healthy
+            .broadcast_self(&room, &client.peer_id(), &connection)
+            .await?;
+        let token = healthy
+            .session_token(&room, &client.peer_id())
+            .expect("Handed out");
+        server.resume_session(&room, &client.peer_id(), token);
+
+        let peers = handle.discovered_peers();
+        futures::pin_mut!(peers);
+        let _ = tokio::time::timeout(Duration::from_millis(200), peers.next()).await;
+
+        assert_eq!(events.recv().await?, ClientEvent::SignalingRestored);
+        assert!(!server.is_degraded());
+        assert!(!healthy.get_peers(&room).await?.contains(&client.peer_id()));
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_clones_are_shared_across_tasks() -> AResult<()> {
+        fn assert_shareable<T: Clone + Send + Sync + 'static>() {}
+        assert_shareable::<SignalServer>();
+
+        let server = SignalServer::new(spawn_signal_server().await?);
+        let room = RoomConfig::new("test", Uuid::new_v4().to_string());
+        let peer_ids = (0..4)
+            .map(|_| Uuid::new_v4().to_string())
+            .collect::<Vec<_>>();
+
+        let announcements = peer_ids.iter().cloned().map(|peer_id| {
+            let (server, room) = (server.clone(), room.clone());
+            tokio::spawn(async move {
+                let args = BroadcastCandidateArgs {
+                    candidates: vec![RTCIceCandidate::default()],
+                    session_description: None,
+                    sealed: None,
+                    room_settings: None,
+                    metadata: BTreeMap::new(),
+                    signature: None,
+                };
+                server.announce_delta(&room, &peer_id, &args).await
+            })
+        });
+        for announcement in futures::future::join_all(announcements).await {
+            announcement??;
+        }
+
+        let mut peers = server.get_peers(&room).await?;
+        peers.sort();
+        let mut expected = peer_ids.clone();
+        expected.sort();
+        assert_eq!(peers, expected);
+        // What the clones announced is tracked by the original too
+        assert_eq!(server.lock_announced().len(), peer_ids.len());
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_discover_yields_new_peers_once() -> AResult<()> {
+        let server = SignalServer::new(spawn_signal_server().await?);
+        let room = RoomConfig::new("test", Uuid::new_v4().to_string());
+        let (first, second) = (Uuid::new_v4().to_string(), Uuid::new_v4().to_string());
+
+        server
+            .join(room.clone(), first.clone())
+            .announce_presence()
+            .await?;
+
+        let discovered = tokio::spawn(
+            server
+                .discover(room.clone(), Duration::from_millis(50))
+                .take(2)
+                .collect::<Vec<_>>(),
+        );
+        // Re-announcing doesn't make a peer new again
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        server
+            .join(room.clone(), first.clone())
+            .announce_presence()
+            .await?;
+        server
+            .join(room.clone(), second.clone())
+            .announce_presence()
+            .await?;
+
+        let discovered = tokio::time::timeout(Duration::from_secs(5), discovered).await??;
+        assert_eq!(discovered, vec![first, second]);
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_features_are_gated_on_server_capabilities() -> AResult<()> {
+        let server = SignalServer::new(spawn_signal_server().await?);
+        let info = server.server_info().await?;
+        assert_eq!(info.protocol_version, PROTOCOL_VERSION);
+        assert!(server.supports(Capability::DirectedMailbox).await?);
+
+        // A server from before `/version`, which serves nothing else either
+        let port = free_port()?;
+        let mut config = rocket::Config::debug_default();
+        config.address = Ipv4Addr::LOCALHOST.into();
+        config.port = port;
+        config.log_level = rocket::config::LogLevel::Off;
+        config.shutdown.ctrlc = false;
+        tokio::spawn(rocket::custom(config).launch());
+
+        let old = SignalServer::new(format!("http://127.0.0.1:{port}"));
+        let handle = old.join(RoomConfig::new("test", "test"), Uuid::new_v4().to_string());
+        let info = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                if let Ok(info) = old.server_info().await {
+                    break info;
+                }
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        })
+        .await?;
+        assert!(info.capabilities.is_empty());
+
+        let err = handle.fetch_offers_for_me().await.unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<SignalError>(),
+            Some(&SignalError::Unsupported(Capability::DirectedMailbox))
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_custom_http_client() -> AResult<()> {
+        let url = spawn_signal_server().await?;
+        let room = RoomConfig::new("test", Uuid::new_v4().to_string());
+
+        let http_client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(1))
+            .build()?;
+        let server = SignalServer::new(url).with_http_client(http_client);
+
+        assert!(server.get_peers(&room).await?.is_empty());
+
+        // A client which can't establish any connection proves the injected client is used
+        let http_client = reqwest::Client::builder()
+            .proxy(reqwest::Proxy::all(format!(
+                "http://127.0.0.1:{}",
+                free_port()?
+            ))?)
+            .build()?;
+        let server = SignalServer::new("http://example.invalid").with_http_client(http_client);
+
+        let err = server
+            .get_peers(&room)
+            .await
+            .expect_err("Proxy is unreachable");
+        assert_eq!(SignalingErrorKind::from(&err), SignalingErrorKind::Network);
+
+        Ok(())
+    }
+
+    /// Serves a SOCKS5 proxy without auth on a free port, returning the port and how many
+    /// connections went through it
+    async fn spawn_socks5_proxy() -> AResult<(u16, Arc<AtomicU32>)> {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+        let port = listener.local_addr()?.port();
+        let proxied = Arc::new(AtomicU32::new(0));
+        let counter = proxied.clone();
+        tokio::spawn(async move {
+            while let Ok((mut client, _)) = listener.accept().await {
+                let counter = counter.clone();
+                tokio::spawn(async move {
+                    // Greeting: version, method count and methods, answered with "no auth"
+                    let mut greeting = [0; 2];
+                    client.read_exact(&mut greeting).await?;
+                    let mut methods = vec![0; greeting[1] as usize];
+                    client.read_exact(&mut methods).await?;
+                    client.write_all(&[5, 0]).await?;
+
+                    // Request: version, CONNECT, reserved and the address type
+                    let mut request = [0; 4];
+                    client.read_exact(&mut request).await?;
+                    let host = match request[3] {
+                        1 => {
+                            let mut ip = [0; 4];
+                            client.read_exact(&mut ip).await?;
+                            Ipv4Addr::from(ip).to_string()
+                        }
+                        3 => {
+                            let mut name = vec![0; client.read_u8().await? as usize];
+                            client.read_exact(&mut name).await?;
+                            String::from_utf8_lossy(&name).into_owned()
+                        }
+                        _ => return AResult::<()>::Ok(()),
+                    };
+                    let port = client.read_u16().await?;
+
+                    let mut target = tokio::net::TcpStream::connect((host.as_str(), port)).await?;
+                    counter.fetch_add(1, Ordering::Relaxed);
+                    client.write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0]).await?;
+                    tokio::io::copy_bidirectional(&mut client, &mut target).await?;
+                    Ok(())
+                });
+            }
+        });
+
+        Ok((port, proxied))
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_socks5_proxy() -> AResult<()> {
+        let url = spawn_signal_server().await?;
+        let (port, proxied) = spawn_socks5_proxy().await?;
+        let room = RoomConfig::new("test", Uuid::new_v4().to_string());
+
+        let http_client = reqwest::Client::builder()
+            .proxy(reqwest::Proxy::all(format!("socks5://127.0.0.1:{port}"))?)
+            .build()?;
+        let server = SignalServer::new(url).with_http_client(http_client);
+
+        assert!(server.get_peers(&room).await?.is_empty());
+        assert_eq!(proxied.load(Ordering::Relaxed), 1);
+
+        Ok(())
+    }
+
+    /// Serves `url` over HTTPS on a free port of localhost, presenting the self-signed
+    /// certificate `cert`, returning the HTTPS url
+    pub(crate) async fn spawn_tls_proxy(url: &str, cert: &rcgen::CertifiedKey) -> AResult<String> {
+        let identity = native_tls::Identity::from_pkcs8(
+            cert.cert.pem().as_bytes(),
+            cert.key_pair.serialize_pem().as_bytes(),
+        )?;
+        let acceptor = tokio_native_tls::TlsAcceptor::from(native_tls::TlsAcceptor::new(identity)?);
+        let target = reqwest::Url::parse(url)?
+            .socket_addrs(|| None)?
+            .into_iter()
+            .next()
+            .ok_or(anyhow::anyhow!("{url} has no address"))?;
+
+        let listener = tokio::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+        let port = listener.local_addr()?.port();
+        tokio::spawn(async move {
+            while let Ok((client, _)) = listener.accept().await {
+                let acceptor = acceptor.clone();
+                tokio::spawn(async move {
+                    // Clients refusing the certificate fail the handshake, which is expected
+                    let mut client = acceptor.accept(client).await?;
+                    let mut target = tokio::net::TcpStream::connect(target).await?;
+                    tokio::io::copy_bidirectional(&mut client, &mut target).await?;
+                    AResult::<()>::Ok(())
+                });
+            }
+        });
+
+        Ok(format!("https://localhost:{port}"))
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_tls_handshake() -> AResult<()> {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()])?;
+        let url = spawn_tls_proxy(&spawn_signal_server().await?, &cert).await?;
+        let pem = cert.cert.pem();
+        let room = RoomConfig::new("test", Uuid::new_v4().to_string());
+
+        let server = SignalServer::new(url.as_str())
+            .with_tls(TlsConfig::default().with_root_certificate(pem.as_bytes())?)?;
+        assert!(server.get_peers(&room).await?.is_empty());
+        let server =
+            SignalServer::new(url.as_str()).with_tls(TlsConfig::pinned(pem.as_bytes())?)?;
+        assert!(server.get_peers(&room).await?.is_empty());
+
+        // Neither the system's roots nor a pin on another certificate trust it
+        let other = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()])?
+            .cert
+            .pem();
+        for tls in [TlsConfig::default(), TlsConfig::pinned(other.as_bytes())?] {
+            let server = SignalServer::new(url.as_str()).with_tls(tls)?;
+            let err = server
+                .get_peers(&room)
+                .await
+                .expect_err("The certificate isn't trusted");
+            assert_eq!(SignalingErrorKind::from(&err), SignalingErrorKind::Network);
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_server_rejections_are_typed() -> AResult<()> {
+        let banned = Uuid::new_v4();
+        let url = spawn_configured_signal_server(
+            free_port()?,
+            ServerConfig {
+                max_peers_per_room: 1,
+                max_rooms_per_channel: 1,
+                banned_peers: [banned].into(),
+                ..Default::default()
+            },
+        )
+        .await?;
+        let server = SignalServer::new(url.as_str());
+        let room = RoomConfig::new("test", Uuid::new_v4().to_string());
+
+        let client = P2PClient::default();
+        let connection = P2PConnection::new(&client, true).await?;
+
+        let announce_error = |peer_id: String, room: RoomConfig| {
+            let (server, connection) = (&server, &connection);
+            async move {
+                server
+                    .join(room, peer_id)
+                    .announce(connection)
+                    .await
+                    .expect_err("Announcement should be rejected")
+                    .downcast::<SignalError>()
+                    .expect("Rejection should be typed")
+            }
+        };
+
+        server
+            .join(room.clone(), Uuid::new_v4().to_string())
+            .announce(&connection)
+            .await?;
+
+        assert_eq!(
+            announce_error(Uuid::new_v4().to_string(), room.clone()).await,
+            SignalError::RoomFull
+        );
+        assert_eq!(
+            announce_error(Uuid::new_v4().to_string(), RoomConfig::new("test", "other")).await,
+            SignalError::QuotaExceeded
+        );
+        assert_eq!(
+            announce_error(banned.to_string(), room.clone()).await,
+            SignalError::Banned
+        );
+
+        let response = reqwest::Client::new()
+            .post(format!("{url}/announce"))
+            .query(&[
+                ("channel", room.channel.as_str()),
+                ("room", room.room.as_str()),
+                ("peer_id", Uuid::new_v4().to_string().as_str()),
+            ])
+            .header(PROTOCOL_VERSION_HEADER, PROTOCOL_VERSION + 1)
+            .json(&BroadcastCandidateArgs {
+                candidates: Vec::new(),
+                session_description: None,
+                sealed: None,
+                room_settings: None,
+                metadata: BTreeMap::new(),
+                signature: None,
+            })
+            .send()
+            .await?;
+        let err = check_rejection(response)
+            .await
+            .expect_err("Version should mismatch");
+        assert_eq!(
+            err.downcast_ref::<SignalError>(),
+            Some(&SignalError::VersionMismatch {
+                server_version: PROTOCOL_VERSION
+            })
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_auth_token() -> AResult<()> {
+        let url = spawn_configured_signal_server(
+            free_port()?,
+            ServerConfig {
+                auth_token: Some("secret".to_owned()),
+                ..Default::default()
+            },
+        )
+        .await?;
+        let room = RoomConfig::new("test", Uuid::new_v4().to_string());
+        let peer_id = Uuid::new_v4().to_string();
+
+        let err = SignalServer::new(url.as_str())
+            .join(room.clone(), peer_id.as_str())
+            .announce_presence()
+            .await
+            .expect_err("No token was given");
+        assert_eq!(
+            err.downcast_ref::<SignalError>(),
+            Some(&SignalError::Unauthorized)
+        );
+
+        // Every call sees the refusal, not only announcements
+        let wrong = SignalServer::new(url.as_str()).with_auth("wrong")?;
+        for err in [
+            wrong
+                .get_peers(&room)
+                .await
+                .expect_err("The token is wrong"),
+            wrong
+                .list_rooms("test")
+                .await
+                .expect_err("The token is wrong"),
+            wrong
+                .withdraw(&room, &peer_id)
+                .await
+                .expect_err("The token is wrong"),
+        ] {
+            assert_eq!(
+                err.downcast_ref::<SignalError>(),
+                Some(&SignalError::Unauthorized)
+            );
+            assert_eq!(SignalingErrorKind::from(&err), SignalingErrorKind::Rejected);
+        }
+
+        assert!(SignalServer::new(url.as_str())
+            .with_auth("not\na header")
+            .is_err());
+        let server = SignalServer::new(url.as_str()).with_auth("secret")?;
+        server
+            .join(room.clone(), peer_id.as_str())
+            .announce_presence()
+            .await?;
+        assert_eq!(
+            server.get_peers(&room).await?,
+            std::slice::from_ref(&peer_id)
+        );
+
+        let server = SignalServer::new(url.as_str()).with_auth_header(
+            HeaderName::from_static("x-signal-token"),
+            HeaderValue::from_static("secret"),
+        );
+        assert_eq!(server.get_peers(&room).await?, [peer_id]);
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_session_token_guards_the_peer_id() -> AResult<()> {
+        let config = ServerConfig::default();
+        let url = spawn_configured_signal_server(free_port()?, config.clone()).await?;
+        let owner = SignalServer::new(url.as_str());
+        let spoofer = SignalServer::new(url.as_str());
+        let room = RoomConfig::new("test", Uuid::new_v4().to_string());
+        let peer_id = Uuid::new_v4().to_string();
+
+        owner
+            .join(room.clone(), peer_id.as_str())
+            .announce_presence()
+            .await?;
+        let token = owner.session_token(&room, &peer_id).expect("Handed out");
+        // Updates present the token
+        owner
+            .join(room.clone(), peer_id.as_str())
+            .announce_presence()
+            .await?;
+        assert_eq!(owner.session_token(&room, &peer_id), Some(token.clone()));
+
+        for err in [
+            spoofer
+                .join(room.clone(), peer_id.as_str())
+                .announce_presence()
+                .await
+                .expect_err("The peer id is taken"),
+            spoofer
+                .withdraw(&room, &peer_id)
+                .await
+                .expect_err("The peer id is taken"),
+        ] {
+            assert_eq!(
+                err.downcast_ref::<SignalError>(),
+                Some(&SignalError::SessionMismatch)
+            );
+        }
+        assert_eq!(
+            spoofer.get_peers(&room).await?,
+            std::slice::from_ref(&peer_id)
+        );
+
+        // A restarted client takes the session back up with the saved token
+        let restarted = SignalServer::new(url.as_str());
+        restarted.resume_session(&room, &peer_id, token.as_str());
+        restarted
+            .join(room.clone(), peer_id.as_str())
+            .announce_presence()
+            .await?;
+
+        // So does a client whose signaling server restarted with the same secret, forgetting
+        // every announcement
+        let fresh = SignalServer::new(spawn_configured_signal_server(free_port()?, config).await?);
+        fresh.resume_session(&room, &peer_id, token.as_str());
+        fresh
+            .join(room.clone(), peer_id.as_str())
+            .announce_presence()
+            .await?;
+        assert_eq!(fresh.session_token(&room, &peer_id), Some(token.clone()));
+
+        // A server with another secret doesn't take the token up, nor one which was made up
+        let other = SignalServer::new(spawn_signal_server().await?);
+        for token in [token.as_str(), "made up"] {
+            other.resume_session(&room, &peer_id, token);
+            let err = other
+                .join(room.clone(), peer_id.as_str())
+                .announce_presence()
+                .await
+                .expect_err("The token doesn't verify");
+            assert_eq!(
+                err.downcast_ref::<SignalError>(),
+                Some(&SignalError::SessionMismatch)
+            );
+        }
+
+        restarted.withdraw(&room, &peer_id).await?;
+        assert!(restarted.session_token(&room, &peer_id).is_none());
+        assert!(owner.get_peers(&room).await?.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_retry_backoff_is_jittered_and_capped() {
+        let policy = RetryPolicy {
+            max_retries: 10,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_millis(400),
+        };
+
+        for (retry, full) in [(0, 100), (1, 200), (2, 400), (3, 400), (9, 400)] {
+            let backoff = policy.backoff(retry);
+            let full = Duration::from_millis(full);
+            assert!(backoff >= full / 2 && backoff <= full, "{backoff:?}");
+        }
+
+        assert!(SignalingErrorKind::Network.is_retryable());
+        assert!(SignalingErrorKind::Status(503).is_retryable());
+        assert!(SignalingErrorKind::Status(503).is_server_error());
+        assert!(!SignalingErrorKind::Status(404).is_retryable());
+        assert!(SignalingErrorKind::Status(404).is_client_error());
+        assert!(!SignalingErrorKind::Rejected.is_retryable());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_polling_is_jittered_and_faster_during_handshakes() -> AResult<()> {
+        let server = SignalServer::new("http://localhost:8000");
+        let handle = server
+            .join(RoomConfig::new("test", "test"), Uuid::new_v4().to_string())
+            .with_poll_interval(Duration::from_millis(1000))
+            .with_handshake_poll_interval(Duration::from_millis(100));
+
+        let intervals = (0..100)
+            .map(|_| handle.poll_interval())
+            .collect::<HashSet<_>>();
+        assert!(intervals.len() > 1);
+        for interval in intervals {
+            assert!(
+                interval >= Duration::from_millis(750) && interval <= Duration::from_millis(1250)
+            );
+        }
+        let handshake = handle.handshake_poll_interval();
+        assert!(handshake >= Duration::from_millis(75) && handshake <= Duration::from_millis(125));
+
+        // Never slower than the poll interval
+        let handle = handle.with_poll_interval(Duration::from_millis(40));
+        assert!(handle.handshake_poll_interval() <= Duration::from_millis(50));
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_announce_is_retried() -> AResult<()> {
+        let port = free_port()?;
+        let server =
+            SignalServer::new(format!("http://127.0.0.1:{port}")).with_retry_policy(RetryPolicy {
+                max_retries: 20,
+                initial_backoff: Duration::from_millis(50),
+                max_backoff: Duration::from_millis(100),
+            });
+        let room = RoomConfig::new("test", Uuid::new_v4().to_string());
+        let peer_id = Uuid::new_v4().to_string();
+
+        // The server only comes up while the announcement is being retried
+        let announcing = tokio::spawn(async move {
+            let result = server
+                .join(room.clone(), peer_id.as_str())
+                .announce_presence()
+                .await;
+            (result, server, room, peer_id)
+        });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        spawn_signal_server_on(port).await?;
+
+        let (result, server, room, peer_id) = announcing.await?;
+        result?;
+        assert_eq!(server.get_peers(&room).await?, [peer_id]);
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_heartbeat_reannounces() -> AResult<()> {
+        let server = SignalServer::new(spawn_signal_server().await?);
+        let room = RoomConfig::new("test", Uuid::new_v4().to_string());
+        let (local_id, remote_id) = (Uuid::new_v4().to_string(), Uuid::new_v4().to_string());
+
+        let local = server.join(room.clone(), local_id.as_str());
+        let remote = server.join(room, remote_id.as_str());
+        remote.announce_presence().await?;
+
+        let events = local.subscribe_room_events();
+        futures::pin_mut!(events);
+        let timeout = Duration::from_secs(5);
+        assert_eq!(
+            tokio::time::timeout(timeout, events.next()).await?,
+            Some(RoomEvent::PeerJoined {
+                peer_id: remote_id.clone()
+            })
+        );
+
+        let heartbeat = remote.start_heartbeat(Duration::from_millis(50));
+        futures::pin_mut!(heartbeat);
+        tokio::select! {
+            _ = &mut heartbeat => unreachable!("The heartbeat never ends"),
+            event = tokio::time::timeout(timeout, events.next()) => assert_eq!(
+                event?,
+                Some(RoomEvent::PeerUpdated {
+                    peer_id: remote_id.clone()
+                })
+            ),
+        }
+
+        Ok(())
+    }
+
+    /// Announces and withdraws peers through `signaling`, checking the room sees them
+    pub(crate) async fn announce_through(
+        signaling: &impl Signaling,
+        room: &RoomConfig,
+    ) -> AResult<()> {
+        let (local_id, remote_id) = (Uuid::new_v4().to_string(), Uuid::new_v4().to_string());
+        let args = BroadcastCandidateArgs {
+            candidates: Vec::new(),
+            session_description: None,
+            sealed: None,
+            room_settings: None,
+            metadata: BTreeMap::new(),
+            signature: None,
+        };
+        signaling.announce(room, &local_id, &args).await?;
+
+        let events = signaling.subscribe(room);
+        futures::pin_mut!(events);
+        let timeout = Duration::from_secs(5);
+        assert_eq!(
+            tokio::time::timeout(timeout, events.next()).await?,
+            Some(RoomEvent::PeerJoined {
+                peer_id: local_id.clone()
+            })
+        );
+
+        signaling.announce(room, &remote_id, &args).await?;
+        assert_eq!(
+            tokio::time::timeout(timeout, events.next()).await?,
+            Some(RoomEvent::PeerJoined {
+                peer_id: remote_id.clone()
+            })
+        );
+        assert!(signaling.peers(room).await?.contains(&remote_id));
+        assert!(signaling.fetch(room, &remote_id).await?.is_some());
+
+        signaling.withdraw(room, &remote_id).await?;
+        assert!(signaling.fetch(room, &remote_id).await?.is_none());
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_signal_server_is_signaling() -> AResult<()> {
+        let server = SignalServer::new(spawn_signal_server().await?);
+        let room = RoomConfig::new("test", Uuid::new_v4().to_string());
+
+        announce_through(&server, &room).await
+    }
+
+    #[test]
+    fn test_room_password_is_redacted() {
+        let room = RoomConfig::new("test", "room").with_password("hunter2");
+
+        let debug = format!("{room:?}");
+        assert!(!debug.contains("hunter2"));
+        assert!(debug.contains("<redacted>"));
+    }
+
+    #[test]
+    fn test_tls_config() -> AResult<()> {
+        let pem = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()])?
+            .cert
+            .pem();
+
+        SignalServer::new("https://localhost:8000")
+            .with_tls(TlsConfig::default().with_root_certificate(pem.as_bytes())?)?;
+        SignalServer::new("https://localhost:8000").with_tls(TlsConfig::pinned(pem.as_bytes())?)?;
+
+        assert!(TlsConfig::pinned(b"not a certificate").is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_room_secret_encrypts_announcements() -> AResult<()> {
+        let server = SignalServer::new(spawn_signal_server().await?);
+        let room = RoomConfig::new("test", Uuid::new_v4().to_string());
+        let secret_room = room.clone().with_secret("correct horse battery staple");
+        let peer_id = Uuid::new_v4().to_string();
+
+        let client = P2PClient::default();
+        let connection = P2PConnection::new(&client, true).await?;
+        connection.get_offer().await?;
+        server
+            .broadcast_self(&secret_room, &peer_id, &connection)
+            .await?;
+
+        // The signaling server only sees the encrypted announcement
+        let signal = server.get_peer(&room, &peer_id).await?.unwrap();
+        assert!(signal.session_description.is_none());
+        assert!(signal.sealed.is_some());
+
+        let signal = server.get_peer(&secret_room, &peer_id).await?.unwrap();
+        assert_eq!(
+            signal
+                .session_description
+                .map(|description| description.sdp_type),
+            Some(RTCSdpType::Offer)
+        );
+
+        let wrong_room = room.with_secret("wrong");
+        assert!(server.get_peer(&wrong_room, &peer_id).await.is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_signed_announcements_are_verified() -> AResult<()> {
+        let server = SignalServer::new(spawn_signal_server().await?);
+        let identity = Identity::generate();
+        let room = RoomConfig::new("test", Uuid::new_v4().to_string());
+        let signed_room = room.clone().with_signing_identity(&identity);
+        let peer_id = Uuid::new_v4().to_string();
+        let trusting_room = room
+            .clone()
+            .with_trusted_signer(peer_id.as_str(), identity.signing_public_key());
+
+        let client = P2PClient::default();
+        let connection = P2PConnection::new(&client, true).await?;
+        connection.get_offer().await?;
+        server
+            .broadcast_self(&signed_room, &peer_id, &connection)
+            .await?;
+
+        let signal = server.get_peer(&trusting_room, &peer_id).await?.unwrap();
+        assert!(signal.verified);
+        assert_eq!(
+            signal.signature.map(|signature| signature.public_key),
+            Some(identity.signing_public_key().to_vec())
+        );
+        let signal = server.fetch_updates(&trusting_room, &peer_id, 1).await?;
+        assert!(signal.is_some_and(|signal| signal.session_description.is_some()));
+        // Without a key trusted for the peer, anyone could have signed it
+        assert!(!server.get_peer(&room, &peer_id).await?.unwrap().verified);
+
+        // Someone else replaces the session description under the same peer id
+        let forged = BroadcastCandidateArgs {
+            candidates: Vec::new(),
+            session_description: None,
+            sealed: None,
+            room_settings: None,
+            metadata: BTreeMap::new(),
+            signature: server.get_peer(&room, &peer_id).await?.unwrap().signature,
+        };
+        server.announce(&room, &peer_id, &forged).await?;
+        let err = server.get_peer(&trusting_room, &peer_id).await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(SignalError::InvalidSignature)
+        ));
+
+        // Validly signed, but by an untrusted key
+        server
+            .broadcast_self(
+                &room.clone().with_signing_identity(&Identity::generate()),
+                &peer_id,
+                &connection,
+            )
+            .await?;
+        assert!(server.get_peer(&room, &peer_id).await?.is_some());
+        let err = server.get_peer(&trusting_room, &peer_id).await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(SignalError::UntrustedSigner)
+        ));
+
+        // Offers left for a peer are signed as well, and only for that peer
+        let other_id = Uuid::new_v4().to_string();
+        let offer = connection.get_offer().await?;
+        let sender = server.join(signed_room, peer_id.as_str());
+        sender.send_offer_to(&other_id, &offer).await?;
+        let receiver = server.join(trusting_room.clone(), other_id.as_str());
+        receiver.announce_presence().await?;
+        let offers = receiver.fetch_offers_for_me().await?;
+        assert!(offers.len() == 1 && offers[0].verified);
+
+        server
+            .join(room, peer_id.as_str())
+            .send_offer_to(&other_id, &offer)
+            .await?;
+        let err = receiver.fetch_offers_for_me().await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(SignalError::UntrustedSigner)
+        ));
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_compressed_announcements() -> AResult<()> {
+        let url = spawn_signal_server().await?;
+        let compressed = SignalServer::new(url.as_str()).with_compression();
+        let plain = SignalServer::new(url.as_str());
+        let room = RoomConfig::new("test", Uuid::new_v4().to_string());
+        let peer_id = Uuid::new_v4().to_string();
+
+        let client = P2PClient::default();
+        let connection = P2PConnection::new(&client, true).await?;
+        connection.get_offer().await?;
+        compressed
+            .broadcast_self(&room, &peer_id, &connection)
+            .await?;
+
+        for server in [&compressed, &plain] {
+            let signal = server.get_peer(&room, &peer_id).await?.unwrap();
+            assert_eq!(
+                signal
+                    .session_description
+                    .map(|description| description.sdp_type),
+                Some(RTCSdpType::Offer)
+            );
+        }
+
+        let response = reqwest::Client::new()
+            .get(format!("{url}/candidate"))
+            .query(&[
+                ("channel", room.channel.as_str()),
+                ("room", room.room.as_str()),
+                ("candidate_id", peer_id.as_str()),
+            ])
+            .header(ACCEPT_ENCODING, "deflate")
+            .send()
+            .await?;
+        assert_eq!(
+            response.headers().get(CONTENT_ENCODING),
+            Some(&HeaderValue::from_static("deflate"))
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_only_new_candidates_are_announced() -> AResult<()> {
+        let server = SignalServer::new(spawn_signal_server().await?);
+        let room = RoomConfig::new("test", Uuid::new_v4().to_string());
+        let peer_id = Uuid::new_v4().to_string();
+        let key = (room.channel.clone(), room.room.clone(), peer_id.clone());
+
+        let first = RTCIceCandidate {
+            address: "192.0.2.1".to_owned(),
+            port: 5000,
+            ..Default::default()
+        };
+        let second = RTCIceCandidate {
+            port: 5001,
+            ..first.clone()
+        };
+        let mut args = BroadcastCandidateArgs {
+            candidates: vec![first.clone()],
+            session_description: None,
+            sealed: None,
+            room_settings: None,
+            metadata: BTreeMap::new(),
+            signature: None,
+        };
+        server.announce_delta(&room, &peer_id, &args).await?;
+        args.candidates.push(second.clone());
+        server.announce_delta(&room, &peer_id, &args).await?;
+
+        assert_eq!(
+            server.lock_announced().get(&key),
+            Some(&vec![first.clone(), second.clone()])
+        );
+        let signal = server.get_peer(&room, &peer_id).await?.unwrap();
+        assert_eq!(signal.candidates, vec![first.clone(), second.clone()]);
+
+        // Everything is announced again after a withdrawal
+        server.withdraw(&room, &peer_id).await?;
+        assert!(server.lock_announced().get(&key).is_none());
+        server.announce_delta(&room, &peer_id, &args).await?;
+        let signal = server.get_peer(&room, &peer_id).await?.unwrap();
+        assert_eq!(signal.candidates, vec![first.clone(), second.clone()]);
+
+        // And when the server forgot the peer behind the client's back, such as when it went
+        // stale, which another client holding the session stands in for here
+        let other = SignalServer::new(server.url());
+        other.resume_session(
+            &room,
+            &peer_id,
+            server.session_token(&room, &peer_id).unwrap(),
+        );
+        other.withdraw(&room, &peer_id).await?;
+        server.announce_delta(&room, &peer_id, &args).await?;
+        let signal = server.get_peer(&room, &peer_id).await?.unwrap();
+        assert_eq!(signal.candidates, vec![first, second]);
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_fetch_updates_since_cursor() -> AResult<()> {
+        let server = SignalServer::new(spawn_signal_server().await?);
+        let room = RoomConfig::new("test", Uuid::new_v4().to_string());
+        let peer_id = Uuid::new_v4().to_string();
+
+        let first = RTCIceCandidate {
+            address: "192.0.2.1".to_owned(),
+            port: 5000,
+            ..Default::default()
+        };
+        let second = RTCIceCandidate {
+            port: 5001,
+            ..first.clone()
+        };
+        let mut args = BroadcastCandidateArgs {
+            candidates: vec![first.clone()],
+            session_description: None,
+            sealed: None,
+            room_settings: None,
+            metadata: BTreeMap::new(),
+            signature: None,
+        };
+        server.announce(&room, &peer_id, &args).await?;
+
+        let update = server.fetch_updates(&room, &peer_id, 0).await?.unwrap();
+        assert_eq!(update.candidates, vec![first.clone()]);
+
+        args.candidates.push(second.clone());
+        server.announce(&room, &peer_id, &args).await?;
+        let newer = server
+            .fetch_updates(&room, &peer_id, update.cursor)
+            .await?
+            .unwrap();
+        assert_eq!(newer.candidates, vec![second]);
+        assert!(newer.cursor > update.cursor);
+
+        let unchanged = server
+            .fetch_updates(&room, &peer_id, newer.cursor)
+            .await?
+            .unwrap();
+        assert!(unchanged.candidates.is_empty());
+        assert_eq!(unchanged.cursor, newer.cursor);
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_list_rooms() -> AResult<()> {
+        let server = SignalServer::new(spawn_signal_server().await?);
+        let channel = Uuid::new_v4().to_string();
+        assert!(server.list_rooms(&channel).await?.is_empty());
+
+        for (room, peers) in [("small", 1), ("large", 2)] {
+            for _ in 0..peers {
+                server
+                    .join(RoomConfig::new(&channel, room), Uuid::new_v4().to_string())
+                    .announce_presence()
+                    .await?;
+            }
+        }
+
+        let mut rooms = server.list_rooms(&channel).await?;
+        rooms.sort_by(|a, b| a.name.cmp(&b.name));
+        assert_eq!(
+            rooms
+                .iter()
+                .map(|room| (room.name.as_str(), room.peer_count))
+                .collect::<Vec<_>>(),
+            [("large", 2), ("small", 1)]
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_hung_server_times_out() -> AResult<()> {
+        // Connections are taken into the backlog, but nothing ever answers them
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?;
+        let url = format!("http://{}", listener.local_addr()?);
+        let server = SignalServer::new(url).with_timeouts(Timeouts {
+            request: Duration::from_millis(100),
+            ..Default::default()
+        })?;
+        let room = RoomConfig::new("test", Uuid::new_v4().to_string());
+
+        let err = tokio::time::timeout(Duration::from_secs(5), server.get_peers(&room))
+            .await?
+            .expect_err("Nothing answers");
+        assert_eq!(SignalingErrorKind::from(&err), SignalingErrorKind::Network);
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_connections_are_reused() -> AResult<()> {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        // Answers every request with an empty room, counting the connections it was sent over
+        let listener = tokio::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+        let url = format!("http://{}", listener.local_addr()?);
+        let connections = Arc::new(AtomicU32::new(0));
+        let counted = connections.clone();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                counted.fetch_add(1, Ordering::SeqCst);
+                tokio::spawn(async move {
+                    let mut request = Vec::new();
+                    let mut buffer = [0; 1024];
+                    while let Ok(read @ 1..) = stream.read(&mut buffer).await {
+                        request.extend_from_slice(&buffer[..read]);
+                        while let Some(end) = request.windows(4).position(|w| w == b"\r\n\r\n") {
+                            request.drain(..end + 4);
+                            let response = "HTTP/1.1 200 OK\r\ncontent-length: 2\r\n\r\n[]";
+                            if stream.write_all(response.as_bytes()).await.is_err() {
+                                return;
+                            }
+                        }
+                    }
+                });
+            }
+        });
+
+        let room = RoomConfig::new("test", Uuid::new_v4().to_string());
+        let pooled = SignalServer::new(url.as_str());
+        for _ in 0..3 {
+            pooled.get_peers(&room).await?;
+            // The connection goes back to the pool in the background once the body is read
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(connections.load(Ordering::SeqCst), 1);
+
+        let unpooled = SignalServer::new(url.as_str()).with_pool(PoolConfig {
+            max_idle: 0,
+            ..Default::default()
+        })?;
+        for _ in 0..3 {
+            unpooled.get_peers(&room).await?;
+        }
+        assert_eq!(connections.load(Ordering::SeqCst), 4);
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_room_settings_are_enforced() -> AResult<()> {
+        let server = SignalServer::new(spawn_signal_server().await?);
+        let channel = Uuid::new_v4().to_string();
+        let room = RoomConfig::new(&channel, "match");
+        let locked = room
+            .clone()
+            .with_password("hunter2")
+            .with_max_peers(2)
+            .with_metadata("mode", "capture the flag");
+
+        let announce = |room: RoomConfig| {
+            let server = &server;
+            async move {
+                server
+                    .join(room, Uuid::new_v4().to_string())
+                    .announce_presence()
+                    .await
+                    .map_err(|err| err.downcast::<SignalError>().expect("Should be typed"))
+            }
+        };
+
+        announce(locked.clone()).await?;
+        assert_eq!(
+            announce(room.clone()).await,
+            Err(SignalError::WrongPassword)
+        );
+        assert_eq!(
+            announce(room.clone().with_password("wrong")).await,
+            Err(SignalError::WrongPassword)
+        );
+        // The password is enough to join, the other settings are the creator's
+        announce(room.clone().with_password("hunter2")).await?;
+        assert_eq!(announce(locked.clone()).await, Err(SignalError::RoomFull));
+
+        let rooms = server.list_rooms(&channel).await?;
+        assert_eq!(
+            rooms,
+            [RoomInfo {
+                name: "match".to_owned(),
+                peer_count: 2,
+                password_protected: true,
+                metadata: [("mode".to_owned(), "capture the flag".to_owned())].into(),
+            }]
+        );
+
+        // Reading from the room takes the password as well
+        assert_eq!(server.get_peers(&locked).await?.len(), 2);
+        for wrong in [room.clone(), room.with_password("wrong")] {
+            let err = server.get_peers(&wrong).await.unwrap_err();
+            assert_eq!(
+                err.downcast_ref::<SignalError>(),
+                Some(&SignalError::WrongPassword)
+            );
+            let peer_id = Uuid::new_v4().to_string();
+            let err = server.get_peer(&wrong, &peer_id).await.unwrap_err();
+            assert_eq!(
+                err.downcast_ref::<SignalError>(),
+                Some(&SignalError::WrongPassword)
+            );
+        }
+
+        Ok(())
+    }
+}
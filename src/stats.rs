@@ -0,0 +1,201 @@
+use serde::Serialize;
+use std::time::{Duration, SystemTime};
+use webrtc::ice::candidate::CandidatePairState;
+use webrtc::stats::{ICECandidatePairStats, ICECandidateStats, StatsReport, StatsReportType};
+
+/// One end of the candidate pair a connection is sending over
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct CandidateInfo {
+    pub address: String,
+    pub port: u16,
+    /// The kind of candidate, such as `host`, `srflx` or `relay`
+    pub candidate_type: String,
+}
+
+impl From<&ICECandidateStats> for CandidateInfo {
+    fn from(stats: &ICECandidateStats) -> Self {
+        Self {
+            address: stats.ip.clone(),
+            port: stats.port,
+            candidate_type: stats.candidate_type.to_string(),
+        }
+    }
+}
+
+/// The transport protocol a candidate pair sends over
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransportProtocol {
+    Udp,
+    Tcp,
+}
+
+/// The route traffic takes to the peer, going by the kinds of the candidates on both ends
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PathKind {
+    /// Straight between the addresses of the two hosts, such as on a LAN
+    Direct,
+    /// Through the public address a NAT mapped one of the hosts to
+    Reflexive,
+    /// Through a TURN server
+    Relayed,
+}
+
+/// The candidate pair ICE selected to send over, from `P2PConnection::selected_candidate_pair`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CandidatePair {
+    pub local: CandidateInfo,
+    pub remote: CandidateInfo,
+    pub protocol: TransportProtocol,
+}
+
+impl CandidatePair {
+    pub fn path(&self) -> PathKind {
+        let types = [&self.local.candidate_type, &self.remote.candidate_type];
+        if types
+            .iter()
+            .any(|candidate_type| *candidate_type == "relay")
+        {
+            PathKind::Relayed
+        } else if types.iter().all(|candidate_type| *candidate_type == "host") {
+            PathKind::Direct
+        } else {
+            PathKind::Reflexive
+        }
+    }
+
+    /// The pair a stats report says was selected, if ICE has selected one yet
+    pub(crate) fn from_report(report: &StatsReport) -> Option<Self> {
+        let pair = selected_pair(report)?;
+        let Some(StatsReportType::LocalCandidate(local)) =
+            report.reports.get(&pair.local_candidate_id)
+        else {
+            return None;
+        };
+        let Some(StatsReportType::RemoteCandidate(remote)) =
+            report.reports.get(&pair.remote_candidate_id)
+        else {
+            return None;
+        };
+
+        Some(Self {
+            local: local.into(),
+            remote: remote.into(),
+            protocol: if local.network_type.is_tcp() {
+                TransportProtocol::Tcp
+            } else {
+                TransportProtocol::Udp
+            },
+        })
+    }
+}
+
+/// The candidate pair which succeeded, preferring the nominated one
+fn selected_pair(report: &StatsReport) -> Option<&ICECandidatePairStats> {
+    report
+        .reports
+        .values()
+        .filter_map(|report| match report {
+            StatsReportType::CandidatePair(pair) => Some(pair),
+            _ => None,
+        })
+        .filter(|pair| pair.state == CandidatePairState::Succeeded)
+        .max_by_key(|pair| pair.nominated)
+}
+
+/// A snapshot of the statistics of a `P2PConnection`, from `P2PConnection::get_stats`
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct ConnectionStats {
+    /// The latest round trip time over the selected candidate pair, once one has been measured
+    pub current_rtt: Option<Duration>,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    /// Packets of media tracks the peer reported as lost. Data channels retransmit or drop
+    /// messages according to their `ChannelReliability` instead
+    pub packets_lost: i64,
+    pub local_candidate: Option<CandidateInfo>,
+    pub remote_candidate: Option<CandidateInfo>,
+}
+
+/// The statistics of a `P2PConnection` along with which peer they are for and when they were
+/// taken, from `P2PConnection::snapshot_stats`. Serializes with the statistics inline, ready to
+/// be shipped as telemetry
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct StatsSnapshot {
+    /// The peer on the other end, once the connection has been handed to one
+    pub peer_id: Option<String>,
+    pub taken_at: SystemTime,
+    #[serde(flatten)]
+    pub stats: ConnectionStats,
+}
+
+impl From<&StatsReport> for ConnectionStats {
+    fn from(report: &StatsReport) -> Self {
+        let mut stats = Self::default();
+
+        if let Some(pair) = selected_pair(report) {
+            stats.apply_pair(report, pair);
+        }
+
+        for report in report.reports.values() {
+            match report {
+                StatsReportType::Transport(transport) => {
+                    // The transport also counts traffic from before the pair was selected
+                    stats.bytes_sent = stats.bytes_sent.max(transport.bytes_sent as u64);
+                    stats.bytes_received =
+                        stats.bytes_received.max(transport.bytes_received as u64);
+                }
+                StatsReportType::RemoteInboundRTP(inbound) => {
+                    stats.packets_lost += inbound.packets_lost;
+                }
+                _ => {}
+            }
+        }
+
+        stats
+    }
+}
+
+impl ConnectionStats {
+    fn apply_pair(&mut self, report: &StatsReport, pair: &ICECandidatePairStats) {
+        if pair.current_round_trip_time > 0.0 {
+            self.current_rtt = Some(Duration::from_secs_f64(pair.current_round_trip_time));
+        }
+        self.bytes_sent = pair.bytes_sent;
+        self.bytes_received = pair.bytes_received;
+
+        self.local_candidate = match report.reports.get(&pair.local_candidate_id) {
+            Some(StatsReportType::LocalCandidate(candidate)) => Some(candidate.into()),
+            _ => None,
+        };
+        self.remote_candidate = match report.reports.get(&pair.remote_candidate_id) {
+            Some(StatsReportType::RemoteCandidate(candidate)) => Some(candidate.into()),
+            _ => None,
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pair(local_type: &str, remote_type: &str) -> CandidatePair {
+        let candidate = |candidate_type: &str| CandidateInfo {
+            address: "203.0.113.1".to_owned(),
+            port: 50000,
+            candidate_type: candidate_type.to_owned(),
+        };
+        CandidatePair {
+            local: candidate(local_type),
+            remote: candidate(remote_type),
+            protocol: TransportProtocol::Udp,
+        }
+    }
+
+    #[test]
+    fn test_path_kind() {
+        assert_eq!(pair("host", "host").path(), PathKind::Direct);
+        assert_eq!(pair("host", "srflx").path(), PathKind::Reflexive);
+        assert_eq!(pair("prflx", "host").path(), PathKind::Reflexive);
+        assert_eq!(pair("srflx", "relay").path(), PathKind::Relayed);
+    }
+}
@@ -0,0 +1,141 @@
+use crate::channel::Message;
+use crate::p2p_connection::P2PConnection;
+use anyhow::Result as AResult;
+use bytes::{Buf, Bytes};
+use futures::future::BoxFuture;
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// A byte stream over the default channel of a connection, from `P2PConnection::into_stream`,
+/// so protocols written for sockets can run over a peer link. Each write goes out as a message
+/// of its own, while reads run across message boundaries. Shutting the stream down sends the
+/// peer an empty message, which ends its stream, so both peers have to read through a stream
+pub struct P2PStream {
+    connection: Arc<P2PConnection>,
+    /// What is left of the last message, once the reader's buffer was full
+    unread: Bytes,
+    eof: bool,
+    shut_down: bool,
+    recv: Option<BoxFuture<'static, Option<Message>>>,
+    /// The write which is still going out, as only one is in flight at a time to keep them in
+    /// order
+    send: Option<BoxFuture<'static, AResult<()>>>,
+}
+
+impl P2PStream {
+    pub(crate) fn new(connection: Arc<P2PConnection>) -> Self {
+        Self {
+            connection,
+            unread: Bytes::new(),
+            eof: false,
+            shut_down: false,
+            recv: None,
+            send: None,
+        }
+    }
+
+    /// The connection the stream runs over
+    pub fn connection(&self) -> &Arc<P2PConnection> {
+        &self.connection
+    }
+
+    fn start_send(&mut self, data: Bytes) {
+        let connection = self.connection.clone();
+        self.send = Some(Box::pin(async move {
+            connection.send_with_backpressure(&data).await
+        }));
+    }
+
+    /// Drives the write in flight, if there is one, until it has gone out
+    fn poll_send(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let Some(send) = &mut self.send else {
+            return Poll::Ready(Ok(()));
+        };
+        let result = ready!(send.as_mut().poll(cx));
+        self.send = None;
+        Poll::Ready(result.map_err(io::Error::other))
+    }
+}
+
+impl AsyncRead for P2PStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            if !this.unread.is_empty() {
+                let len = this.unread.len().min(buf.remaining());
+                buf.put_slice(&this.unread[..len]);
+                this.unread.advance(len);
+                return Poll::Ready(Ok(()));
+            }
+            if this.eof {
+                return Poll::Ready(Ok(()));
+            }
+
+            let connection = &this.connection;
+            let recv = this.recv.get_or_insert_with(|| {
+                let connection = connection.clone();
+                Box::pin(async move { connection.recv().await })
+            });
+            let message = ready!(recv.as_mut().poll(cx));
+            this.recv = None;
+
+            match message {
+                Some(Message::Binary(data)) if !data.is_empty() => this.unread = data,
+                Some(Message::Text(text)) if !text.is_empty() => this.unread = text.into(),
+                // The peer shut its end down, or the channel is gone
+                _ => this.eof = true,
+            }
+        }
+    }
+}
+
+impl AsyncWrite for P2PStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_send(cx))?;
+        if this.shut_down {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+        // An empty message would end the peer's stream
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        this.start_send(Bytes::copy_from_slice(buf));
+        if let Poll::Ready(Err(err)) = this.poll_send(cx) {
+            return Poll::Ready(Err(err));
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.get_mut().poll_send(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_send(cx))?;
+        if !this.shut_down {
+            this.shut_down = true;
+            this.start_send(Bytes::new());
+        }
+        this.poll_send(cx)
+    }
+}
+
+impl std::fmt::Debug for P2PStream {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&format!("P2PStream: {:?}", self.connection))
+    }
+}
@@ -0,0 +1,181 @@
+use crate::framing::FrameKind;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use std::collections::{BTreeMap, HashMap};
+
+/// How many messages of a topic are held waiting for an earlier one. Beyond it, unreliable
+/// channels give up on the earlier one, while on reliable ones, where nothing is lost, the peer
+/// is held to be misbehaving
+const MAX_HELD_PER_TOPIC: usize = 64;
+
+/// topic (2) + sequence (4) + kind (1)
+const HEADER_SIZE: usize = 7;
+
+/// Numbers the messages sent in each topic
+#[derive(Default)]
+pub(crate) struct TopicSequences(std::sync::Mutex<HashMap<u16, u32>>);
+
+impl TopicSequences {
+    /// Wraps `data` with its topic and the next sequence number of that topic
+    pub(crate) fn next(&self, topic: u16, kind: FrameKind, data: &[u8]) -> Bytes {
+        let sequence = {
+            let mut sequences = self
+                .0
+                .lock()
+                .expect("Unable to aquire topic sequences lock");
+            let next = sequences.entry(topic).or_default();
+            let sequence = *next;
+            *next = next.wrapping_add(1);
+            sequence
+        };
+
+        let mut message = BytesMut::with_capacity(HEADER_SIZE + data.len());
+        message.put_u16(topic);
+        message.put_u32(sequence);
+        message.put_u8(kind as u8);
+        message.put_slice(data);
+        message.freeze()
+    }
+}
+
+#[derive(Default)]
+struct Topic {
+    next: u32,
+    /// Messages which arrived ahead of one sent before them
+    held: BTreeMap<u32, (FrameKind, Bytes)>,
+}
+
+/// Puts the messages of each topic from the peer back in the order they were sent, without
+/// holding up one topic for a message missing from another
+pub(crate) struct TopicOrder {
+    topics: HashMap<u16, Topic>,
+    /// Whether the channel delivers every message, so a missing one is never given up on
+    reliable: bool,
+}
+
+impl TopicOrder {
+    /// Orders the topics of a channel which delivers every message if `reliable`
+    pub(crate) fn new(reliable: bool) -> Self {
+        Self {
+            topics: HashMap::new(),
+            reliable,
+        }
+    }
+
+    /// Takes in a message of a topic from the peer, returning the messages which are now ready
+    /// to be received, in order. Returns `None` once a reliable channel holds too many messages
+    /// of a topic waiting for an earlier one, after which the channel should be closed
+    pub(crate) fn receive(&mut self, mut message: Bytes) -> Option<Vec<(FrameKind, Bytes)>> {
+        if message.len() < HEADER_SIZE {
+            return Some(Vec::new());
+        }
+        let topic = message.get_u16();
+        let sequence = message.get_u32();
+        let Ok(kind) = FrameKind::try_from(message.get_u8()) else {
+            return Some(Vec::new());
+        };
+
+        let topic = self.topics.entry(topic).or_default();
+        // Messages from before the ones already received arrived too late
+        if sequence.wrapping_sub(topic.next) > u32::MAX / 2 {
+            return Some(Vec::new());
+        }
+        topic.held.insert(sequence, (kind, message));
+
+        if topic.held.len() > MAX_HELD_PER_TOPIC {
+            if self.reliable {
+                return None;
+            }
+            // Skips over a message which is most likely lost for good
+            if let Some(first) = topic.held.keys().next() {
+                topic.next = *first;
+            }
+        }
+
+        let mut ready = Vec::new();
+        while let Some(message) = topic.held.remove(&topic.next) {
+            ready.push(message);
+            topic.next = topic.next.wrapping_add(1);
+        }
+        Some(ready)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn payloads(ready: Vec<(FrameKind, Bytes)>) -> Vec<Bytes> {
+        ready.into_iter().map(|(_, data)| data).collect()
+    }
+
+    #[test]
+    fn test_reorders_within_a_topic() {
+        let sequences = TopicSequences::default();
+        let first = sequences.next(1, FrameKind::Binary, b"first");
+        let second = sequences.next(1, FrameKind::Text, b"second");
+        let other = sequences.next(2, FrameKind::Binary, b"other");
+
+        let mut order = TopicOrder::new(false);
+        assert!(order.receive(second.clone()) == Some(Vec::new()));
+        // Another topic isn't held up by the missing message
+        assert_eq!(
+            payloads(order.receive(other).unwrap()),
+            vec![Bytes::from_static(b"other")]
+        );
+        assert_eq!(
+            order.receive(first.clone()),
+            Some(vec![
+                (FrameKind::Binary, Bytes::from_static(b"first")),
+                (FrameKind::Text, Bytes::from_static(b"second")),
+            ])
+        );
+        // A duplicate is dropped
+        assert_eq!(order.receive(first), Some(Vec::new()));
+    }
+
+    #[test]
+    fn test_skips_lost_message() {
+        let sequences = TopicSequences::default();
+        let _lost = sequences.next(0, FrameKind::Binary, &[]);
+
+        let mut order = TopicOrder::new(false);
+        for byte in 0..MAX_HELD_PER_TOPIC as u8 {
+            assert_eq!(
+                order.receive(sequences.next(0, FrameKind::Binary, &[byte])),
+                Some(Vec::new())
+            );
+        }
+        let ready = order
+            .receive(sequences.next(0, FrameKind::Binary, &[64]))
+            .unwrap();
+        assert_eq!(ready.len(), MAX_HELD_PER_TOPIC + 1);
+        assert_eq!(payloads(ready)[0], Bytes::from_static(&[0]));
+        assert_eq!(
+            payloads(
+                order
+                    .receive(sequences.next(0, FrameKind::Binary, &[65]))
+                    .unwrap()
+            ),
+            vec![Bytes::from_static(&[65])]
+        );
+    }
+
+    #[test]
+    fn test_reliable_channels_never_skip() {
+        let sequences = TopicSequences::default();
+        let _missing = sequences.next(0, FrameKind::Binary, &[]);
+
+        let mut order = TopicOrder::new(true);
+        for byte in 0..MAX_HELD_PER_TOPIC as u8 {
+            assert_eq!(
+                order.receive(sequences.next(0, FrameKind::Binary, &[byte])),
+                Some(Vec::new())
+            );
+        }
+        // Nothing is lost on a reliable channel, so a peer leaving such a gap is misbehaving
+        assert_eq!(
+            order.receive(sequences.next(0, FrameKind::Binary, &[64])),
+            None
+        );
+    }
+}